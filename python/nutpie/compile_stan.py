@@ -4,6 +4,7 @@ from importlib.util import find_spec
 from pathlib import Path
 from typing import Any, Optional
 
+import numpy as np
 import pandas as pd
 from numpy.typing import NDArray
 
@@ -20,6 +21,8 @@ class CompiledStanModel(CompiledModel):
     model: Any
     model_name: Optional[str] = None
     _transform_adapt_args: dict | None = None
+    _inits: Any = None
+    _init_jitter: float = 0.0
 
     def with_data(self, *, seed=None, **updates):
         if self.data is None:
@@ -67,6 +70,8 @@ class CompiledStanModel(CompiledModel):
             library=self.library,
             dims=self.dims,
             model=model,
+            _inits=self._inits,
+            _init_jitter=self._init_jitter,
         )
 
     def with_coords(self, **coords):
@@ -88,10 +93,40 @@ class CompiledStanModel(CompiledModel):
     def with_transform_adapt(self, **kwargs):
         return replace(self, _transform_adapt_args=kwargs).with_data()
 
+    def with_inits(self, inits, jitter=0.0):
+        """Start the chains at the given values of the parameters.
+
+        Parameters
+        ----------
+        inits: dict or str or list
+            Values of the parameters as for `unconstrain`, used by all
+            chains, or a list with the values for each chain.
+        jitter: float, default=0.0
+            Move each coordinate of the initial points on the unconstrained
+            space by a uniform random value in `[-jitter, jitter]`.
+
+        Returns
+        -------
+        CompiledStanModel
+            A copy of the model that uses the initial values.
+        """
+        # Check the values now instead of when sampling
+        self._make_model(None).with_inits(inits, jitter)
+        return replace(self, _inits=inits, _init_jitter=jitter)
+
     def _make_model(self, init_mean):
+        if init_mean is not None:
+            raise ValueError(
+                "`init_mean` is not supported for Stan models. Use "
+                "`model.with_inits` or `InitStrategy.point` instead."
+            )
         if self.model is None:
-            return self.with_data().model
-        return self.model
+            model = self.with_data().model
+        else:
+            model = self.model
+        if self._inits is not None:
+            model = model.with_inits(self._inits, self._init_jitter)
+        return model
 
     def _make_sampler(self, settings, init_mean, cores, progress_type):
         model = self._make_model(init_mean)
@@ -102,6 +137,49 @@ class CompiledStanModel(CompiledModel):
             progress_type,
         )
 
+    def unconstrain(self, values):
+        """Map values of the parameters to the unconstrained space.
+
+        Parameters
+        ----------
+        values: dict or str
+            An array for each parameter, in the same layout as in the
+            trace, or a JSON string in the format of Stan data files.
+
+        Returns
+        -------
+        ndarray
+            The point on the unconstrained space, for example to initialize
+            the sampler with `InitStrategy.point`. To start at values of the
+            parameters, use `with_inits` instead.
+        """
+        return self._make_model(None).unconstrain(values)
+
+    def constrain(self, points, include_tp=False, include_gq=False, seed=None):
+        """Map points on the unconstrained space to the parameters.
+
+        Parameters
+        ----------
+        points: ndarray
+            A point on the unconstrained space, or an array with one point
+            per row.
+        include_tp: bool, default=False
+            Also return the transformed parameters.
+        include_gq: bool, default=False
+            Also return the generated quantities.
+        seed: int, optional
+            Seed of the random number generator of the generated
+            quantities.
+
+        Returns
+        -------
+        dict
+            An array for each variable, in the same layout as in the trace,
+            with a leading dimension for the points if `points` has one.
+        """
+        points = np.asarray(points, dtype=np.float64)
+        return self._make_model(None).constrain(points, include_tp, include_gq, seed)
+
     @property
     def n_dim(self):
         if self.model is None:
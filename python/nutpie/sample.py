@@ -1,6 +1,6 @@
 import os
 from dataclasses import dataclass
-from typing import Any, Literal, Optional, cast, overload
+from typing import Any, Callable, Literal, Optional, cast, overload
 
 import arviz
 import numpy as np
@@ -10,6 +10,28 @@ import pyarrow
 from nutpie import _lib  # type: ignore
 
 
+@dataclass(frozen=True)
+class MapEstimate:
+    """The posterior mode found by `CompiledModel.find_map`.
+
+    `values` holds the value of each variable of the model at the mode,
+    `unconstrained` the mode on the unconstrained space, and
+    `logp_history` and `grad_norm_history` the logp and the norm of its
+    gradient at the initial point and after each iteration.
+    `convergence` is why the optimizer stopped: `gradient`, `logp`,
+    `max_iters` or `line_search`.
+    """
+
+    values: dict[str, np.ndarray]
+    unconstrained: np.ndarray
+    logp: float
+    converged: bool
+    convergence: str
+    num_iters: int
+    logp_history: np.ndarray
+    grad_norm_history: np.ndarray
+
+
 @dataclass(frozen=True)
 class CompiledModel:
     dims: Optional[dict[str, tuple[str, ...]]]
@@ -35,9 +57,30 @@ class CompiledModel:
     def benchmark_logp(self, point, num_evals, cores):
         """Time how long the logp gradient evaluation takes.
 
-        # Parameters
+        The evaluations run on threads of the Rust backend without holding
+        the GIL, in the same way the sampler evaluates the model, so that the
+        timings of the numba, jax and Stan backends are comparable.
+
+        Parameters
+        ----------
+        point: ndarray
+            Point on the unconstrained parameter space at which the logp
+            and its gradient are evaluated.
+        num_evals: int
+            Number of evaluations on each thread.
+        cores: int or list of int
+            Number of threads that evaluate the model at the same time.
+            With a list, run one benchmark for each number of threads.
+
+        Returns
+        -------
+        pandas.DataFrame
+            The duration of each evaluation in seconds, with one row for
+            each thread of each benchmark and one column for each
+            evaluation.
         """
-        model = self._make_model(point)
+        point = np.ascontiguousarray(point, dtype=np.float64)
+        model = self._make_model(None)
         times = []
         if isinstance(cores, int):
             cores = [cores]
@@ -53,6 +96,311 @@ class CompiledModel:
             times.append(data)
         return pd.concat(times)
 
+    def check_gradient(self, point=None, eps=1e-6):
+        """Compare the gradient of the logp with central finite differences.
+
+        Parameters
+        ----------
+        point: ndarray, optional
+            Point on the unconstrained parameter space at which the
+            gradient is checked. Defaults to zeros.
+        eps: float, default=1e-6
+            Step size of the finite differences, relative to the absolute
+            value of each coordinate, but at least `eps`.
+
+        Returns
+        -------
+        nutpie._lib.GradientCheck
+            The logp, the analytic and numeric gradients and the relative
+            error `|analytic - numeric| / max(|analytic|, |numeric|, 1)` of
+            each coordinate, and its maximum `max_relative_error`.
+        """
+        if point is None:
+            point = np.zeros(self.n_dim)
+        point = np.ascontiguousarray(point, dtype=np.float64)
+        model = self._make_model(None)
+        return model.check_gradient(point, eps)
+
+    def logp_and_grad(self, points):
+        """Evaluate the logp and its gradient at many points.
+
+        The points are evaluated in parallel, without holding the GIL for
+        the Stan and numba backends.
+
+        Parameters
+        ----------
+        points: ndarray
+            Points on the unconstrained parameter space, with shape
+            `(num_points, n_dim)`.
+
+        Returns
+        -------
+        logp: ndarray
+            The logp at each point.
+        grad: ndarray
+            The gradient at each point, with the shape of `points`.
+        failed: ndarray
+            Boolean mask of the points where the logp could not be
+            evaluated, for example because it is not finite. Their logp and
+            gradient are `nan`.
+        """
+        points = np.asarray(points, dtype=np.float64)
+        if points.ndim != 2:
+            raise ValueError("`points` must have shape (num_points, n_dim)")
+        model = self._make_model(None)
+        return model.logp_and_grad(points)
+
+    def find_map(
+        self,
+        start=None,
+        *,
+        max_iters=1000,
+        history=6,
+        grad_tol=1e-8,
+        init_strategy=None,
+        seed=None,
+    ):
+        """Find the posterior mode with L-BFGS.
+
+        The optimizer maximizes the logp on the unconstrained space, with
+        the same logp and gradient functions the sampler uses. Because of
+        the Jacobian of the transformation, this is not the mode of the
+        constrained posterior for bounded parameters.
+
+        Parameters
+        ----------
+        start: ndarray, optional
+            Point on the unconstrained space to start at. By default, the
+            initial point is chosen by `init_strategy`.
+        max_iters: int, default=1000
+            Maximum number of iterations.
+        history: int, default=6
+            Number of iterations that L-BFGS uses to estimate the inverse
+            Hessian.
+        grad_tol: float, default=1e-8
+            Stop once the largest absolute value of the gradient is below
+            this value.
+        init_strategy: nutpie._lib.InitStrategy, optional
+            How to choose the initial point if `start` is not given, as in
+            `sample`.
+        seed: int, optional
+            Seed for the initial point.
+
+        Returns
+        -------
+        MapEstimate
+        """
+        if start is not None:
+            start = np.ascontiguousarray(start, dtype=np.float64)
+        model = self._make_model(None)
+        result = model.find_map(
+            start, max_iters, history, grad_tol, init_strategy, seed
+        )
+        batch = _as_record_batch(result.values)
+        values = {
+            name: col.values.to_numpy().reshape(tuple(self.shapes[name]))
+            for name, col in zip(batch.schema.names, batch.columns)
+        }
+        return MapEstimate(
+            values=values,
+            unconstrained=result.unconstrained,
+            logp=result.logp,
+            converged=result.converged,
+            convergence=result.convergence,
+            num_iters=result.num_iters,
+            logp_history=np.array(result.logp_history),
+            grad_norm_history=np.array(result.grad_norm_history),
+        )
+
+    def laplace(
+        self,
+        num_draws=1000,
+        start=None,
+        *,
+        eps=1e-5,
+        init_strategy=None,
+        seed=None,
+        return_raw=False,
+    ):
+        """Draw from the Laplace approximation around the posterior mode.
+
+        The mode is found with `find_map` on the unconstrained space, and
+        the Hessian of the logp at the mode with central finite
+        differences of the gradient. The draws of the normal approximation
+        with the inverse of the negative Hessian as covariance are expanded
+        into the variables of the model in the same way as the draws of
+        `sample`.
+
+        Parameters
+        ----------
+        num_draws: int, default=1000
+            Number of draws.
+        start: ndarray, optional
+            Point on the unconstrained space to start the optimizer at, as
+            in `find_map`.
+        eps: float, default=1e-5
+            Step size of the finite differences, relative to the absolute
+            value of each coordinate, but at least `eps`.
+        init_strategy: nutpie._lib.InitStrategy, optional
+            How to choose the initial point if `start` is not given.
+        seed: int, optional
+            Seed for the initial point and draws.
+        return_raw: bool, default=False
+            Return the `nutpie._lib.LaplaceResult`, which also holds the
+            mode, Hessian and covariance, instead of an arviz dataset.
+
+        Returns
+        -------
+        arviz.InferenceData
+            The draws as a single chain in the posterior, with their
+            `logp`, the log density `logq` under the approximation and the
+            log importance weight `log_weight` in the sample stats.
+        """
+        if start is not None:
+            start = np.ascontiguousarray(start, dtype=np.float64)
+        model = self._make_model(None)
+        result = model.laplace(num_draws, start, eps, init_strategy, seed)
+        if return_raw:
+            return result
+
+        trace = self._approximation_to_arviz(
+            [(result.trace, result.logp, result.logq, result.log_weight)]
+        )
+        trace.sample_stats.attrs["converged"] = int(result.converged)
+        return trace
+
+    def _approximation_to_arviz(self, chains):
+        """Convert draws of an approximation of the posterior to arviz.
+
+        `chains` contains the expanded draws and the `logp`, `logq` and
+        `log_weight` of each chain.
+        """
+        traces = [
+            (
+                draws,
+                pyarrow.RecordBatch.from_pydict(
+                    {"logp": logp, "logq": logq, "log_weight": log_weight}
+                ),
+            )
+            for draws, logp, logq, log_weight in chains
+        ]
+        return _trace_to_arviz(
+            traces,
+            0,
+            self.shapes,
+            dims={name: list(dim) for name, dim in self.dims.items()},
+            coords={name: pd.Index(vals) for name, vals in self.coords.items()},
+            save_warmup=False,
+        )
+
+    def pathfinder(
+        self,
+        num_paths=4,
+        num_draws=1000,
+        *,
+        max_iters=1000,
+        history=6,
+        num_elbo_draws=25,
+        init_strategy=None,
+        seed=None,
+        return_raw=False,
+    ):
+        """Approximate the posterior with Pathfinder.
+
+        Each path runs L-BFGS from an initial point towards the mode,
+        and draws from the normal approximation along the optimization
+        path with the largest estimate of the ELBO.
+
+        Parameters
+        ----------
+        num_paths: int, default=4
+            Number of independent paths. They run in parallel.
+        num_draws: int, default=1000
+            Number of draws of each path.
+        max_iters: int, default=1000
+            Maximum number of L-BFGS iterations of each path.
+        history: int, default=6
+            Number of iterations that L-BFGS uses to estimate the inverse
+            Hessian.
+        num_elbo_draws: int, default=25
+            Number of draws to estimate the ELBO of each approximation.
+        init_strategy: nutpie._lib.InitStrategy, optional
+            How the paths find their initial points, as in `sample`.
+        seed: int, optional
+            Seed for the initial points and draws.
+        return_raw: bool, default=False
+            Return the `nutpie._lib.PathfinderResult` instead of an
+            arviz dataset.
+
+        Returns
+        -------
+        arviz.InferenceData
+            The draws with one chain per path in the posterior, and their
+            `logp`, the log density `logq` under the approximation and the
+            log importance weight `log_weight` in the sample stats. The
+            attributes `elbo`, `num_iters` and `converged` of the sample
+            stats describe each path.
+        """
+        settings = _lib.PathfinderSettings(
+            max_iters, history, num_elbo_draws, num_draws
+        )
+        model = self._make_model(None)
+        result = model.pathfinder(num_paths, settings, init_strategy, seed)
+        if return_raw:
+            return result
+
+        logp, logq, log_weight = result.logp, result.logq, result.log_weight
+        trace = self._approximation_to_arviz(
+            [
+                (draws, logp[path], logq[path], log_weight[path])
+                for path, draws in enumerate(result.trace)
+            ]
+        )
+        trace.sample_stats.attrs["elbo"] = result.elbo
+        trace.sample_stats.attrs["num_iters"] = result.num_iters
+        trace.sample_stats.attrs["converged"] = [int(c) for c in result.converged]
+        return trace
+
+
+def _read_trace_file(path):
+    path = os.fspath(path)
+    if path.endswith(".parquet"):
+        import pyarrow.parquet
+
+        table = pyarrow.parquet.read_table(path, memory_map=True)
+    else:
+        import pyarrow.ipc
+
+        table = pyarrow.ipc.open_file(pyarrow.memory_map(path)).read_all()
+    # The schema metadata holds the provenance of the trace.
+    batch = pyarrow.RecordBatch.from_struct_array(table.to_struct_array().combine_chunks())
+    return batch.replace_schema_metadata(table.schema.metadata)
+
+
+def _load_trace_files(results):
+    """Read the traces of samplers that write their draws to disk."""
+    loaded = []
+    for draws, stats in results:
+        if isinstance(draws, (str, os.PathLike)):
+            draws = _read_trace_file(draws)
+            stats = _read_trace_file(stats)
+        loaded.append((draws, stats))
+    return loaded
+
+
+def _as_record_batch(data):
+    if isinstance(data, pyarrow.RecordBatch):
+        return data
+    return pyarrow.RecordBatch.from_struct_array(data)
+
+
+def _trace_provenance(batch):
+    """The JSON record of the settings and model that produced a trace."""
+    metadata = batch.schema.metadata or {}
+    if b"nutpie" not in metadata:
+        return None
+    return metadata[b"nutpie"].decode()
+
 
 def _trace_to_arviz(traces, n_tune, shapes, **kwargs):
     n_chains = len(traces)
@@ -65,8 +413,8 @@ def _trace_to_arviz(traces, n_tune, shapes, **kwargs):
     draw_batches = []
     stats_batches = []
     for draws, stats in traces:
-        draw_batches.append(pyarrow.RecordBatch.from_struct_array(draws))
-        stats_batches.append(pyarrow.RecordBatch.from_struct_array(stats))
+        draw_batches.append(_as_record_batch(draws))
+        stats_batches.append(_as_record_batch(stats))
 
     table = pyarrow.Table.from_batches(draw_batches)
     table_stats = pyarrow.Table.from_batches(stats_batches)
@@ -116,6 +464,11 @@ def _trace_to_arviz(traces, n_tune, shapes, **kwargs):
             stats_dict[name] = data[:, n_tune:]
             stats_dict_tune[name] = data[:, :n_tune]
 
+    if draw_batches:
+        provenance = _trace_provenance(draw_batches[0])
+        if provenance is not None:
+            kwargs.setdefault("attrs", {}).setdefault("nutpie", provenance)
+
     return arviz.from_dict(
         data_dict,
         sample_stats=stats_dict,
@@ -125,6 +478,25 @@ def _trace_to_arviz(traces, n_tune, shapes, **kwargs):
     )
 
 
+def _add_init_report(trace, init_report):
+    """Add how each chain found its initial point to the sample stats.
+
+    `init_attempts` is the number of candidate points a chain tried, and
+    `init_failures` why the failed ones were rejected.
+    """
+    if not init_report or "sample_stats" not in trace.groups():
+        return
+    n_chains = trace.sample_stats.sizes["chain"]
+    attempts = np.zeros(n_chains, dtype=np.int64)
+    failures = [""] * n_chains
+    for chain, (num_attempts, reasons) in init_report.items():
+        if chain < n_chains:
+            attempts[chain] = num_attempts
+            failures[chain] = reasons
+    trace.sample_stats["init_attempts"] = ("chain", attempts)
+    trace.sample_stats["init_failures"] = ("chain", np.array(failures, dtype=str))
+
+
 _progress_style = """
 <style>
     :root {
@@ -228,6 +600,9 @@ _progress_template = """
     <p>
         Estimated Time to Completion:
         <span id="eta">{{ time_remaining_estimate }}</span>
+        {% if time_remaining_lower %}
+            ({{ time_remaining_lower }} to {{ time_remaining_upper }})
+        {% endif %}
     </p>
 
     <progress
@@ -383,6 +758,8 @@ class _BackgroundSampler:
         progress_template=None,
         progress_style=None,
         progress_rate=100,
+        progress_callback=None,
+        progress_file=None,
     ):
         self._settings = settings
         self._compiled_model = compiled_model
@@ -391,7 +768,15 @@ class _BackgroundSampler:
 
         self._html = None
 
-        if not progress_bar:
+        if progress_callback is not None:
+            progress_type = _lib.ProgressType.structured_callback(
+                progress_rate, cores, progress_callback
+            )
+        elif progress_file is not None:
+            progress_type = _lib.ProgressType.json_lines(
+                os.fspath(progress_file), progress_rate, cores
+            )
+        elif not progress_bar:
             progress_type = _lib.ProgressType.none()
 
         elif in_notebook():
@@ -439,9 +824,25 @@ class _BackgroundSampler:
             progress_type = _lib.ProgressType.template_callback(
                 progress_rate, progress_template, cores, callback
             )
+        elif progress_bar == "chains":
+            progress_type = _lib.ProgressType.indicatif_chains(progress_rate, cores)
         else:
             progress_type = _lib.ProgressType.indicatif(progress_rate)
 
+        self._cores = cores
+        self._progress_type = progress_type
+
+        storage = settings.trace_storage
+        if storage.kind != "memory":
+            settings.trace_storage = storage.with_metadata(
+                {name: list(shape) for name, shape in compiled_model.shapes.items()},
+                self._dims(),
+                {
+                    name: np.asarray(vals).tolist()
+                    for name, vals in compiled_model.coords.items()
+                },
+            )
+
         self._sampler = compiled_model._make_sampler(
             settings,
             init_mean,
@@ -463,7 +864,7 @@ class _BackgroundSampler:
         results = self._sampler.extract_results()
         return self._extract(results)
 
-    def _extract(self, results):
+    def _dims(self):
         dims = {name: list(dim) for name, dim in self._compiled_model.dims.items()}
         dims["mass_matrix_inv"] = ["unconstrained_parameter"]
         dims["gradient"] = ["unconstrained_parameter"]
@@ -474,11 +875,42 @@ class _BackgroundSampler:
         dims["divergence_momentum"] = ["unconstrained_parameter"]
         dims["transformed_gradient"] = ["unconstrained_parameter"]
         dims["transformed_position"] = ["unconstrained_parameter"]
+        return dims
+
+    def _extract(self, results):
+        if isinstance(results, (str, os.PathLike)):
+            # The trace was written to a zarr store.
+            if self._return_raw_trace:
+                return results
+            trace = arviz.from_zarr(os.fspath(results))
+            if not self._save_warmup:
+                for group in ["warmup_posterior", "warmup_sample_stats"]:
+                    if group in trace.groups():
+                        delattr(trace, group)
+            return trace
+
+        dims = self._dims()
+        attrs = {}
+        if any(isinstance(draws, (str, os.PathLike)) for draws, _ in results):
+            # The files only record the provenance of the run as it started.
+            provenance = self._sampler.provenance_json
+            if provenance is not None:
+                attrs["nutpie"] = provenance
+        results = _load_trace_files(results)
+
+        if self._sampler.stop_reason is not None:
+            # Chains that stopped early can have a few more draws than
+            # others, we only keep the draws that all chains have.
+            length = min(len(draws) for draws, _ in results)
+            results = [
+                (draws.slice(0, length), stats.slice(0, length))
+                for draws, stats in results
+            ]
 
         if self._return_raw_trace:
             return results
         else:
-            return _trace_to_arviz(
+            trace = _trace_to_arviz(
                 results,
                 self._settings.num_tune,
                 self._compiled_model.shapes,
@@ -488,13 +920,86 @@ class _BackgroundSampler:
                     for name, vals in self._compiled_model.coords.items()
                 },
                 save_warmup=self._save_warmup,
+                attrs=attrs,
             )
+            _add_init_report(trace, self._sampler.init_report)
+            return trace
 
     def inspect(self):
         """Get a copy of the current state of the trace"""
         results = self._sampler.inspect()
         return self._extract(results)
 
+    def fetch_new_draws(self):
+        """Get the draws and sampler stats since the previous call.
+
+        Returns a dict that maps the chain index to a tuple of
+        `pyarrow.RecordBatch` objects with the new draws and sampler
+        stats of that chain. Chains without new draws are missing. Warmup
+        draws are included, their `draw` stat is below `tune`.
+        """
+        new_draws = {}
+        for chain, draws, stats in self._sampler.fetch_new_draws():
+            if len(draws) == 0:
+                continue
+            new_draws[chain] = (
+                pyarrow.RecordBatch.from_struct_array(draws),
+                pyarrow.RecordBatch.from_struct_array(stats),
+            )
+        return new_draws
+
+    @property
+    def stop_reason(self):
+        """Why sampling stopped before `draws`, see `stopping_rule`.
+
+        A dict with the reason, the number of posterior draws of the
+        shortest chain, and its minimum bulk ESS and maximum R-hat, or
+        `None` if sampling did not stop early.
+        """
+        return self._sampler.stop_reason
+
+    def diagnostics(self, quantiles=(0.05, 0.5, 0.95)):
+        """Convergence diagnostics of the posterior draws so far.
+
+        Returns a `pyarrow.Table` with one row per scalar element of each
+        variable. `index` is the position of the element in the flattened
+        variable. The columns contain the mean, standard deviation, the
+        requested quantiles (`q5`, `q50`, ...), the Monte Carlo standard
+        errors of mean and standard deviation, bulk and tail ESS and the
+        rank-normalized split R-hat, computed as in arviz.
+        """
+        return self._sampler.diagnostics(list(quantiles))
+
+    def checkpoint(self, path):
+        """Write the draws so far and the state of all chains to `path`.
+
+        Sampling can later be continued with `_lib.PySampler.from_checkpoint`,
+        using the same settings and model. nuts-rs does not let us save its
+        random number generators, so the continued chains use new random
+        numbers. The result is statistically equivalent to an uninterrupted
+        run with the same seed, but not identical to it.
+        """
+        self._sampler.checkpoint(path)
+
+    def adaptation_state(self):
+        """Get the step size and mass matrix that each chain has reached.
+
+        Pass the result as `adaptation_state` to `sample` to start a new
+        run, for example of the same model with new data, with a shorter
+        or no warmup. `nutpie._lib.PyAdaptationState.from_trace` reads the
+        same state from a finished trace.
+        """
+        return self._sampler.adaptation_state()
+
+    def extend(self, draws):
+        """Sample `draws` more posterior draws in each chain of a finished run.
+
+        The chains continue with the step size and mass matrix they ended
+        with, without any further tuning. Call `wait` to get the trace of
+        the whole run, including the new draws.
+        """
+        self._sampler.extend(draws, self._cores, self._progress_type)
+
     def pause(self):
         """Pause the sampler."""
         self._sampler.pause()
@@ -535,14 +1040,17 @@ def sample(
     cores: int | None = None,
     seed: int | None = None,
     save_warmup: bool = True,
-    progress_bar: bool = True,
+    progress_bar: bool | Literal["chains"] = True,
     low_rank_modified_mass_matrix: bool = False,
     transform_adapt: bool = False,
+    dense_mass_matrix: bool = False,
     init_mean: np.ndarray | None = None,
     return_raw_trace: bool = False,
     progress_template: str | None = None,
     progress_style: str | None = None,
     progress_rate: int = 100,
+    progress_callback: Callable[[Any], None] | None = None,
+    progress_file: str | os.PathLike | None = None,
 ) -> arviz.InferenceData: ...
 
 
@@ -556,15 +1064,18 @@ def sample(
     cores: int | None = None,
     seed: int | None = None,
     save_warmup: bool = True,
-    progress_bar: bool = True,
+    progress_bar: bool | Literal["chains"] = True,
     low_rank_modified_mass_matrix: bool = False,
     transform_adapt: bool = False,
+    dense_mass_matrix: bool = False,
     init_mean: np.ndarray | None = None,
     return_raw_trace: bool = False,
     blocking: Literal[True],
     progress_template: str | None = None,
     progress_style: str | None = None,
     progress_rate: int = 100,
+    progress_callback: Callable[[Any], None] | None = None,
+    progress_file: str | os.PathLike | None = None,
     **kwargs,
 ) -> arviz.InferenceData: ...
 
@@ -579,15 +1090,18 @@ def sample(
     cores: int | None = None,
     seed: int | None = None,
     save_warmup: bool = True,
-    progress_bar: bool = True,
+    progress_bar: bool | Literal["chains"] = True,
     low_rank_modified_mass_matrix: bool = False,
     transform_adapt: bool = False,
+    dense_mass_matrix: bool = False,
     init_mean: np.ndarray | None = None,
     return_raw_trace: bool = False,
     blocking: Literal[False],
     progress_template: str | None = None,
     progress_style: str | None = None,
     progress_rate: int = 100,
+    progress_callback: Callable[[Any], None] | None = None,
+    progress_file: str | os.PathLike | None = None,
     **kwargs,
 ) -> _BackgroundSampler: ...
 
@@ -601,15 +1115,18 @@ def sample(
     cores: int | None = None,
     seed: int | None = None,
     save_warmup: bool = True,
-    progress_bar: bool = True,
+    progress_bar: bool | Literal["chains"] = True,
     low_rank_modified_mass_matrix: bool = False,
     transform_adapt: bool = False,
+    dense_mass_matrix: bool = False,
     init_mean: np.ndarray | None = None,
     return_raw_trace: bool = False,
     blocking: bool = True,
     progress_template: str | None = None,
     progress_style: str | None = None,
     progress_rate: int = 100,
+    progress_callback: Callable[[Any], None] | None = None,
+    progress_file: str | os.PathLike | None = None,
     **kwargs,
 ) -> arviz.InferenceData | _BackgroundSampler:
     """Sample the posterior distribution for a compiled model.
@@ -639,12 +1156,15 @@ def sample(
         experimental, as the implementation is very wastefull
         with memory, and a better interface will need breaking
         changes.
-    progress_bar: bool
-        If true, display the progress bar (default)
+    progress_bar: bool or "chains"
+        If true, display the progress bar (default). In a terminal,
+        `"chains"` shows a bar for each chain with its phase, number of
+        divergences, step size and leapfrog steps per draw, in addition
+        to a bar for all chains with the estimated remaining time.
     init_mean: ndarray
         Initialize the chains using jittered values around this
-        point on the transformed parameter space. Defaults to
-        zeros.
+        point on the transformed parameter space. Not supported for
+        Stan models, use `with_inits` or `InitStrategy.point` instead.
     store_unconstrained: bool
         If True, store each draw in the unconstrained (transformed)
         space in the sample stats.
@@ -665,7 +1185,15 @@ def sample(
         be 2 ^ maxdepth.
     return_raw_trace: bool, default=False
         Return the raw trace object (an apache arrow structure)
-        instead of converting to arviz.
+        instead of converting to arviz. For traces in memory or in Arrow
+        or Parquet files this is a list of `(draws, stats)` record batches
+        per chain. Their schema metadata contains a JSON record of all
+        settings, the nutpie version, model backend, number of cores and
+        timings of the run under the key `nutpie`, which is also stored in
+        the `nutpie` attribute of the arviz groups. The schemas of the
+        files record the timings as of when each file was written. Zarr
+        stores keep the final record in the `nutpie` attribute of their
+        root and groups.
     use_grad_based_mass_matrix: bool, default=True
         Use a mass matrix estimate that is based on draw and gradient
         variance. Set to `False` to get mass matrix adaptation more
@@ -678,6 +1206,21 @@ def sample(
         for the progress bar (eg CSS).
     progress_rate: int, default=500
         Rate in ms at which the progress should be updated.
+    progress_callback: Callable, optional
+        Instead of displaying a progress bar, call this function with a
+        `nutpie._lib.SamplerProgress` on each progress update. It holds
+        the elapsed and estimated remaining time in seconds, the bounds
+        `time_remaining_lower` and `time_remaining_upper` of a 90% range
+        of the remaining time, and a
+        `nutpie._lib.ChainProgressRecord` for each chain, with its draws,
+        divergences, divergent draw indices, step size and number of
+        gradient evaluations.
+    progress_file: str or PathLike, optional
+        Instead of displaying a progress bar, append the progress as one
+        JSON object per line to this file every `progress_rate` ms, for
+        example to follow batch jobs with `tail -f`. Each line contains
+        the fields of `progress_callback`, a unix `timestamp` and an
+        `event` that is `started`, `progress` or `finished`.
     low_rank_modified_mass_matrix: bool, default=False
         Allow adaptation to some posterior correlations using
         a low-rank updated mass matrix. This is *experimental*
@@ -694,6 +1237,83 @@ def sample(
     transform_adapt: bool, default=False
         Use the experimental transform adaptation algorithm
         during tuning.
+    dense_mass_matrix: bool, default=False
+        Adapt a dense mass matrix from the covariance of the warmup
+        draws, in windows that double in length like in Stan. This
+        can help for models with strong posterior correlations and up
+        to a few hundred parameters, but each leapfrog step costs
+        time quadratic in the number of parameters.
+    adaptation_state: nutpie._lib.PyAdaptationState, optional
+        Start the chains with the step size and mass matrix of
+        a previous run, as returned by `adaptation_state` of a
+        non-blocking sampler or by
+        `nutpie._lib.PyAdaptationState.from_trace` for a finished
+        trace. Set `tune` to shorten or skip the warmup. Not supported
+        with `transform_adapt`, or with the state of a run that used it.
+    stopping_rule: nutpie._lib.StoppingRule, optional
+        Stop all chains once the posterior draws converged, for example
+        `nutpie._lib.StoppingRule(min_ess=1000, max_rhat=1.01)`. The
+        diagnostics are evaluated every `check_every` draws while the
+        chains sample, optionally only for some `var_names`. `draws` is
+        then only an upper limit.
+        The reason is available as `stop_reason` of a non-blocking
+        sampler, and for traces in memory also in the `nutpie` attribute
+        of the trace.
+    check_gradient: bool or float, optional
+        Before sampling, compare the gradient of the logp at the initial
+        point of each chain with central finite differences, and fail if
+        the relative error of a coordinate is larger than this value, or
+        `1e-3` if `True`. This costs two logp evaluations per parameter
+        for each chain. See also `CompiledModel.check_gradient`.
+    init_strategy: nutpie._lib.InitStrategy, optional
+        How the chains find their initial points on the unconstrained
+        space: `InitStrategy.model()` (the default) uses the initial points
+        of the model, `InitStrategy.uniform(radius)` draws uniformly from
+        `[-radius, radius]`, `InitStrategy.point(point, jitter)` starts at
+        a point plus uniform jitter, and `InitStrategy.callback(func)`
+        calls `func(seed)`, for example to draw from the prior.
+    init_retries: int, default=100
+        How often a chain draws a new initial point if the logp or its
+        gradient is not finite at the previous one. Chains whose initial
+        point does not change between attempts, like a point without
+        jitter, fail after the first attempt. The number of attempts
+        and the reasons of the failed ones are stored as `init_attempts`
+        and `init_failures` in the sample stats of each chain.
+    pathfinder: bool or nutpie._lib.PathfinderSettings, optional
+        Run one Pathfinder path per chain before warmup, and start each
+        chain at a draw of its path, with the variance of the normal
+        approximation of the path as initial mass matrix. With
+        `low_rank_modified_mass_matrix`, the mass matrix also includes
+        the low-rank part of the approximation, and with
+        `dense_mass_matrix` it is the full covariance of the
+        approximation. `True` uses the default
+        settings. Not supported with `transform_adapt` or
+        `adaptation_state`. See also `CompiledModel.pathfinder`.
+    mass_matrix: nutpie._lib.MassMatrix or numpy.ndarray, optional
+        Start all chains with this inverse mass matrix on the
+        unconstrained space, for example to reproduce a published run.
+        A 1d array is the diagonal and a 2d array the full inverse mass
+        matrix. `nutpie._lib.MassMatrix.low_rank(diag, vectors, values)`
+        gives a low-rank modified mass matrix. Unless `adapt=False`, it
+        is still adapted during warmup.
+    step_size: float, optional
+        Start all chains with this step size, instead of searching for
+        one at the initial point.
+    adapt: bool, default=True
+        Adapt the step size and mass matrix during warmup. With
+        `adapt=False` the chains keep their initial step size and mass
+        matrix, and the `tune` warmup draws only serve as burn-in.
+        Together with `mass_matrix` and `step_size` this runs NUTS with
+        a fixed metric, which is useful for debugging.
+    trace_storage: nutpie._lib.TraceStorage, optional
+        Write the draws of each chain to Arrow IPC or Parquet files in
+        a directory while sampling, instead of keeping them in memory,
+        for example `nutpie._lib.TraceStorage.arrow("trace")`. The
+        files are memory mapped when the results are converted. With
+        `nutpie._lib.TraceStorage.zarr("trace.zarr")` the trace is
+        written to a Zarr store in the layout of ArviZ, and the result
+        is read from that store. `return_raw_trace` then returns the
+        path of the store.
     **kwargs
         Pass additional arguments to nutpie._lib.PySamplerArgs
 
@@ -703,15 +1323,18 @@ def sample(
         An ArviZ ``InferenceData`` object that contains the samples.
     """
 
-    if low_rank_modified_mass_matrix and transform_adapt:
+    if low_rank_modified_mass_matrix + transform_adapt + dense_mass_matrix > 1:
         raise ValueError(
-            "Specify only one of `low_rank_modified_mass_matrix` and `transform_adapt`"
+            "Specify only one of `low_rank_modified_mass_matrix`, "
+            "`transform_adapt` and `dense_mass_matrix`"
         )
 
     if low_rank_modified_mass_matrix:
         settings = _lib.PyNutsSettings.LowRank(seed)
     elif transform_adapt:
         settings = _lib.PyNutsSettings.Transform(seed)
+    elif dense_mass_matrix:
+        settings = _lib.PyNutsSettings.Dense(seed)
     else:
         settings = _lib.PyNutsSettings.Diag(seed)
 
@@ -736,9 +1359,6 @@ def sample(
         else:
             cores = min(chains, cast(int, available))
 
-    if init_mean is None:
-        init_mean = np.zeros(compiled_model.n_dim)
-
     sampler = _BackgroundSampler(
         compiled_model,
         settings,
@@ -750,6 +1370,8 @@ def sample(
         progress_template=progress_template,
         progress_style=progress_style,
         progress_rate=progress_rate,
+        progress_callback=progress_callback,
+        progress_file=progress_file,
     )
 
     if not blocking:
@@ -1,3 +1,4 @@
+import base64
 from functools import partial
 from importlib.util import find_spec
 from typing import Callable
@@ -7,6 +8,7 @@ if find_spec("flowjax") is None:
         "The 'flowjax' package is required to use normalizing flow adaptation."
     )
 
+import cloudpickle
 from flowjax import bijections
 from jaxtyping import ArrayLike, PyTree
 import numpy as np
@@ -543,6 +545,32 @@ class TransformAdapter:
     def transformation_id(self):
         return self.index
 
+    def get_state(self):
+        """The state of the adaptation, as a string for checkpoints.
+
+        The flow contains functions that the standard pickle module
+        cannot serialize, so we use cloudpickle.
+        """
+        state = {
+            "bijection": self._bijection,
+            "opt_state": self._opt_state,
+            "index": self.index,
+            "layers": self._layers,
+            "count_trace": self._count_trace,
+            "last_extend_dct": self._last_extend_dct,
+        }
+        return base64.b64encode(cloudpickle.dumps(state)).decode("ascii")
+
+    def set_state(self, state):
+        """Continue the adaptation from a state of `get_state`."""
+        state = cloudpickle.loads(base64.b64decode(state))
+        self._bijection = state["bijection"]
+        self._opt_state = state["opt_state"]
+        self.index = state["index"]
+        self._layers = state["layers"]
+        self._count_trace = state["count_trace"]
+        self._last_extend_dct = state["last_extend_dct"]
+
     def update(self, seed, positions, gradients, logps):
         self.index += 1
         if self._verbose:
@@ -0,0 +1,70 @@
+//! Time logp and gradient evaluations of a model.
+//!
+//! This works on the `Math` of a model, so that the numbers are comparable
+//! between backends: they include the overhead of calling into numba, jax or
+//! Stan that the sampler also pays for each gradient evaluation.
+
+use std::{
+    sync::Barrier,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use nuts_rs::{Math, Model};
+
+/// Evaluate the logp and its gradient at `point` `evals` times on each of
+/// `cores` threads at the same time.
+///
+/// Returns the duration of each evaluation for each thread.
+pub(crate) fn benchmark_logp<M: Model>(
+    model: &M,
+    point: &[f64],
+    cores: usize,
+    evals: usize,
+) -> Result<Vec<Vec<Duration>>> {
+    if cores == 0 {
+        bail!("Benchmark needs at least one core");
+    }
+    let barrier = Barrier::new(cores);
+
+    thread::scope(|scope| {
+        let threads: Vec<_> = (0..cores)
+            .map(|_| {
+                scope.spawn(|| {
+                    let math = model.math();
+                    // All threads must reach the barrier, even if they
+                    // can not evaluate the model.
+                    barrier.wait();
+                    let mut math = math.context("Could not create the model")?;
+                    if math.dim() != point.len() {
+                        bail!(
+                            "Point has length {}, but the model has {} parameters",
+                            point.len(),
+                            math.dim()
+                        );
+                    }
+                    let mut gradient = vec![0f64; point.len()];
+                    (0..evals)
+                        .map(|_| {
+                            let start = Instant::now();
+                            math.logp(point, &mut gradient).map_err(|err| {
+                                anyhow!("Could not evaluate the logp at the point: {:?}", err)
+                            })?;
+                            Ok(start.elapsed())
+                        })
+                        .collect()
+                })
+            })
+            .collect();
+
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .map_err(|_| anyhow!("Benchmark thread panicked"))?
+            })
+            .collect()
+    })
+}
@@ -0,0 +1,939 @@
+//! Checkpoints of partially finished sampler runs.
+//!
+//! A checkpoint is an Arrow IPC file with one record batch per chain and
+//! the columns `draws` and `stats`, holding the trace so far. The state
+//! we need to continue a chain (last position, step size and mass matrix)
+//! is stored as JSON in the schema metadata.
+//!
+//! Low-rank and dense mass matrices are adapted by our `MetricModel`, which
+//! records the whole metric, and the normalizing flows of transform
+//! adaptation are saved with the `get_state` method of their Python adapter.
+//!
+//! nuts-rs does not give us access to the random number generator or the
+//! internal state of the warmup of a chain, so a continued run is only
+//! statistically equivalent to an uninterrupted one, not bit-for-bit
+//! identical: The continued chains get a new random stream, which only
+//! depends on the checkpoint, and chains that were still in warmup restart
+//! the adaptation from the checkpointed metric or flow.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    ops::Range,
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use arrow::{
+    array::{
+        new_null_array, Array, ArrayRef, AsArray, FixedSizeListArray, RecordBatch, StructArray,
+        UInt64Array,
+    },
+    compute::concat,
+    datatypes::{DataType, Field, Fields, Float64Type, Schema},
+    ipc::{reader::FileReader, writer::FileWriter},
+};
+use nuts_rs::{ChainOutput, Trace};
+use serde::{ser::Error as _, Deserialize, Serialize};
+
+use crate::{
+    metric::{ChainMetric, LowRankMetric, MetricOptions},
+    provenance::json_sha256,
+    reporting::INV_MASS_STATS,
+    tracking::ChainRecord,
+    wrapper::PyTransformAdapt,
+};
+
+const METADATA_KEY: &str = "nutpie.checkpoint";
+const FORMAT_VERSION: u64 = 1;
+
+/// The settings that determine the shape of the trace of a run.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct RunShape {
+    pub(crate) variant: String,
+    pub(crate) num_tune: u64,
+    pub(crate) num_draws: u64,
+    pub(crate) num_chains: usize,
+    pub(crate) seed: u64,
+}
+
+/// Everything we need to continue a single chain.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ChainState {
+    pub(crate) chain: u64,
+    pub(crate) num_draws: u64,
+    pub(crate) position: Option<Vec<f64>>,
+    pub(crate) step_size: Option<f64>,
+    pub(crate) inv_mass: Option<Vec<f64>>,
+    /// Cholesky factor of the inverse mass matrix of chains with a dense
+    /// metric.
+    #[serde(default)]
+    pub(crate) dense_factor: Option<Vec<f64>>,
+    /// The low-rank metric of chains that use one.
+    #[serde(default)]
+    pub(crate) low_rank: Option<LowRankState>,
+    /// The state of the Python adapter of chains with transform
+    /// adaptation, see `PyTransformAdapt::state`.
+    #[serde(default)]
+    pub(crate) transform_state: Option<String>,
+    #[serde(default)]
+    pub(crate) metric_source: MetricSource,
+}
+
+/// The inverse mass matrix `D^(1/2) (I + U (diag(values) - I) U^T) D^(1/2)`
+/// with the diagonal `diag` of `D` and the columns `vectors` of `U`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct LowRankState {
+    pub(crate) diag: Vec<f64>,
+    pub(crate) vectors: Vec<Vec<f64>>,
+    pub(crate) values: Vec<f64>,
+}
+
+/// Where the metric in a `ChainState` comes from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MetricSource {
+    /// The chain did not make a draw yet.
+    #[default]
+    None,
+    /// The `MetricModel` of the chain, which tells us the whole metric.
+    Sampler,
+    /// The diagonal mass matrix in the sampler stats of nuts-rs.
+    Stats,
+}
+
+impl ChainState {
+    /// The step size and metric a chain ended with, from the sampler
+    /// `stats` of its draws so far and its record.
+    fn from_stats(chain: u64, stats: &ArrayRef, record: &ChainRecord) -> Self {
+        let num_draws = stats.len();
+        let last_stat = |name: &str| {
+            let stats = stats.as_struct_opt()?;
+            let column = stats.column_by_name(name)?;
+            (num_draws > 0).then(|| column.slice(num_draws - 1, 1))
+        };
+
+        let step_size = last_stat("step_size").and_then(|col| {
+            col.as_primitive_opt::<Float64Type>()
+                .map(|col| col.value(0))
+        });
+
+        // nuts-rs stores its diagonal mass matrix in the sampler stats,
+        // which `Reporting` may have removed from the trace already.
+        let stats_inv_mass = || {
+            let col = match &record.stats_inv_mass {
+                Some(col) if col.len() >= num_draws && num_draws > 0 => col.slice(num_draws - 1, 1),
+                _ => last_stat(INV_MASS_STATS)?,
+            };
+            let col = col.as_any().downcast_ref::<FixedSizeListArray>()?;
+            let values = col.value(0);
+            Some(values.as_primitive_opt::<Float64Type>()?.values().to_vec())
+        };
+        let (inv_mass, metric_source) = if let Some(inv_mass) = &record.inv_mass {
+            (Some(inv_mass.to_vec()), MetricSource::Sampler)
+        } else if let Some(inv_mass) = stats_inv_mass() {
+            (Some(inv_mass), MetricSource::Stats)
+        } else {
+            (None, MetricSource::None)
+        };
+
+        let metric = record.metric.as_ref();
+        let dense_factor = metric
+            .and_then(|metric| metric.dense.as_ref())
+            .map(|factor| factor.to_vec());
+        let low_rank = metric.and_then(|metric| {
+            let low_rank = metric.low_rank.as_ref()?;
+            Some(LowRankState {
+                diag: metric.inv_mass.to_vec(),
+                vectors: low_rank.vectors.iter().map(|u| u.to_vec()).collect(),
+                values: low_rank.values.to_vec(),
+            })
+        });
+
+        Self {
+            chain,
+            num_draws: num_draws as u64,
+            position: None,
+            step_size,
+            inv_mass,
+            dense_factor,
+            low_rank,
+            transform_state: None,
+            metric_source,
+        }
+    }
+
+    /// The metric of the chain, which starts with the step size of the
+    /// chain.
+    fn metric(&self, adapt: bool) -> Option<ChainMetric> {
+        let (inv_mass, low_rank) = match &self.low_rank {
+            Some(low_rank) => (
+                &low_rank.diag,
+                Some(LowRankMetric {
+                    vectors: low_rank
+                        .vectors
+                        .iter()
+                        .map(|u| u.as_slice().into())
+                        .collect(),
+                    values: low_rank.values.as_slice().into(),
+                }),
+            ),
+            None => (self.inv_mass.as_ref()?, None),
+        };
+        Some(ChainMetric {
+            inv_mass: inv_mass.as_slice().into(),
+            low_rank,
+            dense: self
+                .dense_factor
+                .as_ref()
+                .map(|factor| factor.as_slice().into()),
+            adapt,
+            step_size: self
+                .step_size
+                .filter(|step| step.is_finite() && *step > 0f64),
+        })
+    }
+
+    /// Make sure that we know the metric of the chain, which was adapted
+    /// in a run with settings `variant`, well enough to reuse it.
+    fn check_metric(&self, variant: &str, usage: &str) -> Result<()> {
+        if variant == "transform" && self.num_draws > 0 && self.transform_state.is_none() {
+            bail!(
+                "The transformation of chain {} was not saved, so it cannot be {}",
+                self.chain,
+                usage
+            );
+        }
+        match self.metric_source {
+            MetricSource::None | MetricSource::Sampler => Ok(()),
+            MetricSource::Stats if variant == "diag" => Ok(()),
+            MetricSource::Stats => bail!(
+                "Chain {} was adapted by nuts-rs with a {} mass matrix, which nuts-rs does \
+                not report, so it cannot be {}",
+                self.chain,
+                variant,
+                usage
+            ),
+        }
+    }
+}
+
+/// The median of the known step sizes of the chains.
+fn shared_step_size<'a>(chains: impl Iterator<Item = &'a ChainState>) -> Option<f64> {
+    let mut step_sizes: Vec<f64> = chains
+        .filter_map(|chain| chain.step_size)
+        .filter(|step| step.is_finite() && *step > 0f64)
+        .collect();
+    step_sizes.sort_by(f64::total_cmp);
+    step_sizes.get(step_sizes.len() / 2).copied()
+}
+
+#[derive(Serialize, Deserialize)]
+struct Metadata {
+    format_version: u64,
+    nutpie_version: String,
+    run: RunShape,
+    chains: Vec<ChainState>,
+}
+
+pub(crate) struct ChainCheckpoint {
+    pub(crate) state: ChainState,
+    pub(crate) draws: ArrayRef,
+    pub(crate) stats: ArrayRef,
+}
+
+pub(crate) struct Checkpoint {
+    pub(crate) run: RunShape,
+    pub(crate) chains: Vec<ChainCheckpoint>,
+}
+
+impl Checkpoint {
+    /// Collect the state of all chains of a (possibly still running) trace.
+    ///
+    /// `records` must be taken *before* the trace was inspected, so that
+    /// the positions belong to draws that are part of the trace.
+    pub(crate) fn new(
+        run: RunShape,
+        trace: Trace,
+        records: &BTreeMap<u64, ChainRecord>,
+        plan: Option<&ResumePlan>,
+    ) -> Result<Self> {
+        let mut chains = Vec::with_capacity(trace.chains.len());
+        let started: Vec<u64> = trace.chains.iter().map(|chain| chain.chain_id).collect();
+        for output in trace.chains {
+            let record = records.get(&output.chain_id).cloned();
+            let num_draws = record
+                .as_ref()
+                .map(|record| record.num_draws as usize)
+                .unwrap_or(output.draws.len());
+            let record = record.unwrap_or_default();
+            if output.draws.len() < num_draws || output.stats.len() < num_draws {
+                bail!(
+                    "Trace of chain {} is shorter than expected",
+                    output.chain_id
+                );
+            }
+            let draws = output.draws.slice(0, num_draws);
+            let stats = output.stats.slice(0, num_draws);
+
+            let state = ChainState::from_stats(output.chain_id, &stats, &record);
+            // The adapter may have been updated after the last draw of the
+            // snapshot, which only means that we continue with a slightly
+            // later state of the warmup.
+            let transform_state = record
+                .transform
+                .as_deref()
+                .map(PyTransformAdapt::state)
+                .transpose()
+                .with_context(|| {
+                    format!(
+                        "Could not save the transformation of chain {}",
+                        output.chain_id
+                    )
+                })?;
+
+            let output = ChainOutput {
+                draws,
+                stats,
+                chain_id: output.chain_id,
+            };
+            let output = match plan {
+                Some(plan) => plan.stitch_chain(output)?,
+                None => output,
+            };
+
+            chains.push(ChainCheckpoint {
+                state: ChainState {
+                    num_draws: output.draws.len() as u64,
+                    position: record.position.map(|pos| pos.to_vec()),
+                    transform_state,
+                    ..state
+                },
+                draws: output.draws,
+                stats: output.stats,
+            });
+        }
+
+        if let Some(plan) = plan {
+            for output in plan.unstarted_chains(started) {
+                let output = output?;
+                let state = plan.chains[&output.chain_id].state.clone();
+                chains.push(ChainCheckpoint {
+                    state,
+                    draws: output.draws,
+                    stats: output.stats,
+                });
+            }
+            chains.sort_by_key(|chain| chain.state.chain);
+        }
+        Ok(Self { run, chains })
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> Result<()> {
+        let Some(first) = self.chains.first() else {
+            bail!("None of the chains has started yet, there is nothing to checkpoint");
+        };
+
+        let metadata = Metadata {
+            format_version: FORMAT_VERSION,
+            nutpie_version: env!("CARGO_PKG_VERSION").to_string(),
+            run: self.run.clone(),
+            chains: self
+                .chains
+                .iter()
+                .map(|chain| chain.state.clone())
+                .collect(),
+        };
+        let metadata = HashMap::from([(
+            METADATA_KEY.to_string(),
+            serde_json::to_string(&metadata).context("Could not serialize checkpoint")?,
+        )]);
+
+        let schema = Arc::new(
+            Schema::new(vec![
+                Field::new("draws", first.draws.data_type().clone(), false),
+                Field::new("stats", first.stats.data_type().clone(), false),
+            ])
+            .with_metadata(metadata),
+        );
+
+        let file = File::create(path)
+            .with_context(|| format!("Could not create checkpoint file {}", path.display()))?;
+        let mut writer = FileWriter::try_new(file, &schema)?;
+        for chain in self.chains.iter() {
+            let batch = RecordBatch::try_new(
+                schema.clone(),
+                vec![chain.draws.clone(), chain.stats.clone()],
+            )
+            .with_context(|| {
+                format!(
+                    "Trace of chain {} does not match the other chains",
+                    chain.state.chain
+                )
+            })?;
+            writer.write(&batch)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    pub(crate) fn read(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Could not open checkpoint file {}", path.display()))?;
+        let reader = FileReader::try_new(file, None)?;
+
+        let metadata = reader
+            .schema()
+            .metadata()
+            .get(METADATA_KEY)
+            .ok_or_else(|| anyhow!("{} is not a nutpie checkpoint", path.display()))?
+            .clone();
+        let metadata: Metadata =
+            serde_json::from_str(&metadata).context("Invalid checkpoint metadata")?;
+        if metadata.format_version != FORMAT_VERSION {
+            bail!(
+                "Unsupported checkpoint format version {}",
+                metadata.format_version
+            );
+        }
+
+        let batches = reader.collect::<Result<Vec<_>, _>>()?;
+        if batches.len() != metadata.chains.len() {
+            bail!("Checkpoint contains an unexpected number of chains");
+        }
+
+        let chains = metadata
+            .chains
+            .into_iter()
+            .zip(batches)
+            .map(|(state, batch)| ChainCheckpoint {
+                state,
+                draws: batch.column(0).clone(),
+                stats: batch.column(1).clone(),
+            })
+            .collect();
+
+        Ok(Self {
+            run: metadata.run,
+            chains,
+        })
+    }
+
+    /// Work out how to continue the checkpointed chains.
+    ///
+    /// All chains are continued with a sampler on the space transformed by
+    /// their mass matrix or normalizing flow, and start with their step size
+    /// in the checkpoint.
+    pub(crate) fn continuation(&self) -> Result<Continuation> {
+        let step_size = shared_step_size(self.chains.iter().map(|chain| &chain.state));
+
+        let mut chains = BTreeMap::new();
+        let mut metric = MetricOptions::default();
+        let mut init_points = BTreeMap::new();
+        let mut transforms = BTreeMap::new();
+
+        for chain_id in 0..self.run.num_chains as u64 {
+            let checkpoint = self
+                .chains
+                .iter()
+                .find(|chain| chain.state.chain == chain_id);
+
+            let num_done = checkpoint.map(|chain| chain.state.num_draws).unwrap_or(0);
+            let tuned = num_done.min(self.run.num_tune);
+            let keep_tune = self.run.num_tune - tuned;
+            let keep_draws = self
+                .run
+                .num_draws
+                .saturating_sub(num_done.saturating_sub(self.run.num_tune));
+
+            if let Some(checkpoint) = checkpoint {
+                let state = &checkpoint.state;
+                if keep_tune + keep_draws > 0 {
+                    state.check_metric(&self.run.variant, "continued")?;
+                }
+                if let (Some(position), true) = (&state.position, num_done > 0) {
+                    init_points.insert(chain_id, position.clone().into());
+                }
+                if let Some(chain_metric) = state.metric(keep_tune > 0) {
+                    metric.chains.insert(chain_id, chain_metric);
+                }
+                if let Some(transform_state) = &state.transform_state {
+                    transforms.insert(
+                        chain_id,
+                        ChainTransform {
+                            state: transform_state.clone(),
+                            step_size: state
+                                .step_size
+                                .filter(|step| step.is_finite() && *step > 0f64),
+                        },
+                    );
+                }
+                chains.insert(
+                    chain_id,
+                    ChainResume {
+                        state: checkpoint.state.clone(),
+                        draws: checkpoint.draws.clone(),
+                        stats: checkpoint.stats.clone(),
+                        keep_tune,
+                        keep_draws,
+                    },
+                );
+            } else {
+                chains.insert(
+                    chain_id,
+                    ChainResume {
+                        state: Default::default(),
+                        draws: Arc::new(StructArray::new_empty_fields(0, None)),
+                        stats: Arc::new(StructArray::new_empty_fields(0, None)),
+                        keep_tune,
+                        keep_draws,
+                    },
+                );
+            }
+        }
+
+        // The continuation samples on a transformed space and reports
+        // somewhat different sampler statistics. We use the fields of the
+        // original run, and fill in missing values with nulls.
+        let stats_fields = self
+            .chains
+            .iter()
+            .find(|chain| !chain.stats.is_empty())
+            .and_then(|chain| Some(nullable_fields(chain.stats.as_struct_opt()?.fields())));
+
+        let num_tune = chains
+            .values()
+            .map(|chain| chain.keep_tune)
+            .max()
+            .unwrap_or(0);
+        let num_draws = chains
+            .values()
+            .map(|chain| chain.keep_draws)
+            .max()
+            .unwrap_or(0);
+
+        Ok(Continuation {
+            num_tune,
+            num_draws,
+            step_size,
+            metric,
+            transforms,
+            init_points,
+            plan: ResumePlan {
+                num_tune,
+                stats_fields,
+                chains,
+            },
+        })
+    }
+}
+
+pub(crate) struct Continuation {
+    pub(crate) num_tune: u64,
+    pub(crate) num_draws: u64,
+    /// The median step size of the checkpointed chains, if any of them
+    /// made a draw. Chains with a metric start with their own step size.
+    pub(crate) step_size: Option<f64>,
+    pub(crate) metric: MetricOptions,
+    /// The transformations of chains with transform adaptation.
+    pub(crate) transforms: BTreeMap<u64, ChainTransform>,
+    pub(crate) init_points: BTreeMap<u64, Box<[f64]>>,
+    pub(crate) plan: ResumePlan,
+}
+
+/// The normalizing flow of a chain with transform adaptation, and the step
+/// size the chain continues with.
+#[derive(Clone, Debug)]
+pub(crate) struct ChainTransform {
+    pub(crate) state: String,
+    pub(crate) step_size: Option<f64>,
+}
+
+#[derive(Debug)]
+struct ChainResume {
+    state: ChainState,
+    draws: ArrayRef,
+    stats: ArrayRef,
+    keep_tune: u64,
+    keep_draws: u64,
+}
+
+/// Step size and mass matrix that the chains of a run ended with.
+#[derive(Clone, Debug)]
+pub(crate) struct AdaptationState {
+    /// The settings variant of the run, see `RunShape::variant`.
+    pub(crate) variant: String,
+    pub(crate) chains: Vec<ChainState>,
+}
+
+/// How `AdaptationState` appears in the provenance of a trace.
+#[derive(Serialize)]
+struct AdaptationStateRecord<'a> {
+    variant: &'a str,
+    num_chains: usize,
+    sha256: String,
+}
+
+impl Serialize for AdaptationState {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        AdaptationStateRecord {
+            variant: &self.variant,
+            num_chains: self.chains.len(),
+            sha256: json_sha256(&self.chains).map_err(S::Error::custom)?,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// The whole `AdaptationState`, as it is stored in the provenance of a
+/// trace.
+#[derive(Serialize, Deserialize)]
+struct AdaptationStateData {
+    variant: String,
+    chains: Vec<ChainState>,
+}
+
+impl AdaptationState {
+    pub(crate) fn new(checkpoint: &Checkpoint) -> Self {
+        let chains = checkpoint
+            .chains
+            .iter()
+            .map(|chain| ChainState {
+                position: None,
+                // Warm starts are not supported with transform adaptation.
+                transform_state: None,
+                ..chain.state.clone()
+            })
+            .collect();
+        Self {
+            variant: checkpoint.run.variant.clone(),
+            chains,
+        }
+    }
+
+    /// The state at the end of the finished `trace` of a run with settings
+    /// `variant`, whose chains have the records `records`.
+    pub(crate) fn finished(
+        variant: String,
+        trace: &Trace,
+        records: &BTreeMap<u64, ChainRecord>,
+    ) -> Self {
+        let chains = trace
+            .chains
+            .iter()
+            .map(|chain| {
+                let record = records.get(&chain.chain_id).cloned().unwrap_or_default();
+                ChainState::from_stats(chain.chain_id, &chain.stats, &record)
+            })
+            .collect();
+        Self { variant, chains }
+    }
+
+    /// The whole state as JSON, unlike the record of its hash that
+    /// `Serialize` gives.
+    pub(crate) fn to_value(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(AdaptationStateData {
+            variant: self.variant.clone(),
+            chains: self.chains.clone(),
+        })?)
+    }
+
+    /// Read the state from the JSON of `to_value`.
+    pub(crate) fn from_value(value: serde_json::Value) -> Result<Self> {
+        let AdaptationStateData { variant, chains } =
+            serde_json::from_value(value).context("Invalid adaptation state")?;
+        Ok(Self { variant, chains })
+    }
+
+    /// The median step size, if known, and the metric and step size of
+    /// each chain of a new run with `num_chains` chains. If the new run has
+    /// more chains than the old one, the adaptation states are reused in
+    /// turn.
+    pub(crate) fn warm_start(
+        &self,
+        num_chains: usize,
+        adapt: bool,
+    ) -> Result<(Option<f64>, MetricOptions)> {
+        let step_size = shared_step_size(self.chains.iter());
+        let states: Vec<&ChainState> = self
+            .chains
+            .iter()
+            .filter(|chain| chain.inv_mass.is_some())
+            .collect();
+        for state in states.iter() {
+            state.check_metric(&self.variant, "used for a warm start")?;
+        }
+        let chains = if states.is_empty() {
+            BTreeMap::new()
+        } else {
+            (0..num_chains as u64)
+                .filter_map(|chain| {
+                    let state = states[chain as usize % states.len()];
+                    Some((chain, state.metric(adapt)?))
+                })
+                .collect()
+        };
+        let metric = MetricOptions {
+            chains,
+            ..Default::default()
+        };
+        Ok((step_size, metric))
+    }
+}
+
+/// How to combine the trace of a continued run with the earlier part.
+///
+/// All chains of the continued run use the same number of warmup and
+/// posterior draws, but the chains of the checkpoint did not all
+/// get equally far. For each chain we keep only the first `keep_tune`
+/// warmup and `keep_draws` posterior draws of the continuation.
+#[derive(Debug)]
+pub(crate) struct ResumePlan {
+    num_tune: u64,
+    stats_fields: Option<Fields>,
+    chains: BTreeMap<u64, ChainResume>,
+}
+
+impl ResumePlan {
+    pub(crate) fn stitch(&self, trace: Trace) -> Result<Trace> {
+        let mut outputs: BTreeMap<u64, ChainOutput> = BTreeMap::new();
+        for output in trace.chains {
+            outputs.insert(output.chain_id, self.stitch_chain(output)?);
+        }
+        for output in self.unstarted_chains(outputs.keys().copied().collect()) {
+            let output = output?;
+            outputs.insert(output.chain_id, output);
+        }
+        Ok(Trace {
+            chains: outputs.into_values().collect(),
+        })
+    }
+
+    /// The checkpointed part of chains that did not start in the continuation.
+    fn unstarted_chains(
+        &self,
+        started: Vec<u64>,
+    ) -> impl Iterator<Item = Result<ChainOutput>> + '_ {
+        self.chains
+            .keys()
+            .filter(move |chain_id| !started.contains(chain_id))
+            .filter_map(|&chain_id| self.empty_output(chain_id))
+            .map(|output| self.stitch_chain(output))
+    }
+
+    pub(crate) fn chain_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.chains.keys().copied()
+    }
+
+    /// An empty continuation of a chain that has checkpointed draws.
+    pub(crate) fn empty_output(&self, chain_id: u64) -> Option<ChainOutput> {
+        let chain = self.chains.get(&chain_id)?;
+        if chain.draws.is_empty() {
+            return None;
+        }
+        Some(ChainOutput {
+            draws: chain.draws.slice(0, 0),
+            stats: chain.stats.slice(0, 0),
+            chain_id,
+        })
+    }
+
+    fn stitch_chain(&self, output: ChainOutput) -> Result<ChainOutput> {
+        self.stitch_rows(output, 0, 0)
+    }
+
+    /// Stitch the rows `start..start + output.len()` of a continued chain
+    /// onto the checkpointed trace, and return the rows of the result
+    /// starting at row `skip`.
+    pub(crate) fn stitch_rows(
+        &self,
+        output: ChainOutput,
+        start: usize,
+        skip: usize,
+    ) -> Result<ChainOutput> {
+        let Some(chain) = self.chains.get(&output.chain_id) else {
+            return Ok(output);
+        };
+        let pieces = self.pieces(chain, start, output.draws.len(), skip);
+        let draws = pieces
+            .stitch(&chain.draws, &output.draws)
+            .context("Could not append draws to checkpointed trace")?;
+        let stats = self.stitch_chain_stats(chain, &output.stats, &pieces)?;
+        Ok(ChainOutput {
+            draws,
+            stats: renumber_draws(stats, skip as u64)?,
+            chain_id: output.chain_id,
+        })
+    }
+
+    /// Like `stitch_rows`, but only for the draws of a chain.
+    pub(crate) fn stitch_draws(
+        &self,
+        chain_id: u64,
+        draws: &ArrayRef,
+        start: usize,
+        skip: usize,
+    ) -> Result<ArrayRef> {
+        let Some(chain) = self.chains.get(&chain_id) else {
+            return Ok(draws.clone());
+        };
+        self.pieces(chain, start, draws.len(), skip)
+            .stitch(&chain.draws, draws)
+            .context("Could not append draws to checkpointed trace")
+    }
+
+    /// Like `stitch_rows`, but only for the sampler stats of a chain.
+    pub(crate) fn stitch_stats(
+        &self,
+        chain_id: u64,
+        stats: &ArrayRef,
+        start: usize,
+        skip: usize,
+    ) -> Result<ArrayRef> {
+        let Some(chain) = self.chains.get(&chain_id) else {
+            return Ok(stats.clone());
+        };
+        let pieces = self.pieces(chain, start, stats.len(), skip);
+        let stats = self.stitch_chain_stats(chain, stats, &pieces)?;
+        renumber_draws(stats, skip as u64)
+    }
+
+    fn stitch_chain_stats(
+        &self,
+        chain: &ChainResume,
+        stats: &ArrayRef,
+        pieces: &Pieces,
+    ) -> Result<ArrayRef> {
+        let (prefix, stats) = match &self.stats_fields {
+            Some(fields) => (
+                conform_struct(&chain.stats, fields)?,
+                conform_struct(stats, fields)?,
+            ),
+            None => (chain.stats.clone(), stats.clone()),
+        };
+        pieces
+            .stitch(&prefix, &stats)
+            .context("Could not append sampler stats to checkpointed trace")
+    }
+
+    /// Which rows of the checkpoint and of the continuation rows
+    /// `start..start + len` of a chain make up the stitched rows from `skip`.
+    fn pieces(&self, chain: &ChainResume, start: usize, len: usize, skip: usize) -> Pieces {
+        // Continuation rows we keep, as (start, end) in continuation rows.
+        let kept = [
+            (0, chain.keep_tune as usize),
+            (
+                self.num_tune as usize,
+                self.num_tune as usize + chain.keep_draws as usize,
+            ),
+        ];
+        let end = start + len;
+        let num_prefix = chain.draws.len();
+        let mut position = num_prefix
+            + kept
+                .iter()
+                .map(|&(lower, upper)| upper.min(start).saturating_sub(lower))
+                .sum::<usize>();
+
+        let mut pieces = Vec::with_capacity(kept.len());
+        for (lower, upper) in kept {
+            let lower = lower.max(start);
+            let upper = upper.min(end);
+            if lower >= upper {
+                continue;
+            }
+            let dropped = skip.saturating_sub(position).min(upper - lower);
+            position += upper - lower;
+            if dropped < upper - lower {
+                pieces.push((lower + dropped - start, upper - lower - dropped));
+            }
+        }
+        Pieces {
+            prefix: skip.min(num_prefix)..num_prefix,
+            rows: pieces,
+        }
+    }
+}
+
+/// Rows of the checkpointed trace of a chain, and (offset, length) pieces
+/// of rows of its continuation.
+struct Pieces {
+    prefix: Range<usize>,
+    rows: Vec<(usize, usize)>,
+}
+
+impl Pieces {
+    fn stitch(&self, prefix: &ArrayRef, array: &ArrayRef) -> Result<ArrayRef> {
+        if self.prefix.is_empty() {
+            return concat_pieces(None, array, &self.rows);
+        }
+        let prefix = prefix.slice(self.prefix.start, self.prefix.len());
+        concat_pieces(Some(&prefix), array, &self.rows)
+    }
+}
+
+fn concat_pieces(
+    prefix: Option<&ArrayRef>,
+    array: &ArrayRef,
+    pieces: &[(usize, usize)],
+) -> Result<ArrayRef> {
+    let slices: Vec<ArrayRef> = prefix
+        .cloned()
+        .into_iter()
+        .chain(
+            pieces
+                .iter()
+                .map(|&(offset, length)| array.slice(offset, length)),
+        )
+        .collect();
+    if slices.is_empty() {
+        return Ok(array.slice(0, 0));
+    }
+    let slices: Vec<&dyn Array> = slices.iter().map(|array| array.as_ref()).collect();
+    Ok(concat(&slices)?)
+}
+
+fn nullable_fields(fields: &Fields) -> Fields {
+    fields
+        .iter()
+        .map(|field| field.as_ref().clone().with_nullable(true))
+        .collect()
+}
+
+fn conform_struct(array: &ArrayRef, fields: &Fields) -> Result<ArrayRef> {
+    let array = array
+        .as_struct_opt()
+        .ok_or_else(|| anyhow!("Sampler stats must be a struct array"))?;
+    let columns = fields
+        .iter()
+        .map(|field| match array.column_by_name(field.name()) {
+            Some(column) if column.data_type() == field.data_type() => column.clone(),
+            _ => new_null_array(field.data_type(), array.len()),
+        })
+        .collect();
+    Ok(Arc::new(StructArray::try_new(
+        fields.clone(),
+        columns,
+        None,
+    )?))
+}
+
+/// Number the draws of a stitched chain consecutively again, starting
+/// at `first`.
+fn renumber_draws(stats: ArrayRef, first: u64) -> Result<ArrayRef> {
+    let Some(stats) = stats.as_struct_opt() else {
+        return Ok(stats);
+    };
+    let Some((idx, field)) = stats.fields().find("draw") else {
+        return Ok(Arc::new(stats.clone()));
+    };
+    if field.data_type() != &DataType::UInt64 {
+        return Ok(Arc::new(stats.clone()));
+    }
+    let (fields, mut columns, nulls) = stats.clone().into_parts();
+    columns[idx] = Arc::new(UInt64Array::from_iter_values(
+        first..first + stats.len() as u64,
+    ));
+    Ok(Arc::new(StructArray::try_new(fields, columns, nulls)?))
+}
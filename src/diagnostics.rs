@@ -0,0 +1,517 @@
+//! Convergence diagnostics of the posterior draws.
+//!
+//! We compute rank-normalized split R-hat, bulk and tail ESS and the Monte
+//! Carlo standard errors as in Vehtari et al. (2021), "Rank-normalization,
+//! folding, and localization: An improved R-hat for assessing convergence
+//! of MCMC", following the implementation in arviz. The draws are read
+//! directly from the arrow arrays of the trace, and each scalar element of
+//! each variable is summarized in parallel.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use arrow::{
+    array::{
+        Array, ArrayRef, AsArray, Float64Array, GenericListArray, OffsetSizeTrait, StringArray,
+        StructArray, UInt64Array,
+    },
+    compute::cast,
+    datatypes::{DataType, Field, Float64Type},
+};
+use rayon::prelude::*;
+use rustfft::{num_complex::Complex, FftPlanner};
+
+/// The draws of one variable, as a row-major matrix per chain with one row
+/// per draw.
+struct Variable {
+    name: String,
+    width: usize,
+    chains: Vec<Vec<f64>>,
+}
+
+impl Variable {
+    /// The draws of one scalar element in each chain.
+    fn element(&self, index: usize, num_draws: usize) -> Vec<Vec<f64>> {
+        self.chains
+            .iter()
+            .map(|chain| {
+                chain
+                    .iter()
+                    .skip(index)
+                    .step_by(self.width)
+                    .take(num_draws)
+                    .copied()
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+fn list_values<O: OffsetSizeTrait>(
+    list: &GenericListArray<O>,
+) -> Result<Option<(ArrayRef, usize)>> {
+    let offsets = list.value_offsets();
+    let width = match offsets {
+        [first, second, ..] => (*second - *first).as_usize(),
+        _ => 0,
+    };
+    if offsets
+        .windows(2)
+        .any(|pair| (pair[1] - pair[0]).as_usize() != width)
+    {
+        // Variables with a different shape in each draw have no
+        // diagnostics.
+        return Ok(None);
+    }
+    let start = offsets[0].as_usize();
+    let end = offsets[offsets.len() - 1].as_usize();
+    Ok(Some((list.values().slice(start, end - start), width)))
+}
+
+/// The values of a column of the draws as float64, and the number of
+/// values per draw.
+fn column_values(column: &dyn Array) -> Result<Option<(Vec<f64>, usize)>> {
+    let (values, width) = match column.data_type() {
+        DataType::FixedSizeList(_, size) => {
+            let list = column.as_fixed_size_list();
+            let width = *size as usize;
+            let start = list.value_offset(0) as usize;
+            (list.values().slice(start, list.len() * width), width)
+        }
+        DataType::List(_) => match list_values(column.as_list::<i32>())? {
+            Some(values) => values,
+            None => return Ok(None),
+        },
+        DataType::LargeList(_) => match list_values(column.as_list::<i64>())? {
+            Some(values) => values,
+            None => return Ok(None),
+        },
+        dtype if dtype.is_numeric() || *dtype == DataType::Boolean => {
+            (column.slice(0, column.len()), 1)
+        }
+        _ => return Ok(None),
+    };
+    if !(values.data_type().is_numeric() || *values.data_type() == DataType::Boolean) {
+        return Ok(None);
+    }
+    let values = cast(&values, &DataType::Float64).context("Could not convert draws to float")?;
+    let values = values.as_primitive::<Float64Type>();
+    let values = values
+        .iter()
+        .map(|value| value.unwrap_or(f64::NAN))
+        .collect();
+    Ok(Some((values, width)))
+}
+
+/// Collect the draws of each variable from the draws of each chain.
+fn variables(chains: &[ArrayRef]) -> Result<Vec<Variable>> {
+    let Some(first) = chains.first() else {
+        return Ok(vec![]);
+    };
+    let first = first
+        .as_struct_opt()
+        .context("Draws of the trace must be a struct array")?;
+    let mut variables = vec![];
+    'fields: for name in first.column_names() {
+        let mut variable = Variable {
+            name: name.to_string(),
+            width: 0,
+            chains: Vec::with_capacity(chains.len()),
+        };
+        for (i, chain) in chains.iter().enumerate() {
+            let draws = chain
+                .as_struct_opt()
+                .context("Draws of the trace must be a struct array")?;
+            let Some(column) = draws.column_by_name(name) else {
+                bail!("Variable {} is missing in chain {}", name, i);
+            };
+            let Some((values, width)) = column_values(column)? else {
+                continue 'fields;
+            };
+            if i > 0 && width != variable.width {
+                continue 'fields;
+            }
+            variable.width = width;
+            variable.chains.push(values);
+        }
+        variables.push(variable);
+    }
+    Ok(variables)
+}
+
+/// The diagnostics of one scalar element.
+struct Summary {
+    mean: f64,
+    sd: f64,
+    quantiles: Vec<f64>,
+    mcse_mean: f64,
+    mcse_sd: f64,
+    ess_bulk: f64,
+    ess_tail: f64,
+    r_hat: f64,
+}
+
+/// Split each chain in half. The middle draw of chains with an odd
+/// number of draws is dropped.
+fn split_chains(chains: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    chains
+        .iter()
+        .flat_map(|chain| {
+            let half = chain.len() / 2;
+            [chain[..half].to_vec(), chain[chain.len() - half..].to_vec()]
+        })
+        .collect()
+}
+
+fn map_draws(chains: &[Vec<f64>], func: impl Fn(f64) -> f64) -> Vec<Vec<f64>> {
+    chains
+        .iter()
+        .map(|chain| chain.iter().map(|&x| func(x)).collect())
+        .collect()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn variance(values: &[f64]) -> f64 {
+    let mean = mean(values);
+    values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (values.len() as f64 - 1.)
+}
+
+/// Draws with fewer than four draws per chain, with non-finite values, or
+/// where all values are the same have no diagnostics.
+fn is_valid(chains: &[Vec<f64>]) -> bool {
+    let Some(first) = chains.first().and_then(|chain| chain.first()) else {
+        return false;
+    };
+    chains.iter().all(|chain| chain.len() >= 4)
+        && chains.iter().flatten().all(|x| x.is_finite())
+        && chains.iter().flatten().any(|x| x != first)
+}
+
+/// Quantile of sorted values, interpolating linearly between draws.
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    let pos = (sorted.len() - 1) as f64 * q;
+    let lower = pos.floor() as usize;
+    let upper = (lower + 1).min(sorted.len() - 1);
+    sorted[lower] + (pos - lower as f64) * (sorted[upper] - sorted[lower])
+}
+
+/// Inverse of the standard normal CDF, with the rational approximation of
+/// Peter Acklam (relative error below 1.2e-9).
+fn normal_ppf(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e1,
+        2.209460984245205e2,
+        -2.759285104469687e2,
+        1.38357751867269e2,
+        -3.066479806614716e1,
+        2.506628277459239,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e1,
+        1.615858368580409e2,
+        -1.556989798598866e2,
+        6.680131188771972e1,
+        -1.328068155288572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-3,
+        -3.223964580411365e-1,
+        -2.400758277161838,
+        -2.549732539343734,
+        4.374664141464968,
+        2.938163982698783,
+    ];
+    const D: [f64; 4] = [
+        7.784695709041462e-3,
+        3.224671290700398e-1,
+        2.445134137142996,
+        3.754408661907416,
+    ];
+    const P_LOW: f64 = 0.02425;
+
+    let tail = |q: f64| {
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.)
+    };
+    if p < P_LOW {
+        tail((-2. * p.ln()).sqrt())
+    } else if p > 1. - P_LOW {
+        -tail((-2. * (1. - p).ln()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.)
+    }
+}
+
+/// Replace the draws by the normal scores of their ranks in all chains.
+fn z_scale(chains: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let values: Vec<f64> = chains.iter().flatten().copied().collect();
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+
+    // Tied values get the average of their ranks.
+    let mut ranks = vec![0f64; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && values[order[end]] == values[order[start]] {
+            end += 1;
+        }
+        let rank = (start + end + 1) as f64 / 2.;
+        for &idx in &order[start..end] {
+            ranks[idx] = rank;
+        }
+        start = end;
+    }
+
+    let size = values.len() as f64;
+    let mut ranks = ranks.into_iter();
+    chains
+        .iter()
+        .map(|chain| {
+            ranks
+                .by_ref()
+                .take(chain.len())
+                .map(|rank| normal_ppf((rank - 0.375) / (size + 0.25)))
+                .collect()
+        })
+        .collect()
+}
+
+/// Potential scale reduction factor of chains of equal length.
+fn rhat(chains: &[Vec<f64>]) -> f64 {
+    let num_draws = chains[0].len() as f64;
+    let means: Vec<f64> = chains.iter().map(|chain| mean(chain)).collect();
+    let within = mean(
+        &chains
+            .iter()
+            .map(|chain| variance(chain))
+            .collect::<Vec<_>>(),
+    );
+    let between = num_draws * variance(&means);
+    ((between / within + num_draws - 1.) / num_draws).sqrt()
+}
+
+/// Autocovariance of a chain at all lags, computed with an fft.
+fn autocov(chain: &[f64], planner: &mut FftPlanner<f64>) -> Vec<f64> {
+    let n = chain.len();
+    let size = (2 * n).next_power_of_two();
+    let chain_mean = mean(chain);
+    let mut buffer: Vec<Complex<f64>> = chain
+        .iter()
+        .map(|&x| Complex::new(x - chain_mean, 0.))
+        .chain(std::iter::repeat(Complex::new(0., 0.)))
+        .take(size)
+        .collect();
+    planner.plan_fft_forward(size).process(&mut buffer);
+    buffer
+        .iter_mut()
+        .for_each(|value| *value = Complex::new(value.norm_sqr(), 0.));
+    planner.plan_fft_inverse(size).process(&mut buffer);
+    buffer[..n]
+        .iter()
+        .map(|value| value.re / size as f64 / n as f64)
+        .collect()
+}
+
+/// Effective sample size of chains of equal length, with Geyer's initial
+/// monotone sequence estimator.
+fn ess(chains: &[Vec<f64>], planner: &mut FftPlanner<f64>) -> f64 {
+    let num_chains = chains.len();
+    let num_draws = chains[0].len();
+    let acov: Vec<Vec<f64>> = chains.iter().map(|chain| autocov(chain, planner)).collect();
+    let mean_acov = |lag: usize| acov.iter().map(|acov| acov[lag]).sum::<f64>() / num_chains as f64;
+
+    let n = num_draws as f64;
+    let mean_var = mean_acov(0) * n / (n - 1.);
+    let mut var_plus = mean_var * (n - 1.) / n;
+    if num_chains > 1 {
+        let means: Vec<f64> = chains.iter().map(|chain| mean(chain)).collect();
+        var_plus += variance(&means);
+    }
+
+    let mut rho_hat = vec![0f64; num_draws];
+    let mut rho_hat_even = 1.;
+    rho_hat[0] = rho_hat_even;
+    let mut rho_hat_odd = 1. - (mean_var - mean_acov(1)) / var_plus;
+    rho_hat[1] = rho_hat_odd;
+
+    // Sum pairs of autocorrelations until their sum becomes negative.
+    let mut t = 1;
+    while t < num_draws - 3 && rho_hat_even + rho_hat_odd > 0. {
+        rho_hat_even = 1. - (mean_var - mean_acov(t + 1)) / var_plus;
+        rho_hat_odd = 1. - (mean_var - mean_acov(t + 2)) / var_plus;
+        if rho_hat_even + rho_hat_odd >= 0. {
+            rho_hat[t + 1] = rho_hat_even;
+            rho_hat[t + 2] = rho_hat_odd;
+        }
+        t += 2;
+    }
+    let max_t = t - 2;
+    if rho_hat_even > 0. {
+        rho_hat[max_t + 1] = rho_hat_even;
+    }
+
+    // Make the sums of pairs monotone.
+    let mut t = 1;
+    while t + 2 <= max_t {
+        if rho_hat[t + 1] + rho_hat[t + 2] > rho_hat[t - 1] + rho_hat[t] {
+            rho_hat[t + 1] = (rho_hat[t - 1] + rho_hat[t]) / 2.;
+            rho_hat[t + 2] = rho_hat[t + 1];
+        }
+        t += 2;
+    }
+
+    let total = (num_chains * num_draws) as f64;
+    let tau_hat = -1. + 2. * rho_hat[..=max_t].iter().sum::<f64>() + rho_hat[max_t + 1];
+    let tau_hat = tau_hat.max(1. / total.log10());
+    total / tau_hat
+}
+
+fn ess_or_nan(chains: &[Vec<f64>], planner: &mut FftPlanner<f64>) -> f64 {
+    if is_valid(chains) {
+        ess(chains, planner)
+    } else {
+        f64::NAN
+    }
+}
+
+fn summarize(chains: &[Vec<f64>], quantiles: &[f64], planner: &mut FftPlanner<f64>) -> Summary {
+    let mut sorted: Vec<f64> = chains.iter().flatten().copied().collect();
+    if sorted.is_empty() {
+        return Summary {
+            mean: f64::NAN,
+            sd: f64::NAN,
+            quantiles: vec![f64::NAN; quantiles.len()],
+            mcse_mean: f64::NAN,
+            mcse_sd: f64::NAN,
+            ess_bulk: f64::NAN,
+            ess_tail: f64::NAN,
+            r_hat: f64::NAN,
+        };
+    }
+    sorted.sort_by(f64::total_cmp);
+    let draws_mean = mean(&sorted);
+    let sd = variance(&sorted).sqrt();
+    let quantiles = quantiles.iter().map(|&q| quantile(&sorted, q)).collect();
+
+    if !is_valid(chains) {
+        return Summary {
+            mean: draws_mean,
+            sd,
+            quantiles,
+            mcse_mean: f64::NAN,
+            mcse_sd: f64::NAN,
+            ess_bulk: f64::NAN,
+            ess_tail: f64::NAN,
+            r_hat: f64::NAN,
+        };
+    }
+
+    let split = split_chains(chains);
+
+    let median = quantile(&sorted, 0.5);
+    let folded = map_draws(&split, |x| (x - median).abs());
+    let r_hat = rhat(&z_scale(&split)).max(rhat(&z_scale(&folded)));
+
+    let ess_bulk = ess(&z_scale(&split), planner);
+    let (q05, q95) = (quantile(&sorted, 0.05), quantile(&sorted, 0.95));
+    let ess_tail = ess_or_nan(&map_draws(&split, |x| f64::from(x <= q05)), planner).min(
+        ess_or_nan(&map_draws(&split, |x| f64::from(x <= q95)), planner),
+    );
+
+    let mcse_mean = sd / ess(&split, planner).sqrt();
+    let squares = map_draws(&split, |x| (x - draws_mean).powi(2));
+    let mcse_sd = {
+        let flat: Vec<f64> = squares.iter().flatten().copied().collect();
+        let evar = mean(&flat);
+        let varvar = (flat.iter().map(|x| x * x).sum::<f64>() / flat.len() as f64 - evar * evar)
+            / ess_or_nan(&squares, planner);
+        (varvar / evar / 4.).sqrt()
+    };
+
+    Summary {
+        mean: draws_mean,
+        sd,
+        quantiles,
+        mcse_mean,
+        mcse_sd,
+        ess_bulk,
+        ess_tail,
+        r_hat,
+    }
+}
+
+/// The column name of a quantile, for example `q5` for 0.05.
+fn quantile_name(q: f64) -> String {
+    format!("q{}", (q * 1e6).round() / 1e4)
+}
+
+/// Diagnostics of each scalar element of the posterior draws of each chain.
+///
+/// Chains are truncated to the length of the shortest one. Returns a struct
+/// array with one row per element, with the name of the variable and the
+/// index of the element in the flattened variable.
+pub(crate) fn diagnostics(chains: &[ArrayRef], quantiles: &[f64]) -> Result<Arc<dyn Array>> {
+    if let Some(q) = quantiles.iter().find(|q| !(0. ..=1.).contains(*q)) {
+        bail!("Quantile {} is not between 0 and 1", q);
+    }
+    let num_draws = chains.iter().map(|chain| chain.len()).min().unwrap_or(0);
+    let variables = variables(chains)?;
+    let elements: Vec<(&Variable, usize)> = variables
+        .iter()
+        .flat_map(|variable| (0..variable.width).map(move |index| (variable, index)))
+        .collect();
+
+    let summaries: Vec<Summary> = elements
+        .par_iter()
+        .map_init(FftPlanner::new, |planner, (variable, index)| {
+            summarize(&variable.element(*index, num_draws), quantiles, planner)
+        })
+        .collect();
+
+    let float_column = |func: &dyn Fn(&Summary) -> f64| -> ArrayRef {
+        Arc::new(Float64Array::from_iter_values(summaries.iter().map(func)))
+    };
+
+    let mut columns: Vec<(String, ArrayRef)> = vec![
+        (
+            "variable".to_string(),
+            Arc::new(StringArray::from_iter_values(
+                elements.iter().map(|(variable, _)| &variable.name),
+            )),
+        ),
+        (
+            "index".to_string(),
+            Arc::new(UInt64Array::from_iter_values(
+                elements.iter().map(|(_, index)| *index as u64),
+            )),
+        ),
+        ("mean".to_string(), float_column(&|s| s.mean)),
+        ("sd".to_string(), float_column(&|s| s.sd)),
+    ];
+    for (i, &q) in quantiles.iter().enumerate() {
+        columns.push((quantile_name(q), float_column(&|s| s.quantiles[i])));
+    }
+    columns.extend([
+        ("mcse_mean".to_string(), float_column(&|s| s.mcse_mean)),
+        ("mcse_sd".to_string(), float_column(&|s| s.mcse_sd)),
+        ("ess_bulk".to_string(), float_column(&|s| s.ess_bulk)),
+        ("ess_tail".to_string(), float_column(&|s| s.ess_tail)),
+        ("r_hat".to_string(), float_column(&|s| s.r_hat)),
+    ]);
+
+    let (fields, arrays): (Vec<_>, Vec<_>) = columns
+        .into_iter()
+        .map(|(name, array)| (Field::new(name, array.data_type().clone(), false), array))
+        .unzip();
+    Ok(Arc::new(
+        StructArray::try_new(fields.into(), arrays, None)
+            .context("Could not create arrow struct")?,
+    ))
+}
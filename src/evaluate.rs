@@ -0,0 +1,120 @@
+//! Evaluate the logp and gradient of a model at many points from Python.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use arrow::array::Array;
+use numpy::{PyArray1, PyArray2, PyArrayMethods};
+use nuts_rs::{DiagGradNutsSettings, DrawStorage, LogpError, Math, Model};
+use pyo3::{Bound, Python};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+
+/// The logp values, gradients and error mask as numpy arrays.
+pub(crate) type PyEvaluations<'py> = (
+    Bound<'py, PyArray1<f64>>,
+    Bound<'py, PyArray2<f64>>,
+    Bound<'py, PyArray1<bool>>,
+);
+
+/// Logp and gradient at each of `num_points` points.
+pub(crate) struct Evaluations {
+    num_points: usize,
+    dim: usize,
+    logp: Vec<f64>,
+    /// Gradients of all points, one after the other.
+    gradient: Vec<f64>,
+    /// Points where the logp could not be evaluated.
+    failed: Vec<bool>,
+}
+
+impl Evaluations {
+    pub(crate) fn into_numpy(self, py: Python<'_>) -> Result<PyEvaluations<'_>> {
+        let gradient =
+            PyArray1::from_vec(py, self.gradient).reshape([self.num_points, self.dim])?;
+        Ok((
+            PyArray1::from_vec(py, self.logp),
+            gradient,
+            PyArray1::from_vec(py, self.failed),
+        ))
+    }
+}
+
+/// Evaluate the logp and its gradient at the rows of `points`, a row major
+/// matrix with `num_points` rows of length `dim`, in parallel.
+///
+/// Points where the model reports a recoverable error, for example because
+/// the logp is not finite, get a `nan` logp and gradient. Other errors fail
+/// the whole evaluation.
+pub(crate) fn logp_and_grad<M: Model>(
+    model: &M,
+    points: &[f64],
+    num_points: usize,
+    dim: usize,
+) -> Result<Evaluations> {
+    let evaluations = (0..num_points)
+        .into_par_iter()
+        .map_init(
+            || model.math(),
+            |math, i| {
+                let math = math
+                    .as_mut()
+                    .map_err(|err| anyhow!("Could not create the model: {:#}", err))?;
+                if math.dim() != dim {
+                    bail!(
+                        "Points have length {}, but the model has {} parameters",
+                        dim,
+                        math.dim()
+                    );
+                }
+                let point = &points[i * dim..(i + 1) * dim];
+                let mut gradient = vec![0f64; dim];
+                match math.logp(point, &mut gradient) {
+                    Ok(logp) => Ok((logp, gradient, false)),
+                    Err(err) if err.is_recoverable() => Ok((f64::NAN, vec![f64::NAN; dim], true)),
+                    Err(err) => bail!("Could not evaluate the logp at point {}: {:?}", i, err),
+                }
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut logp = Vec::with_capacity(num_points);
+    let mut gradient = Vec::with_capacity(num_points * dim);
+    let mut failed = Vec::with_capacity(num_points);
+    for (point_logp, point_gradient, point_failed) in evaluations {
+        logp.push(point_logp);
+        gradient.extend(point_gradient);
+        failed.push(point_failed);
+    }
+    Ok(Evaluations {
+        num_points,
+        dim,
+        logp,
+        gradient,
+        failed,
+    })
+}
+
+/// Expand points on the unconstrained space into the values of the
+/// variables of the model, with the same layout as the draws of a trace.
+///
+/// `seed` is used for random variables that the model generates in the
+/// expansion, like the generated quantities of Stan models.
+pub(crate) fn expand_points<M: Model>(
+    model: &M,
+    points: &[Box<[f64]>],
+    seed: u64,
+) -> Result<Arc<dyn Array>> {
+    let settings = DiagGradNutsSettings {
+        num_tune: 0,
+        num_draws: points.len() as u64,
+        ..Default::default()
+    };
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut trace = model.new_trace(&mut rng, 0, &settings)?;
+    for point in points {
+        trace.append_value(point)?;
+    }
+    trace.finalize()
+}
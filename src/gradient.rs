@@ -0,0 +1,151 @@
+//! Compare the gradient of a model with finite differences.
+//!
+//! A wrong gradient does not make the sampler fail, it only makes it sample
+//! from the wrong distribution. Comparing the gradient with central finite
+//! differences at a few points catches most mistakes in hand-written or
+//! compiled gradients.
+
+use anyhow::{anyhow, bail, Context, Result};
+use nuts_rs::{Math, Model};
+use pyo3::{pyclass, pymethods};
+
+/// Default step size of the finite differences, relative to the scale of
+/// each coordinate.
+pub(crate) const DEFAULT_EPS: f64 = 1e-6;
+
+/// Default largest relative error of the gradient at the initial points.
+pub(crate) const DEFAULT_TOLERANCE: f64 = 1e-3;
+
+/// The gradient of the logp at a point, compared with finite differences.
+///
+/// The relative error of a coordinate is
+/// `|analytic - numeric| / max(|analytic|, |numeric|, 1)`, so it is the
+/// absolute error for small gradients. It is `nan` if the logp could not
+/// be evaluated next to the point.
+#[pyclass(frozen, get_all)]
+#[derive(Clone, Debug)]
+pub struct GradientCheck {
+    point: Vec<f64>,
+    logp: f64,
+    analytic: Vec<f64>,
+    numeric: Vec<f64>,
+    relative_error: Vec<f64>,
+}
+
+#[pymethods]
+impl GradientCheck {
+    /// The largest relative error of all coordinates.
+    #[getter(max_relative_error)]
+    fn py_max_relative_error(&self) -> f64 {
+        self.max_relative_error()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "GradientCheck(logp={}, max_relative_error={:e})",
+            self.logp,
+            self.max_relative_error()
+        )
+    }
+}
+
+impl GradientCheck {
+    pub(crate) fn max_relative_error(&self) -> f64 {
+        self.relative_error.iter().copied().fold(0., f64::max)
+    }
+
+    /// The coordinate with the largest relative error.
+    fn worst(&self) -> Option<usize> {
+        self.relative_error
+            .iter()
+            .enumerate()
+            .filter(|(_, err)| !err.is_nan())
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(i, _)| i)
+    }
+
+    /// Fail if the relative error of a coordinate is above `tolerance`.
+    pub(crate) fn ensure(&self, tolerance: f64) -> Result<()> {
+        let Some(i) = self.worst() else {
+            return Ok(());
+        };
+        if self.relative_error[i] > tolerance {
+            bail!(
+                "The gradient does not match finite differences: coordinate {} has gradient {} \
+                 but finite differences give {} (relative error {:e})",
+                i,
+                self.analytic[i],
+                self.numeric[i],
+                self.relative_error[i],
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Compare the gradient at `point` with central finite differences.
+///
+/// The step of each coordinate is `eps * max(|x|, 1)`. This needs two logp
+/// evaluations per dimension.
+pub(crate) fn check_gradient<M: Math>(
+    math: &mut M,
+    point: &[f64],
+    eps: f64,
+) -> Result<GradientCheck> {
+    if eps.is_nan() || eps <= 0. {
+        bail!("`eps` must be positive");
+    }
+    if math.dim() != point.len() {
+        bail!(
+            "Point has length {}, but the model has {} parameters",
+            point.len(),
+            math.dim()
+        );
+    }
+
+    let mut analytic = vec![0f64; point.len()];
+    let logp = math
+        .logp(point, &mut analytic)
+        .map_err(|err| anyhow!("Could not evaluate the logp at the point: {:?}", err))?;
+
+    let mut shifted = point.to_vec();
+    let mut gradient = vec![0f64; point.len()];
+    let numeric: Vec<f64> = (0..point.len())
+        .map(|i| {
+            let step = eps * point[i].abs().max(1.);
+            let mut logp_at = |x: f64| {
+                shifted[i] = x;
+                let logp = math.logp(&shifted, &mut gradient).ok();
+                shifted[i] = point[i];
+                logp.unwrap_or(f64::NAN)
+            };
+            let upper = logp_at(point[i] + step);
+            let lower = logp_at(point[i] - step);
+            (upper - lower) / (2. * step)
+        })
+        .collect();
+
+    let relative_error = analytic
+        .iter()
+        .zip(&numeric)
+        .map(|(&a, &n)| (a - n).abs() / a.abs().max(n.abs()).max(1.))
+        .collect();
+
+    Ok(GradientCheck {
+        point: point.to_vec(),
+        logp,
+        analytic,
+        numeric,
+        relative_error,
+    })
+}
+
+/// Compare the gradient of `model` at `point` with finite differences.
+pub(crate) fn check_model_gradient<M: Model>(
+    model: &M,
+    point: &[f64],
+    eps: f64,
+) -> Result<GradientCheck> {
+    let mut math = model.math().context("Could not create the model")?;
+    check_gradient(&mut math, point, eps)
+}
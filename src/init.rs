@@ -0,0 +1,338 @@
+//! Find initial points for the chains.
+//!
+//! nuts-rs asks the model for new initial points until the sampler accepts
+//! one, and if none works, only reports the error of the last attempt. We
+//! draw candidates from an `InitStrategy` and evaluate the logp ourselves
+//! before handing them to nuts-rs, so that we can give up after a
+//! configurable number of retries and record why the attempts failed.
+//! Retries only help if the candidates are random, so we stop as soon as
+//! a candidate is the same as the one that failed before.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use numpy::PyReadonlyArray1;
+use nuts_rs::{Math, Model};
+use pyo3::{pyclass, pymethods, Py, PyAny, Python};
+use rand::Rng;
+use rand_distr::{Distribution, Uniform};
+use serde::{ser::Error as _, Serialize};
+
+use crate::provenance::json_sha256;
+
+/// Default number of additional attempts if the logp at an initial point
+/// is not finite.
+pub(crate) const DEFAULT_RETRIES: usize = 100;
+
+/// Set each coordinate to a uniform random value in `[-radius, radius]`.
+pub(crate) fn init_uniform<R: Rng + ?Sized>(
+    radius: f64,
+    rng: &mut R,
+    position: &mut [f64],
+) -> Result<()> {
+    let dist = Uniform::new_inclusive(-radius, radius)?;
+    position
+        .iter_mut()
+        .zip(dist.sample_iter(rng))
+        .for_each(|(pos, val)| *pos = val);
+    Ok(())
+}
+
+/// Start at `point` and move each coordinate by a uniform random value in
+/// `[-jitter, jitter]`.
+pub(crate) fn init_point<R: Rng + ?Sized>(
+    point: &[f64],
+    jitter: f64,
+    rng: &mut R,
+    position: &mut [f64],
+) -> Result<()> {
+    if point.len() != position.len() {
+        bail!(
+            "Initial point has length {}, but the model has {} parameters",
+            point.len(),
+            position.len()
+        );
+    }
+    position.copy_from_slice(point);
+    if jitter > 0. {
+        let dist = Uniform::new_inclusive(-jitter, jitter)?;
+        position
+            .iter_mut()
+            .zip(dist.sample_iter(rng))
+            .for_each(|(pos, val)| *pos += val);
+    }
+    Ok(())
+}
+
+/// Call `func(seed)` for an initial point, for example a draw from the
+/// prior.
+pub(crate) fn init_from_callback<R: Rng + ?Sized>(
+    func: &Py<PyAny>,
+    rng: &mut R,
+    position: &mut [f64],
+) -> Result<()> {
+    let seed = rng.next_u64();
+
+    Python::with_gil(|py| {
+        let init_point = func
+            .call1(py, (seed,))
+            .context("Failed to initialize point")?;
+
+        let init_point: PyReadonlyArray1<f64> = init_point
+            .extract(py)
+            .context("Initializition array returned incorrect argument")?;
+
+        let init_point = init_point
+            .as_slice()
+            .context("Initial point must be contiguous")?;
+
+        if init_point.len() != position.len() {
+            bail!("Initial point has incorrect length");
+        }
+
+        position.copy_from_slice(init_point);
+        Ok(())
+    })
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum Strategy {
+    /// Ask the model, which for PyMC models draws from the initial point
+    /// function, for Stan models uses its inits or a standard normal, and
+    /// for Python function models draws uniformly from `[-2, 2]`.
+    Model,
+    Uniform {
+        radius: f64,
+    },
+    Point {
+        point: Arc<[f64]>,
+        jitter: f64,
+    },
+    Callback(Arc<Py<PyAny>>),
+}
+
+/// How the chains find their initial points on the unconstrained space.
+#[pyclass]
+#[derive(Clone, Debug)]
+pub struct InitStrategy(pub(crate) Strategy);
+
+impl Default for InitStrategy {
+    fn default() -> Self {
+        InitStrategy(Strategy::Model)
+    }
+}
+
+#[pymethods]
+impl InitStrategy {
+    /// Use the initial points of the model.
+    #[staticmethod]
+    fn model() -> Self {
+        InitStrategy(Strategy::Model)
+    }
+
+    /// Draw each coordinate uniformly from `[-radius, radius]`.
+    #[staticmethod]
+    #[pyo3(signature = (radius=2.0))]
+    fn uniform(radius: f64) -> Result<Self> {
+        if !(radius.is_finite() && radius > 0.) {
+            bail!("`radius` must be a positive number");
+        }
+        Ok(InitStrategy(Strategy::Uniform { radius }))
+    }
+
+    /// Start all chains at `point`, with each coordinate moved by a
+    /// uniform random value in `[-jitter, jitter]`.
+    #[staticmethod]
+    #[pyo3(signature = (point, jitter=0.0))]
+    fn point(point: PyReadonlyArray1<f64>, jitter: f64) -> Result<Self> {
+        if !(jitter.is_finite() && jitter >= 0.) {
+            bail!("`jitter` must be a non-negative number");
+        }
+        let point = point.as_array().to_vec().into();
+        Ok(InitStrategy(Strategy::Point { point, jitter }))
+    }
+
+    /// Call `func(seed)` for each initial point, for example to draw from
+    /// the prior. It must return a float64 array with a value for each
+    /// unconstrained parameter.
+    #[staticmethod]
+    fn callback(func: Py<PyAny>) -> Self {
+        InitStrategy(Strategy::Callback(Arc::new(func)))
+    }
+
+    fn __repr__(&self) -> String {
+        match &self.0 {
+            Strategy::Model => "InitStrategy.model()".to_string(),
+            Strategy::Uniform { radius } => format!("InitStrategy.uniform(radius={})", radius),
+            Strategy::Point { point, jitter } => format!(
+                "InitStrategy.point(<{} values>, jitter={})",
+                point.len(),
+                jitter
+            ),
+            Strategy::Callback(_) => "InitStrategy.callback(...)".to_string(),
+        }
+    }
+}
+
+/// How `InitStrategy` appears in the provenance of a trace.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum StrategyRecord {
+    Model,
+    Uniform {
+        radius: f64,
+    },
+    Point {
+        dim: usize,
+        point_sha256: String,
+        jitter: f64,
+    },
+    Callback,
+}
+
+impl Serialize for InitStrategy {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.0 {
+            Strategy::Model => StrategyRecord::Model,
+            &Strategy::Uniform { radius } => StrategyRecord::Uniform { radius },
+            Strategy::Point { point, jitter } => StrategyRecord::Point {
+                dim: point.len(),
+                point_sha256: json_sha256(&point[..]).map_err(S::Error::custom)?,
+                jitter: *jitter,
+            },
+            Strategy::Callback(_) => StrategyRecord::Callback,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// The strategy and how often to try it.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct InitOptions {
+    pub(crate) strategy: InitStrategy,
+    /// Additional attempts after the first initial point failed.
+    pub(crate) retries: usize,
+}
+
+impl Default for InitOptions {
+    fn default() -> Self {
+        Self {
+            strategy: Default::default(),
+            retries: DEFAULT_RETRIES,
+        }
+    }
+}
+
+/// How a chain found its initial point.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct InitRecord {
+    /// Number of candidate points, including the one that worked.
+    pub(crate) attempts: usize,
+    /// How often each reason made a candidate fail.
+    pub(crate) failures: BTreeMap<String, usize>,
+}
+
+impl InitRecord {
+    pub(crate) fn merge(&mut self, other: InitRecord) {
+        self.attempts += other.attempts;
+        for (reason, count) in other.failures {
+            *self.failures.entry(reason).or_default() += count;
+        }
+    }
+
+    /// The reasons of the failed attempts, like `logp is -inf (3 times)`.
+    pub(crate) fn summary(&self) -> String {
+        self.failures
+            .iter()
+            .map(|(reason, count)| match count {
+                1 => reason.clone(),
+                _ => format!("{} ({} times)", reason, count),
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+/// Models whose own initial points can depend on the chain.
+///
+/// `Model::init_position` of nuts-rs does not know which chain the point
+/// is for, so everything that looks for initial points passes the chain
+/// explicitly through this trait.
+pub(crate) trait ModelInit: Model {
+    /// Store the initial point of the model for `chain` in `position`.
+    fn init_chain_position<R: Rng + ?Sized>(
+        &self,
+        chain: u64,
+        rng: &mut R,
+        position: &mut [f64],
+    ) -> Result<()> {
+        let _ = chain;
+        self.init_position(rng, position)
+    }
+}
+
+impl InitOptions {
+    fn draw<M: ModelInit, R: Rng + ?Sized>(
+        &self,
+        model: &M,
+        chain: u64,
+        rng: &mut R,
+        position: &mut [f64],
+    ) -> Result<()> {
+        match &self.strategy.0 {
+            Strategy::Model => model.init_chain_position(chain, rng, position),
+            Strategy::Uniform { radius } => init_uniform(*radius, rng, position),
+            Strategy::Point { point, jitter } => init_point(point, *jitter, rng, position),
+            Strategy::Callback(func) => init_from_callback(func, rng, position),
+        }
+    }
+
+    /// Draw candidates for `chain` until the logp and gradient at one of
+    /// them are finite, and store it in `position`. Gives up early if a
+    /// failed candidate is drawn again.
+    pub(crate) fn initialize<M: ModelInit, R: Rng + ?Sized>(
+        &self,
+        model: &M,
+        chain: u64,
+        rng: &mut R,
+        position: &mut [f64],
+    ) -> Result<InitRecord> {
+        let mut math = model.math().context("Could not create the model")?;
+        let mut gradient = vec![0f64; position.len()];
+        let mut record = InitRecord::default();
+        let mut failed: Option<Vec<f64>> = None;
+        while record.attempts <= self.retries {
+            self.draw(model, chain, rng, position)?;
+            if failed
+                .as_deref()
+                .is_some_and(|failed| same_point(failed, position))
+            {
+                bail!(
+                    "The initial point failed, and retries give the same point: {}",
+                    record.summary()
+                );
+            }
+            record.attempts += 1;
+            let reason = match math.logp(position, &mut gradient) {
+                Ok(logp) if !logp.is_finite() => format!("logp is {}", logp),
+                Ok(_) if !gradient.iter().all(|val| val.is_finite()) => {
+                    "gradient is not finite".to_string()
+                }
+                Ok(_) => return Ok(record),
+                Err(err) => err.to_string(),
+            };
+            *record.failures.entry(reason).or_default() += 1;
+            failed = Some(position.to_vec());
+        }
+        bail!(
+            "No initial point with finite logp and gradient in {} attempts: {}",
+            record.attempts,
+            record.summary()
+        )
+    }
+}
+
+/// Whether the points are equal, also if they contain the same NaN values.
+fn same_point(a: &[f64], b: &[f64]) -> bool {
+    a.iter().zip(b).all(|(a, b)| a.to_bits() == b.to_bits())
+}
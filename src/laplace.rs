@@ -0,0 +1,276 @@
+//! Laplace approximation around the posterior mode.
+//!
+//! We find the mode of the unconstrained posterior with L-BFGS, and compute
+//! the Hessian of the logp at the mode with central finite differences of
+//! the gradient, so that this works for every backend without second
+//! derivatives. The draws of the normal approximation with covariance
+//! `(-H)^(-1)` are expanded in the same way as the draws of a trace.
+
+use std::{f64::consts::PI, sync::Arc};
+
+use anyhow::{anyhow, bail, Context, Result};
+use arrow::array::Array;
+use numpy::{PyArray1, PyArray2, PyArrayMethods};
+use nuts_rs::Math;
+use pyo3::{pyclass, pymethods, Bound, PyObject, Python};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, StandardNormal};
+
+use crate::{
+    evaluate::expand_points,
+    init::{InitStrategy, ModelInit},
+    lbfgs::{Convergence, Iterate, LbfgsOptions},
+    linalg::{cholesky, invert_upper, matmul, transpose},
+    optimize::maximize_model,
+    wrapper::export_array,
+};
+
+/// Default step size of the finite differences of the gradient, relative
+/// to the scale of each coordinate.
+pub(crate) const DEFAULT_HESSIAN_EPS: f64 = 1e-5;
+
+/// The Hessian of the logp at `point` by central finite differences of the
+/// gradient, as a symmetric row major matrix.
+///
+/// The step of each coordinate is `eps * max(|x|, 1)`. This needs two
+/// gradient evaluations per dimension.
+fn hessian<M: Math>(math: &mut M, point: &[f64], eps: f64) -> Result<Vec<f64>> {
+    if eps.is_nan() || eps <= 0. {
+        bail!("`eps` must be positive");
+    }
+    let n = point.len();
+    let mut hessian = vec![0f64; n * n];
+    let mut shifted = point.to_vec();
+    let mut upper = vec![0f64; n];
+    let mut lower = vec![0f64; n];
+    for i in 0..n {
+        let step = eps * point[i].abs().max(1.);
+        let mut gradient_at = |x: f64, gradient: &mut [f64]| {
+            shifted[i] = x;
+            let logp = math.logp(&shifted, gradient);
+            shifted[i] = point[i];
+            match logp {
+                Ok(logp) if logp.is_finite() => Ok(()),
+                Ok(logp) => Err(anyhow!("logp is {}", logp)),
+                Err(err) => Err(anyhow!("{}", err)),
+            }
+        };
+        gradient_at(point[i] + step, &mut upper)
+            .and_then(|_| gradient_at(point[i] - step, &mut lower))
+            .with_context(|| {
+                format!(
+                    "Could not evaluate the gradient next to the mode in coordinate {}",
+                    i
+                )
+            })?;
+        for j in 0..n {
+            hessian[j * n + i] = (upper[j] - lower[j]) / (2. * step);
+        }
+    }
+    for i in 0..n {
+        for j in 0..i {
+            let mean = (hessian[i * n + j] + hessian[j * n + i]) / 2.;
+            hessian[i * n + j] = mean;
+            hessian[j * n + i] = mean;
+        }
+    }
+    Ok(hessian)
+}
+
+/// The result of `laplace`.
+#[pyclass]
+pub struct LaplaceResult {
+    mode: Iterate,
+    convergence: Convergence,
+    num_iters: usize,
+    hessian: Vec<f64>,
+    /// `L^(-T)` for the lower Cholesky factor `L` of `-H`, row major.
+    scale: Vec<f64>,
+    draws: Vec<Box<[f64]>>,
+    logp: Vec<f64>,
+    logq: Vec<f64>,
+    /// The draws, expanded like the draws of a trace.
+    trace: Arc<dyn Array>,
+}
+
+impl LaplaceResult {
+    fn dim(&self) -> usize {
+        self.mode.position.len()
+    }
+
+    fn square<'py>(
+        py: Python<'py>,
+        values: Vec<f64>,
+        n: usize,
+    ) -> Result<Bound<'py, PyArray2<f64>>> {
+        Ok(PyArray1::from_vec(py, values).reshape([n, n])?)
+    }
+}
+
+#[pymethods]
+impl LaplaceResult {
+    /// The mode on the unconstrained space.
+    #[getter]
+    fn mode<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f64>> {
+        PyArray1::from_slice(py, &self.mode.position)
+    }
+
+    /// The logp at the mode.
+    #[getter]
+    fn logp_mode(&self) -> f64 {
+        self.mode.logp
+    }
+
+    /// The number of L-BFGS iterations to find the mode.
+    #[getter]
+    fn num_iters(&self) -> usize {
+        self.num_iters
+    }
+
+    /// Why the optimizer stopped: `gradient`, `logp`, `max_iters` or
+    /// `line_search`.
+    #[getter]
+    fn convergence(&self) -> &'static str {
+        self.convergence.name()
+    }
+
+    /// Whether the optimizer converged.
+    #[getter]
+    fn converged(&self) -> bool {
+        self.convergence.converged()
+    }
+
+    /// The Hessian of the logp at the mode.
+    #[getter]
+    fn hessian<'py>(&self, py: Python<'py>) -> Result<Bound<'py, PyArray2<f64>>> {
+        Self::square(py, self.hessian.clone(), self.dim())
+    }
+
+    /// The covariance of the approximation, the inverse of the negative
+    /// Hessian.
+    #[getter]
+    fn covariance<'py>(&self, py: Python<'py>) -> Result<Bound<'py, PyArray2<f64>>> {
+        let n = self.dim();
+        let covariance = matmul(&self.scale, &transpose(&self.scale, n, n), n, n, n);
+        Self::square(py, covariance, n)
+    }
+
+    /// The draws on the unconstrained space, with shape
+    /// `(num_draws, n_dim)`.
+    #[getter]
+    fn draws<'py>(&self, py: Python<'py>) -> Result<Bound<'py, PyArray2<f64>>> {
+        let values = self
+            .draws
+            .iter()
+            .flat_map(|draw| draw.iter().copied())
+            .collect();
+        Ok(PyArray1::from_vec(py, values).reshape([self.draws.len(), self.dim()])?)
+    }
+
+    /// The logp of each draw, `-inf` where the model could not be
+    /// evaluated.
+    #[getter]
+    fn logp(&self) -> Vec<f64> {
+        self.logp.clone()
+    }
+
+    /// The log density of each draw under the approximation.
+    #[getter]
+    fn logq(&self) -> Vec<f64> {
+        self.logq.clone()
+    }
+
+    /// The log importance weight `logp - logq` of each draw.
+    #[getter]
+    fn log_weight(&self) -> Vec<f64> {
+        self.logp
+            .iter()
+            .zip(&self.logq)
+            .map(|(logp, logq)| logp - logq)
+            .collect()
+    }
+
+    /// The draws as a pyarrow array with the layout of the draws of a
+    /// trace.
+    #[getter]
+    fn trace(&self, py: Python<'_>) -> Result<PyObject> {
+        Ok(export_array(py, self.trace.clone())?)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "LaplaceResult(logp_mode={}, num_draws={}, convergence={})",
+            self.mode.logp,
+            self.draws.len(),
+            self.convergence()
+        )
+    }
+}
+
+/// Find the mode of `model` and draw `num_draws` times from the Laplace
+/// approximation, for the `laplace` method of the models.
+pub(crate) fn laplace<M: ModelInit>(
+    model: &M,
+    start: Option<Vec<f64>>,
+    num_draws: usize,
+    eps: f64,
+    init_strategy: Option<InitStrategy>,
+    seed: Option<u64>,
+) -> Result<LaplaceResult> {
+    let seed = seed.unwrap_or_else(|| rand::rng().next_u64());
+    let path = maximize_model(model, start, &LbfgsOptions::default(), init_strategy, seed)?;
+    let mode = path.optimum().clone();
+    let n = mode.position.len();
+
+    let mut math = model.math().context("Could not create the model")?;
+    let hessian = hessian(&mut math, &mode.position, eps)?;
+    let precision: Vec<f64> = hessian.iter().map(|h| -h).collect();
+    let Some(chol) = cholesky(&precision, n) else {
+        bail!(
+            "The Hessian at the mode is not negative definite (optimizer stopped because of {})",
+            path.convergence.name()
+        );
+    };
+    let scale =
+        invert_upper(&transpose(&chol, n, n), n).context("The Hessian at the mode is singular")?;
+    let logdet: f64 = -2. * (0..n).map(|i| chol[i * n + i].ln()).sum::<f64>();
+
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_stream(1);
+    let mut gradient = vec![0f64; n];
+    let mut draws = Vec::with_capacity(num_draws);
+    let mut logp = Vec::with_capacity(num_draws);
+    let mut logq = Vec::with_capacity(num_draws);
+    for _ in 0..num_draws {
+        let z: Vec<f64> = StandardNormal.sample_iter(&mut rng).take(n).collect();
+        let offset = matmul(&scale, &z, n, n, 1);
+        let draw: Box<[f64]> = mode
+            .position
+            .iter()
+            .zip(&offset)
+            .map(|(x, offset)| x + offset)
+            .collect();
+        logq.push(
+            -0.5 * (logdet + z.iter().map(|z| z * z).sum::<f64>() + n as f64 * (2. * PI).ln()),
+        );
+        logp.push(match math.logp(&draw, &mut gradient) {
+            Ok(logp) if !logp.is_nan() => logp,
+            _ => f64::NEG_INFINITY,
+        });
+        draws.push(draw);
+    }
+
+    let trace = expand_points(model, &draws, seed).context("Could not expand the draws")?;
+    Ok(LaplaceResult {
+        mode,
+        convergence: path.convergence,
+        num_iters: path.path.len() - 1,
+        hessian,
+        scale,
+        draws,
+        logp,
+        logq,
+        trace,
+    })
+}
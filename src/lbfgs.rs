@@ -0,0 +1,274 @@
+//! Maximize the log density of a model with L-BFGS.
+//!
+//! The optimizer works on the same `Math` as the sampler, so it sees the
+//! unconstrained log density and gradient of all backends. Besides the
+//! optimum it keeps every iterate and the curvature pairs of the history,
+//! which Pathfinder needs to build its normal approximations.
+
+use std::collections::VecDeque;
+
+use anyhow::{bail, Result};
+use nuts_rs::{LogpError, Math};
+use serde::Serialize;
+
+use crate::linalg::{axpy, dot};
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct LbfgsOptions {
+    /// Maximum number of iterations.
+    pub(crate) max_iters: usize,
+    /// Number of curvature pairs of the inverse Hessian approximation.
+    pub(crate) history: usize,
+    /// Stop if the largest absolute gradient is below this value.
+    pub(crate) grad_tol: f64,
+    /// Stop if the logp improves by less than this, relative to the logp.
+    pub(crate) rel_logp_tol: f64,
+}
+
+impl Default for LbfgsOptions {
+    fn default() -> Self {
+        Self {
+            max_iters: 1000,
+            history: 6,
+            grad_tol: 1e-8,
+            rel_logp_tol: 1e4 * f64::EPSILON,
+        }
+    }
+}
+
+/// The difference of positions and of the negative gradients between two
+/// iterates.
+pub(crate) type CurvaturePair = (Box<[f64]>, Box<[f64]>);
+
+/// A point the optimizer visited.
+#[derive(Clone, Debug)]
+pub(crate) struct Iterate {
+    pub(crate) position: Box<[f64]>,
+    pub(crate) logp: f64,
+    /// Gradient of the logp.
+    pub(crate) gradient: Box<[f64]>,
+}
+
+/// Why the optimizer stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Convergence {
+    Gradient,
+    Logp,
+    MaxIterations,
+    /// The line search could not find a point with a larger logp.
+    LineSearch,
+}
+
+impl Convergence {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Convergence::Gradient => "gradient",
+            Convergence::Logp => "logp",
+            Convergence::MaxIterations => "max_iters",
+            Convergence::LineSearch => "line_search",
+        }
+    }
+
+    pub(crate) fn converged(&self) -> bool {
+        matches!(self, Convergence::Gradient | Convergence::Logp)
+    }
+}
+
+/// The iterates of an optimization run.
+///
+/// `pairs[i]` is the curvature pair of `path[i + 1]` and `path[i]`, or
+/// `None` if it did not satisfy the curvature condition and was left out of
+/// the history.
+#[derive(Clone, Debug)]
+pub(crate) struct LbfgsPath {
+    pub(crate) path: Vec<Iterate>,
+    pub(crate) pairs: Vec<Option<CurvaturePair>>,
+    pub(crate) convergence: Convergence,
+}
+
+impl LbfgsPath {
+    pub(crate) fn optimum(&self) -> &Iterate {
+        self.path.last().expect("Path contains the initial point")
+    }
+}
+
+/// Evaluate the logp at a point, with `None` for points outside of the
+/// support.
+fn evaluate<M: Math>(math: &mut M, position: &[f64]) -> Result<Option<Iterate>> {
+    let mut gradient = vec![0f64; position.len()];
+    match math.logp(position, &mut gradient) {
+        Ok(logp) if logp.is_finite() && gradient.iter().all(|g| g.is_finite()) => {
+            Ok(Some(Iterate {
+                position: position.into(),
+                logp,
+                gradient: gradient.into(),
+            }))
+        }
+        Ok(_) => Ok(None),
+        Err(err) if err.is_recoverable() => Ok(None),
+        Err(err) => bail!("Could not evaluate the logp: {}", err),
+    }
+}
+
+/// The search direction `H * gradient` of the two-loop recursion, for
+/// maximizing the logp.
+fn direction(history: &VecDeque<CurvaturePair>, gradient: &[f64]) -> Vec<f64> {
+    // We minimize `-logp`, whose gradient is `-gradient`.
+    let mut q: Vec<f64> = gradient.iter().map(|g| -g).collect();
+    let mut alphas = Vec::with_capacity(history.len());
+    for (s, y) in history.iter().rev() {
+        let alpha = dot(s, &q) / dot(s, y);
+        axpy(-alpha, y, &mut q);
+        alphas.push(alpha);
+    }
+    if let Some((s, y)) = history.back() {
+        let scale = dot(s, y) / dot(y, y);
+        q.iter_mut().for_each(|x| *x *= scale);
+    }
+    for ((s, y), alpha) in history.iter().zip(alphas.iter().rev()) {
+        let beta = dot(y, &q) / dot(s, y);
+        axpy(alpha - beta, s, &mut q);
+    }
+    q.iter_mut().for_each(|x| *x = -*x);
+    q
+}
+
+/// Find a step along `direction` that satisfies the strong Wolfe
+/// conditions, by bracketing and bisection.
+fn line_search<M: Math>(
+    math: &mut M,
+    start: &Iterate,
+    direction: &[f64],
+    initial_step: f64,
+) -> Result<Option<Iterate>> {
+    const C1: f64 = 1e-4;
+    const C2: f64 = 0.9;
+    const MAX_EVALS: usize = 40;
+
+    let slope = dot(&start.gradient, direction);
+    if slope.is_nan() || slope <= 0. {
+        return Ok(None);
+    }
+    let mut point = start.position.to_vec();
+    let mut at = |math: &mut M, step: f64| {
+        point
+            .iter_mut()
+            .zip(start.position.iter().zip(direction))
+            .for_each(|(x, (x0, d))| *x = x0 + step * d);
+        evaluate(math, &point)
+    };
+
+    let mut lower = 0f64;
+    let mut upper = f64::INFINITY;
+    let mut step = initial_step;
+    let mut best: Option<Iterate> = None;
+    for _ in 0..MAX_EVALS {
+        let candidate = at(math, step)?;
+        match candidate {
+            Some(candidate) if candidate.logp >= start.logp + C1 * step * slope => {
+                let new_slope = dot(&candidate.gradient, direction);
+                if new_slope.abs() <= C2 * slope {
+                    return Ok(Some(candidate));
+                }
+                let improves = best
+                    .as_ref()
+                    .map_or(true, |best| candidate.logp > best.logp);
+                if new_slope > 0. {
+                    lower = step;
+                } else {
+                    upper = step;
+                }
+                if improves {
+                    best = Some(candidate);
+                }
+            }
+            // Too far, or outside of the support.
+            _ => upper = step,
+        }
+        step = if upper.is_finite() {
+            (lower + upper) / 2.
+        } else {
+            2. * step
+        };
+    }
+    // Accept a sufficient increase even if the curvature condition does
+    // not hold. The history skips such pairs.
+    Ok(best)
+}
+
+/// Maximize the logp with L-BFGS, starting at `start`.
+pub(crate) fn maximize<M: Math>(
+    math: &mut M,
+    start: &[f64],
+    options: &LbfgsOptions,
+) -> Result<LbfgsPath> {
+    if math.dim() != start.len() {
+        bail!(
+            "Initial point has length {}, but the model has {} parameters",
+            start.len(),
+            math.dim()
+        );
+    }
+    let Some(initial) = evaluate(math, start)? else {
+        bail!("The logp or its gradient is not finite at the initial point");
+    };
+
+    let mut history: VecDeque<CurvaturePair> = VecDeque::new();
+    let mut path = vec![initial];
+    let mut pairs = Vec::new();
+    let convergence = loop {
+        let current = path.last().expect("Path contains the initial point");
+        if current.gradient.iter().all(|g| g.abs() <= options.grad_tol) {
+            break Convergence::Gradient;
+        }
+        if path.len() > options.max_iters {
+            break Convergence::MaxIterations;
+        }
+        let direction = direction(&history, &current.gradient);
+        // Without curvature information the first step is scaled to
+        // length one.
+        let initial_step = if history.is_empty() {
+            dot(&direction, &direction).sqrt().recip().min(1.)
+        } else {
+            1.
+        };
+        let Some(next) = line_search(math, current, &direction, initial_step)? else {
+            break Convergence::LineSearch;
+        };
+
+        let s: Box<[f64]> = next
+            .position
+            .iter()
+            .zip(current.position.iter())
+            .map(|(a, b)| a - b)
+            .collect();
+        let y: Box<[f64]> = next
+            .gradient
+            .iter()
+            .zip(current.gradient.iter())
+            .map(|(a, b)| b - a)
+            .collect();
+        let improvement = next.logp - current.logp;
+        let scale = current.logp.abs().max(next.logp.abs()).max(1.);
+        let curvature = dot(&s, &y);
+        if curvature > f64::EPSILON * dot(&y, &y) {
+            if history.len() == options.history {
+                history.pop_front();
+            }
+            history.push_back((s.clone(), y.clone()));
+            pairs.push(Some((s, y)));
+        } else {
+            pairs.push(None);
+        }
+        path.push(next);
+        if improvement < options.rel_logp_tol * scale {
+            break Convergence::Logp;
+        }
+    };
+
+    Ok(LbfgsPath {
+        path,
+        pairs,
+        convergence,
+    })
+}
@@ -1,7 +1,27 @@
+mod benchmark;
+mod checkpoint;
+mod diagnostics;
+mod evaluate;
+mod gradient;
+mod init;
+mod laplace;
+mod lbfgs;
+mod linalg;
+mod metric;
+mod monitor;
+mod optimize;
+mod pathfinder;
 mod progress;
+mod provenance;
 mod pyfunc;
 mod pymc;
+mod reporting;
+mod settings;
 mod stan;
+mod stopping;
+mod storage;
+mod tracking;
 mod wrapper;
+mod zarr;
 
 pub use wrapper::_lib;
@@ -0,0 +1,125 @@
+//! Dense linear algebra for the optimizers and approximations.
+//!
+//! The matrices here are either tiny (the size of an L-BFGS history) or
+//! stored as a list of columns of the length of the model. Square matrices
+//! are row major. The factorizations are done by faer, which nuts-rs uses
+//! as well, we only convert between its matrices and our slices.
+
+use faer::{linalg::triangular_inverse::invert_upper_triangular, Mat, MatRef, Par, Side};
+
+pub(crate) fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+/// `y += alpha * x`
+pub(crate) fn axpy(alpha: f64, x: &[f64], y: &mut [f64]) {
+    y.iter_mut().zip(x).for_each(|(y, x)| *y += alpha * x);
+}
+
+fn view(a: &[f64], nrows: usize, ncols: usize) -> MatRef<'_, f64> {
+    MatRef::from_row_major_slice(a, nrows, ncols)
+}
+
+fn row_major(a: MatRef<'_, f64>) -> Vec<f64> {
+    (0..a.nrows())
+        .flat_map(|i| (0..a.ncols()).map(move |j| a[(i, j)]))
+        .collect()
+}
+
+fn all_finite(values: &[f64]) -> bool {
+    values.iter().all(|val| val.is_finite())
+}
+
+/// Lower Cholesky factor of the symmetric `n x n` matrix `a`, or `None` if
+/// it is not positive definite.
+pub(crate) fn cholesky(a: &[f64], n: usize) -> Option<Vec<f64>> {
+    let llt = view(a, n, n).llt(Side::Lower).ok()?;
+    let l = row_major(llt.L());
+    all_finite(&l).then_some(l)
+}
+
+/// Inverse of the upper triangular `n x n` matrix `r`, or `None` if it is
+/// singular.
+pub(crate) fn invert_upper(r: &[f64], n: usize) -> Option<Vec<f64>> {
+    let r = view(r, n, n);
+    if (0..n).any(|i| r[(i, i)] == 0.) {
+        return None;
+    }
+    let mut inv = Mat::zeros(n, n);
+    invert_upper_triangular(inv.as_mut(), r, Par::Seq);
+    let inv = row_major(inv.as_ref());
+    all_finite(&inv).then_some(inv)
+}
+
+/// `a * b` for row major matrices of shapes `n x m` and `m x p`.
+pub(crate) fn matmul(a: &[f64], b: &[f64], n: usize, m: usize, p: usize) -> Vec<f64> {
+    row_major((view(a, n, m) * view(b, m, p)).as_ref())
+}
+
+pub(crate) fn transpose(a: &[f64], n: usize, m: usize) -> Vec<f64> {
+    row_major(view(a, n, m).transpose())
+}
+
+/// Thin QR decomposition of the matrix with the given columns, with column
+/// pivoting to find its numerical rank.
+///
+/// Returns the orthonormal columns of `Q` and the upper trapezoidal `R`
+/// with one row per column of `Q`, in the original order of the columns.
+/// If the columns are linearly dependent, `Q` only spans their column
+/// space, and `Q R` is the matrix up to rounding errors.
+pub(crate) fn thin_qr(columns: &[Vec<f64>]) -> (Vec<Vec<f64>>, Vec<Vec<f64>>) {
+    let k = columns.len();
+    let Some(dim) = columns.first().map(Vec::len) else {
+        return (Vec::new(), Vec::new());
+    };
+    let a = Mat::from_fn(dim, k, |i, j| columns[j][i]);
+    let qr = a.col_piv_qr();
+    let r = qr.thin_R();
+    let q = qr.compute_thin_Q();
+    // The diagonal of `R` decreases in magnitude, and columns are pivoted
+    // to the front, so the rank is the number of large entries.
+    let size = r.nrows();
+    let largest = if size > 0 { r[(0, 0)].abs() } else { 0. };
+    let rank = (0..size)
+        .take_while(|&i| largest > 0. && r[(i, i)].abs() > 1e-10 * largest)
+        .count();
+    let (_, inverse) = qr.P().arrays();
+    let q_columns = (0..rank).map(|col| q.col(col).iter().copied().collect());
+    let r_rows = (0..rank).map(|row| (0..k).map(|j| r[(row, inverse[j])]).collect());
+    (q_columns.collect(), r_rows.collect())
+}
+
+/// Eigenvalues and eigenvectors of the symmetric `n x n` matrix `a`, or
+/// `None` if the decomposition did not converge.
+///
+/// Returns the eigenvalues and a row major matrix whose columns are the
+/// eigenvectors.
+pub(crate) fn symmetric_eigen(a: &[f64], n: usize) -> Option<(Vec<f64>, Vec<f64>)> {
+    let eigen = view(a, n, n).self_adjoint_eigen(Side::Lower).ok()?;
+    let values: Vec<f64> = eigen.S().column_vector().iter().copied().collect();
+    let vectors = row_major(eigen.U());
+    (all_finite(&values) && all_finite(&vectors)).then_some((values, vectors))
+}
+
+/// `x = l * x` for the lower triangular `n x n` matrix `l`.
+pub(crate) fn lower_mul_in_place(l: &[f64], n: usize, x: &mut [f64]) {
+    for i in (0..n).rev() {
+        x[i] = (0..=i).map(|k| l[i * n + k] * x[k]).sum();
+    }
+}
+
+/// `x = l^T * x` for the lower triangular `n x n` matrix `l`.
+pub(crate) fn lower_transpose_mul_in_place(l: &[f64], n: usize, x: &mut [f64]) {
+    for i in 0..n {
+        x[i] = (i..n).map(|k| l[k * n + i] * x[k]).sum();
+    }
+}
+
+/// Solve `l * y = x` for the lower triangular `n x n` matrix `l`, and store
+/// `y` in `x`.
+pub(crate) fn solve_lower_in_place(l: &[f64], n: usize, x: &mut [f64]) {
+    for i in 0..n {
+        let sum: f64 = (0..i).map(|k| l[i * n + k] * x[k]).sum();
+        x[i] = (x[i] - sum) / l[i * n + i];
+    }
+}
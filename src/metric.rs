@@ -0,0 +1,1129 @@
+//! Mass matrices that we control from the Rust side.
+//!
+//! nuts-rs does not allow us to set the mass matrix or the step size of a chain
+//! directly. It does however support samplers that work on a transformed
+//! space (`TransformedNutsSettings`), and an affine transformation
+//! `x = sigma * y` is equivalent to running NUTS with the metric
+//! `diag(sigma^2)`. `MetricModel` wraps any model in such a transformation,
+//! so that we can start chains with a known metric, keep it fixed, or refine
+//! it during warmup. The transformation can also include a low-rank
+//! correction of the diagonal metric, or use a dense metric instead, which
+//! is how we implement the `LowRank` and `Dense` settings. nuts-rs does not
+//! report the low-rank part of the mass matrices it adapts itself, but we
+//! need the whole metric to checkpoint and continue chains.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::{bail, Result};
+use numpy::{PyReadonlyArray1, PyReadonlyArray2};
+use nuts_rs::{
+    CpuLogpFunc, CpuMath, DualAverageSettings, LogpError, Math, Model, NutsSettings, Settings,
+    TransformedNutsSettings,
+};
+use pyo3::{prelude::*, pyclass, pymethods};
+use serde::{ser::Error as _, Serialize};
+use thiserror::Error;
+
+use crate::{
+    init::ModelInit,
+    linalg::{
+        axpy, cholesky, dot, lower_mul_in_place, lower_transpose_mul_in_place, matmul,
+        solve_lower_in_place, symmetric_eigen, thin_qr, transpose,
+    },
+    provenance::json_sha256,
+    tracking::ChainRegistry,
+};
+
+/// The metric used for one chain at the start of sampling.
+#[derive(Clone, Debug)]
+pub(crate) struct ChainMetric {
+    /// Diagonal of the inverse mass matrix, ie an estimate of the
+    /// posterior variance of each unconstrained parameter.
+    pub(crate) inv_mass: Box<[f64]>,
+    /// Correction of the diagonal inverse mass matrix in a few directions.
+    pub(crate) low_rank: Option<LowRankMetric>,
+    /// Row major lower Cholesky factor of a dense inverse mass matrix.
+    /// If this is set, `inv_mass` must be its diagonal and `low_rank` is
+    /// ignored.
+    pub(crate) dense: Option<Box<[f64]>>,
+    /// Refine the metric from the draws during warmup. Unless the run
+    /// adapts a low-rank correction, this drops it at the first update.
+    pub(crate) adapt: bool,
+    /// The step size the chain reached with this metric, if we know it.
+    pub(crate) step_size: Option<f64>,
+}
+
+/// A low-rank correction of a diagonal inverse mass matrix `D`, so that the
+/// inverse mass matrix is `D^(1/2) (I + U (diag(values) - I) U^T) D^(1/2)`
+/// with orthonormal columns `U`.
+#[derive(Clone, Debug)]
+pub(crate) struct LowRankMetric {
+    pub(crate) vectors: Vec<Box<[f64]>>,
+    pub(crate) values: Box<[f64]>,
+}
+
+/// An inverse mass matrix given by the user.
+///
+/// All chains start with this metric. Unless adaptation is turned off, it
+/// is refined during warmup like any other initial metric.
+#[pyclass]
+#[derive(Clone, Debug)]
+pub struct MassMatrix(ChainMetric);
+
+impl MassMatrix {
+    /// The options to start `num_chains` chains with this metric.
+    pub(crate) fn options(&self, num_chains: usize, adapt: bool) -> MetricOptions {
+        let metric = ChainMetric {
+            adapt,
+            ..self.0.clone()
+        };
+        MetricOptions {
+            chains: (0..num_chains as u64)
+                .map(|chain| (chain, metric.clone()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// A `MassMatrix`, or a numpy array with the diagonal or the full
+    /// inverse mass matrix.
+    pub(crate) fn from_py(value: &Bound<PyAny>) -> Result<Self> {
+        if let Ok(mass_matrix) = value.extract::<MassMatrix>() {
+            Ok(mass_matrix)
+        } else if let Ok(inv_mass) = value.extract::<PyReadonlyArray1<f64>>() {
+            MassMatrix::diag(inv_mass)
+        } else if let Ok(inv_mass) = value.extract::<PyReadonlyArray2<f64>>() {
+            MassMatrix::dense(inv_mass)
+        } else {
+            bail!("`mass_matrix` must be a MassMatrix or a 1d or 2d float64 array")
+        }
+    }
+}
+
+/// How `MassMatrix` appears in the provenance of a trace.
+#[derive(Serialize)]
+struct MassMatrixRecord {
+    kind: &'static str,
+    dim: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rank: Option<usize>,
+    sha256: String,
+}
+
+impl Serialize for MassMatrix {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let metric = &self.0;
+        let low_rank = metric
+            .low_rank
+            .as_ref()
+            .map(|low_rank| (&low_rank.vectors, &low_rank.values));
+        let kind = match (&metric.dense, &low_rank) {
+            (Some(_), _) => "dense",
+            (None, Some(_)) => "low_rank",
+            (None, None) => "diag",
+        };
+        MassMatrixRecord {
+            kind,
+            dim: metric.inv_mass.len(),
+            rank: low_rank.map(|(_, values)| values.len()),
+            sha256: json_sha256(&(&metric.inv_mass, &metric.dense, low_rank))
+                .map_err(S::Error::custom)?,
+        }
+        .serialize(serializer)
+    }
+}
+
+fn check_positive(values: &[f64], name: &str) -> Result<()> {
+    if values.iter().any(|val| !(val.is_finite() && *val > 0.)) {
+        bail!("All values of `{}` must be positive and finite", name);
+    }
+    Ok(())
+}
+
+#[pymethods]
+impl MassMatrix {
+    /// A diagonal inverse mass matrix, ie the posterior variances of the
+    /// unconstrained parameters.
+    #[staticmethod]
+    fn diag(inv_mass: PyReadonlyArray1<f64>) -> Result<Self> {
+        let inv_mass: Box<[f64]> = inv_mass.as_array().iter().copied().collect();
+        check_positive(&inv_mass, "inv_mass")?;
+        Ok(MassMatrix(ChainMetric {
+            inv_mass,
+            low_rank: None,
+            dense: None,
+            adapt: false,
+            step_size: None,
+        }))
+    }
+
+    /// A dense inverse mass matrix, ie the posterior covariance of the
+    /// unconstrained parameters. It must be symmetric and positive
+    /// definite.
+    #[staticmethod]
+    fn dense(inv_mass: PyReadonlyArray2<f64>) -> Result<Self> {
+        let inv_mass = inv_mass.as_array();
+        let (rows, cols) = inv_mass.dim();
+        if rows != cols {
+            bail!(
+                "`inv_mass` must be a square matrix, but has shape ({}, {})",
+                rows,
+                cols
+            );
+        }
+        let values: Vec<f64> = inv_mass.iter().copied().collect();
+        let diag: Box<[f64]> = (0..rows).map(|i| values[i * rows + i]).collect();
+        check_positive(&diag, "the diagonal of inv_mass")?;
+        for i in 0..rows {
+            for j in 0..i {
+                let (upper, lower) = (values[j * rows + i], values[i * rows + j]);
+                if (upper - lower).abs() > 1e-8 * (diag[i] * diag[j]).sqrt() {
+                    bail!("`inv_mass` must be symmetric");
+                }
+            }
+        }
+        let Some(chol) = cholesky(&values, rows) else {
+            bail!("`inv_mass` must be positive definite");
+        };
+        Ok(MassMatrix(ChainMetric {
+            inv_mass: diag,
+            low_rank: None,
+            dense: Some(chol.into()),
+            adapt: false,
+            step_size: None,
+        }))
+    }
+
+    /// The inverse mass matrix `D^(1/2) (I + U (diag(values) - I) U^T) D^(1/2)`
+    /// with the diagonal matrix `D` and the orthonormal columns `U` of
+    /// `vectors`, which has shape `(n_dim, k)`. This is the form of the
+    /// low-rank modified mass matrix.
+    #[staticmethod]
+    fn low_rank(
+        diag: PyReadonlyArray1<f64>,
+        vectors: PyReadonlyArray2<f64>,
+        values: PyReadonlyArray1<f64>,
+    ) -> Result<Self> {
+        let diag: Box<[f64]> = diag.as_array().iter().copied().collect();
+        let values: Box<[f64]> = values.as_array().iter().copied().collect();
+        check_positive(&diag, "diag")?;
+        check_positive(&values, "values")?;
+        let vectors = vectors.as_array();
+        if vectors.dim() != (diag.len(), values.len()) {
+            bail!(
+                "`vectors` must have shape ({}, {}), but has shape {:?}",
+                diag.len(),
+                values.len(),
+                vectors.dim()
+            );
+        }
+        let vectors: Vec<Box<[f64]>> = vectors
+            .columns()
+            .into_iter()
+            .map(|col| col.iter().copied().collect())
+            .collect();
+        for (i, u) in vectors.iter().enumerate() {
+            for (j, v) in vectors.iter().enumerate().take(i + 1) {
+                let target = if i == j { 1. } else { 0. };
+                if (dot(u, v) - target).abs() > 1e-6 {
+                    bail!("The columns of `vectors` must be orthonormal");
+                }
+            }
+        }
+        Ok(MassMatrix(ChainMetric {
+            inv_mass: diag,
+            low_rank: Some(LowRankMetric { vectors, values }),
+            dense: None,
+            adapt: false,
+            step_size: None,
+        }))
+    }
+
+    /// The number of unconstrained parameters.
+    #[getter]
+    fn n_dim(&self) -> usize {
+        self.0.inv_mass.len()
+    }
+
+    /// `diag`, `dense` or `low_rank`.
+    #[getter]
+    fn kind(&self) -> &'static str {
+        match (&self.0.dense, &self.0.low_rank) {
+            (Some(_), _) => "dense",
+            (None, Some(_)) => "low_rank",
+            (None, None) => "diag",
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!("MassMatrix.{}(<{} parameters>)", self.kind(), self.n_dim())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MetricOptions {
+    /// Initial metric per chain. Chains without an entry start with
+    /// a metric estimated from the gradient at the initial point and
+    /// adapt it during warmup.
+    pub(crate) chains: BTreeMap<u64, ChainMetric>,
+    /// Scale of the transformation on top of the metric of each chain,
+    /// see `set_step_size`. Chains without an entry are not scaled.
+    pub(crate) step_scales: BTreeMap<u64, f64>,
+    /// nuts-rs keeps the step size at `FIXED_SAMPLER_STEP`.
+    pub(crate) fixed_step: bool,
+    /// Adapt a dense metric in windows during warmup, instead of a
+    /// diagonal one.
+    pub(crate) dense: Option<DenseAdaptation>,
+    /// Adapt a low-rank correction of the diagonal metric during warmup.
+    pub(crate) low_rank: Option<LowRankAdaptation>,
+}
+
+/// The step size nuts-rs uses in runs with a fixed step size.
+///
+/// When a chain starts, nuts-rs searches for a step size with an acceptance
+/// rate close to `target_accept`, doubling or halving `initial_step`. With a
+/// target of 1 it halves, and stops as soon as the step size falls below
+/// `1e-10`, so a smaller `initial_step` is kept as it is. We use a power of
+/// two, so that scaling the step size in the sampler stats is exact.
+pub(crate) const FIXED_SAMPLER_STEP: f64 = 1. / (1u64 << 40) as f64;
+
+impl MetricOptions {
+    /// Start the chains of `settings` with the step size of their metric,
+    /// or with `step_size` if it is not known, and keep them if the
+    /// settings do not tune.
+    ///
+    /// nuts-rs starts all chains with the same step size, and always
+    /// searches for a better one, which there is no option to turn off.
+    /// Without warmup we let it keep a tiny step size instead. In both
+    /// cases we scale the transformation of the `MetricModel` of each
+    /// chain by the ratio of the step size we want and the one nuts-rs
+    /// uses. Leapfrog steps on the scaled space are the same as on the
+    /// unscaled space with the step size we want. The sampler stats and
+    /// the progress report the step size on the scaled space, which
+    /// `Reporting` multiplies by the scale of the chain. Without warmup
+    /// `Reporting` also checks that nuts-rs really kept
+    /// `FIXED_SAMPLER_STEP` in every draw, in case a later version
+    /// searches differently.
+    pub(crate) fn set_step_size(&mut self, settings: &mut TransformedNutsSettings, step_size: f64) {
+        let chains = &self.chains;
+        (self.step_scales, self.fixed_step) = step_scales(settings, step_size, |chain| {
+            chains.get(&chain).and_then(|metric| metric.step_size)
+        });
+    }
+
+    /// The scale of the transformation of `chain`.
+    pub(crate) fn step_scale(&self, chain: u64) -> f64 {
+        self.step_scales.get(&chain).copied().unwrap_or(1.)
+    }
+}
+
+/// Let nuts-rs start the chains of `settings` with a step size, such that
+/// scaling the transformation of each chain gives it the step size
+/// `chain_step`, or `step_size` if that is not known. Returns the scales
+/// that are not 1, and whether nuts-rs keeps its step size fixed. See
+/// `MetricOptions::set_step_size`.
+pub(crate) fn step_scales(
+    settings: &mut TransformedNutsSettings,
+    step_size: f64,
+    chain_step: impl Fn(u64) -> Option<f64>,
+) -> (BTreeMap<u64, f64>, bool) {
+    let options = &mut settings.adapt_options.dual_average_options;
+    let fixed_step = settings.num_tune == 0;
+    let sampler_step = if fixed_step {
+        options.target_accept = 1.;
+        FIXED_SAMPLER_STEP
+    } else {
+        step_size
+    };
+    options.initial_step = sampler_step;
+    let scales = (0..settings.num_chains as u64)
+        .map(|chain| {
+            let step = chain_step(chain).unwrap_or(step_size);
+            (chain, step / sampler_step)
+        })
+        .filter(|&(_, scale)| scale != 1.)
+        .collect();
+    (scales, fixed_step)
+}
+
+/// Options for the adaptation of a dense mass matrix.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct DenseAdaptOptions {
+    pub(crate) dual_average_options: DualAverageSettings,
+    /// Fraction of warmup at the end in which only the step size is
+    /// adapted.
+    pub(crate) step_size_window: f64,
+    /// Number of draws at the start of warmup before the first window.
+    /// The mass matrix is diagonal until the first window ends.
+    pub(crate) initial_window: u64,
+    /// Number of draws in the first window. Each further window is twice
+    /// as long as the one before.
+    pub(crate) base_window: u64,
+    /// How strongly the covariance of the draws in a window is shrunk
+    /// towards its diagonal, in numbers of draws.
+    pub(crate) regularization: f64,
+}
+
+impl Default for DenseAdaptOptions {
+    fn default() -> Self {
+        Self {
+            dual_average_options: Default::default(),
+            step_size_window: 0.07,
+            initial_window: 75,
+            base_window: 25,
+            regularization: 5.,
+        }
+    }
+}
+
+/// Options for the adaptation of a low-rank modified mass matrix, the same
+/// as in the `LowRankSettings` of nuts-rs.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LowRankAdaptation {
+    /// Regularization of the covariances of the draws and gradients.
+    pub(crate) gamma: f64,
+    /// Only eigenvalues larger than this or smaller than its inverse are
+    /// part of the low-rank correction.
+    pub(crate) eigval_cutoff: f64,
+}
+
+/// nuts-rs does not support dense mass matrices. We run those with a
+/// `MetricModel` on top of the transformed sampler.
+pub(crate) type DenseNutsSettings = NutsSettings<DenseAdaptOptions>;
+
+/// `DenseNutsSettings` with the same defaults as the other variants.
+pub(crate) fn default_dense_settings() -> DenseNutsSettings {
+    let settings = TransformedNutsSettings::default();
+    DenseNutsSettings {
+        num_tune: settings.num_tune,
+        num_draws: settings.num_draws,
+        maxdepth: settings.maxdepth,
+        store_gradient: settings.store_gradient,
+        store_unconstrained: settings.store_unconstrained,
+        max_energy_error: settings.max_energy_error,
+        store_divergences: settings.store_divergences,
+        adapt_options: Default::default(),
+        check_turning: settings.check_turning,
+        num_chains: settings.num_chains,
+        seed: settings.seed,
+    }
+}
+
+/// The windows of warmup draws that the dense mass matrix is estimated
+/// from.
+#[derive(Clone, Debug)]
+pub(crate) struct DenseAdaptation {
+    /// Start and end of each window, counted in draws since the start of
+    /// warmup.
+    pub(crate) windows: Vec<(u64, u64)>,
+    pub(crate) regularization: f64,
+}
+
+impl DenseAdaptation {
+    /// The windows for a warmup of `num_tune` draws, like the windowed
+    /// adaptation in Stan: Each window is twice as long as the previous
+    /// one, and a window that leaves too little room for the next one is
+    /// extended to the start of the final step size window.
+    pub(crate) fn new(num_tune: u64, options: &DenseAdaptOptions) -> Self {
+        let end = ((num_tune as f64) * (1. - options.step_size_window)).floor() as u64;
+        let (mut start, mut size) = (options.initial_window, options.base_window.max(1));
+        if start + size > end {
+            // Too short for the usual windows, so use a single one.
+            start = (0.15 * num_tune as f64) as u64;
+            size = end.saturating_sub(start);
+        }
+        let mut windows = Vec::new();
+        while size >= 2 && start + size <= end {
+            let mut window_end = start + size;
+            if window_end + 2 * size > end {
+                window_end = end;
+            }
+            windows.push((start, window_end));
+            start = window_end;
+            size *= 2;
+        }
+        Self {
+            windows,
+            regularization: options.regularization,
+        }
+    }
+}
+
+pub(crate) struct MetricModel<M> {
+    model: M,
+    options: Arc<MetricOptions>,
+    registry: ChainRegistry,
+}
+
+impl<M: Model> MetricModel<M> {
+    pub(crate) fn new(model: M, options: MetricOptions, registry: ChainRegistry) -> Self {
+        Self {
+            model,
+            options: Arc::new(options),
+            registry,
+        }
+    }
+}
+
+impl<M: Model> Model for MetricModel<M> {
+    type Math<'model> = CpuMath<MetricDensity<M::Math<'model>>>;
+
+    type DrawStorage<'model, S: Settings> = M::DrawStorage<'model, S>;
+
+    fn new_trace<'model, S: Settings, R: rand::Rng + ?Sized>(
+        &'model self,
+        rng: &mut R,
+        chain_id: u64,
+        settings: &'model S,
+    ) -> Result<Self::DrawStorage<'model, S>> {
+        self.model.new_trace(rng, chain_id, settings)
+    }
+
+    fn math(&self) -> Result<Self::Math<'_>> {
+        Ok(CpuMath::new(MetricDensity {
+            math: self.model.math()?,
+            options: self.options.clone(),
+            registry: self.registry.clone(),
+        }))
+    }
+
+    fn init_position<R: rand::Rng + ?Sized>(
+        &self,
+        rng: &mut R,
+        position: &mut [f64],
+    ) -> Result<()> {
+        self.model.init_position(rng, position)
+    }
+}
+
+impl<M: ModelInit> ModelInit for MetricModel<M> {
+    fn init_chain_position<R: rand::Rng + ?Sized>(
+        &self,
+        chain: u64,
+        rng: &mut R,
+        position: &mut [f64],
+    ) -> Result<()> {
+        self.model.init_chain_position(chain, rng, position)
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum MetricError<E: LogpError + 'static> {
+    #[error(transparent)]
+    Logp(E),
+    #[error("Mass matrix has length {found}, but the model has {expected} parameters")]
+    DimensionMismatch { expected: usize, found: usize },
+}
+
+impl<E: LogpError + 'static> LogpError for MetricError<E> {
+    fn is_recoverable(&self) -> bool {
+        match self {
+            MetricError::Logp(err) => err.is_recoverable(),
+            MetricError::DimensionMismatch { .. } => false,
+        }
+    }
+}
+
+pub(crate) struct MetricParams {
+    id: i64,
+    chain: u64,
+    adapt: bool,
+    sigma: Box<[f64]>,
+    /// Orthonormal directions and the square roots of their `values`.
+    low_rank: Vec<(Box<[f64]>, f64)>,
+    /// Lower Cholesky factor of a dense inverse mass matrix. The sampler
+    /// space is transformed by `chol * diag(sigma)`.
+    chol: Option<Box<[f64]>>,
+    /// Factor of the whole transformation, see `MetricOptions::set_step_size`.
+    scale: f64,
+    logdet: f64,
+    /// The next window of the dense adaptation.
+    window: usize,
+}
+
+impl MetricParams {
+    fn new(
+        id: i64,
+        chain: u64,
+        adapt: bool,
+        inv_mass: &[f64],
+        low_rank: Option<&LowRankMetric>,
+    ) -> Self {
+        let sigma: Box<[f64]> = inv_mass.iter().map(|&var| var.sqrt()).collect();
+        let low_rank: Vec<_> = low_rank
+            .map(|low_rank| {
+                low_rank
+                    .vectors
+                    .iter()
+                    .cloned()
+                    .zip(low_rank.values.iter().map(|val| val.sqrt()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let logdet = sigma.iter().map(|s| s.ln()).sum::<f64>()
+            + low_rank.iter().map(|(_, scale)| scale.ln()).sum::<f64>();
+        Self {
+            id,
+            chain,
+            adapt,
+            sigma,
+            low_rank,
+            chol: None,
+            scale: 1.,
+            logdet,
+            window: 0,
+        }
+    }
+
+    /// Params for the dense inverse mass matrix `chol * chol^T`.
+    fn dense(id: i64, chain: u64, adapt: bool, chol: Box<[f64]>) -> Self {
+        let dim = (chol.len() as f64).sqrt() as usize;
+        let logdet = (0..dim).map(|i| chol[i * dim + i].ln()).sum();
+        Self {
+            id,
+            chain,
+            adapt,
+            sigma: vec![1f64; dim].into(),
+            low_rank: vec![],
+            chol: Some(chol),
+            scale: 1.,
+            logdet,
+            window: 0,
+        }
+    }
+
+    /// Scale the transformation by `scale`.
+    fn scaled(mut self, scale: f64) -> Self {
+        self.logdet += (scale / self.scale).ln() * self.sigma.len() as f64;
+        self.scale = scale;
+        self
+    }
+
+    /// The diagonal of the inverse mass matrix, including the low-rank
+    /// correction.
+    fn inv_mass(&self) -> Box<[f64]> {
+        let dim = self.sigma.len();
+        if let Some(chol) = &self.chol {
+            return (0..dim)
+                .map(|i| chol[i * dim..i * dim + i + 1].iter().map(|l| l * l).sum())
+                .collect();
+        }
+        self.sigma
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let correction: f64 = self
+                    .low_rank
+                    .iter()
+                    .map(|(u, scale)| (scale * scale - 1.) * u[i] * u[i])
+                    .sum();
+                s * s * (1. + correction)
+            })
+            .collect()
+    }
+
+    /// Multiply `values` with `I + U (diag(scale) - I) U^T`, or its inverse.
+    fn scale_low_rank(&self, values: &mut [f64], inverse: bool) {
+        // The directions are orthonormal, so we can apply them one by one.
+        for (u, scale) in self.low_rank.iter() {
+            let factor = if inverse { scale.recip() } else { *scale };
+            let coef = (factor - 1.) * u.iter().zip(values.iter()).map(|(u, v)| u * v).sum::<f64>();
+            values
+                .iter_mut()
+                .zip(u.iter())
+                .for_each(|(v, u)| *v += coef * u);
+        }
+    }
+}
+
+pub(crate) struct MetricDensity<Mt> {
+    math: Mt,
+    options: Arc<MetricOptions>,
+    registry: ChainRegistry,
+}
+
+impl<Mt: Math> MetricDensity<Mt> {
+    fn transformed_gradient(params: &MetricParams, gradient: &[f64], out: &mut [f64]) {
+        out.copy_from_slice(gradient);
+        if let Some(chol) = &params.chol {
+            lower_transpose_mul_in_place(chol, out.len(), out);
+        }
+        out.iter_mut()
+            .zip(params.sigma.iter())
+            .for_each(|(out, &sigma)| *out *= sigma * params.scale);
+        params.scale_low_rank(out, false);
+    }
+
+    /// Tell the registry about the new metric of a chain.
+    fn record(&self, params: &MetricParams) {
+        let low_rank = (!params.low_rank.is_empty()).then(|| LowRankMetric {
+            vectors: params.low_rank.iter().map(|(u, _)| u.clone()).collect(),
+            values: params
+                .low_rank
+                .iter()
+                .map(|(_, scale)| scale * scale)
+                .collect(),
+        });
+        let metric = ChainMetric {
+            inv_mass: params.sigma.iter().map(|sigma| sigma * sigma).collect(),
+            low_rank,
+            dense: params.chol.clone(),
+            adapt: params.adapt,
+            step_size: None,
+        };
+        self.registry
+            .set_metric(params.chain, params.inv_mass(), metric);
+    }
+}
+
+impl<Mt: Math> CpuLogpFunc for MetricDensity<Mt> {
+    type LogpError = MetricError<Mt::LogpErr>;
+    type TransformParams = MetricParams;
+
+    fn dim(&self) -> usize {
+        self.math.dim()
+    }
+
+    fn logp(&mut self, position: &[f64], gradient: &mut [f64]) -> Result<f64, Self::LogpError> {
+        self.math
+            .logp(position, gradient)
+            .map_err(MetricError::Logp)
+    }
+
+    fn inv_transform_normalize(
+        &mut self,
+        params: &MetricParams,
+        untransformed_position: &[f64],
+        untransformed_gradient: &[f64],
+        transformed_position: &mut [f64],
+        transformed_gradient: &mut [f64],
+    ) -> Result<f64, Self::LogpError> {
+        transformed_position.copy_from_slice(untransformed_position);
+        if let Some(chol) = &params.chol {
+            solve_lower_in_place(chol, transformed_position.len(), transformed_position);
+        }
+        transformed_position
+            .iter_mut()
+            .zip(params.sigma.iter())
+            .for_each(|(out, &sigma)| *out /= sigma * params.scale);
+        params.scale_low_rank(transformed_position, true);
+        Self::transformed_gradient(params, untransformed_gradient, transformed_gradient);
+        Ok(params.logdet)
+    }
+
+    fn init_from_untransformed_position(
+        &mut self,
+        params: &MetricParams,
+        untransformed_position: &[f64],
+        untransformed_gradient: &mut [f64],
+        transformed_position: &mut [f64],
+        transformed_gradient: &mut [f64],
+    ) -> Result<(f64, f64), Self::LogpError> {
+        let logp = self.logp(untransformed_position, untransformed_gradient)?;
+        let logdet = self.inv_transform_normalize(
+            params,
+            untransformed_position,
+            untransformed_gradient,
+            transformed_position,
+            transformed_gradient,
+        )?;
+        Ok((logp, logdet))
+    }
+
+    fn init_from_transformed_position(
+        &mut self,
+        params: &MetricParams,
+        untransformed_position: &mut [f64],
+        untransformed_gradient: &mut [f64],
+        transformed_position: &[f64],
+        transformed_gradient: &mut [f64],
+    ) -> Result<(f64, f64), Self::LogpError> {
+        untransformed_position.copy_from_slice(transformed_position);
+        params.scale_low_rank(untransformed_position, false);
+        untransformed_position
+            .iter_mut()
+            .zip(params.sigma.iter())
+            .for_each(|(out, &sigma)| *out *= sigma * params.scale);
+        if let Some(chol) = &params.chol {
+            lower_mul_in_place(chol, untransformed_position.len(), untransformed_position);
+        }
+        let logp = self.logp(untransformed_position, untransformed_gradient)?;
+        Self::transformed_gradient(params, untransformed_gradient, transformed_gradient);
+        Ok((logp, params.logdet))
+    }
+
+    fn update_transformation<'a, R: rand::Rng + ?Sized>(
+        &'a mut self,
+        _rng: &mut R,
+        untransformed_positions: impl ExactSizeIterator<Item = &'a [f64]>,
+        untransformed_gradients: impl ExactSizeIterator<Item = &'a [f64]>,
+        _untransformed_logp: impl ExactSizeIterator<Item = &'a f64>,
+        params: &'a mut MetricParams,
+    ) -> Result<(), Self::LogpError> {
+        if !params.adapt {
+            return Ok(());
+        }
+
+        if let Some(dense) = &self.options.dense {
+            // With the settings of the dense variant nuts-rs keeps every
+            // warmup draw (the energy error limit is infinite, and accepted
+            // draws have a finite logp), so position `i` is draw `i`. This
+            // makes the windows fixed ranges of draws, no matter how often
+            // nuts-rs asks for an update. If several windows ended since the
+            // last update, only the latest one is used.
+            let num_draws = untransformed_positions.len() as u64;
+            let ended = dense.windows[params.window..]
+                .iter()
+                .take_while(|&&(_, end)| end <= num_draws)
+                .count();
+            if ended > 0 {
+                let window = params.window + ended;
+                let (start, end) = dense.windows[window - 1];
+                let draws: Vec<&[f64]> = untransformed_positions
+                    .skip(start as usize)
+                    .take((end - start) as usize)
+                    .collect();
+                if let Some(chol) = window_covariance_chol(&draws, dense.regularization) {
+                    *params = MetricParams::dense(params.id + 1, params.chain, true, chol)
+                        .scaled(self.options.step_scale(params.chain));
+                    self.record(params);
+                }
+                params.window = window;
+                return Ok(());
+            }
+            // Until the first window ends we use the diagonal estimate.
+            if params.chol.is_some() {
+                return Ok(());
+            }
+        }
+
+        // nuts-rs hands us all draws since the start of warmup. Like the
+        // windowed adaptation of the euclidean samplers we only use the
+        // more recent half of them.
+        let num_draws = untransformed_positions.len();
+        let skip = num_draws / 2;
+        let updated = match &self.options.low_rank {
+            Some(options) => {
+                let draws: Vec<&[f64]> = untransformed_positions.skip(skip).collect();
+                let grads: Vec<&[f64]> = untransformed_gradients.skip(skip).collect();
+                low_rank_inv_mass(&draws, &grads, options).map(|(inv_mass, low_rank)| {
+                    MetricParams::new(
+                        params.id + 1,
+                        params.chain,
+                        params.adapt,
+                        &inv_mass,
+                        Some(&low_rank),
+                    )
+                })
+            }
+            None => grad_based_inv_mass(
+                untransformed_positions.skip(skip),
+                untransformed_gradients.skip(skip),
+                params.sigma.len(),
+            )
+            .map(|inv_mass| {
+                MetricParams::new(params.id + 1, params.chain, params.adapt, &inv_mass, None)
+            }),
+        };
+        let Some(updated) = updated else {
+            return Ok(());
+        };
+
+        let window = params.window;
+        *params = updated.scaled(self.options.step_scale(params.chain));
+        params.window = window;
+        self.record(params);
+        Ok(())
+    }
+
+    fn new_transformation<R: rand::Rng + ?Sized>(
+        &mut self,
+        _rng: &mut R,
+        _untransformed_position: &[f64],
+        untransformed_gradient: &[f64],
+        chain: u64,
+    ) -> Result<MetricParams, Self::LogpError> {
+        let params = match self.options.chains.get(&chain) {
+            Some(metric) => {
+                if metric.inv_mass.len() != untransformed_gradient.len() {
+                    return Err(MetricError::DimensionMismatch {
+                        expected: untransformed_gradient.len(),
+                        found: metric.inv_mass.len(),
+                    });
+                }
+                if let Some(chol) = &metric.dense {
+                    if chol.len() != metric.inv_mass.len().pow(2) {
+                        return Err(MetricError::DimensionMismatch {
+                            expected: metric.inv_mass.len().pow(2),
+                            found: chol.len(),
+                        });
+                    }
+                    MetricParams::dense(0, chain, metric.adapt, chol.clone())
+                } else {
+                    if let Some(low_rank) = &metric.low_rank {
+                        if low_rank
+                            .vectors
+                            .iter()
+                            .any(|u| u.len() != metric.inv_mass.len())
+                        {
+                            return Err(MetricError::DimensionMismatch {
+                                expected: metric.inv_mass.len(),
+                                found: low_rank.vectors.iter().map(|u| u.len()).max().unwrap_or(0),
+                            });
+                        }
+                    }
+                    MetricParams::new(
+                        0,
+                        chain,
+                        metric.adapt,
+                        &metric.inv_mass,
+                        metric.low_rank.as_ref(),
+                    )
+                }
+            }
+            None => {
+                let inv_mass: Box<[f64]> = untransformed_gradient
+                    .iter()
+                    .map(|grad| clamp_inv_mass(grad.abs().recip()))
+                    .collect();
+                MetricParams::new(0, chain, true, &inv_mass, None)
+            }
+        };
+        let params = params.scaled(self.options.step_scale(chain));
+        self.record(&params);
+        Ok(params)
+    }
+
+    fn transformation_id(&self, params: &MetricParams) -> Result<i64, Self::LogpError> {
+        Ok(params.id)
+    }
+}
+
+fn clamp_inv_mass(value: f64) -> f64 {
+    if value.is_finite() {
+        value.clamp(1e-20, 1e20)
+    } else {
+        1f64
+    }
+}
+
+/// Estimate the diagonal of the inverse mass matrix as
+/// `sqrt(var(draws) / var(grads))`, the estimator nuts-rs uses for
+/// `DiagGradNutsSettings`.
+fn grad_based_inv_mass<'a>(
+    draws: impl Iterator<Item = &'a [f64]>,
+    grads: impl Iterator<Item = &'a [f64]>,
+    dim: usize,
+) -> Option<Box<[f64]>> {
+    let mut draw_stats = RunningVariance::new(dim);
+    let mut grad_stats = RunningVariance::new(dim);
+    for (draw, grad) in draws.zip(grads) {
+        draw_stats.add(draw);
+        grad_stats.add(grad);
+    }
+    if draw_stats.count < 2 {
+        return None;
+    }
+    Some(
+        draw_stats
+            .variance()
+            .iter()
+            .zip(grad_stats.variance().iter())
+            .map(|(&draw_var, &grad_var)| clamp_inv_mass((draw_var / grad_var).sqrt()))
+            .collect(),
+    )
+}
+
+/// Estimate a low-rank modified inverse mass matrix from draws and their
+/// gradients, with the estimator nuts-rs uses for `LowRankNutsSettings`.
+///
+/// Returns the diagonal and the low-rank correction. The diagonal is
+/// `sqrt(var(draws) / var(grads))`, and the correction is the geometric mean
+/// of the regularized covariance of the scaled draws and the inverse
+/// covariance of the scaled gradients, restricted to the space they span.
+fn low_rank_inv_mass(
+    draws: &[&[f64]],
+    grads: &[&[f64]],
+    options: &LowRankAdaptation,
+) -> Option<(Box<[f64]>, LowRankMetric)> {
+    let n = draws.len();
+    let dim = draws.first()?.len();
+    if n < 3 {
+        return None;
+    }
+
+    let mut draw_cols: Vec<Vec<f64>> = draws.iter().map(|draw| draw.to_vec()).collect();
+    let mut grad_cols: Vec<Vec<f64>> = grads.iter().map(|grad| grad.to_vec()).collect();
+    let mut stds = vec![0f64; dim];
+    for (i, std) in stds.iter_mut().enumerate() {
+        let draw_mean = draw_cols.iter().map(|draw| draw[i]).sum::<f64>() / n as f64;
+        let grad_mean = grad_cols.iter().map(|grad| grad[i]).sum::<f64>() / n as f64;
+        let draw_ss: f64 = draw_cols
+            .iter()
+            .map(|draw| (draw[i] - draw_mean).powi(2))
+            .sum();
+        let grad_ss: f64 = grad_cols
+            .iter()
+            .map(|grad| (grad[i] - grad_mean).powi(2))
+            .sum();
+        *std = (draw_ss.sqrt() / grad_ss.sqrt()).sqrt();
+        if !(std.is_finite() && *std > 0.) {
+            return None;
+        }
+        draw_cols
+            .iter_mut()
+            .for_each(|draw| draw[i] = (draw[i] - draw_mean) / (*std * n as f64));
+        grad_cols
+            .iter_mut()
+            .for_each(|grad| grad[i] = (grad[i] - grad_mean) * *std / n as f64);
+    }
+    let inv_mass: Box<[f64]> = stds.iter().map(|std| clamp_inv_mass(std * std)).collect();
+
+    let columns: Vec<Vec<f64>> = draw_cols.iter().chain(grad_cols.iter()).cloned().collect();
+    let (basis, _) = thin_qr(&columns);
+    let k = basis.len();
+    let project_cov = |cols: &[Vec<f64>]| {
+        let projected: Vec<Vec<f64>> = cols
+            .iter()
+            .map(|col| basis.iter().map(|q| dot(q, col)).collect())
+            .collect();
+        let mut cov = vec![0f64; k * k];
+        for i in 0..k {
+            for j in 0..k {
+                let sum: f64 = projected.iter().map(|p| p[i] * p[j]).sum();
+                cov[i * k + j] = sum / options.gamma + if i == j { 1. } else { 0. };
+            }
+        }
+        cov
+    };
+    let mean = spd_mean(&project_cov(&draw_cols), &project_cov(&grad_cols), k)?;
+    let (values, vectors) = symmetric_eigen(&mean, k)?;
+
+    let (vectors, values): (Vec<Box<[f64]>>, Vec<f64>) = values
+        .iter()
+        .enumerate()
+        .filter(|(_, &val)| val > options.eigval_cutoff || val < options.eigval_cutoff.recip())
+        .map(|(col, &val)| {
+            let mut u = vec![0f64; dim];
+            for (row, q) in basis.iter().enumerate() {
+                axpy(vectors[row * k + col], q, &mut u);
+            }
+            (u.into(), val)
+        })
+        .unzip();
+    Some((
+        inv_mass,
+        LowRankMetric {
+            vectors,
+            values: values.into(),
+        },
+    ))
+}
+
+/// The geometric mean of the symmetric positive definite `k x k` matrices
+/// `a` and `b^-1`, the matrix `m` with `m b m = a`.
+fn spd_mean(a: &[f64], b: &[f64], k: usize) -> Option<Vec<f64>> {
+    let (values, vectors) = symmetric_eigen(b, k)?;
+    // `u diag(f(values)) u^T` for the eigenvectors `u` of `b`.
+    let apply = |vectors: &[f64], values: &[f64], f: fn(f64) -> f64| {
+        let scaled: Vec<f64> = (0..k * k)
+            .map(|idx| vectors[idx] * f(values[idx % k]))
+            .collect();
+        matmul(&scaled, &transpose(vectors, k, k), k, k, k)
+    };
+    let b_sqrt = apply(&vectors, &values, f64::sqrt);
+    let b_inv_sqrt = apply(&vectors, &values, |val| val.sqrt().recip());
+    let inner = matmul(&matmul(&b_sqrt, a, k, k, k), &b_sqrt, k, k, k);
+    let (inner_values, inner_vectors) = symmetric_eigen(&inner, k)?;
+    let inner_sqrt = apply(&inner_vectors, &inner_values, f64::sqrt);
+    let mean = matmul(
+        &matmul(&b_inv_sqrt, &inner_sqrt, k, k, k),
+        &b_inv_sqrt,
+        k,
+        k,
+        k,
+    );
+    mean.iter().all(|val| val.is_finite()).then_some(mean)
+}
+
+/// Lower Cholesky factor of the covariance of `draws`, shrunk towards its
+/// diagonal like in Stan: `(n S + regularization diag(S)) / (n + regularization)`
+/// for `n` draws with sample covariance `S`.
+fn window_covariance_chol(draws: &[&[f64]], regularization: f64) -> Option<Box<[f64]>> {
+    let n = draws.len();
+    let dim = draws.first()?.len();
+    if n < 2 {
+        return None;
+    }
+    let mut mean = vec![0f64; dim];
+    for draw in draws {
+        mean.iter_mut().zip(draw.iter()).for_each(|(m, x)| *m += x);
+    }
+    mean.iter_mut().for_each(|m| *m /= n as f64);
+
+    let mut cov = vec![0f64; dim * dim];
+    let mut centered = vec![0f64; dim];
+    for draw in draws {
+        centered
+            .iter_mut()
+            .zip(draw.iter().zip(mean.iter()))
+            .for_each(|(c, (x, m))| *c = x - m);
+        for i in 0..dim {
+            let row = &mut cov[i * dim..i * dim + i + 1];
+            row.iter_mut()
+                .zip(centered.iter())
+                .for_each(|(cov, c)| *cov += centered[i] * c);
+        }
+    }
+    let weight = n as f64 / (n as f64 + regularization);
+    for i in 0..dim {
+        for j in 0..=i {
+            let value = cov[i * dim + j] / (n - 1) as f64;
+            let value = if i == j {
+                clamp_inv_mass(value)
+            } else {
+                weight * value
+            };
+            cov[i * dim + j] = value;
+            cov[j * dim + i] = value;
+        }
+    }
+    cholesky(&cov, dim).map(Into::into)
+}
+
+/// Welford accumulator for elementwise means and variances.
+#[derive(Clone, Debug)]
+struct RunningVariance {
+    count: u64,
+    mean: Box<[f64]>,
+    sum_sq: Box<[f64]>,
+}
+
+impl RunningVariance {
+    fn new(dim: usize) -> Self {
+        Self {
+            count: 0,
+            mean: vec![0f64; dim].into(),
+            sum_sq: vec![0f64; dim].into(),
+        }
+    }
+
+    fn add(&mut self, value: &[f64]) {
+        self.count += 1;
+        let count = self.count as f64;
+        self.mean
+            .iter_mut()
+            .zip(self.sum_sq.iter_mut())
+            .zip(value)
+            .for_each(|((mean, sum_sq), &val)| {
+                let delta = val - *mean;
+                *mean += delta / count;
+                *sum_sq += delta * (val - *mean);
+            });
+    }
+
+    fn variance(&self) -> Box<[f64]> {
+        let denom = (self.count.max(2) - 1) as f64;
+        self.sum_sq.iter().map(|sum_sq| sum_sq / denom).collect()
+    }
+}
@@ -0,0 +1,317 @@
+//! Work on the trace of a running sampler that should not wait until the
+//! user asks for the results.
+//!
+//! nuts-rs hands out the sampler stats of a chain only when we inspect the
+//! trace or when it is done with all chains. If the trace is stored on disk,
+//! a `Monitor` thread shares the sampler with the `PySampler`. The progress
+//! callback wakes it up, and it appends the sampler stats of each chain to
+//! the storage whenever the chain made `buffer_draws` more draws, and once
+//! the chain is finished.
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use arrow::array::ArrayRef;
+use nuts_rs::{ChainOutput, ChainProgress, ProgressCallback, Trace};
+
+use crate::{
+    checkpoint::ResumePlan,
+    stopping::StopFeed,
+    storage::{PartWriter, StorageOptions},
+};
+
+/// How often the monitor looks at the progress if the user did not ask
+/// for a progress callback.
+const MONITOR_RATE: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct Events {
+    /// The latest progress of the chains, if the monitor did not see it yet.
+    progress: Option<Box<[ChainProgress]>>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Signal {
+    events: Mutex<Events>,
+    wake: Condvar,
+}
+
+impl Signal {
+    fn update(&self, update: impl FnOnce(&mut Events)) {
+        update(&mut self.events.lock().expect("Poisoned monitor lock"));
+        self.wake.notify_all();
+    }
+
+    /// The next progress, or `None` once the monitor is closed.
+    fn next(&self) -> Option<Box<[ChainProgress]>> {
+        let mut events = self.events.lock().expect("Poisoned monitor lock");
+        loop {
+            if events.closed {
+                return None;
+            }
+            if let Some(progress) = events.progress.take() {
+                return Some(progress);
+            }
+            events = self.wake.wait(events).expect("Poisoned monitor lock");
+        }
+    }
+}
+
+/// What the monitor can do with the sampler it shares with the `PySampler`.
+pub(crate) trait SharedSampler: Send + 'static {
+    /// The trace of the sampler, where only the sampler stats matter, or
+    /// `None` if the sampler is not running anymore.
+    fn inspect(&mut self) -> Result<Option<Trace>>;
+
+    /// Abort the sampler, if it is still running.
+    fn abort(&mut self) -> Result<()>;
+}
+
+/// The sampler stats of a chain that are in the storage so far.
+struct ChainStats {
+    /// `None` once all sampler stats of the chain are written.
+    writer: Option<PartWriter>,
+    /// The number of rows of the sampler that are written.
+    sampler_rows: usize,
+    /// The number of rows that are written, including the checkpoint of
+    /// a continued run.
+    rows: usize,
+}
+
+struct StatsFiles {
+    storage: StorageOptions,
+    resume: Option<Arc<ResumePlan>>,
+    chains: BTreeMap<u64, ChainStats>,
+}
+
+impl StatsFiles {
+    /// Whether we should write the sampler stats of a chain that made
+    /// `draws` draws.
+    fn due(&self, chain: u64, draws: usize, finished: bool) -> bool {
+        let Some(stats) = self.chains.get(&chain) else {
+            return finished || draws >= self.storage.buffer_draws;
+        };
+        stats.writer.is_some()
+            && (finished || draws >= stats.sampler_rows + self.storage.buffer_draws)
+    }
+
+    /// Append the sampler stats of a chain that are not written yet, given
+    /// as in the trace of the sampler, which does not contain the checkpoint
+    /// of a continued run.
+    fn append(&mut self, output: &ChainOutput, finished: bool) -> Result<()> {
+        let chain = output.chain_id;
+        let (start, skip) = self
+            .chains
+            .get(&chain)
+            .map_or((0, 0), |stats| (stats.sampler_rows, stats.rows));
+        let rows = output.stats.slice(start, output.stats.len() - start);
+        let rows = match &self.resume {
+            Some(plan) => plan.stitch_stats(chain, &rows, start, skip)?,
+            None => rows,
+        };
+        self.write(chain, &rows, output.stats.len())?;
+        if finished {
+            self.finish(chain)?;
+        }
+        Ok(())
+    }
+
+    /// Append sampler stats in the layout of the storage, and remember how
+    /// many rows of the sampler we wrote.
+    fn write(&mut self, chain: u64, rows: &ArrayRef, sampler_rows: usize) -> Result<()> {
+        let stats = match self.chains.entry(chain) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(ChainStats {
+                writer: Some(self.storage.stats_writer(chain, rows)?),
+                sampler_rows: 0,
+                rows: 0,
+            }),
+        };
+        let Some(writer) = &mut stats.writer else {
+            return Ok(());
+        };
+        writer.write(rows)?;
+        stats.rows += rows.len();
+        stats.sampler_rows = sampler_rows;
+        Ok(())
+    }
+
+    fn finish(&mut self, chain: u64) -> Result<()> {
+        match self
+            .chains
+            .get_mut(&chain)
+            .and_then(|stats| stats.writer.take())
+        {
+            Some(writer) => writer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the sampler stats that are due after `progress`. Returns
+    /// `false` if the sampler is not running anymore.
+    fn update(
+        &mut self,
+        progress: &[ChainProgress],
+        sampler: &mut impl SharedSampler,
+    ) -> Result<bool> {
+        let due = progress.iter().enumerate().any(|(chain, progress)| {
+            let finished = progress.finished_draws == progress.total_draws;
+            self.due(chain as u64, progress.finished_draws, finished)
+        });
+        if !due {
+            return Ok(true);
+        }
+        let Some(trace) = sampler.inspect()? else {
+            return Ok(false);
+        };
+        for chain in trace.chains {
+            let Some(progress) = progress.get(chain.chain_id as usize) else {
+                continue;
+            };
+            // The trace may be ahead of the progress we saw.
+            let draws = chain.stats.len();
+            let finished = draws == progress.total_draws;
+            if self.due(chain.chain_id, draws, finished) {
+                self.append(&chain, finished)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+pub(crate) struct Monitor {
+    signal: Arc<Signal>,
+    stats: Option<Arc<Mutex<StatsFiles>>>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl Monitor {
+    /// Start the monitor thread of a run that stores its trace in `storage`
+    /// or stops when `stop` is met, if either is given.
+    pub(crate) fn start(
+        storage: Option<StorageOptions>,
+        resume: Option<Arc<ResumePlan>>,
+        stop: Option<Arc<Mutex<StopFeed>>>,
+        mut sampler: impl SharedSampler,
+    ) -> Option<Self> {
+        if storage.is_none() && stop.is_none() {
+            return None;
+        }
+        let signal: Arc<Signal> = Default::default();
+        let stats = storage.map(|storage| {
+            Arc::new(Mutex::new(StatsFiles {
+                storage,
+                resume,
+                chains: BTreeMap::new(),
+            }))
+        });
+        let thread = {
+            let signal = signal.clone();
+            let stats = stats.clone();
+            std::thread::spawn(move || {
+                while let Some(progress) = signal.next() {
+                    if let Some(stop) = &stop {
+                        let stop = stop.lock().expect("Poisoned stopping rule");
+                        if stop.stopping().stopped().is_some() {
+                            drop(stop);
+                            sampler.abort()?;
+                            break;
+                        }
+                    }
+                    if let Some(stats) = &stats {
+                        let mut stats = stats.lock().expect("Poisoned monitor lock");
+                        if !stats.update(&progress, &mut sampler)? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        };
+        Some(Self {
+            signal,
+            stats,
+            thread: Some(thread),
+        })
+    }
+
+    /// The progress callback of a run with an optional monitor.
+    pub(crate) fn wrap(
+        monitor: Option<&Monitor>,
+        callback: Option<ProgressCallback>,
+    ) -> Option<ProgressCallback> {
+        match monitor {
+            Some(monitor) => Some(monitor.callback(callback)),
+            None => callback,
+        }
+    }
+
+    /// Wake the monitor whenever nuts-rs reports the progress.
+    fn callback(&self, callback: Option<ProgressCallback>) -> ProgressCallback {
+        let signal = self.signal.clone();
+        let wake = move |progress: &[ChainProgress]| {
+            signal.update(|events| events.progress = Some(progress.into()));
+        };
+        match callback {
+            Some(ProgressCallback {
+                callback: mut inner,
+                rate,
+            }) => ProgressCallback {
+                callback: Box::new(move |elapsed, progress| {
+                    wake(&progress);
+                    inner(elapsed, progress)
+                }),
+                rate,
+            },
+            None => ProgressCallback {
+                callback: Box::new(move |_, progress| wake(&progress)),
+                rate: MONITOR_RATE,
+            },
+        }
+    }
+
+    /// Stop the monitor thread, and return the error it stopped with.
+    pub(crate) fn stop(&mut self) -> Result<()> {
+        self.signal.update(|events| events.closed = true);
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| anyhow!("The monitor of the sampler panicked"))?,
+            None => Ok(()),
+        }
+    }
+
+    /// Stop the monitor, and write the sampler stats it did not write yet
+    /// from the final `trace` of the run.
+    pub(crate) fn finish(mut self, trace: &Trace) -> Result<()> {
+        self.stop()?;
+        let Some(stats) = &self.stats else {
+            return Ok(());
+        };
+        let mut stats = stats.lock().expect("Poisoned monitor lock");
+        for chain in trace.chains.iter() {
+            // The final trace already contains the checkpoint.
+            let skip = stats
+                .chains
+                .get(&chain.chain_id)
+                .map_or(0, |stats| stats.rows)
+                .min(chain.stats.len());
+            let rows = chain.stats.slice(skip, chain.stats.len() - skip);
+            stats.write(chain.chain_id, &rows, 0)?;
+            stats.finish(chain.chain_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.signal.update(|events| events.closed = true);
+    }
+}
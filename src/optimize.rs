@@ -0,0 +1,162 @@
+//! Find the posterior mode of a model.
+//!
+//! The mode is found with the same L-BFGS optimizer as Pathfinder, on the
+//! unconstrained space, and then expanded into the values of the variables
+//! of the model in the same way as the draws of a trace. Note that because
+//! of the Jacobian of the transformation, this is the mode of the
+//! unconstrained posterior, which differs from the mode of the constrained
+//! posterior for bounded parameters.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use arrow::array::Array;
+use numpy::{PyArray1, PyArray2, PyArrayMethods};
+use nuts_rs::Math;
+use pyo3::{pyclass, pymethods, Bound, PyObject, Python};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::{
+    evaluate::expand_points,
+    init::{InitOptions, InitStrategy, ModelInit},
+    lbfgs::{maximize, LbfgsOptions, LbfgsPath},
+    linalg::dot,
+    wrapper::export_array,
+};
+
+/// The result of `find_map`.
+#[pyclass]
+pub struct MapResult {
+    path: LbfgsPath,
+    /// The mode, expanded like the draws of a trace.
+    values: Arc<dyn Array>,
+}
+
+#[pymethods]
+impl MapResult {
+    /// The mode on the unconstrained space.
+    #[getter]
+    fn unconstrained<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f64>> {
+        PyArray1::from_slice(py, &self.path.optimum().position)
+    }
+
+    /// The values of the variables of the model at the mode, as a pyarrow
+    /// array with one row and the layout of the draws of a trace.
+    #[getter]
+    fn values(&self, py: Python<'_>) -> Result<PyObject> {
+        Ok(export_array(py, self.values.clone())?)
+    }
+
+    /// The logp at the mode.
+    #[getter]
+    fn logp(&self) -> f64 {
+        self.path.optimum().logp
+    }
+
+    /// The number of L-BFGS iterations.
+    #[getter]
+    fn num_iters(&self) -> usize {
+        self.path.path.len() - 1
+    }
+
+    /// The unconstrained position at the initial point and after each
+    /// iteration, with shape `(num_iters + 1, n_dim)`.
+    #[getter]
+    fn positions<'py>(&self, py: Python<'py>) -> Result<Bound<'py, PyArray2<f64>>> {
+        let dim = self.path.optimum().position.len();
+        let values = self
+            .path
+            .path
+            .iter()
+            .flat_map(|iterate| iterate.position.iter().copied())
+            .collect();
+        Ok(PyArray1::from_vec(py, values).reshape([self.path.path.len(), dim])?)
+    }
+
+    /// The logp at the initial point and after each iteration.
+    #[getter]
+    fn logp_history(&self) -> Vec<f64> {
+        self.path.path.iter().map(|iterate| iterate.logp).collect()
+    }
+
+    /// The norm of the gradient at the initial point and after each
+    /// iteration.
+    #[getter]
+    fn grad_norm_history(&self) -> Vec<f64> {
+        self.path
+            .path
+            .iter()
+            .map(|iterate| dot(&iterate.gradient, &iterate.gradient).sqrt())
+            .collect()
+    }
+
+    /// Why the optimizer stopped: `gradient`, `logp`, `max_iters` or
+    /// `line_search`.
+    #[getter]
+    fn convergence(&self) -> &'static str {
+        self.path.convergence.name()
+    }
+
+    /// Whether the gradient or the improvement of the logp became small
+    /// enough.
+    #[getter]
+    fn converged(&self) -> bool {
+        self.path.convergence.converged()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "MapResult(logp={}, num_iters={}, convergence={})",
+            self.logp(),
+            self.num_iters(),
+            self.convergence()
+        )
+    }
+}
+
+/// Maximize the logp of `model` from `start`, or from an initial point
+/// chosen by `init_strategy`.
+pub(crate) fn maximize_model<M: ModelInit>(
+    model: &M,
+    start: Option<Vec<f64>>,
+    options: &LbfgsOptions,
+    init_strategy: Option<InitStrategy>,
+    seed: u64,
+) -> Result<LbfgsPath> {
+    if options.history == 0 {
+        bail!("`history` must be positive");
+    }
+    let mut math = model.math().context("Could not create the model")?;
+    let start = match start {
+        Some(start) => start,
+        None => {
+            let init = InitOptions {
+                strategy: init_strategy.unwrap_or_default(),
+                ..Default::default()
+            };
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            let mut start = vec![0f64; math.dim()];
+            // Start where the first chain would.
+            init.initialize(model, 0, &mut rng, &mut start)
+                .context("Could not find an initial point for the optimizer")?;
+            start
+        }
+    };
+    maximize(&mut math, &start, options)
+}
+
+/// Find the mode for the `find_map` method of the models.
+pub(crate) fn find_map<M: ModelInit>(
+    model: &M,
+    start: Option<Vec<f64>>,
+    options: LbfgsOptions,
+    init_strategy: Option<InitStrategy>,
+    seed: Option<u64>,
+) -> Result<MapResult> {
+    let seed = seed.unwrap_or_else(|| rand::rng().next_u64());
+    let path = maximize_model(model, start, &options, init_strategy, seed)?;
+    let values = expand_points(model, &[path.optimum().position.clone()], seed)
+        .context("Could not expand the mode")?;
+    Ok(MapResult { path, values })
+}
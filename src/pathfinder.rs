@@ -0,0 +1,771 @@
+//! Pathfinder variational inference.
+//!
+//! Pathfinder (Zhang et al., 2022) runs L-BFGS from a starting point towards
+//! the mode and turns the inverse Hessian estimate at each iterate into a
+//! normal approximation of the posterior. The approximation with the
+//! largest estimate of the ELBO is used for draws. We use its draws as
+//! approximate posterior draws, and its mean and covariance to start NUTS
+//! chains close to the typical set with a good initial mass matrix.
+//!
+//! The covariance of each approximation is `diag(alpha) + beta gamma beta^T`
+//! with a low rank of at most twice the L-BFGS history, which we keep in
+//! factored form so that the cost is linear in the number of parameters.
+
+use std::{collections::BTreeMap, f64::consts::PI, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use arrow::array::Array;
+use numpy::{PyArray1, PyArray2, PyArray3, PyArrayMethods};
+use nuts_rs::Math;
+use pyo3::{pyclass, pymethods, Bound, PyObject, Python};
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, StandardNormal};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    evaluate::expand_points,
+    init::{InitOptions, InitStrategy, ModelInit},
+    lbfgs::{maximize, Convergence, LbfgsOptions, LbfgsPath},
+    linalg::{axpy, cholesky, dot, invert_upper, matmul, symmetric_eigen, thin_qr, transpose},
+    metric::{ChainMetric, LowRankMetric, MetricOptions},
+    wrapper::export_array,
+};
+
+/// Options of Pathfinder.
+#[pyclass]
+#[derive(Clone, Debug, Serialize)]
+pub struct PathfinderSettings {
+    #[serde(flatten)]
+    pub(crate) lbfgs: LbfgsOptions,
+    /// Draws per approximation to estimate its ELBO.
+    pub(crate) num_elbo_draws: usize,
+    /// Draws from the best approximation of each path.
+    pub(crate) num_draws: usize,
+}
+
+impl Default for PathfinderSettings {
+    fn default() -> Self {
+        Self {
+            lbfgs: Default::default(),
+            num_elbo_draws: 25,
+            num_draws: 1000,
+        }
+    }
+}
+
+#[pymethods]
+impl PathfinderSettings {
+    #[new]
+    #[pyo3(signature = (max_iters=1000, history=6, num_elbo_draws=25, num_draws=1000))]
+    fn new(
+        max_iters: usize,
+        history: usize,
+        num_elbo_draws: usize,
+        num_draws: usize,
+    ) -> Result<Self> {
+        if history == 0 {
+            bail!("`history` must be positive");
+        }
+        if num_elbo_draws == 0 || num_draws == 0 {
+            bail!("`num_elbo_draws` and `num_draws` must be positive");
+        }
+        Ok(Self {
+            lbfgs: LbfgsOptions {
+                max_iters,
+                history,
+                ..Default::default()
+            },
+            num_elbo_draws,
+            num_draws,
+        })
+    }
+
+    #[getter]
+    fn max_iters(&self) -> usize {
+        self.lbfgs.max_iters
+    }
+
+    #[getter]
+    fn history(&self) -> usize {
+        self.lbfgs.history
+    }
+
+    #[getter]
+    fn num_elbo_draws(&self) -> usize {
+        self.num_elbo_draws
+    }
+
+    #[getter]
+    fn num_draws(&self) -> usize {
+        self.num_draws
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "PathfinderSettings(max_iters={}, history={}, num_elbo_draws={}, num_draws={})",
+            self.lbfgs.max_iters, self.lbfgs.history, self.num_elbo_draws, self.num_draws
+        )
+    }
+}
+
+/// The normal approximation `N(mean, D^(1/2) (I + Q (L L^T - I) Q^T) D^(1/2))`
+/// with `D = diag(alpha)` and orthonormal columns `Q`.
+#[derive(Clone, Debug)]
+pub(crate) struct NormalApprox {
+    pub(crate) mean: Box<[f64]>,
+    alpha: Box<[f64]>,
+    q: Vec<Vec<f64>>,
+    /// Row major lower Cholesky factor `L`.
+    chol: Vec<f64>,
+    logdet: f64,
+}
+
+impl NormalApprox {
+    fn rank(&self) -> usize {
+        self.q.len()
+    }
+
+    /// A draw and its log density under the approximation.
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> (Box<[f64]>, f64) {
+        let dim = self.mean.len();
+        let k = self.rank();
+        let u: Vec<f64> = StandardNormal.sample_iter(&mut *rng).take(dim).collect();
+        let mut v = u.clone();
+        if k > 0 {
+            let w: Vec<f64> = self.q.iter().map(|q| dot(q, &u)).collect();
+            for (i, q) in self.q.iter().enumerate() {
+                let z: f64 = (0..=i).map(|j| self.chol[i * k + j] * w[j]).sum::<f64>() - w[i];
+                axpy(z, q, &mut v);
+            }
+        }
+        let draw = self
+            .mean
+            .iter()
+            .zip(self.alpha.iter())
+            .zip(&v)
+            .map(|((mean, alpha), v)| mean + alpha.sqrt() * v)
+            .collect();
+        let logq = -0.5 * (self.logdet + dot(&u, &u) + dim as f64 * (2. * PI).ln());
+        (draw, logq)
+    }
+
+    /// `L L^T`, the covariance on the span of `Q` in the scaled space.
+    fn scaled_low_rank_cov(&self) -> Vec<f64> {
+        let k = self.rank();
+        matmul(&self.chol, &transpose(&self.chol, k, k), k, k, k)
+    }
+
+    /// The full covariance as a row major matrix.
+    pub(crate) fn covariance(&self) -> Vec<f64> {
+        let dim = self.mean.len();
+        let k = self.rank();
+        let cov = self.scaled_low_rank_cov();
+        let mut out = vec![0f64; dim * dim];
+        (0..dim).for_each(|i| out[i * dim + i] = 1.);
+        for a in 0..k {
+            for b in 0..k {
+                let identity = if a == b { 1. } else { 0. };
+                let coef = cov[a * k + b] - identity;
+                for i in 0..dim {
+                    let factor = coef * self.q[a][i];
+                    out[i * dim..(i + 1) * dim]
+                        .iter_mut()
+                        .zip(self.q[b].iter())
+                        .for_each(|(out, q)| *out += factor * q);
+                }
+            }
+        }
+        for i in 0..dim {
+            for j in 0..dim {
+                out[i * dim + j] *= (self.alpha[i] * self.alpha[j]).sqrt();
+            }
+        }
+        out
+    }
+
+    /// The diagonal of the covariance.
+    pub(crate) fn variance(&self) -> Box<[f64]> {
+        let k = self.rank();
+        let cov = self.scaled_low_rank_cov();
+        self.alpha
+            .iter()
+            .enumerate()
+            .map(|(i, alpha)| {
+                let mut extra = 0.;
+                for a in 0..k {
+                    for b in 0..k {
+                        let identity = if a == b { 1. } else { 0. };
+                        extra += self.q[a][i] * (cov[a * k + b] - identity) * self.q[b][i];
+                    }
+                }
+                alpha * (1. + extra)
+            })
+            .collect()
+    }
+
+    /// Eigenvectors and eigenvalues of the covariance in the space scaled
+    /// by `diag(alpha)^(-1/2)`, apart from the directions with variance
+    /// one, or `None` if the eigendecomposition did not converge.
+    pub(crate) fn scaled_eigen(&self) -> Option<LowRankMetric> {
+        let k = self.rank();
+        if k == 0 {
+            return Some(LowRankMetric {
+                vectors: Vec::new(),
+                values: Box::new([]),
+            });
+        }
+        let (values, vectors) = symmetric_eigen(&self.scaled_low_rank_cov(), k)?;
+        let dim = self.mean.len();
+        let vectors = (0..k)
+            .map(|col| {
+                let mut u = vec![0f64; dim];
+                for (row, q) in self.q.iter().enumerate() {
+                    axpy(vectors[row * k + col], q, &mut u);
+                }
+                u.into()
+            })
+            .collect();
+        Some(LowRankMetric {
+            vectors,
+            values: values.into(),
+        })
+    }
+}
+
+/// Update the diagonal of the inverse Hessian estimate with a curvature
+/// pair, as in Gilbert and Lemaréchal (1989).
+fn update_diagonal(alpha: &mut [f64], s: &[f64], y: &[f64]) {
+    let a: f64 = alpha.iter().zip(y).map(|(alpha, y)| alpha * y * y).sum();
+    let b = dot(y, s);
+    let c: f64 = alpha.iter().zip(s).map(|(alpha, s)| s * s / alpha).sum();
+    let updated: Vec<f64> = alpha
+        .iter()
+        .zip(s.iter().zip(y))
+        .map(|(&alpha, (&s, &y))| {
+            (a / (b * alpha) + y * y / b - a * (s / alpha).powi(2) / (b * c)).recip()
+        })
+        .collect();
+    if updated.iter().all(|val| val.is_finite() && *val > 0.) {
+        alpha.copy_from_slice(&updated);
+    }
+}
+
+/// The normal approximation at `iterate` from the inverse Hessian estimate
+/// of the curvature pairs `history`, or `None` if it is degenerate.
+fn approximate(
+    alpha: &[f64],
+    history: &[(&[f64], &[f64])],
+    position: &[f64],
+    gradient: &[f64],
+) -> Option<NormalApprox> {
+    let m = history.len();
+
+    // Compact form of the L-BFGS inverse Hessian (Byrd et al., 1994):
+    // H = diag(alpha) + beta gamma beta^T with beta = [diag(alpha) Y, S].
+    let mut beta: Vec<Vec<f64>> = history
+        .iter()
+        .map(|(_, y)| alpha.iter().zip(y.iter()).map(|(a, y)| a * y).collect())
+        .collect();
+    beta.extend(history.iter().map(|(s, _)| s.to_vec()));
+
+    let k = 2 * m;
+    let mut gamma = vec![0f64; k * k];
+    if m > 0 {
+        let mut r = vec![0f64; m * m];
+        for i in 0..m {
+            for j in i..m {
+                r[i * m + j] = dot(history[i].0, history[j].1);
+            }
+        }
+        let r_inv = invert_upper(&r, m)?;
+        let mut inner = vec![0f64; m * m];
+        for i in 0..m {
+            for j in 0..m {
+                inner[i * m + j] = dot(&beta[i], history[j].1);
+            }
+            inner[i * m + i] += r[i * m + i];
+        }
+        let lower_right = matmul(
+            &transpose(&r_inv, m, m),
+            &matmul(&inner, &r_inv, m, m, m),
+            m,
+            m,
+            m,
+        );
+        for i in 0..m {
+            for j in 0..m {
+                gamma[i * k + m + j] = -r_inv[i * m + j];
+                gamma[(m + j) * k + i] = -r_inv[i * m + j];
+                gamma[(m + i) * k + m + j] = lower_right[i * m + j];
+            }
+        }
+    }
+
+    // mean = x + H g
+    let mut mean: Vec<f64> = position
+        .iter()
+        .zip(alpha.iter().zip(gradient))
+        .map(|(x, (a, g))| x + a * g)
+        .collect();
+    let beta_g: Vec<f64> = beta.iter().map(|b| dot(b, gradient)).collect();
+    for (i, b) in beta.iter().enumerate() {
+        let coef: f64 = (0..k).map(|j| gamma[i * k + j] * beta_g[j]).sum();
+        axpy(coef, b, &mut mean);
+    }
+
+    // Scaled by diag(alpha)^(-1/2), the low-rank part is Q R gamma R^T Q^T.
+    let scaled: Vec<Vec<f64>> = beta
+        .iter()
+        .map(|b| {
+            b.iter()
+                .zip(alpha.iter())
+                .map(|(b, a)| b / a.sqrt())
+                .collect()
+        })
+        .collect();
+    let (q, r) = thin_qr(&scaled);
+    let rank = q.len();
+    let r: Vec<f64> = r.into_iter().flatten().collect();
+    let mut cov = matmul(
+        &matmul(&r, &gamma, rank, k, k),
+        &transpose(&r, rank, k),
+        rank,
+        k,
+        rank,
+    );
+    (0..rank).for_each(|i| cov[i * rank + i] += 1.);
+    let chol = cholesky(&cov, rank)?;
+
+    let logdet = alpha.iter().map(|a| a.ln()).sum::<f64>()
+        + 2. * (0..rank).map(|i| chol[i * rank + i].ln()).sum::<f64>();
+    if !logdet.is_finite() || mean.iter().any(|x| !x.is_finite()) {
+        return None;
+    }
+    Some(NormalApprox {
+        mean: mean.into(),
+        alpha: alpha.into(),
+        q,
+        chol,
+        logdet,
+    })
+}
+
+/// Draws from an approximation with their logp and log density under the
+/// approximation. The logp is `-inf` where the model fails.
+struct Draws {
+    draws: Vec<Box<[f64]>>,
+    logp: Vec<f64>,
+    logq: Vec<f64>,
+}
+
+fn draw<M: Math, R: Rng + ?Sized>(
+    math: &mut M,
+    approx: &NormalApprox,
+    num_draws: usize,
+    rng: &mut R,
+) -> Draws {
+    let mut gradient = vec![0f64; approx.mean.len()];
+    let mut draws = Draws {
+        draws: Vec::with_capacity(num_draws),
+        logp: Vec::with_capacity(num_draws),
+        logq: Vec::with_capacity(num_draws),
+    };
+    for _ in 0..num_draws {
+        let (point, logq) = approx.sample(rng);
+        let logp = match math.logp(&point, &mut gradient) {
+            Ok(logp) if !logp.is_nan() => logp,
+            _ => f64::NEG_INFINITY,
+        };
+        draws.draws.push(point);
+        draws.logp.push(logp);
+        draws.logq.push(logq);
+    }
+    draws
+}
+
+/// The result of one Pathfinder run.
+pub(crate) struct PathfinderPath {
+    pub(crate) approx: NormalApprox,
+    pub(crate) draws: Vec<Box<[f64]>>,
+    pub(crate) logp: Vec<f64>,
+    pub(crate) logq: Vec<f64>,
+    pub(crate) elbo: f64,
+    /// The L-BFGS iteration of the chosen approximation.
+    pub(crate) iteration: usize,
+    pub(crate) num_iters: usize,
+    pub(crate) convergence: Convergence,
+}
+
+impl PathfinderPath {
+    /// Pick one of the draws with probability proportional to its
+    /// importance weight.
+    pub(crate) fn resample<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<&[f64]> {
+        let log_weights: Vec<f64> = self
+            .logp
+            .iter()
+            .zip(&self.logq)
+            .map(|(logp, logq)| logp - logq)
+            .collect();
+        let max = log_weights
+            .iter()
+            .copied()
+            .fold(f64::NEG_INFINITY, f64::max);
+        if !max.is_finite() {
+            return None;
+        }
+        let weights: Vec<f64> = log_weights.iter().map(|w| (w - max).exp()).collect();
+        let total: f64 = weights.iter().sum();
+        let mut target = rng.random::<f64>() * total;
+        for (draw, weight) in self.draws.iter().zip(&weights) {
+            if target < *weight {
+                return Some(draw);
+            }
+            target -= weight;
+        }
+        self.draws.last().map(|draw| draw.as_ref())
+    }
+}
+
+/// Run Pathfinder on `math` from `start`.
+pub(crate) fn pathfinder_path<M: Math, R: Rng + ?Sized>(
+    math: &mut M,
+    start: &[f64],
+    settings: &PathfinderSettings,
+    rng: &mut R,
+) -> Result<PathfinderPath> {
+    let LbfgsPath {
+        path,
+        pairs,
+        convergence,
+    } = maximize(math, start, &settings.lbfgs)?;
+
+    let dim = start.len();
+    let mut alpha = vec![1f64; dim];
+    let mut best: Option<(f64, usize, NormalApprox)> = None;
+    for (l, iterate) in path.iter().enumerate().skip(1) {
+        if let Some((s, y)) = &pairs[l - 1] {
+            update_diagonal(&mut alpha, s, y);
+        }
+        let history: Vec<(&[f64], &[f64])> = pairs[..l]
+            .iter()
+            .flatten()
+            .map(|(s, y)| (s.as_ref(), y.as_ref()))
+            .collect();
+        let history = &history[history.len().saturating_sub(settings.lbfgs.history)..];
+        let Some(approx) = approximate(&alpha, history, &iterate.position, &iterate.gradient)
+        else {
+            continue;
+        };
+        let draws = draw(math, &approx, settings.num_elbo_draws, rng);
+        let elbo = draws
+            .logp
+            .iter()
+            .zip(&draws.logq)
+            .map(|(logp, logq)| logp - logq)
+            .sum::<f64>()
+            / settings.num_elbo_draws as f64;
+        if elbo.is_nan() {
+            continue;
+        }
+        if best.as_ref().map_or(true, |(best, ..)| elbo > *best) {
+            best = Some((elbo, l, approx));
+        }
+    }
+
+    let Some((elbo, iteration, approx)) = best.filter(|(elbo, ..)| elbo.is_finite()) else {
+        bail!(
+            "Pathfinder found no normal approximation with a finite ELBO in {} iterations \
+             (optimizer stopped because of {})",
+            path.len() - 1,
+            convergence.name()
+        );
+    };
+    let Draws { draws, logp, logq } = draw(math, &approx, settings.num_draws, rng);
+    Ok(PathfinderPath {
+        approx,
+        draws,
+        logp,
+        logq,
+        elbo,
+        iteration,
+        num_iters: path.len() - 1,
+        convergence,
+    })
+}
+
+/// Run `num_paths` independent Pathfinder paths in parallel, each from an
+/// initial point chosen by `init`.
+pub(crate) fn pathfinder<M: ModelInit>(
+    model: &M,
+    num_paths: usize,
+    settings: &PathfinderSettings,
+    init: &InitOptions,
+    seed: u64,
+) -> Result<Vec<PathfinderPath>> {
+    (0..num_paths)
+        .into_par_iter()
+        .map(|path| {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            rng.set_stream(path as u64);
+            let mut math = model.math().context("Could not create the model")?;
+            let mut start = vec![0f64; math.dim()];
+            // Path `i` starts where chain `i` would.
+            init.initialize(model, path as u64, &mut rng, &mut start)
+                .with_context(|| format!("Could not initialize Pathfinder path {}", path))?;
+            pathfinder_path(&mut math, &start, settings, &mut rng)
+                .with_context(|| format!("Pathfinder path {} failed", path))
+        })
+        .collect()
+}
+
+/// Which part of the covariance of the approximation `warm_start` uses as
+/// inverse mass matrix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum WarmStartMetric {
+    Diag,
+    LowRank,
+    Dense,
+}
+
+/// Initial points and metrics for `num_chains` NUTS chains, one per path.
+///
+/// Each chain starts at a draw of its path, resampled by importance
+/// weights, and with the variance of the normal approximation as its
+/// inverse mass matrix. With `LowRank`, the metric also includes the
+/// low-rank part of the covariance of the approximation, and with `Dense`
+/// it is the full covariance.
+pub(crate) fn warm_start(
+    paths: &[PathfinderPath],
+    kind: WarmStartMetric,
+    adapt: bool,
+    seed: u64,
+) -> (MetricOptions, BTreeMap<u64, Box<[f64]>>) {
+    let mut chains = BTreeMap::new();
+    let mut init_points = BTreeMap::new();
+    for (chain, path) in paths.iter().enumerate() {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        rng.set_stream(chain as u64);
+        let point = path.resample(&mut rng).unwrap_or(&path.approx.mean).into();
+        // If the eigendecomposition fails, we fall back to the variance of
+        // the approximation, like for a dense metric without a Cholesky
+        // factor.
+        let low_rank = (kind == WarmStartMetric::LowRank)
+            .then(|| path.approx.scaled_eigen())
+            .flatten();
+        let metric = if let Some(low_rank) = low_rank {
+            ChainMetric {
+                inv_mass: path.approx.alpha.clone(),
+                low_rank: Some(low_rank),
+                dense: None,
+                adapt,
+                step_size: None,
+            }
+        } else {
+            let dim = path.approx.mean.len();
+            let dense = (kind == WarmStartMetric::Dense)
+                .then(|| cholesky(&path.approx.covariance(), dim))
+                .flatten()
+                .map(Into::into);
+            ChainMetric {
+                inv_mass: path.approx.variance(),
+                low_rank: None,
+                dense,
+                adapt,
+                step_size: None,
+            }
+        };
+        chains.insert(chain as u64, metric);
+        init_points.insert(chain as u64, point);
+    }
+    let metric = MetricOptions {
+        chains,
+        ..Default::default()
+    };
+    (metric, init_points)
+}
+
+/// The paths of a Pathfinder run and their draws as approximate
+/// posterior draws.
+#[pyclass]
+pub struct PathfinderResult {
+    paths: Vec<PathfinderPath>,
+    /// The draws of each path, expanded like the draws of a trace.
+    traces: Vec<Arc<dyn Array>>,
+}
+
+impl PathfinderResult {
+    fn dim(&self) -> usize {
+        self.paths[0].approx.mean.len()
+    }
+
+    fn num_draws(&self) -> usize {
+        self.paths[0].draws.len()
+    }
+
+    fn per_draw<'py>(
+        &self,
+        py: Python<'py>,
+        values: impl Fn(&PathfinderPath) -> Vec<f64>,
+    ) -> Result<Bound<'py, PyArray2<f64>>> {
+        let values = self.paths.iter().flat_map(values).collect();
+        Ok(PyArray1::from_vec(py, values).reshape([self.paths.len(), self.num_draws()])?)
+    }
+
+    fn per_parameter<'py>(
+        &self,
+        py: Python<'py>,
+        values: impl Fn(&PathfinderPath) -> Box<[f64]>,
+    ) -> Result<Bound<'py, PyArray2<f64>>> {
+        let values = self
+            .paths
+            .iter()
+            .flat_map(|path| values(path).into_vec())
+            .collect();
+        Ok(PyArray1::from_vec(py, values).reshape([self.paths.len(), self.dim()])?)
+    }
+}
+
+#[pymethods]
+impl PathfinderResult {
+    /// The draws on the unconstrained space, with shape
+    /// `(num_paths, num_draws, n_dim)`.
+    #[getter]
+    fn draws<'py>(&self, py: Python<'py>) -> Result<Bound<'py, PyArray3<f64>>> {
+        let values = self
+            .paths
+            .iter()
+            .flat_map(|path| path.draws.iter().flat_map(|draw| draw.iter().copied()))
+            .collect();
+        Ok(PyArray1::from_vec(py, values).reshape([
+            self.paths.len(),
+            self.num_draws(),
+            self.dim(),
+        ])?)
+    }
+
+    /// The logp of each draw, `-inf` where the model could not be
+    /// evaluated.
+    #[getter]
+    fn logp<'py>(&self, py: Python<'py>) -> Result<Bound<'py, PyArray2<f64>>> {
+        self.per_draw(py, |path| path.logp.clone())
+    }
+
+    /// The log density of each draw under its normal approximation.
+    #[getter]
+    fn logq<'py>(&self, py: Python<'py>) -> Result<Bound<'py, PyArray2<f64>>> {
+        self.per_draw(py, |path| path.logq.clone())
+    }
+
+    /// The log importance weight `logp - logq` of each draw.
+    #[getter]
+    fn log_weight<'py>(&self, py: Python<'py>) -> Result<Bound<'py, PyArray2<f64>>> {
+        self.per_draw(py, |path| {
+            path.logp
+                .iter()
+                .zip(&path.logq)
+                .map(|(logp, logq)| logp - logq)
+                .collect()
+        })
+    }
+
+    /// The mean of the chosen normal approximation of each path.
+    #[getter]
+    fn mean<'py>(&self, py: Python<'py>) -> Result<Bound<'py, PyArray2<f64>>> {
+        self.per_parameter(py, |path| path.approx.mean.clone())
+    }
+
+    /// The diagonal of the covariance of the chosen normal approximation
+    /// of each path.
+    #[getter]
+    fn variance<'py>(&self, py: Python<'py>) -> Result<Bound<'py, PyArray2<f64>>> {
+        self.per_parameter(py, |path| path.approx.variance())
+    }
+
+    /// The ELBO estimate of the chosen approximation of each path.
+    #[getter]
+    fn elbo(&self) -> Vec<f64> {
+        self.paths.iter().map(|path| path.elbo).collect()
+    }
+
+    /// The L-BFGS iteration of the chosen approximation of each path.
+    #[getter]
+    fn iteration(&self) -> Vec<usize> {
+        self.paths.iter().map(|path| path.iteration).collect()
+    }
+
+    /// The number of L-BFGS iterations of each path.
+    #[getter]
+    fn num_iters(&self) -> Vec<usize> {
+        self.paths.iter().map(|path| path.num_iters).collect()
+    }
+
+    /// Why the optimizer of each path stopped: `gradient`, `logp`,
+    /// `max_iters` or `line_search`.
+    #[getter]
+    fn convergence(&self) -> Vec<&'static str> {
+        self.paths
+            .iter()
+            .map(|path| path.convergence.name())
+            .collect()
+    }
+
+    /// Whether the optimizer of each path converged.
+    #[getter]
+    fn converged(&self) -> Vec<bool> {
+        self.paths
+            .iter()
+            .map(|path| path.convergence.converged())
+            .collect()
+    }
+
+    /// The draws of each path as pyarrow arrays with the layout of the
+    /// draws of a trace.
+    #[getter]
+    fn trace(&self, py: Python<'_>) -> Result<Vec<PyObject>> {
+        self.traces
+            .iter()
+            .map(|trace| Ok(export_array(py, trace.clone())?))
+            .collect()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "PathfinderResult(num_paths={}, num_draws={}, elbo={:?})",
+            self.paths.len(),
+            self.num_draws(),
+            self.elbo()
+        )
+    }
+}
+
+/// Run Pathfinder for the `pathfinder` method of the models, with a
+/// random seed if `seed` is `None`.
+pub(crate) fn run_pathfinder<M: ModelInit>(
+    model: &M,
+    num_paths: usize,
+    settings: Option<PathfinderSettings>,
+    init_strategy: Option<InitStrategy>,
+    seed: Option<u64>,
+) -> Result<PathfinderResult> {
+    if num_paths == 0 {
+        bail!("`num_paths` must be positive");
+    }
+    let settings = settings.unwrap_or_default();
+    let init = InitOptions {
+        strategy: init_strategy.unwrap_or_default(),
+        ..Default::default()
+    };
+    let seed = seed.unwrap_or_else(|| rand::rng().next_u64());
+    let paths = pathfinder(model, num_paths, &settings, &init, seed)?;
+    let traces = paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            expand_points(model, &path.draws, seed.wrapping_add(i as u64))
+                .with_context(|| format!("Could not expand the draws of path {}", i))
+        })
+        .collect::<Result<_>>()?;
+    Ok(PathfinderResult { paths, traces })
+}
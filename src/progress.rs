@@ -1,9 +1,18 @@
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    ops::Deref,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{Context, Result};
-use indicatif::ProgressBar;
+use indicatif::{HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
 use nuts_rs::{ChainProgress, ProgressCallback};
-use pyo3::{Py, PyAny, Python};
+use pyo3::{pyclass, pymethods, Py, PyAny, Python};
+use serde::Serialize;
 use time_humanize::{Accuracy, Tense};
 use upon::{Engine, Value};
 
@@ -13,10 +22,17 @@ pub struct ProgressHandler {
     callback: Arc<Py<PyAny>>,
     rate: Duration,
     n_cores: usize,
+    num_tune: u64,
 }
 
 impl ProgressHandler {
-    pub fn new(callback: Arc<Py<PyAny>>, rate: Duration, template: String, n_cores: usize) -> Self {
+    pub fn new(
+        callback: Arc<Py<PyAny>>,
+        rate: Duration,
+        template: String,
+        n_cores: usize,
+        num_tune: u64,
+    ) -> Self {
         let engine = Engine::new();
         Self {
             engine,
@@ -24,6 +40,7 @@ impl ProgressHandler {
             rate,
             template,
             n_cores,
+            num_tune,
         }
     }
 
@@ -35,6 +52,7 @@ impl ProgressHandler {
 
         let mut finished = false;
         let mut progress_update_count = 0;
+        let mut estimator = TimeEstimator::new(self.n_cores, self.num_tune);
 
         let callback = move |time_sampling, progress: Box<[ChainProgress]>| {
             if finished {
@@ -46,8 +64,15 @@ impl ProgressHandler {
             {
                 finished = true;
             }
-            let progress =
-                progress_to_value(progress_update_count, self.n_cores, time_sampling, progress);
+            let remaining = estimator.update(&progress);
+            let progress = SamplerProgress::new(
+                progress_update_count,
+                self.n_cores,
+                time_sampling,
+                &progress,
+                remaining,
+            )
+            .to_value();
             let rendered = template.render_from(&self.engine, &progress).to_string();
             let rendered = rendered.unwrap_or_else(|err| format!("{err}"));
             let _ = Python::with_gil(|py| self.callback.call1(py, (rendered,)));
@@ -61,168 +86,569 @@ impl ProgressHandler {
     }
 }
 
-fn progress_to_value(
+/// The progress of a single chain.
+#[pyclass(frozen, get_all)]
+#[derive(Clone, Debug, Serialize)]
+pub struct ChainProgressRecord {
+    chain_index: usize,
+    finished_draws: usize,
+    total_draws: usize,
+    divergences: usize,
+    tuning: bool,
+    started: bool,
+    finished: bool,
+    latest_num_steps: usize,
+    total_num_steps: usize,
+    step_size: f64,
+    /// Indices of the draws that diverged.
+    divergent_draws: Vec<usize>,
+}
+
+#[pymethods]
+impl ChainProgressRecord {
+    fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+/// The progress of all chains, as passed to structured progress callbacks.
+///
+/// Times are in seconds. The remaining time and the bounds of its 90%
+/// range are `None` until the first draws are finished.
+#[pyclass(frozen, get_all)]
+#[derive(Clone, Debug, Serialize)]
+pub struct SamplerProgress {
+    chains: Vec<ChainProgressRecord>,
+    total_draws: usize,
+    total_finished_draws: usize,
+    time_sampling: f64,
+    time_remaining_estimate: Option<f64>,
+    time_remaining_lower: Option<f64>,
+    time_remaining_upper: Option<f64>,
+    num_cores: usize,
+    finished_chains: usize,
+    running_chains: usize,
+    num_chains: usize,
+    finished: bool,
     progress_update_count: usize,
+}
+
+#[pymethods]
+impl SamplerProgress {
+    fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+impl SamplerProgress {
+    fn new(
+        progress_update_count: usize,
+        n_cores: usize,
+        time_sampling: Duration,
+        progress: &[ChainProgress],
+        remaining: Option<RemainingTime>,
+    ) -> Self {
+        let chains: Vec<_> = progress
+            .iter()
+            .enumerate()
+            .map(|(i, chain)| ChainProgressRecord {
+                chain_index: i,
+                finished_draws: chain.finished_draws,
+                total_draws: chain.total_draws,
+                divergences: chain.divergences,
+                tuning: chain.tuning,
+                started: chain.started,
+                finished: chain.total_draws == chain.finished_draws,
+                latest_num_steps: chain.latest_num_steps,
+                total_num_steps: chain.total_num_steps,
+                step_size: chain.step_size,
+                divergent_draws: chain.divergent_draws.clone(),
+            })
+            .collect();
+        let finished_chains = chains.iter().filter(|chain| chain.finished).count();
+        let running_chains = chains
+            .iter()
+            .filter(|chain| chain.started & !chain.finished)
+            .count();
+
+        Self {
+            total_draws: chains.iter().map(|chain| chain.total_draws).sum(),
+            total_finished_draws: chains.iter().map(|chain| chain.finished_draws).sum(),
+            time_sampling: time_sampling.as_secs_f64(),
+            time_remaining_estimate: remaining.map(|time| time.estimate.as_secs_f64()),
+            time_remaining_lower: remaining.map(|time| time.lower.as_secs_f64()),
+            time_remaining_upper: remaining.map(|time| time.upper.as_secs_f64()),
+            num_cores: n_cores,
+            finished_chains,
+            running_chains,
+            num_chains: chains.len(),
+            finished: chains.len() == finished_chains,
+            progress_update_count,
+            chains,
+        }
+    }
+
+    /// The values for the progress template.
+    fn to_value(&self) -> Value {
+        let humanize = |seconds: f64| {
+            Value::String(
+                time_humanize::HumanTime::from(Duration::from_secs_f64(seconds))
+                    .to_text_en(Accuracy::Rough, Tense::Present),
+            )
+        };
+
+        let chains: Vec<_> = self
+            .chains
+            .iter()
+            .map(|chain| {
+                let mut values = BTreeMap::new();
+                values.insert(
+                    "chain_index".into(),
+                    Value::Integer(chain.chain_index as i64),
+                );
+                values.insert(
+                    "finished_draws".into(),
+                    Value::Integer(chain.finished_draws as i64),
+                );
+                values.insert(
+                    "total_draws".into(),
+                    Value::Integer(chain.total_draws as i64),
+                );
+                values.insert(
+                    "divergences".into(),
+                    Value::Integer(chain.divergences as i64),
+                );
+                values.insert("tuning".into(), Value::Bool(chain.tuning));
+                values.insert("started".into(), Value::Bool(chain.started));
+                values.insert("finished".into(), Value::Bool(chain.finished));
+                values.insert(
+                    "latest_num_steps".into(),
+                    Value::Integer(chain.latest_num_steps as i64),
+                );
+                values.insert(
+                    "total_num_steps".into(),
+                    Value::Integer(chain.total_num_steps as i64),
+                );
+                values.insert(
+                    "step_size".into(),
+                    Value::String(format!("{:.2}", chain.step_size)),
+                );
+                values.insert(
+                    "divergent_draws".into(),
+                    Value::List(
+                        chain
+                            .divergent_draws
+                            .iter()
+                            .map(|&idx| Value::Integer(idx as _))
+                            .collect(),
+                    ),
+                );
+                upon::Value::Map(values)
+            })
+            .collect();
+
+        let mut map = BTreeMap::new();
+        map.insert("chains".into(), Value::List(chains));
+        map.insert(
+            "total_draws".into(),
+            Value::Integer(self.total_draws as i64),
+        );
+        map.insert(
+            "total_finished_draws".into(),
+            Value::Integer(self.total_finished_draws as i64),
+        );
+        map.insert("time_sampling".into(), humanize(self.time_sampling));
+        let humanize_opt = |seconds: Option<f64>| seconds.map_or(Value::None, humanize);
+        map.insert(
+            "time_remaining_estimate".into(),
+            humanize_opt(self.time_remaining_estimate),
+        );
+        map.insert(
+            "time_remaining_lower".into(),
+            humanize_opt(self.time_remaining_lower),
+        );
+        map.insert(
+            "time_remaining_upper".into(),
+            humanize_opt(self.time_remaining_upper),
+        );
+        map.insert("num_cores".into(), Value::Integer(self.num_cores as _));
+        map.insert(
+            "finished_chains".into(),
+            Value::Integer(self.finished_chains as _),
+        );
+        map.insert(
+            "running_chains".into(),
+            Value::Integer(self.running_chains as _),
+        );
+        map.insert("num_chains".into(), Value::Integer(self.num_chains as _));
+        map.insert("finished".into(), Value::Bool(self.finished));
+        map.insert(
+            "progress_update_count".into(),
+            Value::Integer(self.progress_update_count as i64),
+        );
+
+        Value::Map(map)
+    }
+}
+
+/// Estimated remaining sampling time with a 90% range.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RemainingTime {
+    estimate: Duration,
+    lower: Duration,
+    upper: Duration,
+}
+
+/// The quantile of the standard normal for the bounds of the 90% range.
+const RANGE_Z: f64 = 1.645;
+
+/// Time spent in one phase of a chain.
+///
+/// We see the draws and busy time of a chain at each progress update, and
+/// use the batches of draws between updates to estimate the variance of
+/// the time per draw.
+#[derive(Clone, Copy, Debug, Default)]
+struct PhaseTimes {
+    draws: f64,
+    seconds: f64,
+    batches: usize,
+    /// Sum of `seconds^2 / draws` of the batches.
+    sum_sq: f64,
+}
+
+impl PhaseTimes {
+    fn add(&mut self, draws: f64, seconds: f64) {
+        if draws <= 0. {
+            return;
+        }
+        self.draws += draws;
+        self.seconds += seconds;
+        self.batches += 1;
+        self.sum_sq += seconds * seconds / draws;
+    }
+
+    fn merge(self, other: Self) -> Self {
+        Self {
+            draws: self.draws + other.draws,
+            seconds: self.seconds + other.seconds,
+            batches: self.batches + other.batches,
+            sum_sq: self.sum_sq + other.sum_sq,
+        }
+    }
+
+    fn observed(&self) -> bool {
+        self.draws > 0.
+    }
+
+    /// Mean and standard deviation of the time of `n` more draws.
+    ///
+    /// This includes the uncertainty of the mean time per draw, which
+    /// dominates while we have seen few draws.
+    fn predict(&self, n: f64) -> (f64, f64) {
+        if n <= 0. || !self.observed() {
+            return (0., 0.);
+        }
+        let mean = self.seconds / self.draws;
+        let variance = if self.batches > 1 {
+            let ss = (self.sum_sq - mean * self.seconds).max(0.);
+            ss / (self.batches - 1) as f64
+        } else {
+            mean * mean
+        };
+        let sd = (n * variance + n * n * variance / self.draws).sqrt();
+        (n * mean, sd)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct ChainTimes {
+    last_draws: usize,
+    last_runtime: Duration,
+    tuning: PhaseTimes,
+    sampling: PhaseTimes,
+}
+
+/// Estimates the remaining sampling time from the throughput of each chain.
+///
+/// We use the time the chains were actually busy instead of the wall-clock
+/// time, so that idle cores do not distort the estimate. Tuning draws often
+/// take a lot more or fewer leapfrog steps than the draws after tuning, so
+/// both phases are tracked separately. Chains that have not started yet are
+/// scheduled on the first core that becomes free.
+pub(crate) struct TimeEstimator {
     n_cores: usize,
-    time_sampling: Duration,
-    progress: Box<[ChainProgress]>,
-) -> Value {
-    let chains: Vec<_> = progress
-        .iter()
-        .enumerate()
-        .map(|(i, chain)| {
-            let mut values = BTreeMap::new();
-            values.insert("chain_index".into(), Value::Integer(i as i64));
-            values.insert(
-                "finished_draws".into(),
-                Value::Integer(chain.finished_draws as i64),
-            );
-            values.insert(
-                "total_draws".into(),
-                Value::Integer(chain.total_draws as i64),
-            );
-            values.insert(
-                "divergences".into(),
-                Value::Integer(chain.divergences as i64),
-            );
-            values.insert("tuning".into(), Value::Bool(chain.tuning));
-            values.insert("started".into(), Value::Bool(chain.started));
-            values.insert(
-                "finished".into(),
-                Value::Bool(chain.total_draws == chain.finished_draws),
-            );
-            values.insert(
-                "latest_num_steps".into(),
-                Value::Integer(chain.latest_num_steps as i64),
-            );
-            values.insert(
-                "total_num_steps".into(),
-                Value::Integer(chain.total_num_steps as i64),
-            );
-            values.insert(
-                "step_size".into(),
-                Value::String(format!("{:.2}", chain.step_size)),
-            );
-            values.insert(
-                "divergent_draws".into(),
-                Value::List(
-                    chain
-                        .divergent_draws
-                        .iter()
-                        .map(|&idx| Value::Integer(idx as _))
-                        .collect(),
-                ),
+    num_tune: usize,
+    chains: Vec<ChainTimes>,
+}
+
+impl TimeEstimator {
+    pub(crate) fn new(n_cores: usize, num_tune: u64) -> Self {
+        Self {
+            n_cores: n_cores.max(1),
+            num_tune: num_tune as usize,
+            chains: vec![],
+        }
+    }
+
+    pub(crate) fn update(&mut self, progress: &[ChainProgress]) -> Option<RemainingTime> {
+        self.chains.resize_with(progress.len(), Default::default);
+
+        for (chain, times) in progress.iter().zip(self.chains.iter_mut()) {
+            let draws = chain.finished_draws.saturating_sub(times.last_draws);
+            if draws == 0 {
+                continue;
+            }
+            let seconds = chain
+                .runtime
+                .saturating_sub(times.last_runtime)
+                .as_secs_f64();
+            let tuning =
+                chain.finished_draws.min(self.num_tune) - times.last_draws.min(self.num_tune);
+            let tuning_seconds = seconds * tuning as f64 / draws as f64;
+            times.tuning.add(tuning as f64, tuning_seconds);
+            times
+                .sampling
+                .add((draws - tuning) as f64, seconds - tuning_seconds);
+            times.last_draws = chain.finished_draws;
+            times.last_runtime = chain.runtime;
+        }
+
+        let tuning = self
+            .chains
+            .iter()
+            .fold(PhaseTimes::default(), |acc, times| acc.merge(times.tuning));
+        let sampling = self
+            .chains
+            .iter()
+            .fold(PhaseTimes::default(), |acc, times| {
+                acc.merge(times.sampling)
+            });
+        if !tuning.observed() && !sampling.observed() {
+            return None;
+        }
+
+        let mut running = vec![];
+        let mut waiting = vec![];
+        for (chain, times) in progress.iter().zip(&self.chains) {
+            if chain.finished_draws >= chain.total_draws {
+                continue;
+            }
+            let remaining_tuning = self
+                .num_tune
+                .min(chain.total_draws)
+                .saturating_sub(chain.finished_draws);
+            let remaining_sampling = chain.total_draws - chain.finished_draws - remaining_tuning;
+
+            // Prefer what we saw of this chain, then what we saw of all
+            // chains.
+            let pick = |own: PhaseTimes, all: PhaseTimes| {
+                if own.observed() {
+                    Some(own)
+                } else if all.observed() {
+                    Some(all)
+                } else {
+                    None
+                }
+            };
+            let (tuning_mean, tuning_sd) = pick(times.tuning, tuning)
+                .unwrap_or(sampling)
+                .predict(remaining_tuning as f64);
+            let (sampling_mean, sampling_sd) = match pick(times.sampling, sampling) {
+                Some(phase) => phase.predict(remaining_sampling as f64),
+                None => {
+                    // We only know how long tuning draws take, so we are
+                    // unsure about the scale of the draws after tuning.
+                    let (mean, _) = pick(times.tuning, tuning)
+                        .unwrap_or_default()
+                        .predict(remaining_sampling as f64);
+                    (mean, mean / 2.)
+                }
+            };
+
+            let mean = tuning_mean + sampling_mean;
+            let sd = tuning_sd.hypot(sampling_sd);
+            let bounds = [mean, (mean - RANGE_Z * sd).max(0.), mean + RANGE_Z * sd];
+            if chain.started {
+                running.push(bounds);
+            } else {
+                waiting.push(bounds);
+            }
+        }
+
+        let [estimate, lower, upper] = [0, 1, 2].map(|i| {
+            let mut cores: Vec<f64> = running.iter().map(|bounds| bounds[i]).collect();
+            if cores.len() < self.n_cores {
+                cores.resize(self.n_cores, 0.);
+            }
+            for bounds in waiting.iter() {
+                let first_free = cores
+                    .iter_mut()
+                    .min_by(|a, b| a.total_cmp(b))
+                    .expect("At least one core");
+                *first_free += bounds[i];
+            }
+            Duration::from_secs_f64(cores.into_iter().fold(0., f64::max))
+        });
+
+        Some(RemainingTime {
+            estimate,
+            lower,
+            upper,
+        })
+    }
+}
+
+/// Calls a python function with a `SamplerProgress` on each update.
+pub struct StructuredHandler {
+    callback: Arc<Py<PyAny>>,
+    rate: Duration,
+    n_cores: usize,
+    num_tune: u64,
+}
+
+impl StructuredHandler {
+    pub fn new(callback: Arc<Py<PyAny>>, rate: Duration, n_cores: usize, num_tune: u64) -> Self {
+        Self {
+            callback,
+            rate,
+            n_cores,
+            num_tune,
+        }
+    }
+
+    pub fn into_callback(self) -> Result<ProgressCallback> {
+        let mut finished = false;
+        let mut progress_update_count = 0;
+        let mut estimator = TimeEstimator::new(self.n_cores, self.num_tune);
+
+        let callback = move |time_sampling, progress: Box<[ChainProgress]>| {
+            if finished {
+                return;
+            }
+            let remaining = estimator.update(&progress);
+            let progress = SamplerProgress::new(
+                progress_update_count,
+                self.n_cores,
+                time_sampling,
+                &progress,
+                remaining,
             );
-            upon::Value::Map(values)
+            finished = progress.finished;
+            let _ = Python::with_gil(|py| self.callback.call1(py, (progress,)));
+            progress_update_count += 1;
+        };
+
+        Ok(ProgressCallback {
+            callback: Box::new(callback),
+            rate: self.rate,
         })
-        .collect();
-
-    let mut map = BTreeMap::new();
-    map.insert("chains".into(), Value::List(chains));
-    map.insert(
-        "total_draws".into(),
-        Value::Integer(
-            progress
-                .iter()
-                .map(|chain| chain.total_draws)
-                .sum::<usize>() as i64,
-        ),
-    );
-    map.insert(
-        "total_finished_draws".into(),
-        Value::Integer(
-            progress
-                .iter()
-                .map(|chain| chain.finished_draws)
-                .sum::<usize>() as i64,
-        ),
-    );
-    map.insert(
-        "time_sampling".into(),
-        Value::String(
-            time_humanize::HumanTime::from(time_sampling)
-                .to_text_en(Accuracy::Rough, Tense::Present),
-        ),
-    );
-
-    let remaining = estimate_remaining_time(n_cores, time_sampling, &progress);
-    map.insert(
-        "time_remaining_estimate".into(),
-        match remaining {
-            Some(remaining) => Value::String(
-                time_humanize::HumanTime::from(remaining)
-                    .to_text_en(Accuracy::Rough, Tense::Present),
-            ),
-            None => Value::None,
-        },
-    );
-
-    map.insert("num_cores".into(), Value::Integer(n_cores as _));
-
-    let finished_chains = progress
-        .iter()
-        .map(|chain| (chain.finished_draws == chain.total_draws) as u64)
-        .sum::<u64>();
-    map.insert(
-        "finished_chains".into(),
-        Value::Integer(finished_chains as _),
-    );
-    map.insert(
-        "running_chains".into(),
-        Value::Integer(
-            progress
-                .iter()
-                .map(|chain| (chain.started & (chain.finished_draws < chain.total_draws)) as u64)
-                .sum::<u64>() as i64,
-        ),
-    );
-    map.insert("num_chains".into(), Value::Integer(progress.len() as _));
-    map.insert(
-        "finished".into(),
-        Value::Bool(progress.len() == finished_chains as usize),
-    );
-    map.insert(
-        "progress_update_count".into(),
-        Value::Integer(progress_update_count as i64),
-    );
-
-    Value::Map(map)
+    }
 }
 
-fn estimate_remaining_time(
+/// One line of a progress log.
+#[derive(Serialize)]
+struct ProgressEvent<'a> {
+    event: &'static str,
+    /// Seconds since the unix epoch.
+    timestamp: f64,
+    #[serde(flatten)]
+    progress: &'a SamplerProgress,
+}
+
+/// Appends one JSON object per progress update to a file, so that the
+/// progress of batch jobs can be followed with `tail -f`.
+pub struct JsonLinesHandler {
+    path: PathBuf,
+    rate: Duration,
     n_cores: usize,
-    time_sampling: Duration,
-    progress: &[ChainProgress],
-) -> Option<Duration> {
-    let finished_draws: u64 = progress
-        .iter()
-        .map(|chain| chain.finished_draws as u64)
-        .sum();
-    if finished_draws == 0 {
-        return None;
+    num_tune: u64,
+}
+
+impl JsonLinesHandler {
+    pub fn new(path: PathBuf, rate: Duration, n_cores: usize, num_tune: u64) -> Self {
+        Self {
+            path,
+            rate,
+            n_cores,
+            num_tune,
+        }
     }
 
-    let finished_draws = finished_draws as f64;
+    pub fn into_callback(self) -> Result<ProgressCallback> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Could not open progress file {}", self.path.display()))?;
+        let mut writer = BufWriter::new(file);
+        let mut finished = false;
+        let mut progress_update_count = 0;
+        let mut estimator = TimeEstimator::new(self.n_cores, self.num_tune);
+
+        let callback = move |time_sampling, progress: Box<[ChainProgress]>| {
+            if finished {
+                return;
+            }
+            let remaining = estimator.update(&progress);
+            let progress = SamplerProgress::new(
+                progress_update_count,
+                self.n_cores,
+                time_sampling,
+                &progress,
+                remaining,
+            );
+            finished = progress.finished;
+            let event = ProgressEvent {
+                event: if finished {
+                    "finished"
+                } else if progress_update_count == 0 {
+                    "started"
+                } else {
+                    "progress"
+                },
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64(),
+                progress: &progress,
+            };
+            // Failing to write the log should not stop sampling.
+            let _ = serde_json::to_writer(&mut writer, &event)
+                .map_err(std::io::Error::from)
+                .and_then(|_| writer.write_all(b"\n"))
+                .and_then(|_| writer.flush());
+            progress_update_count += 1;
+        };
 
-    // TODO this assumes that so far all cores were used all the time
-    let time_per_draw = time_sampling.mul_f64((n_cores as f64) / finished_draws);
+        Ok(ProgressCallback {
+            callback: Box::new(callback),
+            rate: self.rate,
+        })
+    }
+}
 
-    let mut core_times = vec![Duration::ZERO; n_cores];
+/// A progress bar that stays on the terminal as it is if it is dropped
+/// before it finished.
+///
+/// nuts-rs drops the progress callback when the sampler finishes, also if
+/// it was aborted, stopped early or failed. Without this, the bars of such
+/// runs would keep looking as if they were still running.
+struct Bar(ProgressBar);
 
-    progress
-        .iter()
-        .map(|chain| time_per_draw.mul_f64((chain.total_draws - chain.finished_draws) as f64))
-        .for_each(|time| {
-            let min_index = core_times
-                .iter()
-                .enumerate()
-                .min_by_key(|&(_, v)| v)
-                .unwrap()
-                .0;
-            core_times[min_index] += time;
-        });
+impl Deref for Bar {
+    type Target = ProgressBar;
 
-    Some(core_times.into_iter().max().unwrap_or(Duration::ZERO))
+    fn deref(&self) -> &ProgressBar {
+        &self.0
+    }
+}
+
+impl Drop for Bar {
+    fn drop(&mut self) {
+        if !self.0.is_finished() {
+            self.0.abandon();
+        }
+    }
 }
 
 pub struct IndicatifHandler {
@@ -243,7 +669,7 @@ impl IndicatifHandler {
             let total: u64 = progress.iter().map(|chain| chain.total_draws as u64).sum();
 
             if bar.is_none() {
-                bar = Some(ProgressBar::new(total));
+                bar = Some(Bar(ProgressBar::new(total)));
             }
 
             let Some(ref bar) = bar else { unreachable!() };
@@ -278,3 +704,121 @@ impl IndicatifHandler {
         })
     }
 }
+
+/// Terminal progress with one bar per chain, so that slow or stuck chains
+/// stand out, and a bar for all chains with the estimated remaining time.
+pub struct MultiIndicatifHandler {
+    rate: Duration,
+    n_cores: usize,
+    num_tune: u64,
+}
+
+struct ChainBars {
+    overall: Bar,
+    chains: Vec<Bar>,
+}
+
+impl MultiIndicatifHandler {
+    pub fn new(rate: Duration, n_cores: usize, num_tune: u64) -> Self {
+        Self {
+            rate,
+            n_cores,
+            num_tune,
+        }
+    }
+
+    pub fn into_callback(self) -> Result<ProgressCallback> {
+        let overall_style = ProgressStyle::with_template(
+            "{prefix:>8} [{bar:40.cyan/blue}] {pos}/{len} draws, {msg}",
+        )?
+        .progress_chars("=> ");
+        let tuning_style =
+            ProgressStyle::with_template("{prefix:>8} [{bar:40.yellow}] {pos:>5}/{len} {msg}")?
+                .progress_chars("=> ");
+        let sampling_style =
+            ProgressStyle::with_template("{prefix:>8} [{bar:40.green}] {pos:>5}/{len} {msg}")?
+                .progress_chars("=> ");
+
+        let mut finished = false;
+        let mut bars: Option<ChainBars> = None;
+        let mut estimator = TimeEstimator::new(self.n_cores, self.num_tune);
+
+        let callback = move |time_sampling, progress: Box<[ChainProgress]>| {
+            if finished {
+                return;
+            }
+            let bars = bars.get_or_insert_with(|| {
+                let multi = MultiProgress::new();
+                let total = progress.iter().map(|chain| chain.total_draws as u64).sum();
+                let overall = Bar(multi.add(ProgressBar::new(total)));
+                overall.set_style(overall_style.clone());
+                overall.set_prefix("total");
+                let chains = progress
+                    .iter()
+                    .enumerate()
+                    .map(|(i, chain)| {
+                        let bar = multi.add(ProgressBar::new(chain.total_draws as u64));
+                        bar.set_style(tuning_style.clone());
+                        bar.set_prefix(format!("chain {i}"));
+                        Bar(bar)
+                    })
+                    .collect();
+                ChainBars { overall, chains }
+            });
+
+            for (chain, bar) in progress.iter().zip(&bars.chains) {
+                let phase = if chain.finished_draws == chain.total_draws {
+                    "done"
+                } else if !chain.started {
+                    "waiting"
+                } else if chain.tuning {
+                    "tuning"
+                } else {
+                    "sampling"
+                };
+                if !chain.tuning {
+                    bar.set_style(sampling_style.clone());
+                }
+                bar.set_position(chain.finished_draws as u64);
+                bar.set_message(format!(
+                    "{phase:<8} {} divergences, step size {:.3}, {} steps/draw",
+                    chain.divergences, chain.step_size, chain.latest_num_steps,
+                ));
+            }
+
+            let remaining = estimator.update(&progress);
+            bars.overall.set_position(
+                progress
+                    .iter()
+                    .map(|chain| chain.finished_draws as u64)
+                    .sum(),
+            );
+            bars.overall.set_message(format!(
+                "{} elapsed, {} remaining",
+                HumanDuration(time_sampling),
+                remaining.map_or("unknown".to_string(), |remaining| {
+                    format!(
+                        "{} ({} to {})",
+                        HumanDuration(remaining.estimate),
+                        HumanDuration(remaining.lower),
+                        HumanDuration(remaining.upper),
+                    )
+                }),
+            ));
+
+            if progress
+                .iter()
+                .all(|chain| chain.finished_draws == chain.total_draws)
+            {
+                finished = true;
+                bars.chains.iter().for_each(|bar| bar.finish());
+                bars.overall.finish();
+            }
+        };
+
+        Ok(ProgressCallback {
+            callback: Box::new(callback),
+            rate: self.rate,
+        })
+    }
+}
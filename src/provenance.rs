@@ -0,0 +1,162 @@
+//! A record of how a trace was produced.
+//!
+//! We attach it as JSON to the schema metadata of the arrays of the trace,
+//! of the files of a stored trace and to the attributes of a Zarr store,
+//! so that traces that were saved long ago can still tell which settings,
+//! model and nutpie version produced them. Arrays in the settings, like
+//! initial points and mass matrices, are only recorded by their hash. Finished
+//! traces also record the step size and metric each chain ended with in
+//! full, so that later runs can warm start from the trace.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{checkpoint::AdaptationState, stopping::Stopping};
+
+/// Key of the provenance record in the schema metadata of the trace.
+pub(crate) const METADATA_KEY: &str = "nutpie";
+
+/// Wall-clock timings of a run, including all extensions of it.
+#[derive(Clone, Debug)]
+pub(crate) struct Timings {
+    started_at: SystemTime,
+    finished_at: Option<SystemTime>,
+    /// Sampling time of the parts of the run that are finished.
+    elapsed: Duration,
+    running_since: Option<Instant>,
+}
+
+impl Timings {
+    pub(crate) fn start() -> Self {
+        Self {
+            started_at: SystemTime::now(),
+            finished_at: None,
+            elapsed: Duration::ZERO,
+            running_since: Some(Instant::now()),
+        }
+    }
+
+    /// Continue the timings of a run that is extended.
+    pub(crate) fn restart(&mut self) {
+        self.finish();
+        self.finished_at = None;
+        self.running_since = Some(Instant::now());
+    }
+
+    pub(crate) fn finish(&mut self) {
+        if let Some(since) = self.running_since.take() {
+            self.elapsed += since.elapsed();
+            self.finished_at = Some(SystemTime::now());
+        }
+    }
+
+    fn sampling_time(&self) -> Duration {
+        self.elapsed
+            + self
+                .running_since
+                .map(|since| since.elapsed())
+                .unwrap_or_default()
+    }
+}
+
+/// The backend of the sampled model.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum Backend {
+    Stan {
+        library: PathBuf,
+        data_sha256: Option<String>,
+    },
+    Pymc,
+    Pyfunc,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Provenance {
+    pub(crate) nutpie_version: &'static str,
+    pub(crate) settings: serde_json::Value,
+    pub(crate) backend: Backend,
+    pub(crate) cores: usize,
+    #[serde(serialize_with = "serialize_timings")]
+    pub(crate) timings: Timings,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) stopping: Option<Stopping>,
+    /// See `AdaptationState::to_value`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) adaptation_state: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct TimingsRecord {
+    /// Seconds since the unix epoch.
+    started_at: f64,
+    finished_at: Option<f64>,
+    sampling_seconds: f64,
+}
+
+fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+fn serialize_timings<S: serde::Serializer>(
+    timings: &Timings,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    TimingsRecord {
+        started_at: unix_seconds(timings.started_at),
+        finished_at: timings.finished_at.map(unix_seconds),
+        sampling_seconds: timings.sampling_time().as_secs_f64(),
+    }
+    .serialize(serializer)
+}
+
+impl Provenance {
+    pub(crate) fn new(
+        settings: serde_json::Value,
+        backend: Backend,
+        cores: usize,
+        timings: Timings,
+        stopping: Option<Stopping>,
+    ) -> Self {
+        Self {
+            nutpie_version: env!("CARGO_PKG_VERSION"),
+            settings,
+            backend,
+            cores,
+            timings,
+            stopping,
+            adaptation_state: None,
+        }
+    }
+
+    /// Record the adaptation state at the end of the run.
+    pub(crate) fn with_adaptation_state(mut self, state: &AdaptationState) -> Result<Self> {
+        self.adaptation_state = Some(state.to_value()?);
+        Ok(self)
+    }
+
+    pub(crate) fn to_value(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    pub(crate) fn metadata(&self) -> Result<HashMap<String, String>> {
+        Ok(HashMap::from([(
+            METADATA_KEY.to_string(),
+            serde_json::to_string(self)?,
+        )]))
+    }
+}
+
+/// The SHA-256 hash of the JSON representation of `value`.
+pub(crate) fn json_sha256<T: Serialize + ?Sized>(value: &T) -> serde_json::Result<String> {
+    Ok(format!("{:x}", Sha256::digest(serde_json::to_vec(value)?)))
+}
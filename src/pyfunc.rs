@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::{anyhow, bail, Context, Result};
 use arrow::{
@@ -8,7 +8,7 @@ use arrow::{
     },
     datatypes::{DataType, Field, Float32Type, Float64Type, Int64Type},
 };
-use numpy::{NotContiguousError, PyArray1, PyReadonlyArray1};
+use numpy::{NotContiguousError, PyArray1, PyReadonlyArray1, PyReadonlyArray2};
 use nuts_rs::{CpuLogpFunc, CpuMath, DrawStorage, LogpError, Model};
 use pyo3::{
     exceptions::PyRuntimeError,
@@ -17,11 +17,20 @@ use pyo3::{
     Bound, Py, PyAny, PyErr, Python,
 };
 use rand::Rng;
-use rand_distr::{Distribution, Uniform};
 use smallvec::SmallVec;
 use thiserror::Error;
 
-use crate::wrapper::PyTransformAdapt;
+use crate::{
+    benchmark::benchmark_logp,
+    evaluate::{logp_and_grad, PyEvaluations},
+    gradient::{check_model_gradient, GradientCheck, DEFAULT_EPS},
+    init::{init_from_callback, init_uniform, InitStrategy, ModelInit},
+    laplace::{laplace, LaplaceResult, DEFAULT_HESSIAN_EPS},
+    lbfgs::LbfgsOptions,
+    optimize::{find_map, MapResult},
+    pathfinder::{run_pathfinder, PathfinderResult, PathfinderSettings},
+    wrapper::{PyTransformAdapt, PyTransformation},
+};
 
 #[pyclass]
 #[derive(Debug, Clone)]
@@ -86,7 +95,7 @@ pub struct PyModel {
 impl PyModel {
     #[new]
     #[pyo3(signature = (make_logp_func, make_expand_func, variables, ndim, *, init_point_func=None, transform_adapter=None))]
-    fn new<'py>(
+    fn new(
         make_logp_func: Py<PyAny>,
         make_expand_func: Py<PyAny>,
         variables: Vec<PyVariable>,
@@ -103,6 +112,115 @@ impl PyModel {
             transform_adapter: transform_adapter.map(PyTransformAdapt::new),
         }
     }
+
+    /// Time `evals` evaluations of the logp and its gradient at `point`
+    /// on each of `cores` threads, in seconds.
+    fn benchmark_logp(
+        &self,
+        py: Python<'_>,
+        point: PyReadonlyArray1<'_, f64>,
+        cores: usize,
+        evals: usize,
+    ) -> Result<Vec<Vec<f64>>> {
+        let point = point.as_array().to_vec();
+        let durations = py.allow_threads(|| benchmark_logp(self, &point, cores, evals))?;
+        Ok(durations
+            .into_iter()
+            .map(|thread| thread.iter().map(Duration::as_secs_f64).collect())
+            .collect())
+    }
+
+    /// Compare the gradient of the logp at `point` with central finite
+    /// differences with relative step size `eps`.
+    #[pyo3(signature = (point, eps=DEFAULT_EPS))]
+    fn check_gradient(
+        &self,
+        py: Python<'_>,
+        point: PyReadonlyArray1<'_, f64>,
+        eps: f64,
+    ) -> Result<GradientCheck> {
+        let point = point.as_array().to_vec();
+        py.allow_threads(|| check_model_gradient(self, &point, eps))
+    }
+
+    /// Evaluate the logp and its gradient at each row of `points`.
+    ///
+    /// Returns the logp values, the gradients and a mask of the points
+    /// where the logp could not be evaluated, for example because it is not
+    /// finite. Their logp and gradient are `nan`.
+    fn logp_and_grad<'py>(
+        &self,
+        py: Python<'py>,
+        points: PyReadonlyArray2<'py, f64>,
+    ) -> Result<PyEvaluations<'py>> {
+        let points = points.as_array();
+        let (num_points, dim) = points.dim();
+        let points: Vec<f64> = points.iter().copied().collect();
+        let evaluations = py.allow_threads(|| logp_and_grad(self, &points, num_points, dim))?;
+        evaluations.into_numpy(py)
+    }
+
+    /// Run Pathfinder with `num_paths` independent paths, each from an
+    /// initial point chosen by `init_strategy`.
+    #[pyo3(signature = (num_paths=4, settings=None, init_strategy=None, seed=None))]
+    fn pathfinder(
+        &self,
+        py: Python<'_>,
+        num_paths: usize,
+        settings: Option<PathfinderSettings>,
+        init_strategy: Option<InitStrategy>,
+        seed: Option<u64>,
+    ) -> Result<PathfinderResult> {
+        py.allow_threads(|| run_pathfinder(self, num_paths, settings, init_strategy, seed))
+    }
+
+    /// Maximize the logp with L-BFGS, from `start` or from an initial
+    /// point chosen by `init_strategy`.
+    #[pyo3(signature = (
+        start=None,
+        max_iters=1000,
+        history=6,
+        grad_tol=1e-8,
+        init_strategy=None,
+        seed=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn find_map(
+        &self,
+        py: Python<'_>,
+        start: Option<PyReadonlyArray1<'_, f64>>,
+        max_iters: usize,
+        history: usize,
+        grad_tol: f64,
+        init_strategy: Option<InitStrategy>,
+        seed: Option<u64>,
+    ) -> Result<MapResult> {
+        let start = start.map(|start| start.as_array().to_vec());
+        let options = LbfgsOptions {
+            max_iters,
+            history,
+            grad_tol,
+            ..Default::default()
+        };
+        py.allow_threads(|| find_map(self, start, options, init_strategy, seed))
+    }
+
+    /// Find the mode with L-BFGS, from `start` or from an initial point
+    /// chosen by `init_strategy`, and draw `num_draws` times from the
+    /// normal approximation with the curvature of the logp at the mode.
+    #[pyo3(signature = (num_draws=1000, start=None, eps=DEFAULT_HESSIAN_EPS, init_strategy=None, seed=None))]
+    fn laplace(
+        &self,
+        py: Python<'_>,
+        num_draws: usize,
+        start: Option<PyReadonlyArray1<'_, f64>>,
+        eps: f64,
+        init_strategy: Option<InitStrategy>,
+        seed: Option<u64>,
+    ) -> Result<LaplaceResult> {
+        let start = start.map(|start| start.as_array().to_vec());
+        py.allow_threads(|| laplace(self, start, num_draws, eps, init_strategy, seed))
+    }
 }
 
 #[derive(Debug, Error)]
@@ -161,7 +279,7 @@ impl PyDensity {
 
 impl CpuLogpFunc for PyDensity {
     type LogpError = PyLogpError;
-    type TransformParams = Py<PyAny>;
+    type TransformParams = PyTransformation;
 
     fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, Self::LogpError> {
         Python::with_gil(|py| {
@@ -195,7 +313,7 @@ impl CpuLogpFunc for PyDensity {
 
     fn inv_transform_normalize(
         &mut self,
-        params: &Py<PyAny>,
+        params: &PyTransformation,
         untransformed_position: &[f64],
         untransformed_gradient: &[f64],
         transformed_position: &mut [f64],
@@ -217,7 +335,7 @@ impl CpuLogpFunc for PyDensity {
 
     fn init_from_transformed_position(
         &mut self,
-        params: &Py<PyAny>,
+        params: &PyTransformation,
         untransformed_position: &mut [f64],
         untransformed_gradient: &mut [f64],
         transformed_position: &[f64],
@@ -239,7 +357,7 @@ impl CpuLogpFunc for PyDensity {
 
     fn init_from_untransformed_position(
         &mut self,
-        params: &Py<PyAny>,
+        params: &PyTransformation,
         untransformed_position: &[f64],
         untransformed_gradient: &mut [f64],
         transformed_position: &mut [f64],
@@ -265,7 +383,7 @@ impl CpuLogpFunc for PyDensity {
         untransformed_positions: impl ExactSizeIterator<Item = &'a [f64]>,
         untransformed_gradients: impl ExactSizeIterator<Item = &'a [f64]>,
         untransformed_logp: impl ExactSizeIterator<Item = &'a f64>,
-        params: &'a mut Py<PyAny>,
+        params: &'a mut PyTransformation,
     ) -> std::result::Result<(), Self::LogpError> {
         self.transform_adapter
             .as_mut()
@@ -286,7 +404,7 @@ impl CpuLogpFunc for PyDensity {
         untransformed_position: &[f64],
         untransformed_gradient: &[f64],
         chain: u64,
-    ) -> std::result::Result<Py<PyAny>, Self::LogpError> {
+    ) -> std::result::Result<PyTransformation, Self::LogpError> {
         let trafo = self
             .transform_adapter
             .as_mut()
@@ -295,7 +413,10 @@ impl CpuLogpFunc for PyDensity {
         Ok(trafo)
     }
 
-    fn transformation_id(&self, params: &Py<PyAny>) -> std::result::Result<i64, Self::LogpError> {
+    fn transformation_id(
+        &self,
+        params: &PyTransformation,
+    ) -> std::result::Result<i64, Self::LogpError> {
         let id = self
             .transform_adapter
             .as_ref()
@@ -598,6 +719,19 @@ impl DrawStorage for PyTrace {
     }
 }
 
+impl PyModel {
+    /// A copy of the model with the transformation adapter `map` returns.
+    pub(crate) fn map_transform_adapter(
+        &self,
+        map: impl FnOnce(&PyTransformAdapt) -> PyTransformAdapt,
+    ) -> Self {
+        Self {
+            transform_adapter: self.transform_adapter.as_ref().map(map),
+            ..self.clone()
+        }
+    }
+}
+
 impl Model for PyModel {
     type Math<'model>
         = CpuMath<PyDensity>
@@ -639,34 +773,11 @@ impl Model for PyModel {
         rng: &mut R,
         position: &mut [f64],
     ) -> Result<()> {
-        let Some(init_func) = self.init_point_func.as_ref() else {
-            let dist = Uniform::new(-2f64, 2f64).expect("Could not create uniform distribution");
-            position.iter_mut().for_each(|x| *x = dist.sample(rng));
-            return Ok(());
-        };
-
-        let seed = rng.next_u64();
-
-        Python::with_gil(|py| {
-            let init_point = init_func
-                .call1(py, (seed,))
-                .context("Failed to initialize point")?;
-
-            let init_point: PyReadonlyArray1<f64> = init_point
-                .extract(py)
-                .context("Initializition array returned incorrect argument")?;
-
-            let init_point = init_point
-                .as_slice()
-                .context("Initial point must be contiguous")?;
-
-            if init_point.len() != position.len() {
-                bail!("Initial point has incorrect length");
-            }
-
-            position.copy_from_slice(init_point);
-            Ok(())
-        })?;
-        Ok(())
+        match self.init_point_func.as_ref() {
+            None => init_uniform(2., rng, position),
+            Some(init_func) => init_from_callback(init_func, rng, position),
+        }
     }
 }
+
+impl ModelInit for PyModel {}
@@ -1,13 +1,13 @@
-use std::{ffi::c_void, fmt::Display, sync::Arc};
+use std::{ffi::c_void, fmt::Display, sync::Arc, time::Duration};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{Context, Result};
 use arrow::{
     array::{Array, Float64Array, LargeListArray, StructArray},
     buffer::OffsetBuffer,
     datatypes::{DataType, Field, Fields},
 };
 use itertools::{izip, Itertools};
-use numpy::PyReadonlyArray1;
+use numpy::{PyReadonlyArray1, PyReadonlyArray2};
 use nuts_rs::{CpuLogpFunc, CpuMath, DrawStorage, LogpError, Model, Settings};
 use pyo3::{
     pyclass, pymethods,
@@ -18,6 +18,17 @@ use pyo3::{
 use rand_distr::num_traits::CheckedEuclid;
 use thiserror::Error;
 
+use crate::{
+    benchmark::benchmark_logp,
+    evaluate::{logp_and_grad, PyEvaluations},
+    gradient::{check_model_gradient, GradientCheck, DEFAULT_EPS},
+    init::{init_from_callback, InitStrategy, ModelInit},
+    laplace::{laplace, LaplaceResult, DEFAULT_HESSIAN_EPS},
+    lbfgs::LbfgsOptions,
+    optimize::{find_map, MapResult},
+    pathfinder::{run_pathfinder, PathfinderResult, PathfinderSettings},
+};
+
 type UserData = *const std::ffi::c_void;
 
 type RawLogpFunc = unsafe extern "C" fn(
@@ -269,25 +280,114 @@ impl PyMcModel {
         })
     }
 
-    /*
-    fn benchmark_logp<'py>(
+    /// Time `evals` evaluations of the logp and its gradient at `point`
+    /// on each of `cores` threads, in seconds.
+    fn benchmark_logp(
         &self,
-        py: Python<'py>,
-        point: PyReadonlyArray1<'py, f64>,
+        py: Python<'_>,
+        point: PyReadonlyArray1<'_, f64>,
         cores: usize,
         evals: usize,
-    ) -> PyResult<&'py PyList> {
-        let point = point.to_vec()?;
-        let durations = py.allow_threads(|| Model::benchmark_logp(self, &point, cores, evals))?;
-        let out = PyList::new(
-            py,
-            durations
-                .into_iter()
-                .map(|inner| PyList::new(py, inner.into_iter().map(|d| d.as_secs_f64()))),
-        );
-        Ok(out)
+    ) -> Result<Vec<Vec<f64>>> {
+        let point = point.as_array().to_vec();
+        let durations = py.allow_threads(|| benchmark_logp(self, &point, cores, evals))?;
+        Ok(durations
+            .into_iter()
+            .map(|thread| thread.iter().map(Duration::as_secs_f64).collect())
+            .collect())
+    }
+
+    /// Compare the gradient of the logp at `point` with central finite
+    /// differences with relative step size `eps`.
+    #[pyo3(signature = (point, eps=DEFAULT_EPS))]
+    fn check_gradient(
+        &self,
+        py: Python<'_>,
+        point: PyReadonlyArray1<'_, f64>,
+        eps: f64,
+    ) -> Result<GradientCheck> {
+        let point = point.as_array().to_vec();
+        py.allow_threads(|| check_model_gradient(self, &point, eps))
+    }
+
+    /// Evaluate the logp and its gradient at each row of `points`.
+    ///
+    /// Returns the logp values, the gradients and a mask of the points
+    /// where the logp could not be evaluated, for example because it is not
+    /// finite. Their logp and gradient are `nan`.
+    fn logp_and_grad<'py>(
+        &self,
+        py: Python<'py>,
+        points: PyReadonlyArray2<'py, f64>,
+    ) -> Result<PyEvaluations<'py>> {
+        let points = points.as_array();
+        let (num_points, dim) = points.dim();
+        let points: Vec<f64> = points.iter().copied().collect();
+        let evaluations = py.allow_threads(|| logp_and_grad(self, &points, num_points, dim))?;
+        evaluations.into_numpy(py)
+    }
+
+    /// Run Pathfinder with `num_paths` independent paths, each from an
+    /// initial point chosen by `init_strategy`.
+    #[pyo3(signature = (num_paths=4, settings=None, init_strategy=None, seed=None))]
+    fn pathfinder(
+        &self,
+        py: Python<'_>,
+        num_paths: usize,
+        settings: Option<PathfinderSettings>,
+        init_strategy: Option<InitStrategy>,
+        seed: Option<u64>,
+    ) -> Result<PathfinderResult> {
+        py.allow_threads(|| run_pathfinder(self, num_paths, settings, init_strategy, seed))
+    }
+
+    /// Maximize the logp with L-BFGS, from `start` or from an initial
+    /// point chosen by `init_strategy`.
+    #[pyo3(signature = (
+        start=None,
+        max_iters=1000,
+        history=6,
+        grad_tol=1e-8,
+        init_strategy=None,
+        seed=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn find_map(
+        &self,
+        py: Python<'_>,
+        start: Option<PyReadonlyArray1<'_, f64>>,
+        max_iters: usize,
+        history: usize,
+        grad_tol: f64,
+        init_strategy: Option<InitStrategy>,
+        seed: Option<u64>,
+    ) -> Result<MapResult> {
+        let start = start.map(|start| start.as_array().to_vec());
+        let options = LbfgsOptions {
+            max_iters,
+            history,
+            grad_tol,
+            ..Default::default()
+        };
+        py.allow_threads(|| find_map(self, start, options, init_strategy, seed))
+    }
+
+    /// Find the mode with L-BFGS, from `start` or from an initial point
+    /// chosen by `init_strategy`, and draw `num_draws` times from the
+    /// normal approximation with the curvature of the logp at the mode.
+    #[pyo3(signature = (num_draws=1000, start=None, eps=DEFAULT_HESSIAN_EPS, init_strategy=None, seed=None))]
+    fn laplace(
+        &self,
+        py: Python<'_>,
+        num_draws: usize,
+        start: Option<PyReadonlyArray1<'_, f64>>,
+        eps: f64,
+        init_strategy: Option<InitStrategy>,
+        seed: Option<u64>,
+    ) -> Result<LaplaceResult> {
+        let start = start.map(|start| start.as_array().to_vec());
+        py.allow_threads(|| laplace(self, start, num_draws, eps, init_strategy, seed))
     }
-    */
 }
 
 impl Model for PyMcModel {
@@ -304,30 +404,7 @@ impl Model for PyMcModel {
         rng: &mut R,
         position: &mut [f64],
     ) -> Result<()> {
-        let seed = rng.next_u64();
-
-        Python::with_gil(|py| {
-            let init_point = self
-                .init_func
-                .call1(py, (seed,))
-                .context("Failed to initialize point")?;
-
-            let init_point: PyReadonlyArray1<f64> = init_point
-                .extract(py)
-                .context("Initializition array returned incorrect argument")?;
-
-            let init_point = init_point
-                .as_slice()
-                .context("Initial point must be contiguous")?;
-
-            if init_point.len() != position.len() {
-                bail!("Initial point has incorrect length");
-            }
-
-            position.copy_from_slice(init_point);
-            Ok(())
-        })?;
-        Ok(())
+        init_from_callback(&self.init_func, rng, position)
     }
 
     fn new_trace<'model, S: Settings, R: rand::prelude::Rng + ?Sized>(
@@ -339,3 +416,5 @@ impl Model for PyMcModel {
         Ok(PyMcTrace::new(self, settings))
     }
 }
+
+impl ModelInit for PyMcModel {}
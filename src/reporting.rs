@@ -0,0 +1,165 @@
+//! Corrections of what nuts-rs reports about a run.
+//!
+//! Some runs use options that nuts-rs does not have, and we implement them
+//! by handing nuts-rs different settings than the user asked for. A run
+//! with a fixed step size for example samples on a space scaled by the
+//! ratio of the requested step size and the step size nuts-rs uses (see
+//! `MetricOptions::set_step_size`). And runs without adaptation sample their
+//! warmup draws as posterior draws, because nuts-rs always tunes the step
+//! size during warmup. `Reporting` translates the sampler stats and the
+//! progress of such runs back to the settings of the user, and removes the
+//! sampler stats we asked nuts-rs for but the user did not.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::{bail, Result};
+use arrow::{
+    array::{ArrayRef, AsArray, StructArray},
+    compute::kernels::arity::unary,
+    datatypes::{DataType, Fields, Float64Type},
+};
+use nuts_rs::{ChainOutput, ChainProgress, ProgressCallback, Trace};
+
+use crate::metric::FIXED_SAMPLER_STEP;
+
+/// Sampler stats that contain a step size.
+const STEP_SIZE_STATS: [&str; 2] = ["step_size", "step_size_bar"];
+
+/// The sampler stats with the inverse mass matrix of the diagonal
+/// adaptation of nuts-rs.
+pub(crate) const INV_MASS_STATS: &str = "mass_matrix_inv";
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Reporting {
+    /// Ratio of the step size on the space of the model and the step
+    /// size that nuts-rs reports, for each chain where they differ.
+    pub(crate) step_scales: Arc<BTreeMap<u64, f64>>,
+    /// nuts-rs should keep the step size at `FIXED_SAMPLER_STEP`.
+    pub(crate) fixed_step: bool,
+    /// The first draws of each chain, which nuts-rs samples as posterior
+    /// draws but which are the warmup of a run without adaptation.
+    pub(crate) burn_in: u64,
+    /// Remove the `INV_MASS_STATS`, which we only need for checkpoints.
+    pub(crate) hide_inv_mass: bool,
+}
+
+impl Reporting {
+    fn scales_step_size(&self) -> bool {
+        !self.step_scales.is_empty()
+    }
+
+    fn step_scale(&self, chain: u64) -> f64 {
+        self.step_scales.get(&chain).copied().unwrap_or(1.)
+    }
+
+    pub(crate) fn progress(&self, progress: &mut [ChainProgress]) {
+        let burn_in = self.burn_in as usize;
+        for (chain_id, chain) in progress.iter_mut().enumerate() {
+            chain.step_size *= self.step_scale(chain_id as u64);
+            if burn_in == 0 {
+                continue;
+            }
+            // Like nuts-rs, we report a chain as tuning until it finished
+            // the last warmup draw, and only count divergences after it.
+            chain.tuning = chain.finished_draws <= burn_in;
+            let warmup = chain
+                .divergent_draws
+                .iter()
+                .take_while(|&&draw| draw < burn_in)
+                .count();
+            chain.divergent_draws.drain(..warmup);
+            chain.divergences -= warmup;
+        }
+    }
+
+    /// Apply `progress` before handing the progress to `callback`.
+    pub(crate) fn callback(&self, mut callback: ProgressCallback) -> ProgressCallback {
+        if !self.scales_step_size() && self.burn_in == 0 {
+            return callback;
+        }
+        let reporting = self.clone();
+        let mut inner = callback.callback;
+        callback.callback = Box::new(move |elapsed, mut progress| {
+            reporting.progress(&mut progress);
+            inner(elapsed, progress)
+        });
+        callback
+    }
+
+    pub(crate) fn stats(&self, chain: u64, stats: ArrayRef) -> ArrayRef {
+        let scale = self.step_scale(chain);
+        if scale == 1. && !self.hide_inv_mass {
+            return stats;
+        }
+        let Some(struct_array) = stats.as_struct_opt() else {
+            return stats;
+        };
+        let (fields, columns, nulls) = struct_array.clone().into_parts();
+        let (fields, columns): (Vec<_>, Vec<_>) = fields
+            .iter()
+            .cloned()
+            .zip(columns)
+            .filter(|(field, _)| !(self.hide_inv_mass && field.name() == INV_MASS_STATS))
+            .map(|(field, column)| {
+                if scale == 1.
+                    || !STEP_SIZE_STATS.contains(&field.name().as_str())
+                    || field.data_type() != &DataType::Float64
+                {
+                    return (field, column);
+                }
+                let scaled: ArrayRef = Arc::new(unary::<Float64Type, _, Float64Type>(
+                    column.as_primitive(),
+                    |step| step * scale,
+                ));
+                (field, scaled)
+            })
+            .unzip();
+        Arc::new(StructArray::new(Fields::from(fields), columns, nulls))
+    }
+
+    /// Check that nuts-rs kept the step size of a run with a fixed step
+    /// size in all draws of `trace`, before the step sizes are scaled.
+    pub(crate) fn check(&self, trace: &Trace) -> Result<()> {
+        if !self.fixed_step {
+            return Ok(());
+        }
+        for chain in trace.chains.iter() {
+            let step_size = chain
+                .stats
+                .as_struct_opt()
+                .and_then(|stats| stats.column_by_name("step_size"))
+                .and_then(|column| column.as_primitive_opt::<Float64Type>());
+            let Some(step_size) = step_size else {
+                continue;
+            };
+            if let Some(&step) = step_size
+                .values()
+                .iter()
+                .find(|&&step| step != FIXED_SAMPLER_STEP)
+            {
+                bail!(
+                    "nuts-rs changed the fixed step size of chain {} to {}",
+                    chain.chain_id,
+                    step * self.step_scale(chain.chain_id)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn trace(&self, trace: Trace) -> Trace {
+        if !self.scales_step_size() && !self.hide_inv_mass {
+            return trace;
+        }
+        Trace {
+            chains: trace
+                .chains
+                .into_iter()
+                .map(|chain| ChainOutput {
+                    stats: self.stats(chain.chain_id, chain.stats),
+                    ..chain
+                })
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,409 @@
+//! Serde versions of the nuts-rs settings.
+//!
+//! nuts-rs does not implement serde for its settings, so we mirror each of
+//! the structs here. `PyNutsSettings` converts its settings to nested python
+//! dicts with those, which lets us expose every option without writing a
+//! getter and setter for each of them.
+
+use anyhow::{anyhow, bail, Result};
+use nuts_rs::{
+    DiagAdaptExpSettings, DiagGradNutsSettings, DualAverageSettings, EuclideanAdaptOptions,
+    LowRankNutsSettings, LowRankSettings, TransformedNutsSettings, TransformedSettings,
+};
+use pyo3::{
+    prelude::*,
+    types::{PyDict, PyString},
+};
+use pythonize::{pythonize, Depythonizer};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::metric::{default_dense_settings, DenseAdaptOptions, DenseNutsSettings};
+
+/// Define a serde mirror of a nuts-rs settings struct, with conversions
+/// in both directions. Missing fields take their nuts-rs defaults, or the
+/// values of the `= default` expression if one is given.
+macro_rules! mirror {
+    ($(#[$meta:meta])* $def:ident => $remote:ty { $($field:ident: $ty:ty),* $(,)? }) => {
+        mirror!($(#[$meta])* $def => $remote = (<$remote>::default()) { $($field: $ty),* });
+    };
+    ($(#[$meta:meta])* $def:ident => $remote:ty = ($default:expr) { $($field:ident: $ty:ty),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, Serialize, Deserialize)]
+        #[serde(default, deny_unknown_fields)]
+        pub(crate) struct $def {
+            $(pub(crate) $field: $ty),*
+        }
+
+        #[allow(clippy::useless_conversion)]
+        impl From<$remote> for $def {
+            fn from(value: $remote) -> Self {
+                Self { $($field: value.$field.into()),* }
+            }
+        }
+
+        #[allow(clippy::useless_conversion)]
+        impl From<$def> for $remote {
+            fn from(value: $def) -> Self {
+                Self { $($field: value.$field.into()),* }
+            }
+        }
+
+        impl Default for $def {
+            fn default() -> Self {
+                $default.into()
+            }
+        }
+    };
+}
+
+/// nuts-rs does not export the type of the dual averaging parameters,
+/// so we can only convert it as part of `DualAverageSettings`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DualAverageOptionsDef {
+    pub(crate) k: f64,
+    pub(crate) t0: f64,
+    pub(crate) gamma: f64,
+}
+
+impl Default for DualAverageOptionsDef {
+    fn default() -> Self {
+        DualAverageSettingsDef::default().params
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DualAverageSettingsDef {
+    pub(crate) target_accept: f64,
+    pub(crate) initial_step: f64,
+    pub(crate) params: DualAverageOptionsDef,
+}
+
+impl From<DualAverageSettings> for DualAverageSettingsDef {
+    fn from(value: DualAverageSettings) -> Self {
+        Self {
+            target_accept: value.target_accept,
+            initial_step: value.initial_step,
+            params: DualAverageOptionsDef {
+                k: value.params.k,
+                t0: value.params.t0,
+                gamma: value.params.gamma,
+            },
+        }
+    }
+}
+
+impl From<DualAverageSettingsDef> for DualAverageSettings {
+    fn from(value: DualAverageSettingsDef) -> Self {
+        let mut settings = DualAverageSettings {
+            target_accept: value.target_accept,
+            initial_step: value.initial_step,
+            ..Default::default()
+        };
+        settings.params.k = value.params.k;
+        settings.params.t0 = value.params.t0;
+        settings.params.gamma = value.params.gamma;
+        settings
+    }
+}
+
+impl Default for DualAverageSettingsDef {
+    fn default() -> Self {
+        DualAverageSettings::default().into()
+    }
+}
+
+mirror!(DiagAdaptExpSettingsDef => DiagAdaptExpSettings {
+    store_mass_matrix: bool,
+    use_grad_based_estimate: bool,
+});
+
+mirror!(LowRankSettingsDef => LowRankSettings {
+    store_mass_matrix: bool,
+    gamma: f64,
+    eigval_cutoff: f64,
+});
+
+mirror!(DiagAdaptOptionsDef => EuclideanAdaptOptions<DiagAdaptExpSettings> {
+    dual_average_options: DualAverageSettingsDef,
+    mass_matrix_options: DiagAdaptExpSettingsDef,
+    early_window: f64,
+    step_size_window: f64,
+    mass_matrix_switch_freq: u64,
+    early_mass_matrix_switch_freq: u64,
+    mass_matrix_update_freq: u64,
+});
+
+mirror!(LowRankAdaptOptionsDef => EuclideanAdaptOptions<LowRankSettings> {
+    dual_average_options: DualAverageSettingsDef,
+    mass_matrix_options: LowRankSettingsDef,
+    early_window: f64,
+    step_size_window: f64,
+    mass_matrix_switch_freq: u64,
+    early_mass_matrix_switch_freq: u64,
+    mass_matrix_update_freq: u64,
+});
+
+mirror!(TransformedSettingsDef => TransformedSettings {
+    step_size_window: f64,
+    transform_update_freq: u64,
+    use_orbit_for_training: bool,
+    dual_average_options: DualAverageSettingsDef,
+    transform_train_max_energy_error: f64,
+});
+
+mirror!(DenseAdaptOptionsDef => DenseAdaptOptions {
+    dual_average_options: DualAverageSettingsDef,
+    step_size_window: f64,
+    initial_window: u64,
+    base_window: u64,
+    regularization: f64,
+});
+
+mirror!(DiagGradNutsSettingsDef => DiagGradNutsSettings {
+    num_tune: u64,
+    num_draws: u64,
+    maxdepth: u64,
+    store_gradient: bool,
+    store_unconstrained: bool,
+    max_energy_error: f64,
+    store_divergences: bool,
+    adapt_options: DiagAdaptOptionsDef,
+    check_turning: bool,
+    num_chains: usize,
+    seed: u64,
+});
+
+mirror!(LowRankNutsSettingsDef => LowRankNutsSettings {
+    num_tune: u64,
+    num_draws: u64,
+    maxdepth: u64,
+    store_gradient: bool,
+    store_unconstrained: bool,
+    max_energy_error: f64,
+    store_divergences: bool,
+    adapt_options: LowRankAdaptOptionsDef,
+    check_turning: bool,
+    num_chains: usize,
+    seed: u64,
+});
+
+mirror!(TransformedNutsSettingsDef => TransformedNutsSettings {
+    num_tune: u64,
+    num_draws: u64,
+    maxdepth: u64,
+    store_gradient: bool,
+    store_unconstrained: bool,
+    max_energy_error: f64,
+    store_divergences: bool,
+    adapt_options: TransformedSettingsDef,
+    check_turning: bool,
+    num_chains: usize,
+    seed: u64,
+});
+
+mirror!(DenseNutsSettingsDef => DenseNutsSettings = (default_dense_settings()) {
+    num_tune: u64,
+    num_draws: u64,
+    maxdepth: u64,
+    store_gradient: bool,
+    store_unconstrained: bool,
+    max_energy_error: f64,
+    store_divergences: bool,
+    adapt_options: DenseAdaptOptionsDef,
+    check_turning: bool,
+    num_chains: usize,
+    seed: u64,
+});
+
+/// Names of options from before we exposed the nested settings, and the
+/// path of the option for the diag, low rank, transform and dense variants.
+const ALIASES: &[(&str, [Option<&str>; 4])] = &[
+    (
+        "window_switch_freq",
+        [
+            Some("adapt_options.mass_matrix_switch_freq"),
+            Some("adapt_options.mass_matrix_switch_freq"),
+            Some("adapt_options.transform_update_freq"),
+            None,
+        ],
+    ),
+    (
+        "early_window_switch_freq",
+        [
+            Some("adapt_options.early_mass_matrix_switch_freq"),
+            Some("adapt_options.early_mass_matrix_switch_freq"),
+            None,
+            None,
+        ],
+    ),
+    (
+        "mass_matrix_switch_freq",
+        [
+            Some("adapt_options.mass_matrix_switch_freq"),
+            Some("adapt_options.mass_matrix_switch_freq"),
+            None,
+            None,
+        ],
+    ),
+    (
+        "initial_step",
+        [Some("adapt_options.dual_average_options.initial_step"); 4],
+    ),
+    (
+        "target_accept",
+        [Some("adapt_options.dual_average_options.target_accept"); 4],
+    ),
+    (
+        "store_mass_matrix",
+        [
+            Some("adapt_options.mass_matrix_options.store_mass_matrix"),
+            Some("adapt_options.mass_matrix_options.store_mass_matrix"),
+            None,
+            None,
+        ],
+    ),
+    (
+        "use_grad_based_mass_matrix",
+        [
+            Some("adapt_options.mass_matrix_options.use_grad_based_estimate"),
+            None,
+            None,
+            None,
+        ],
+    ),
+    (
+        "mass_matrix_eigval_cutoff",
+        [
+            None,
+            Some("adapt_options.mass_matrix_options.eigval_cutoff"),
+            None,
+            None,
+        ],
+    ),
+    (
+        "mass_matrix_gamma",
+        [
+            None,
+            Some("adapt_options.mass_matrix_options.gamma"),
+            None,
+            None,
+        ],
+    ),
+    (
+        "train_on_orbit",
+        [
+            None,
+            None,
+            Some("adapt_options.use_orbit_for_training"),
+            None,
+        ],
+    ),
+];
+
+pub(crate) const VARIANTS: [&str; 4] = ["diag", "low_rank", "transform", "dense"];
+
+pub(crate) fn variant_index(name: &str) -> Result<usize> {
+    VARIANTS
+        .iter()
+        .position(|variant| *variant == name)
+        .ok_or_else(|| {
+            anyhow!(
+                "Unknown settings variant {}, expected one of {}",
+                name,
+                VARIANTS.join(", ")
+            )
+        })
+}
+
+/// The path of an option in the nested settings of a variant.
+pub(crate) fn option_path(name: &str, variant: usize) -> Result<Vec<&str>> {
+    match ALIASES.iter().find(|(alias, _)| *alias == name) {
+        Some((_, paths)) => match paths[variant] {
+            Some(path) => Ok(path.split('.').collect()),
+            None => bail!(
+                "Option {} is not available for {} settings",
+                name,
+                VARIANTS[variant]
+            ),
+        },
+        None => Ok(name.split('.').collect()),
+    }
+}
+
+pub(crate) fn to_py<'py, T: Serialize>(py: Python<'py>, value: &T) -> Result<Bound<'py, PyDict>> {
+    Ok(pythonize(py, value)?
+        .downcast_into::<PyDict>()
+        .map_err(PyErr::from)?)
+}
+
+/// Read the settings of a variant from a python dict, with errors that
+/// name the field that is invalid.
+pub(crate) fn from_py<T: DeserializeOwned>(value: &Bound<PyAny>, variant: &str) -> Result<T> {
+    let mut depythonizer = Depythonizer::from_object(value);
+    serde_path_to_error::deserialize(&mut depythonizer).map_err(|err| {
+        let path = err.path().to_string();
+        let inner = err.into_inner();
+        if path == "." {
+            anyhow!("Invalid {} settings: {}", variant, inner)
+        } else {
+            anyhow!("Invalid {} settings at `{}`: {}", variant, path, inner)
+        }
+    })
+}
+
+/// Look up a nested option in a settings dict.
+pub(crate) fn get_path<'py>(
+    dict: &Bound<'py, PyDict>,
+    path: &[&str],
+    variant: &str,
+) -> Result<Bound<'py, PyAny>> {
+    let mut value = dict.clone().into_any();
+    for (i, key) in path.iter().enumerate() {
+        let item = value
+            .downcast::<PyDict>()
+            .ok()
+            .and_then(|dict| dict.get_item(key).ok().flatten());
+        let Some(item) = item else {
+            bail!(
+                "Unknown option `{}` for {} settings",
+                path[..=i].join("."),
+                variant
+            );
+        };
+        value = item;
+    }
+    Ok(value)
+}
+
+/// Set a nested option in a settings dict. Dicts are merged into the
+/// existing options instead of replacing them.
+pub(crate) fn set_path(
+    dict: &Bound<PyDict>,
+    path: &[&str],
+    value: &Bound<PyAny>,
+    variant: &str,
+) -> Result<()> {
+    let (last, parents) = path
+        .split_last()
+        .ok_or_else(|| anyhow!("Empty option name"))?;
+    let parent = if parents.is_empty() {
+        dict.clone()
+    } else {
+        get_path(dict, parents, variant)?
+            .downcast_into::<PyDict>()
+            .map_err(|_| anyhow!("Option `{}` is not a dict", parents.join(".")))?
+    };
+    match (parent.get_item(last)?, value.downcast::<PyDict>()) {
+        (Some(current), Ok(update)) if current.is_instance_of::<PyDict>() => {
+            let current = current.downcast_into::<PyDict>().map_err(PyErr::from)?;
+            for (key, item) in update.iter() {
+                let key = key.downcast_into::<PyString>().map_err(PyErr::from)?;
+                set_path(&current, &[key.to_str()?], &item, variant)?;
+            }
+        }
+        _ => parent.set_item(last, value)?,
+    }
+    Ok(())
+}
@@ -1,30 +1,49 @@
 use std::sync::Arc;
-use std::{ffi::CString, path::PathBuf};
+use std::time::Duration;
+use std::{
+    ffi::CString,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{bail, Context};
 use arrow::array::{Array, FixedSizeListArray, Float64Array, StructArray};
 use arrow::datatypes::{DataType, Field};
 use bridgestan::open_library;
 use itertools::{izip, Itertools};
+use numpy::{
+    AllowTypeChange, PyArray1, PyArrayLikeDyn, PyArrayMethods, PyReadonlyArray1, PyReadonlyArray2,
+    PyReadonlyArrayDyn,
+};
 use nuts_rs::{CpuLogpFunc, CpuMath, DrawStorage, LogpError, Model, Settings};
 use pyo3::exceptions::PyRuntimeError;
 use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyTuple};
+use pyo3::types::{PyDict, PyList, PyTuple};
 use pyo3::{exceptions::PyValueError, pyclass, pymethods, PyResult};
 use rand::prelude::Distribution;
 use rand::{rng, RngCore};
 use rand_distr::StandardNormal;
+use sha2::{Digest, Sha256};
 use smallvec::{SmallVec, ToSmallVec};
 
 use thiserror::Error;
 
-use crate::wrapper::PyTransformAdapt;
+use crate::{
+    benchmark::benchmark_logp,
+    evaluate::{logp_and_grad, PyEvaluations},
+    gradient::{check_model_gradient, GradientCheck, DEFAULT_EPS},
+    init::{init_point, InitStrategy, ModelInit},
+    laplace::{laplace, LaplaceResult, DEFAULT_HESSIAN_EPS},
+    lbfgs::LbfgsOptions,
+    optimize::{find_map, MapResult},
+    pathfinder::{run_pathfinder, PathfinderResult, PathfinderSettings},
+    wrapper::{PyTransformAdapt, PyTransformation},
+};
 
 type InnerModel = bridgestan::Model<Arc<bridgestan::StanLibrary>>;
 
 #[pyclass]
 #[derive(Clone)]
-pub struct StanLibrary(Arc<bridgestan::StanLibrary>);
+pub struct StanLibrary(Arc<bridgestan::StanLibrary>, Arc<Path>);
 
 #[derive(Clone, Debug)]
 struct Parameter {
@@ -39,9 +58,9 @@ struct Parameter {
 impl StanLibrary {
     #[new]
     fn new(path: PathBuf) -> PyResult<Self> {
-        let lib = open_library(path)
+        let lib = open_library(&path)
             .map_err(|e| PyValueError::new_err(format!("Could not open stan libray: {e}")))?;
-        Ok(Self(Arc::new(lib)))
+        Ok(Self(Arc::new(lib), path.into()))
     }
 }
 
@@ -82,6 +101,18 @@ pub struct StanModel {
     model: Arc<InnerModel>,
     variables: Vec<Parameter>,
     transform_adapter: Option<PyTransformAdapt>,
+    library_path: Arc<Path>,
+    data_hash: Option<String>,
+    inits: Option<Arc<StanInits>>,
+}
+
+/// User supplied initial points on the unconstrained space.
+#[derive(Debug)]
+struct StanInits {
+    points: Vec<Box<[f64]>>,
+    /// Whether there is one point for each chain, or one for all chains.
+    per_chain: bool,
+    jitter: f64,
 }
 
 /// Return meta information about the constrained parameters of the model
@@ -251,6 +282,9 @@ impl StanModel {
             Some(seed) => seed,
             None => rng().next_u32(),
         };
+        let data_hash = data
+            .as_ref()
+            .map(|data| format!("{:x}", Sha256::digest(data.as_bytes())));
         let data: Option<CString> = data.map(CString::new).transpose()?;
         let model = Arc::new(
             bridgestan::Model::new(lib.0, data.as_ref(), seed).map_err(anyhow::Error::new)?,
@@ -263,6 +297,9 @@ impl StanModel {
             model,
             variables,
             transform_adapter,
+            library_path: lib.1,
+            data_hash,
+            inits: None,
         })
     }
 
@@ -296,25 +333,326 @@ impl StanModel {
             .collect())
     }
 
-    /*
-    fn benchmark_logp<'py>(
+    /// Time `evals` evaluations of the logp and its gradient at `point`
+    /// on each of `cores` threads, in seconds.
+    fn benchmark_logp(
         &self,
-        py: Python<'py>,
-        point: PyReadonlyArray1<'py, f64>,
+        py: Python<'_>,
+        point: PyReadonlyArray1<'_, f64>,
         cores: usize,
         evals: usize,
-    ) -> PyResult<&'py PyList> {
-        let point = point.as_slice()?;
-        let durations = py.allow_threads(|| Model::benchmark_logp(self, &point, cores, evals))?;
-        let out = PyList::new(
-            py,
-            durations
+    ) -> anyhow::Result<Vec<Vec<f64>>> {
+        let point = point.as_array().to_vec();
+        let durations = py.allow_threads(|| benchmark_logp(self, &point, cores, evals))?;
+        Ok(durations
+            .into_iter()
+            .map(|thread| thread.iter().map(Duration::as_secs_f64).collect())
+            .collect())
+    }
+
+    /// Compare the gradient of the logp at `point` with central finite
+    /// differences with relative step size `eps`.
+    #[pyo3(signature = (point, eps=DEFAULT_EPS))]
+    fn check_gradient(
+        &self,
+        py: Python<'_>,
+        point: PyReadonlyArray1<'_, f64>,
+        eps: f64,
+    ) -> anyhow::Result<GradientCheck> {
+        let point = point.as_array().to_vec();
+        py.allow_threads(|| check_model_gradient(self, &point, eps))
+    }
+
+    /// Evaluate the logp and its gradient at each row of `points`.
+    ///
+    /// Returns the logp values, the gradients and a mask of the points
+    /// where the logp could not be evaluated, for example because it is not
+    /// finite. Their logp and gradient are `nan`.
+    fn logp_and_grad<'py>(
+        &self,
+        py: Python<'py>,
+        points: PyReadonlyArray2<'py, f64>,
+    ) -> anyhow::Result<PyEvaluations<'py>> {
+        let points = points.as_array();
+        let (num_points, dim) = points.dim();
+        let points: Vec<f64> = points.iter().copied().collect();
+        let evaluations = py.allow_threads(|| logp_and_grad(self, &points, num_points, dim))?;
+        evaluations.into_numpy(py)
+    }
+
+    /// Run Pathfinder with `num_paths` independent paths, each from an
+    /// initial point chosen by `init_strategy`.
+    #[pyo3(signature = (num_paths=4, settings=None, init_strategy=None, seed=None))]
+    fn pathfinder(
+        &self,
+        py: Python<'_>,
+        num_paths: usize,
+        settings: Option<PathfinderSettings>,
+        init_strategy: Option<InitStrategy>,
+        seed: Option<u64>,
+    ) -> anyhow::Result<PathfinderResult> {
+        py.allow_threads(|| run_pathfinder(self, num_paths, settings, init_strategy, seed))
+    }
+
+    /// Maximize the logp with L-BFGS, from `start` or from an initial
+    /// point chosen by `init_strategy`.
+    #[pyo3(signature = (
+        start=None,
+        max_iters=1000,
+        history=6,
+        grad_tol=1e-8,
+        init_strategy=None,
+        seed=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn find_map(
+        &self,
+        py: Python<'_>,
+        start: Option<PyReadonlyArray1<'_, f64>>,
+        max_iters: usize,
+        history: usize,
+        grad_tol: f64,
+        init_strategy: Option<InitStrategy>,
+        seed: Option<u64>,
+    ) -> anyhow::Result<MapResult> {
+        let start = start.map(|start| start.as_array().to_vec());
+        let options = LbfgsOptions {
+            max_iters,
+            history,
+            grad_tol,
+            ..Default::default()
+        };
+        py.allow_threads(|| find_map(self, start, options, init_strategy, seed))
+    }
+
+    /// Find the mode with L-BFGS, from `start` or from an initial point
+    /// chosen by `init_strategy`, and draw `num_draws` times from the
+    /// normal approximation with the curvature of the logp at the mode.
+    #[pyo3(signature = (num_draws=1000, start=None, eps=DEFAULT_HESSIAN_EPS, init_strategy=None, seed=None))]
+    fn laplace(
+        &self,
+        py: Python<'_>,
+        num_draws: usize,
+        start: Option<PyReadonlyArray1<'_, f64>>,
+        eps: f64,
+        init_strategy: Option<InitStrategy>,
+        seed: Option<u64>,
+    ) -> anyhow::Result<LaplaceResult> {
+        let start = start.map(|start| start.as_array().to_vec());
+        py.allow_threads(|| laplace(self, start, num_draws, eps, init_strategy, seed))
+    }
+
+    /// Map values of the parameters to the unconstrained space.
+    ///
+    /// `values` is either a JSON string in the format of Stan data files, or
+    /// a dict with an array for each parameter, in the same layout as in the
+    /// trace.
+    fn unconstrain<'py>(
+        &self,
+        py: Python<'py>,
+        values: &Bound<'py, PyAny>,
+    ) -> anyhow::Result<Bound<'py, PyArray1<f64>>> {
+        Ok(PyArray1::from_vec(py, self.unconstrain_values(values)?))
+    }
+
+    /// A copy of the model whose chains start at the given values.
+    ///
+    /// `inits` are the values of the parameters as for `unconstrain`, either
+    /// the same for all chains or a list with the values for each chain.
+    /// Each coordinate of the initial points on the unconstrained space is
+    /// moved by a uniform random value in `[-jitter, jitter]`.
+    #[pyo3(signature = (inits, jitter=0.0))]
+    fn with_inits(&self, inits: &Bound<'_, PyAny>, jitter: f64) -> anyhow::Result<Self> {
+        if !(jitter.is_finite() && jitter >= 0.) {
+            bail!("`jitter` must be a non-negative number");
+        }
+        let points = match inits.downcast::<PyList>() {
+            Ok(inits) => inits
+                .iter()
+                .enumerate()
+                .map(|(chain, values)| {
+                    self.unconstrain_values(&values)
+                        .map(Vec::into_boxed_slice)
+                        .with_context(|| format!("Invalid initial values for chain {}", chain))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            Err(_) => vec![self.unconstrain_values(inits)?.into_boxed_slice()],
+        };
+        if points.is_empty() {
+            bail!("The list of initial values is empty");
+        }
+        let per_chain = inits.is_instance_of::<PyList>();
+        Ok(Self {
+            inits: Some(Arc::new(StanInits {
+                points,
+                per_chain,
+                jitter,
+            })),
+            ..self.clone()
+        })
+    }
+
+    /// Map points on the unconstrained space to the values of the variables.
+    ///
+    /// `points` is a single point or an array with one point per row.
+    /// Returns a dict with the values of each parameter, and optionally the
+    /// transformed parameters and generated quantities, in the same layout
+    /// as in the trace, with a leading dimension for the points if there
+    /// are several. `seed` is the seed of the random number generator of
+    /// the generated quantities.
+    #[pyo3(signature = (points, include_tp=false, include_gq=false, seed=None))]
+    fn constrain<'py>(
+        &self,
+        py: Python<'py>,
+        points: PyReadonlyArrayDyn<'py, f64>,
+        include_tp: bool,
+        include_gq: bool,
+        seed: Option<u32>,
+    ) -> anyhow::Result<Bound<'py, PyDict>> {
+        let points = points.as_array();
+        let dim = self.model.param_unc_num();
+        let (num_points, leading) = match points.shape() {
+            &[n] if n == dim => (1, None),
+            &[n, d] if d == dim => (n, Some(n)),
+            shape => bail!(
+                "Points must have shape ({},) or (n, {}), but have shape {:?}",
+                dim,
+                dim,
+                shape,
+            ),
+        };
+        let points: Vec<f64> = points.iter().copied().collect();
+
+        let num_values = self.model.param_num(include_tp, include_gq);
+        let variables = self.variables_until(num_values);
+        let seed = seed.unwrap_or_else(|| rng().next_u32());
+
+        let values = py.allow_threads(|| {
+            let mut rng = self.model.new_rng(seed)?;
+            let mut constrained = vec![0f64; num_values];
+            let mut values: Vec<Vec<f64>> = variables
+                .iter()
+                .map(|var| Vec::with_capacity(var.size * num_points))
+                .collect();
+            for i in 0..num_points {
+                self.model
+                    .param_constrain(
+                        &points[i * dim..(i + 1) * dim],
+                        include_tp,
+                        include_gq,
+                        &mut constrained,
+                        Some(&mut rng),
+                    )
+                    .with_context(|| format!("Could not constrain point {}", i))?;
+                append_variables(variables, &constrained, &mut values);
+            }
+            anyhow::Ok(values)
+        })?;
+
+        let out = PyDict::new(py);
+        for (var, values) in variables.iter().zip_eq(values) {
+            let shape: Vec<usize> = leading
                 .into_iter()
-                .map(|inner| PyList::new(py, inner.into_iter().map(|d| d.as_secs_f64()))),
-        );
+                .chain(var.shape.iter().copied())
+                .collect();
+            out.set_item(&var.name, PyArray1::from_vec(py, values).reshape(shape)?)?;
+        }
         Ok(out)
     }
-    */
+}
+
+impl StanModel {
+    fn unconstrain_values(&self, values: &Bound<'_, PyAny>) -> anyhow::Result<Vec<f64>> {
+        let mut unconstrained = vec![0f64; self.model.param_unc_num()];
+        if let Ok(json) = values.extract::<String>() {
+            let json = CString::new(json)?;
+            self.model
+                .param_unconstrain_json(&json, &mut unconstrained)
+                .context("Could not unconstrain the parameters")?;
+            return Ok(unconstrained);
+        }
+
+        let values = values
+            .downcast::<PyDict>()
+            .map_err(|_| anyhow::anyhow!("Values must be a dict or a JSON string"))?;
+        let num_params = self.model.param_num(false, false);
+        let parameters = self.variables_until(num_params);
+        for key in values.keys() {
+            let name: String = key.extract()?;
+            if !parameters.iter().any(|var| var.name == name) {
+                bail!("Unknown parameter {}", name);
+            }
+        }
+
+        let mut constrained = vec![0f64; num_params];
+        for var in parameters {
+            let value = values
+                .get_item(&var.name)?
+                .with_context(|| format!("Missing value for parameter {}", var.name))?;
+            let value: PyArrayLikeDyn<f64, AllowTypeChange> = value
+                .extract()
+                .with_context(|| format!("Value of parameter {} is not numeric", var.name))?;
+            let value = value.as_array();
+            let size: usize = value.shape().iter().product();
+            if size != var.size || (value.ndim() > 0 && value.shape() != var.shape.as_slice()) {
+                bail!(
+                    "Parameter {} has shape {:?}, but the value has shape {:?}",
+                    var.name,
+                    var.shape,
+                    value.shape(),
+                );
+            }
+            let c_order: Vec<f64> = value.iter().copied().collect();
+            let target = &mut constrained[var.start_idx..var.end_idx];
+            if var.shape.len() < 2 {
+                target.copy_from_slice(&c_order);
+            } else if var.size > 0 {
+                // An array in C order is the transpose of one in Fortran order
+                let reversed: Vec<usize> = var.shape.iter().rev().copied().collect();
+                let mut fortran_order = Vec::with_capacity(var.size);
+                fortran_to_c_order(&c_order, &reversed, &mut fortran_order);
+                target.copy_from_slice(&fortran_order);
+            }
+        }
+
+        self.model
+            .param_unconstrain(&constrained, &mut unconstrained)
+            .context("Could not unconstrain the parameters")?;
+        Ok(unconstrained)
+    }
+
+    /// The variables that fit into the first `num_values` entries of the
+    /// output of `param_constrain`. Stan orders them as parameters,
+    /// transformed parameters and then generated quantities.
+    fn variables_until(&self, num_values: usize) -> &[Parameter] {
+        let count = self
+            .variables
+            .iter()
+            .take_while(|var| var.end_idx <= num_values)
+            .count();
+        &self.variables[..count]
+    }
+
+    /// The path of the compiled Stan library of the model.
+    pub(crate) fn library_path(&self) -> &Path {
+        &self.library_path
+    }
+
+    /// The SHA-256 hash of the JSON data of the model, if it has any.
+    pub(crate) fn data_hash(&self) -> Option<&str> {
+        self.data_hash.as_deref()
+    }
+
+    /// A copy of the model with the transformation adapter `map` returns.
+    pub(crate) fn map_transform_adapter(
+        &self,
+        map: impl FnOnce(&PyTransformAdapt) -> PyTransformAdapt,
+    ) -> Self {
+        Self {
+            transform_adapter: self.transform_adapter.as_ref().map(map),
+            ..self.clone()
+        }
+    }
 }
 
 pub struct StanDensity<'model> {
@@ -342,7 +680,7 @@ impl LogpError for StanLogpError {
 
 impl<'model> CpuLogpFunc for StanDensity<'model> {
     type LogpError = StanLogpError;
-    type TransformParams = Py<PyAny>;
+    type TransformParams = PyTransformation;
 
     fn logp(&mut self, position: &[f64], grad: &mut [f64]) -> Result<f64, Self::LogpError> {
         let logp = self
@@ -360,7 +698,7 @@ impl<'model> CpuLogpFunc for StanDensity<'model> {
 
     fn inv_transform_normalize(
         &mut self,
-        params: &Py<PyAny>,
+        params: &PyTransformation,
         untransformed_position: &[f64],
         untransformed_gradient: &[f64],
         transformed_position: &mut [f64],
@@ -383,7 +721,7 @@ impl<'model> CpuLogpFunc for StanDensity<'model> {
 
     fn init_from_transformed_position(
         &mut self,
-        params: &Py<PyAny>,
+        params: &PyTransformation,
         untransformed_position: &mut [f64],
         untransformed_gradient: &mut [f64],
         transformed_position: &[f64],
@@ -422,7 +760,7 @@ impl<'model> CpuLogpFunc for StanDensity<'model> {
 
     fn init_from_untransformed_position(
         &mut self,
-        params: &Py<PyAny>,
+        params: &PyTransformation,
         untransformed_position: &[f64],
         untransformed_gradient: &mut [f64],
         transformed_position: &mut [f64],
@@ -453,7 +791,7 @@ impl<'model> CpuLogpFunc for StanDensity<'model> {
         untransformed_positions: impl ExactSizeIterator<Item = &'a [f64]>,
         untransformed_gradients: impl ExactSizeIterator<Item = &'a [f64]>,
         untransformed_logp: impl ExactSizeIterator<Item = &'a f64>,
-        params: &'a mut Py<PyAny>,
+        params: &'a mut PyTransformation,
     ) -> std::result::Result<(), Self::LogpError> {
         self.transform_adapter
             .as_mut()
@@ -475,7 +813,7 @@ impl<'model> CpuLogpFunc for StanDensity<'model> {
         untransformed_position: &[f64],
         untransformed_gradient: &[f64],
         chain: u64,
-    ) -> std::result::Result<Py<PyAny>, Self::LogpError> {
+    ) -> std::result::Result<PyTransformation, Self::LogpError> {
         let trafo = self
             .transform_adapter
             .as_mut()
@@ -485,7 +823,10 @@ impl<'model> CpuLogpFunc for StanDensity<'model> {
         Ok(trafo)
     }
 
-    fn transformation_id(&self, params: &Py<PyAny>) -> std::result::Result<i64, Self::LogpError> {
+    fn transformation_id(
+        &self,
+        params: &PyTransformation,
+    ) -> std::result::Result<i64, Self::LogpError> {
         let id = self
             .transform_adapter
             .as_ref()
@@ -537,6 +878,28 @@ fn fortran_to_c_order(data: &[f64], shape: &[usize], out: &mut Vec<f64>) {
     }
 }
 
+/// Append the values of each variable in the output of `param_constrain`
+/// to `out`, in C order.
+fn append_variables(variables: &[Parameter], constrained: &[f64], out: &mut [Vec<f64>]) {
+    for (var, trace) in variables.iter().zip_eq(out.iter_mut()) {
+        let slice = &constrained[var.start_idx..var.end_idx];
+        assert!(slice.len() == var.size);
+
+        if var.size == 0 {
+            continue;
+        }
+
+        // The slice is in fortran order. This doesn't matter if it low dim
+        if var.shape.len() < 2 {
+            trace.extend_from_slice(slice);
+            continue;
+        }
+
+        // We need to transpose
+        fortran_to_c_order(slice, &var.shape, trace);
+    }
+}
+
 pub struct StanTrace<'model> {
     inner: &'model InnerModel,
     model: &'model StanModel,
@@ -579,23 +942,11 @@ impl<'model> DrawStorage for StanTrace<'model> {
                 Some(&mut self.rng),
             )
             .context("Failed to constrain the parameters of the draw")?;
-        for (var, trace) in self.model.variables.iter().zip_eq(self.trace.iter_mut()) {
-            let slice = &self.expanded_buffer[var.start_idx..var.end_idx];
-            assert!(slice.len() == var.size);
-
-            if var.size == 0 {
-                continue;
-            }
-
-            // The slice is in fortran order. This doesn't matter if it low dim
-            if var.shape.len() < 2 {
-                trace.extend_from_slice(slice);
-                continue;
-            }
-
-            // We need to transpose
-            fortran_to_c_order(slice, &var.shape, trace);
-        }
+        append_variables(
+            &self.model.variables,
+            &self.expanded_buffer,
+            &mut self.trace,
+        );
         self.count += 1;
         Ok(())
     }
@@ -671,11 +1022,39 @@ impl Model for StanModel {
         rng: &mut R,
         position: &mut [f64],
     ) -> anyhow::Result<()> {
-        let dist = StandardNormal;
-        dist.sample_iter(rng)
-            .zip(position.iter_mut())
-            .for_each(|(val, pos)| *pos = val);
-        Ok(())
+        let Some(inits) = self.inits.as_ref() else {
+            let dist = StandardNormal;
+            dist.sample_iter(rng)
+                .zip(position.iter_mut())
+                .for_each(|(val, pos)| *pos = val);
+            return Ok(());
+        };
+
+        if inits.per_chain {
+            bail!("The model has initial values for each chain, but the chain is unknown");
+        }
+        init_point(&inits.points[0], inits.jitter, rng, position)
+    }
+}
+
+impl ModelInit for StanModel {
+    fn init_chain_position<R: rand::Rng + ?Sized>(
+        &self,
+        chain: u64,
+        rng: &mut R,
+        position: &mut [f64],
+    ) -> anyhow::Result<()> {
+        let Some(inits) = self.inits.as_ref().filter(|inits| inits.per_chain) else {
+            return self.init_position(rng, position);
+        };
+        let point = inits.points.get(chain as usize).with_context(|| {
+            format!(
+                "No initial values for chain {}, there are only initial values for {} chains",
+                chain,
+                inits.points.len()
+            )
+        })?;
+        init_point(point, inits.jitter, rng, position)
     }
 }
 
@@ -743,7 +1122,7 @@ mod tests {
     fn parse_vars() {
         let vars = "";
         let parsed = super::params(vars).unwrap();
-        assert!(parsed.len() == 0);
+        assert!(parsed.is_empty());
 
         let vars = "x.1.1,x.2.1,x.3.1,x.1.2,x.2.2,x.3.2";
         let parsed = super::params(vars).unwrap();
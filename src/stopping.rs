@@ -0,0 +1,286 @@
+//! Stop sampling once the draws so far are good enough.
+//!
+//! Instead of picking a large number of draws up front, users can give a
+//! minimum bulk ESS and a maximum R-hat. The draw storage of each chain
+//! hands its posterior draws to a shared `StopFeed` every `check_every`
+//! draws, which evaluates the diagnostics once all chains made that many
+//! more draws. The `monitor` then aborts all chains once both targets are
+//! met.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use arrow::array::{Array, ArrayRef, AsArray, StructArray};
+use arrow::compute::concat;
+use arrow::datatypes::Float64Type;
+use pyo3::prelude::*;
+use serde::Serialize;
+
+use crate::{checkpoint::ResumePlan, diagnostics::diagnostics};
+
+/// When to stop sampling before all draws are done.
+///
+/// Sampling stops as soon as the bulk ESS of every scalar element of the
+/// selected variables is at least `min_ess` and their R-hat is at most
+/// `max_rhat`. Elements where the diagnostics are not defined, for
+/// example because all draws are equal, are ignored.
+#[pyclass]
+#[derive(Clone, Debug, Serialize)]
+pub struct StoppingRule {
+    min_ess: Option<f64>,
+    max_rhat: Option<f64>,
+    var_names: Option<Vec<String>>,
+    check_every: u64,
+}
+
+#[pymethods]
+impl StoppingRule {
+    #[new]
+    #[pyo3(signature = (min_ess=None, max_rhat=None, var_names=None, check_every=100))]
+    fn new(
+        min_ess: Option<f64>,
+        max_rhat: Option<f64>,
+        var_names: Option<Vec<String>>,
+        check_every: u64,
+    ) -> Result<Self> {
+        if min_ess.is_none() && max_rhat.is_none() {
+            bail!("A stopping rule needs `min_ess` or `max_rhat`");
+        }
+        if check_every == 0 {
+            bail!("`check_every` must be positive");
+        }
+        Ok(Self {
+            min_ess,
+            max_rhat,
+            var_names,
+            check_every,
+        })
+    }
+
+    #[getter]
+    fn min_ess(&self) -> Option<f64> {
+        self.min_ess
+    }
+
+    #[getter]
+    fn max_rhat(&self) -> Option<f64> {
+        self.max_rhat
+    }
+
+    #[getter]
+    fn var_names(&self) -> Option<Vec<String>> {
+        self.var_names.clone()
+    }
+
+    #[getter]
+    fn check_every(&self) -> u64 {
+        self.check_every
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+/// Why the sampler stopped before all draws were done.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct StopReason {
+    pub(crate) reason: &'static str,
+    /// Number of posterior draws of the shortest chain when we checked.
+    pub(crate) num_draws: usize,
+    pub(crate) min_ess_bulk: f64,
+    pub(crate) max_r_hat: f64,
+}
+
+/// A stopping rule and the state of its evaluation during a run.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Stopping {
+    rule: StoppingRule,
+    /// The number of posterior draws of the shortest chain at the next check.
+    #[serde(skip)]
+    next_check: usize,
+    stopped: Option<StopReason>,
+}
+
+impl Stopping {
+    pub(crate) fn new(rule: StoppingRule) -> Self {
+        Self {
+            next_check: rule.check_every as usize,
+            rule,
+            stopped: None,
+        }
+    }
+
+    pub(crate) fn stopped(&self) -> Option<&StopReason> {
+        self.stopped.as_ref()
+    }
+
+    /// Whether the chains should stop now.
+    ///
+    /// `progress` is the number of posterior draws of the shortest chain, so
+    /// that we only ask for the selected posterior draws of all chains with
+    /// `draws` once they made `check_every` more draws since the last check.
+    fn check(
+        &mut self,
+        progress: usize,
+        draws: impl FnOnce() -> Result<Vec<ArrayRef>>,
+    ) -> Result<bool> {
+        if self.stopped.is_some() {
+            return Ok(true);
+        }
+        if progress < self.next_check {
+            return Ok(false);
+        }
+        self.next_check = progress + self.rule.check_every as usize;
+
+        let draws = draws()?;
+        let num_draws = draws.iter().map(|draws| draws.len()).min().unwrap_or(0);
+        if num_draws < 4 {
+            return Ok(false);
+        }
+
+        let summary = diagnostics(&draws, &[])?;
+        let summary = summary.as_struct();
+        let column = |name: &str| {
+            summary
+                .column_by_name(name)
+                .and_then(|column| column.as_primitive_opt::<Float64Type>())
+                .map(|column| column.values().iter().copied().filter(|x| x.is_finite()))
+                .context("Invalid diagnostics")
+        };
+        let min_ess_bulk = column("ess_bulk")?.fold(f64::NAN, f64::min);
+        let max_r_hat = column("r_hat")?.fold(f64::NAN, f64::max);
+
+        let ess_ok = self.rule.min_ess.map_or(true, |min| min_ess_bulk >= min);
+        let rhat_ok = self.rule.max_rhat.map_or(true, |max| max_r_hat <= max);
+        if ess_ok && rhat_ok {
+            self.stopped = Some(StopReason {
+                reason: "converged",
+                num_draws,
+                min_ess_bulk,
+                max_r_hat,
+            });
+        }
+        Ok(self.stopped.is_some())
+    }
+
+    /// The draws of the variables of the stopping rule.
+    fn select(&self, draws: &ArrayRef) -> Result<ArrayRef> {
+        let Some(names) = &self.rule.var_names else {
+            return Ok(draws.clone());
+        };
+        let draws = draws
+            .as_struct_opt()
+            .context("Draws of the trace must be a struct array")?;
+        let mut fields = vec![];
+        let mut columns = vec![];
+        for (field, column) in draws.fields().iter().zip(draws.columns()) {
+            if names.contains(field.name()) {
+                fields.push(field.clone());
+                columns.push(column.clone());
+            }
+        }
+        if let Some(name) = names
+            .iter()
+            .find(|name| !fields.iter().any(|field| field.name() == *name))
+        {
+            bail!("Unknown variable {} in stopping rule", name);
+        }
+        Ok(Arc::new(StructArray::try_new_with_length(
+            fields.into(),
+            columns,
+            None,
+            draws.len(),
+        )?))
+    }
+}
+
+/// The posterior draws a `StopFeed` got from a chain so far.
+#[derive(Debug, Default)]
+struct FedChain {
+    /// The number of rows of the trace, including the checkpoint of a
+    /// continued run.
+    rows: usize,
+    /// The variables of the stopping rule in the posterior draws.
+    posterior: Vec<ArrayRef>,
+    num_posterior: usize,
+}
+
+/// Evaluates a stopping rule on the draws of the chains while they sample.
+#[derive(Debug)]
+pub(crate) struct StopFeed {
+    stopping: Stopping,
+    num_chains: usize,
+    /// The number of warmup draws of the whole run.
+    num_tune: usize,
+    resume: Option<Arc<ResumePlan>>,
+    chains: BTreeMap<u64, FedChain>,
+}
+
+impl StopFeed {
+    pub(crate) fn new(
+        stopping: Stopping,
+        num_chains: usize,
+        num_tune: u64,
+        resume: Option<Arc<ResumePlan>>,
+    ) -> Self {
+        Self {
+            stopping,
+            num_chains,
+            num_tune: num_tune as usize,
+            resume,
+            chains: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn stopping(&self) -> &Stopping {
+        &self.stopping
+    }
+
+    /// How many draws a chain should make before it feeds them.
+    pub(crate) fn check_every(&self) -> usize {
+        self.stopping.rule.check_every as usize
+    }
+
+    /// Add the rows `start..start + draws.len()` of the trace of a chain
+    /// in the sampler, and check the stopping rule.
+    pub(crate) fn feed(&mut self, chain: u64, draws: &ArrayRef, start: usize) -> Result<()> {
+        if self.stopping.stopped.is_some() {
+            return Ok(());
+        }
+        let fed = self.chains.entry(chain).or_default();
+        let draws = match &self.resume {
+            Some(plan) => plan.stitch_draws(chain, draws, start, fed.rows)?,
+            None => draws.clone(),
+        };
+        let first = fed.rows;
+        fed.rows += draws.len();
+        let warmup = self.num_tune.saturating_sub(first).min(draws.len());
+        if warmup < draws.len() {
+            let posterior = self
+                .stopping
+                .select(&draws.slice(warmup, draws.len() - warmup))?;
+            fed.num_posterior += posterior.len();
+            fed.posterior.push(posterior);
+        }
+
+        if self.chains.len() < self.num_chains {
+            return Ok(());
+        }
+        let Some(progress) = self.chains.values().map(|chain| chain.num_posterior).min() else {
+            return Ok(());
+        };
+        let chains = &self.chains;
+        self.stopping.check(progress, || {
+            chains
+                .values()
+                .map(|chain| {
+                    let arrays: Vec<&dyn Array> =
+                        chain.posterior.iter().map(|array| array.as_ref()).collect();
+                    Ok(concat(&arrays)?)
+                })
+                .collect()
+        })?;
+        Ok(())
+    }
+}
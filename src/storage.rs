@@ -0,0 +1,475 @@
+//! Traces that are written to disk while sampling.
+//!
+//! With a `TraceStorage` other than `memory`, the draws of each chain are
+//! kept in memory only until `buffer_draws` of them have accumulated. They are
+//! then written as a record batch to a file `chain-<id>.<ext>` in the storage
+//! directory. nuts-rs keeps the sampler stats of a chain in memory until the
+//! chain is finalized, so the `monitor` copies them from the running sampler
+//! and appends them to `chain-<id>-stats.<ext>` in blocks of the same size.
+//!
+//! While sampling, the draws are always written to an Arrow IPC stream
+//! `chain-<id>.partial.arrows`. A stream reader stops at the end of the
+//! record batches that were written so far, so that `inspect` and
+//! checkpoints work as usual. Once the chain is finished we convert that
+//! stream batch by batch to the final file. A Zarr store contains the whole
+//! trace in the layout of ArviZ instead of one file per chain, see the
+//! `zarr` module.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use arrow::{
+    array::{new_empty_array, Array, ArrayRef, AsArray, RecordBatch, StructArray},
+    compute::concat,
+    datatypes::{DataType, Fields, Schema},
+    ipc::{
+        reader::{FileReader, StreamReader},
+        writer::{FileWriter, StreamWriter},
+    },
+};
+use parquet::arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter};
+use pyo3::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    checkpoint::RunShape,
+    provenance::METADATA_KEY,
+    zarr::{
+        create_store, read_chain, trim_store, write_attributes, Part, StoreArrays, TraceLayout,
+        ZarrChainWriter,
+    },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FileFormat {
+    ArrowIpc,
+    Parquet,
+    Zarr,
+}
+
+impl FileFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            FileFormat::ArrowIpc => "arrow",
+            FileFormat::Parquet => "parquet",
+            FileFormat::Zarr => "zarr",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct StorageOptions {
+    pub(crate) dir: PathBuf,
+    pub(crate) format: FileFormat,
+    pub(crate) buffer_draws: usize,
+    /// Shapes and labels of the variables, only used for Zarr stores.
+    pub(crate) layout: Arc<TraceLayout>,
+    /// The number of chains and draws of the run we store.
+    pub(crate) run: Option<RunShape>,
+    /// The arrays of the Zarr store of the run that exist already.
+    pub(crate) zarr_arrays: Arc<StoreArrays>,
+    /// The provenance record of the run as it started, which we add to the
+    /// schema of each file, or to the attributes of the Zarr store.
+    pub(crate) provenance: Arc<serde_json::Value>,
+}
+
+impl StorageOptions {
+    pub(crate) fn for_run(self, run: RunShape, provenance: serde_json::Value) -> Self {
+        Self {
+            run: Some(run),
+            zarr_arrays: Default::default(),
+            provenance: Arc::new(provenance),
+            ..self
+        }
+    }
+
+    /// Prepare the directory or store before the chains start.
+    pub(crate) fn create(&self) -> Result<()> {
+        match self.format {
+            FileFormat::Zarr => create_store(self),
+            _ => std::fs::create_dir_all(&self.dir).with_context(|| {
+                format!("Could not create trace directory {}", self.dir.display())
+            }),
+        }
+    }
+
+    /// Tidy up the storage once all chains are done and their sampler
+    /// stats are written, and record the final `provenance` of the run in
+    /// a Zarr store. The files of the chains keep the provenance of the
+    /// start of the run.
+    pub(crate) fn finish_run(&self, provenance: serde_json::Value) -> Result<()> {
+        match self.format {
+            FileFormat::Zarr => {
+                trim_store(self)?;
+                write_attributes(&self.dir, &provenance)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The schema metadata of the files of the trace.
+    fn metadata(&self) -> HashMap<String, String> {
+        if self.provenance.is_null() {
+            return HashMap::new();
+        }
+        HashMap::from([(METADATA_KEY.to_string(), self.provenance.to_string())])
+    }
+
+    /// The file with the draws of a chain, or the store for Zarr.
+    pub(crate) fn draws_path(&self, chain: u64) -> PathBuf {
+        match self.format {
+            FileFormat::Zarr => self.dir.clone(),
+            format => self
+                .dir
+                .join(format!("chain-{}.{}", chain, format.extension())),
+        }
+    }
+
+    /// The file with the sampler stats of a chain, or the store for Zarr.
+    pub(crate) fn stats_path(&self, chain: u64) -> PathBuf {
+        match self.format {
+            FileFormat::Zarr => self.dir.clone(),
+            format => self
+                .dir
+                .join(format!("chain-{}-stats.{}", chain, format.extension())),
+        }
+    }
+
+    /// The Arrow IPC stream we write the draws of a chain to while sampling.
+    pub(crate) fn staging_path(&self, chain: u64) -> PathBuf {
+        self.dir.join(format!("chain-{}.partial.arrows", chain))
+    }
+
+    /// The draws of a finished chain, as a struct array of the type of `like`.
+    pub(crate) fn read_draws(&self, chain: u64, like: &ArrayRef) -> Result<Option<ArrayRef>> {
+        let path = self.draws_path(chain);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let batches = match self.format {
+            FileFormat::ArrowIpc => {
+                FileReader::try_new(open(&path)?, None)?.collect::<Result<_, _>>()?
+            }
+            FileFormat::Parquet => read_parquet(&path)?,
+            FileFormat::Zarr => return read_chain(self, chain, like.as_ref()),
+        };
+        concat_batches(batches)
+    }
+
+    /// Replace the draws of a finished chain.
+    pub(crate) fn write_draws(&self, chain: u64, draws: &ArrayRef) -> Result<()> {
+        if self.format == FileFormat::Zarr {
+            let mut writer =
+                ZarrChainWriter::create(self, chain, Part::Draws, &struct_fields(draws)?)?;
+            writer.write(draws.as_ref())?;
+            return writer.finish();
+        }
+        let mut file =
+            ChainFile::create(&self.draws_path(chain), self.format, draws, self.metadata())?;
+        file.write(draws)?;
+        file.finish()
+    }
+
+    /// The draws of a running chain that were written to disk so far.
+    pub(crate) fn read_staged_draws(&self, chain: u64) -> Result<Option<ArrayRef>> {
+        let batches = read_staged(&self.staging_path(chain))?.collect::<Result<_, _>>()?;
+        concat_batches(batches)
+    }
+
+    /// Move the draws of a finished chain to their final file.
+    pub(crate) fn finish_chain(&self, chain: u64) -> Result<()> {
+        let staging = self.staging_path(chain);
+        let reader = read_staged(&staging)?;
+        let like = new_empty_array(&DataType::Struct(reader.schema().fields().clone()));
+        let mut writer = self.part_writer(chain, Part::Draws, &like)?;
+        for batch in reader {
+            writer.write(&(Arc::new(StructArray::from(batch?)) as ArrayRef))?;
+        }
+        writer.finish()?;
+        std::fs::remove_file(&staging)?;
+        Ok(())
+    }
+
+    /// A writer for the sampler stats of a chain, with the fields of the
+    /// struct array `like`.
+    pub(crate) fn stats_writer(&self, chain: u64, like: &ArrayRef) -> Result<PartWriter> {
+        self.part_writer(chain, Part::Stats, like)
+    }
+
+    fn part_writer(&self, chain: u64, part: Part, like: &ArrayRef) -> Result<PartWriter> {
+        if self.format == FileFormat::Zarr {
+            let writer = ZarrChainWriter::create(self, chain, part, &struct_fields(like)?)?;
+            return Ok(PartWriter::Zarr(writer));
+        }
+        let path = match part {
+            Part::Draws => self.draws_path(chain),
+            Part::Stats => self.stats_path(chain),
+        };
+        Ok(PartWriter::File(ChainFile::create(
+            &path,
+            self.format,
+            like,
+            self.metadata(),
+        )?))
+    }
+}
+
+/// Appends the draws or the sampler stats of a chain to their final file,
+/// or to the Zarr store.
+pub(crate) enum PartWriter {
+    File(ChainFile),
+    Zarr(ZarrChainWriter),
+}
+
+impl PartWriter {
+    pub(crate) fn write(&mut self, array: &ArrayRef) -> Result<()> {
+        match self {
+            PartWriter::File(file) => file.write(array),
+            PartWriter::Zarr(writer) => writer.write(array.as_ref()),
+        }
+    }
+
+    pub(crate) fn finish(self) -> Result<()> {
+        match self {
+            PartWriter::File(file) => file.finish(),
+            PartWriter::Zarr(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Where the trace of a sampler is stored.
+#[pyclass]
+#[derive(Clone, Debug, Default)]
+pub struct TraceStorage(pub(crate) Option<StorageOptions>);
+
+#[pymethods]
+impl TraceStorage {
+    /// Keep the whole trace in memory.
+    #[staticmethod]
+    fn memory() -> Self {
+        TraceStorage(None)
+    }
+
+    /// Write the draws of each chain to an Arrow IPC (Feather) file in `path`.
+    #[staticmethod]
+    #[pyo3(signature = (path, buffer_draws=100))]
+    fn arrow(path: PathBuf, buffer_draws: usize) -> Result<Self> {
+        Self::new(path, FileFormat::ArrowIpc, buffer_draws)
+    }
+
+    /// Write the draws of each chain to a Parquet file in `path`.
+    #[staticmethod]
+    #[pyo3(signature = (path, buffer_draws=100))]
+    fn parquet(path: PathBuf, buffer_draws: usize) -> Result<Self> {
+        Self::new(path, FileFormat::Parquet, buffer_draws)
+    }
+
+    /// Write the trace to a Zarr v3 store in `path`, which can be opened
+    /// as an ArviZ `InferenceData` or an xarray `DataTree`.
+    ///
+    /// The draws are chunked in blocks of `buffer_draws` draws of a chain.
+    #[staticmethod]
+    #[pyo3(signature = (path, buffer_draws=100))]
+    fn zarr(path: PathBuf, buffer_draws: usize) -> Result<Self> {
+        Self::new(path, FileFormat::Zarr, buffer_draws)
+    }
+
+    /// Add the shapes, dimension names and coordinates of the variables.
+    ///
+    /// This is done by `nutpie.sample`, and is only used for Zarr stores.
+    fn with_metadata(
+        &self,
+        shapes: HashMap<String, Vec<usize>>,
+        dims: HashMap<String, Vec<String>>,
+        coords: &Bound<PyAny>,
+    ) -> PyResult<Self> {
+        let layout = Arc::new(TraceLayout::from_py(shapes, dims, coords)?);
+        Ok(TraceStorage(
+            self.0
+                .clone()
+                .map(|options| StorageOptions { layout, ..options }),
+        ))
+    }
+
+    #[getter]
+    fn path(&self) -> Option<PathBuf> {
+        self.0.as_ref().map(|options| options.dir.clone())
+    }
+
+    /// One of `memory`, `arrow`, `parquet` or `zarr`.
+    #[getter]
+    fn kind(&self) -> &'static str {
+        match &self.0 {
+            None => "memory",
+            Some(options) => match options.format {
+                FileFormat::ArrowIpc => "arrow",
+                FileFormat::Parquet => "parquet",
+                FileFormat::Zarr => "zarr",
+            },
+        }
+    }
+}
+
+impl TraceStorage {
+    fn new(dir: PathBuf, format: FileFormat, buffer_draws: usize) -> Result<Self> {
+        if buffer_draws == 0 {
+            bail!("buffer_draws must be positive");
+        }
+        Ok(TraceStorage(Some(StorageOptions {
+            dir,
+            format,
+            buffer_draws,
+            layout: Default::default(),
+            run: None,
+            zarr_arrays: Default::default(),
+            provenance: Default::default(),
+        })))
+    }
+}
+
+/// How `TraceStorage` appears in the provenance of a trace.
+#[derive(Serialize)]
+struct TraceStorageRecord<'a> {
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    buffer_draws: Option<usize>,
+}
+
+impl Serialize for TraceStorage {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        TraceStorageRecord {
+            kind: self.kind(),
+            path: self.0.as_ref().map(|options| options.dir.as_path()),
+            buffer_draws: self.0.as_ref().map(|options| options.buffer_draws),
+        }
+        .serialize(serializer)
+    }
+}
+
+enum Writer {
+    Stream(StreamWriter<BufWriter<File>>),
+    Ipc(FileWriter<BufWriter<File>>),
+    Parquet(ArrowWriter<File>),
+}
+
+/// A file that we append record batches of a chain to.
+pub(crate) struct ChainFile {
+    writer: Writer,
+}
+
+impl ChainFile {
+    /// Create the file, with the fields of the struct array `like`.
+    /// Create a file with the fields of `like`, and `metadata` in its schema.
+    pub(crate) fn create(
+        path: &Path,
+        format: FileFormat,
+        like: &ArrayRef,
+        metadata: HashMap<String, String>,
+    ) -> Result<Self> {
+        let (file, schema) = Self::open(path, like, metadata)?;
+        let writer = match format {
+            FileFormat::ArrowIpc => Writer::Ipc(FileWriter::try_new_buffered(file, &schema)?),
+            FileFormat::Parquet => Writer::Parquet(ArrowWriter::try_new(file, schema, None)?),
+            FileFormat::Zarr => bail!("Zarr stores are not written file by file"),
+        };
+        Ok(Self { writer })
+    }
+
+    /// Create an Arrow IPC stream that can be read before it is finished.
+    pub(crate) fn staging(path: &Path, like: &ArrayRef) -> Result<Self> {
+        let (file, schema) = Self::open(path, like, HashMap::new())?;
+        Ok(Self {
+            writer: Writer::Stream(StreamWriter::try_new_buffered(file, &schema)?),
+        })
+    }
+
+    fn open(
+        path: &Path,
+        like: &ArrayRef,
+        metadata: HashMap<String, String>,
+    ) -> Result<(File, Arc<Schema>)> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Could not create trace directory {}", dir.display()))?;
+        }
+        let schema = Arc::new(Schema::new_with_metadata(struct_fields(like)?, metadata));
+        let file = File::create(path)
+            .with_context(|| format!("Could not create trace file {}", path.display()))?;
+        Ok((file, schema))
+    }
+
+    pub(crate) fn write(&mut self, array: &ArrayRef) -> Result<()> {
+        let batch = RecordBatch::from(
+            array
+                .as_struct_opt()
+                .context("Trace values must be a struct array")?,
+        );
+        match &mut self.writer {
+            Writer::Stream(writer) => {
+                writer.write(&batch)?;
+                // Make the batch visible to readers of the unfinished stream.
+                writer.flush()?;
+            }
+            Writer::Ipc(writer) => writer.write(&batch)?,
+            Writer::Parquet(writer) => writer.write(&batch)?,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> Result<()> {
+        match self.writer {
+            Writer::Stream(mut writer) => writer.finish()?,
+            Writer::Ipc(mut writer) => writer.finish()?,
+            Writer::Parquet(writer) => {
+                writer.close()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn struct_fields(array: &ArrayRef) -> Result<Fields> {
+    Ok(array
+        .as_struct_opt()
+        .context("Trace values must be a struct array")?
+        .fields()
+        .clone())
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open trace file {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+/// Read the record batches of a possibly unfinished IPC stream.
+///
+/// The IPC stream format allows a stream to end without its end marker, so
+/// the reader stops at the end of the batches that were written so far.
+fn read_staged(path: &Path) -> Result<StreamReader<BufReader<File>>> {
+    Ok(StreamReader::try_new(open(path)?, None)?)
+}
+
+fn concat_batches(batches: Vec<RecordBatch>) -> Result<Option<ArrayRef>> {
+    let arrays: Vec<StructArray> = batches.into_iter().map(StructArray::from).collect();
+    if arrays.is_empty() {
+        return Ok(None);
+    }
+    let arrays: Vec<&dyn Array> = arrays.iter().map(|array| array as &dyn Array).collect();
+    Ok(Some(concat(&arrays)?))
+}
+
+fn read_parquet(path: &Path) -> Result<Vec<RecordBatch>> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open trace file {}", path.display()))?;
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file)?.build()?;
+    Ok(reader.collect::<Result<_, _>>()?)
+}
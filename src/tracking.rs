@@ -0,0 +1,528 @@
+//! Bookkeeping about running chains that nuts-rs does not expose.
+//!
+//! Every model we sample is wrapped in a `TrackedModel`. Its draw storage
+//! records the latest unconstrained position of each chain in a shared
+//! `ChainRegistry`, so that we can checkpoint and continue chains later on.
+//! It can also start chains at given positions instead of asking the
+//! model for an initial point, find initial points with a finite logp as
+//! configured in `InitOptions`, and compare the gradient at the initial
+//! point of each chain with finite differences.
+//!
+//! The draws of a chain are stored in segments, so that we can hand out the
+//! draws since the last fetch without copying the whole trace. A new segment
+//! starts whenever the trace is inspected while the registry is in streaming
+//! mode, if the trace is stored on disk, whenever the current segment is
+//! full, and with a stopping rule, every `check_every` posterior draws.
+//! Finished segments are then written to the trace file of the chain and
+//! handed to the `StopFeed` of the stopping rule.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Context, Result};
+use arrow::{
+    array::{Array, ArrayRef, AsArray},
+    compute::concat,
+};
+use nuts_rs::{DrawStorage, Model, Settings, Trace};
+use pyo3::{Py, PyAny};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+use crate::{
+    gradient::{check_model_gradient, DEFAULT_EPS},
+    init::{InitOptions, InitRecord, ModelInit},
+    metric::ChainMetric,
+    reporting::INV_MASS_STATS,
+    stopping::StopFeed,
+    storage::{ChainFile, StorageOptions},
+};
+
+thread_local! {
+    // nuts-rs creates the trace of a chain and asks for its initial position
+    // on the same worker thread, but does not tell `init_position` which
+    // chain the position is for. Only `TrackedModel` reads this, and passes
+    // the chain on explicitly.
+    static CURRENT_CHAIN: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// What we know about a chain besides its trace.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ChainRecord {
+    /// Number of draws that were stored in the trace.
+    pub(crate) num_draws: u64,
+    /// Unconstrained position of the latest draw.
+    pub(crate) position: Option<Box<[f64]>>,
+    /// Diagonal of the inverse mass matrix, if the sampler told us about it.
+    pub(crate) inv_mass: Option<Box<[f64]>>,
+    /// The whole metric of chains that run on a `MetricModel`, including
+    /// a low-rank correction or dense factor.
+    pub(crate) metric: Option<ChainMetric>,
+    /// The `mass_matrix_inv` sampler stats of all draws so far, for chains
+    /// that nuts-rs adapts a diagonal mass matrix for. We ask nuts-rs to
+    /// store them even if the user does not want them in the trace.
+    pub(crate) stats_inv_mass: Option<ArrayRef>,
+    /// How the chain found its initial point, unless it was given.
+    pub(crate) init: Option<InitRecord>,
+    /// The Python adapter of chains with transform adaptation, which
+    /// nuts-rs updates in place.
+    pub(crate) transform: Option<Arc<Py<PyAny>>>,
+}
+
+/// What inspecting the trace of a chain returns.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum InspectMode {
+    /// All draws so far.
+    #[default]
+    Full,
+    /// Only the draws since the previous inspection.
+    Streaming,
+    /// No draws, if we only need the sampler stats.
+    StatsOnly,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ChainRegistry {
+    chains: Arc<Mutex<BTreeMap<u64, ChainRecord>>>,
+    inspect_mode: Arc<Mutex<InspectMode>>,
+    /// Held for the whole duration of an inspection.
+    inspections: Arc<Mutex<()>>,
+}
+
+impl ChainRegistry {
+    pub(crate) fn snapshot(&self) -> BTreeMap<u64, ChainRecord> {
+        self.chains.lock().expect("Poisoned chain registry").clone()
+    }
+
+    /// Record the metric of a chain, and the diagonal of its inverse mass
+    /// matrix.
+    pub(crate) fn set_metric(&self, chain: u64, inv_mass: Box<[f64]>, metric: ChainMetric) {
+        let mut chains = self.chains.lock().expect("Poisoned chain registry");
+        let record = chains.entry(chain).or_default();
+        record.inv_mass = Some(inv_mass);
+        record.metric = Some(metric);
+    }
+
+    /// Record the Python adapter of the transformation of a chain.
+    pub(crate) fn set_transform(&self, chain: u64, transform: Arc<Py<PyAny>>) {
+        let mut chains = self.chains.lock().expect("Poisoned chain registry");
+        chains.entry(chain).or_default().transform = Some(transform);
+    }
+
+    /// Record the `mass_matrix_inv` sampler stats of the chains of a
+    /// trace, before `Reporting` removes them. The stats must not be
+    /// sliced yet.
+    pub(crate) fn record_stats(&self, trace: &Trace) {
+        let mut chains = self.chains.lock().expect("Poisoned chain registry");
+        for chain in trace.chains.iter() {
+            let column = chain
+                .stats
+                .as_struct_opt()
+                .and_then(|stats| stats.column_by_name(INV_MASS_STATS));
+            if let Some(column) = column {
+                chains.entry(chain.chain_id).or_default().stats_inv_mass = Some(column.clone());
+            }
+        }
+    }
+
+    /// The `mass_matrix_inv` sampler stats of a chain, see `record_stats`.
+    pub(crate) fn stats_inv_mass(&self, chain: u64) -> Option<ArrayRef> {
+        let chains = self.chains.lock().expect("Poisoned chain registry");
+        chains.get(&chain)?.stats_inv_mass.clone()
+    }
+
+    fn record_init(&self, chain: u64, init: InitRecord) {
+        let mut chains = self.chains.lock().expect("Poisoned chain registry");
+        // nuts-rs asks for another initial point if the sampler rejects one.
+        chains
+            .entry(chain)
+            .or_default()
+            .init
+            .get_or_insert_with(Default::default)
+            .merge(init);
+    }
+
+    /// Inspect the trace of a sampler with the draw storages of this
+    /// registry in `mode`.
+    ///
+    /// nuts-rs inspects the draw storages on its controller thread and
+    /// has no way to pass arguments along, so the mode is stored here
+    /// while `inspect` runs. Inspections wait for each other, so that one
+    /// call never sees the mode of another.
+    pub(crate) fn inspect_with<T>(&self, mode: InspectMode, inspect: impl FnOnce() -> T) -> T {
+        let _inspection = self.inspections.lock().expect("Poisoned chain registry");
+        *self.inspect_mode.lock().expect("Poisoned chain registry") = mode;
+        let result = inspect();
+        *self.inspect_mode.lock().expect("Poisoned chain registry") = InspectMode::Full;
+        result
+    }
+
+    fn inspect_mode(&self) -> InspectMode {
+        *self.inspect_mode.lock().expect("Poisoned chain registry")
+    }
+
+    fn record_draw(&self, chain: u64, point: &[f64]) {
+        let mut chains = self.chains.lock().expect("Poisoned chain registry");
+        let record = chains.entry(chain).or_default();
+        record.num_draws += 1;
+        match record.position {
+            Some(ref mut position) if position.len() == point.len() => {
+                position.copy_from_slice(point)
+            }
+            _ => record.position = Some(point.into()),
+        }
+    }
+}
+
+/// Everything a `TrackedModel` needs besides the model.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TrackingOptions {
+    pub(crate) registry: ChainRegistry,
+    pub(crate) init_points: BTreeMap<u64, Box<[f64]>>,
+    pub(crate) storage: Option<StorageOptions>,
+    /// Largest relative error of the gradient at the initial points.
+    pub(crate) gradient_tolerance: Option<f64>,
+    pub(crate) init: InitOptions,
+    pub(crate) stop: Option<Arc<Mutex<StopFeed>>>,
+}
+
+impl TrackingOptions {
+    pub(crate) fn track<M: ModelInit>(self, model: M) -> TrackedModel<M> {
+        TrackedModel::new(model, self.registry)
+            .with_init_points(self.init_points)
+            .with_storage(self.storage)
+            .with_gradient_check(self.gradient_tolerance)
+            .with_init(self.init)
+            .with_stop_feed(self.stop)
+    }
+}
+
+pub(crate) struct TrackedModel<M> {
+    model: M,
+    registry: ChainRegistry,
+    init_points: Arc<BTreeMap<u64, Box<[f64]>>>,
+    storage: Option<Arc<StorageOptions>>,
+    gradient_tolerance: Option<f64>,
+    init: InitOptions,
+    stop: Option<Arc<Mutex<StopFeed>>>,
+}
+
+impl<M: Model> TrackedModel<M> {
+    pub(crate) fn new(model: M, registry: ChainRegistry) -> Self {
+        Self {
+            model,
+            registry,
+            init_points: Default::default(),
+            storage: None,
+            gradient_tolerance: None,
+            init: Default::default(),
+            stop: None,
+        }
+    }
+
+    /// Write the draws of each chain to disk instead of keeping them in memory.
+    pub(crate) fn with_storage(mut self, storage: Option<StorageOptions>) -> Self {
+        self.storage = storage.map(Arc::new);
+        self
+    }
+
+    /// Start the given chains at fixed unconstrained positions.
+    pub(crate) fn with_init_points(mut self, init_points: BTreeMap<u64, Box<[f64]>>) -> Self {
+        self.init_points = Arc::new(init_points);
+        self
+    }
+
+    /// Check the gradient at the initial point of each chain.
+    pub(crate) fn with_gradient_check(mut self, tolerance: Option<f64>) -> Self {
+        self.gradient_tolerance = tolerance;
+        self
+    }
+
+    /// How to find initial points for chains without a fixed position.
+    pub(crate) fn with_init(mut self, init: InitOptions) -> Self {
+        self.init = init;
+        self
+    }
+
+    /// Hand the draws of each chain to the feed of a stopping rule.
+    pub(crate) fn with_stop_feed(mut self, stop: Option<Arc<Mutex<StopFeed>>>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    fn check_init_gradient(&self, position: &[f64], tolerance: f64) -> Result<()> {
+        // If the logp fails at this point, nuts-rs tries another one, and
+        // we check the gradient there.
+        let Ok(check) = check_model_gradient(&self.model, position, DEFAULT_EPS) else {
+            return Ok(());
+        };
+        check
+            .ensure(tolerance)
+            .with_context(|| match CURRENT_CHAIN.get() {
+                Some(chain) => {
+                    format!("Gradient check at the initial point of chain {chain} failed")
+                }
+                None => "Gradient check at the initial point failed".to_string(),
+            })
+    }
+}
+
+impl<M: ModelInit> Model for TrackedModel<M> {
+    type Math<'model> = M::Math<'model>;
+
+    type DrawStorage<'model, S: Settings> = TrackedTrace<'model, M, S>;
+
+    fn new_trace<'model, S: Settings, R: rand::Rng + ?Sized>(
+        &'model self,
+        rng: &mut R,
+        chain_id: u64,
+        settings: &'model S,
+    ) -> Result<Self::DrawStorage<'model, S>> {
+        CURRENT_CHAIN.set(Some(chain_id));
+        let current = self.model.new_trace(rng, chain_id, settings)?;
+        let empty = current.inspect()?;
+        Ok(TrackedTrace {
+            segments: RefCell::new(Segments {
+                current,
+                empty,
+                current_len: 0,
+                start: 0,
+                finished: Vec::new(),
+                file: None,
+                rng: segment_rng(settings.seed(), chain_id),
+            }),
+            model: &self.model,
+            settings,
+            storage: self.storage.clone(),
+            chain: chain_id,
+            num_tune: settings.hint_num_tune() as u64,
+            registry: self.registry.clone(),
+            stop: self.stop.clone(),
+        })
+    }
+
+    fn math(&self) -> Result<Self::Math<'_>> {
+        self.model.math()
+    }
+
+    fn init_position<R: rand::Rng + ?Sized>(
+        &self,
+        rng: &mut R,
+        position: &mut [f64],
+    ) -> Result<()> {
+        let chain = CURRENT_CHAIN
+            .get()
+            .context("The initial point was requested before the trace of its chain")?;
+        match self.init_points.get(&chain) {
+            None => {
+                let record = self
+                    .init
+                    .initialize(&self.model, chain, rng, position)
+                    .with_context(|| format!("Could not initialize chain {chain}"))?;
+                self.registry.record_init(chain, record);
+            }
+            Some(init_point) => {
+                if init_point.len() != position.len() {
+                    bail!(
+                        "Initial point has length {}, but the model has {} parameters",
+                        init_point.len(),
+                        position.len()
+                    );
+                }
+                position.copy_from_slice(init_point);
+            }
+        }
+        if let Some(tolerance) = self.gradient_tolerance {
+            self.check_init_gradient(position, tolerance)?;
+        }
+        Ok(())
+    }
+}
+
+/// Random number generator for the traces of later segments of a chain.
+///
+/// The first segment uses the generator of the chain, like an untracked
+/// model would. Later ones must not draw from it, or seeded runs would give
+/// different draws depending on how often the trace is inspected.
+fn segment_rng(seed: u64, chain: u64) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed ^ SEGMENT_SEED_KEY);
+    rng.set_stream(chain);
+    rng
+}
+
+/// Distinguishes the segment generators from the chain generators of
+/// nuts-rs, which use the plain seed.
+const SEGMENT_SEED_KEY: u64 = 0x5e9_3e47;
+
+/// Hand the rows `start..start + draws.len()` of the trace of a chain to
+/// the feed of the stopping rule.
+fn feed(
+    stop: Option<&Mutex<StopFeed>>,
+    chain: u64,
+    start: usize,
+    draws: &Arc<dyn Array>,
+) -> Result<()> {
+    match stop {
+        Some(stop) => stop
+            .lock()
+            .expect("Poisoned stopping rule")
+            .feed(chain, draws, start),
+        None => Ok(()),
+    }
+}
+
+struct Segments<D> {
+    current: D,
+    /// An array without rows of the type of the draws.
+    empty: Arc<dyn Array>,
+    current_len: usize,
+    /// The row of the trace where the current segment starts.
+    start: usize,
+    /// Finished segments, unless they are written to `file`.
+    finished: Vec<Arc<dyn Array>>,
+    file: Option<ChainFile>,
+    rng: ChaCha8Rng,
+}
+
+pub(crate) struct TrackedTrace<'model, M: Model, S: Settings> {
+    // nuts-rs only gives us shared access when it inspects the trace, but
+    // that is when we start a new segment.
+    segments: RefCell<Segments<M::DrawStorage<'model, S>>>,
+    model: &'model M,
+    settings: &'model S,
+    storage: Option<Arc<StorageOptions>>,
+    chain: u64,
+    num_tune: u64,
+    registry: ChainRegistry,
+    stop: Option<Arc<Mutex<StopFeed>>>,
+}
+
+impl<'model, M: Model, S: Settings> TrackedTrace<'model, M, S> {
+    /// Finish the current segment and return it.
+    fn next_segment(
+        &self,
+        segments: &mut Segments<M::DrawStorage<'model, S>>,
+    ) -> Result<Arc<dyn Array>> {
+        if segments.current_len == 0 {
+            return segments.current.inspect();
+        }
+        let next = self
+            .model
+            .new_trace(&mut segments.rng, self.chain, self.settings)?;
+        let segment = std::mem::replace(&mut segments.current, next).finalize()?;
+        segments.current_len = 0;
+        self.store_segment(segments, &segment)?;
+        feed(self.stop.as_deref(), self.chain, segments.start, &segment)?;
+        segments.start += segment.len();
+        Ok(segment)
+    }
+
+    /// Whether the current segment should end after the draw we just added.
+    fn segment_full(&self, segments: &Segments<M::DrawStorage<'model, S>>) -> bool {
+        if let Some(storage) = &self.storage {
+            if segments.current_len >= storage.buffer_draws {
+                return true;
+            }
+        }
+        let Some(stop) = &self.stop else {
+            return false;
+        };
+        let check_every = stop.lock().expect("Poisoned stopping rule").check_every();
+        let num_tune = self.num_tune as usize;
+        let rows = segments.start + segments.current_len;
+        rows > num_tune && (rows - num_tune) % check_every == 0
+    }
+
+    fn store_segment(
+        &self,
+        segments: &mut Segments<M::DrawStorage<'model, S>>,
+        segment: &Arc<dyn Array>,
+    ) -> Result<()> {
+        let Some(storage) = &self.storage else {
+            segments.finished.push(segment.clone());
+            return Ok(());
+        };
+        let file = match &mut segments.file {
+            Some(file) => file,
+            None => segments.file.insert(ChainFile::staging(
+                &storage.staging_path(self.chain),
+                segment,
+            )?),
+        };
+        file.write(segment)
+    }
+}
+
+impl<M: Model, S: Settings> DrawStorage for TrackedTrace<'_, M, S> {
+    fn append_value(&mut self, point: &[f64]) -> Result<()> {
+        let mut segments = self.segments.borrow_mut();
+        segments.current.append_value(point)?;
+        segments.current_len += 1;
+        if self.segment_full(&segments) {
+            self.next_segment(&mut segments)?;
+        }
+        drop(segments);
+        self.registry.record_draw(self.chain, point);
+        Ok(())
+    }
+
+    fn finalize(self) -> Result<Arc<dyn Array>> {
+        let Segments {
+            current,
+            start,
+            mut finished,
+            file,
+            ..
+        } = self.segments.into_inner();
+        let last = current.finalize()?;
+        feed(self.stop.as_deref(), self.chain, start, &last)?;
+        if let Some(storage) = &self.storage {
+            // The trace of the chain is in its file, we only return
+            // an empty array with the right type.
+            let mut file = match file {
+                Some(file) => file,
+                None => ChainFile::staging(&storage.staging_path(self.chain), &last)?,
+            };
+            if !last.is_empty() {
+                file.write(&last)?;
+            }
+            file.finish()?;
+            storage.finish_chain(self.chain)?;
+            return Ok(last.slice(0, 0));
+        }
+        if finished.is_empty() {
+            return Ok(last);
+        }
+        finished.push(last);
+        let arrays: Vec<&dyn Array> = finished.iter().map(|array| array.as_ref()).collect();
+        Ok(concat(&arrays)?)
+    }
+
+    fn inspect(&self) -> Result<Arc<dyn Array>> {
+        let mut segments = self.segments.borrow_mut();
+        match self.registry.inspect_mode() {
+            InspectMode::Full => {}
+            InspectMode::Streaming => return self.next_segment(&mut segments),
+            InspectMode::StatsOnly => return Ok(segments.empty.clone()),
+        }
+        let current = segments.current.inspect()?;
+        let stored = match (&self.storage, &segments.file) {
+            (Some(storage), Some(_)) => {
+                storage.read_staged_draws(self.chain)?.into_iter().collect()
+            }
+            _ => segments.finished.clone(),
+        };
+        if stored.is_empty() {
+            return Ok(current);
+        }
+        let arrays: Vec<&dyn Array> = stored
+            .iter()
+            .map(|array| array.as_ref())
+            .chain([current.as_ref()])
+            .collect();
+        Ok(concat(&arrays)?)
+    }
+}
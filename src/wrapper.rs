@@ -1,32 +1,71 @@
 use std::{
+    collections::{BTreeMap, HashMap},
     fmt::Debug,
     ops::{Deref, DerefMut},
-    sync::{Arc, Mutex},
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard, Weak},
     time::{Duration, Instant},
 };
 
 use crate::{
-    progress::{IndicatifHandler, ProgressHandler},
+    checkpoint::{
+        AdaptationState, ChainState, Checkpoint, Continuation, MetricSource, ResumePlan, RunShape,
+    },
+    diagnostics::diagnostics,
+    gradient::{GradientCheck, DEFAULT_TOLERANCE},
+    init::{InitOptions, InitStrategy, ModelInit},
+    laplace::LaplaceResult,
+    metric::{
+        default_dense_settings, step_scales, DenseAdaptation, DenseNutsSettings, LowRankAdaptation,
+        MassMatrix, MetricModel, MetricOptions,
+    },
+    monitor::Monitor,
+    monitor::SharedSampler,
+    optimize::MapResult,
+    pathfinder::{
+        pathfinder, warm_start, PathfinderPath, PathfinderResult, PathfinderSettings,
+        WarmStartMetric,
+    },
+    progress::{
+        ChainProgressRecord, IndicatifHandler, JsonLinesHandler, MultiIndicatifHandler,
+        ProgressHandler, SamplerProgress, StructuredHandler,
+    },
+    provenance::{Backend, Provenance, Timings, METADATA_KEY},
     pyfunc::{ExpandDtype, PyModel, PyVariable, TensorShape},
     pymc::{ExpandFunc, LogpFunc, PyMcModel},
+    reporting::{Reporting, INV_MASS_STATS},
+    settings::{
+        from_py, get_path, option_path, set_path, to_py, variant_index, DenseNutsSettingsDef,
+        DiagGradNutsSettingsDef, LowRankNutsSettingsDef, TransformedNutsSettingsDef, VARIANTS,
+    },
     stan::{StanLibrary, StanModel},
+    stopping::{StopFeed, Stopping, StoppingRule},
+    storage::{FileFormat, StorageOptions, TraceStorage},
+    tracking::{ChainRegistry, InspectMode, TrackingOptions},
 };
 
 use anyhow::{bail, Context, Result};
-use arrow::array::Array;
+use arrow::{
+    array::Array,
+    datatypes::Field,
+    ffi::{FFI_ArrowArray, FFI_ArrowSchema},
+};
 use numpy::{PyArray1, PyReadonlyArray1};
 use nuts_rs::{
-    ChainProgress, DiagGradNutsSettings, LowRankNutsSettings, ProgressCallback, Sampler,
-    SamplerWaitResult, Trace, TransformedNutsSettings,
+    ChainOutput, ChainProgress, DiagGradNutsSettings, LowRankNutsSettings, NutsSettings,
+    ProgressCallback, Sampler, SamplerWaitResult, Trace, TransformedNutsSettings,
+    TransformedSettings,
 };
 use pyo3::{
-    exceptions::PyTimeoutError,
+    exceptions::{PyAttributeError, PyTimeoutError},
     ffi::Py_uintptr_t,
     intern,
     prelude::*,
-    types::{PyList, PyTuple},
+    types::{PyBool, PyDict, PyList, PyString, PyTuple},
+    IntoPyObjectExt,
 };
 use rand::{rng, RngCore};
+use serde::Serialize;
 
 #[pyclass]
 struct PyChainProgress(ChainProgress);
@@ -73,6 +112,15 @@ impl PyChainProgress {
 #[derive(Clone)]
 pub struct PyNutsSettings {
     inner: Settings,
+    adaptation_state: Option<AdaptationState>,
+    trace_storage: TraceStorage,
+    stopping_rule: Option<StoppingRule>,
+    check_gradient: Option<f64>,
+    init: InitOptions,
+    pathfinder: Option<PathfinderSettings>,
+    mass_matrix: Option<MassMatrix>,
+    step_size: Option<f64>,
+    adapt: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -80,9 +128,25 @@ enum Settings {
     Diag(DiagGradNutsSettings),
     LowRank(LowRankNutsSettings),
     Transforming(TransformedNutsSettings),
+    Dense(DenseNutsSettings),
 }
 
 impl PyNutsSettings {
+    fn new(inner: Settings) -> Self {
+        Self {
+            inner,
+            adaptation_state: None,
+            trace_storage: TraceStorage::default(),
+            stopping_rule: None,
+            check_gradient: None,
+            init: Default::default(),
+            pathfinder: None,
+            mass_matrix: None,
+            step_size: None,
+            adapt: true,
+        }
+    }
+
     fn new_diag(seed: Option<u64>) -> Self {
         let seed = seed.unwrap_or_else(|| {
             let mut rng = rng();
@@ -93,9 +157,7 @@ impl PyNutsSettings {
             ..Default::default()
         };
 
-        Self {
-            inner: Settings::Diag(settings),
-        }
+        Self::new(Settings::Diag(settings))
     }
 
     fn new_low_rank(seed: Option<u64>) -> Self {
@@ -108,9 +170,7 @@ impl PyNutsSettings {
             ..Default::default()
         };
 
-        Self {
-            inner: Settings::LowRank(settings),
-        }
+        Self::new(Settings::LowRank(settings))
     }
 
     fn new_tranform_adapt(seed: Option<u64>) -> Self {
@@ -123,13 +183,280 @@ impl PyNutsSettings {
             ..Default::default()
         };
 
-        Self {
-            inner: Settings::Transforming(settings),
+        Self::new(Settings::Transforming(settings))
+    }
+
+    fn new_dense(seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            let mut rng = rng();
+            rng.next_u64()
+        });
+        let settings = DenseNutsSettings {
+            seed,
+            ..default_dense_settings()
+        };
+
+        Self::new(Settings::Dense(settings))
+    }
+
+    /// All settings as JSON, for the provenance of a trace.
+    fn to_record(&self) -> Result<serde_json::Value> {
+        #[derive(Serialize)]
+        struct Options<'a> {
+            adaptation_state: &'a Option<AdaptationState>,
+            trace_storage: &'a TraceStorage,
+            stopping_rule: &'a Option<StoppingRule>,
+            check_gradient: Option<f64>,
+            init: &'a InitOptions,
+            pathfinder: &'a Option<PathfinderSettings>,
+            mass_matrix: &'a Option<MassMatrix>,
+            step_size: Option<f64>,
+            adapt: bool,
+        }
+
+        let mut record = self.inner.to_json_value()?;
+        let serde_json::Value::Object(fields) = &mut record else {
+            bail!("Settings must serialize to a JSON object");
+        };
+        let options = serde_json::to_value(Options {
+            adaptation_state: &self.adaptation_state,
+            trace_storage: &self.trace_storage,
+            stopping_rule: &self.stopping_rule,
+            check_gradient: self.check_gradient,
+            init: &self.init,
+            pathfinder: &self.pathfinder,
+            mass_matrix: &self.mass_matrix,
+            step_size: self.step_size,
+            adapt: self.adapt,
+        })?;
+        let serde_json::Value::Object(options) = options else {
+            bail!("Settings must serialize to a JSON object");
+        };
+        for (name, value) in options {
+            if fields.insert(name.clone(), value).is_some() {
+                bail!("The setting `{}` would be recorded twice", name);
+            }
+        }
+        Ok(record)
+    }
+}
+
+impl Serialize for Settings {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Settings::Diag(settings) => {
+                DiagGradNutsSettingsDef::from(*settings).serialize(serializer)
+            }
+            Settings::LowRank(settings) => {
+                LowRankNutsSettingsDef::from(*settings).serialize(serializer)
+            }
+            Settings::Transforming(settings) => {
+                TransformedNutsSettingsDef::from(*settings).serialize(serializer)
+            }
+            Settings::Dense(settings) => {
+                DenseNutsSettingsDef::from(*settings).serialize(serializer)
+            }
+        }
+    }
+}
+
+impl Settings {
+    /// All settings as JSON, including the name of the variant.
+    fn to_json_value(&self) -> Result<serde_json::Value> {
+        let mut value = serde_json::to_value(self)?;
+        if let serde_json::Value::Object(map) = &mut value {
+            map.insert("variant".to_string(), VARIANTS[self.variant()].into());
+        }
+        Ok(value)
+    }
+
+    /// The index of the variant in `VARIANTS`.
+    fn variant(&self) -> usize {
+        match self {
+            Settings::Diag(_) => 0,
+            Settings::LowRank(_) => 1,
+            Settings::Transforming(_) => 2,
+            Settings::Dense(_) => 3,
+        }
+    }
+
+    fn from_py(variant: usize, value: &Bound<PyAny>) -> Result<Self> {
+        let name = VARIANTS[variant];
+        Ok(match variant {
+            0 => Settings::Diag(from_py::<DiagGradNutsSettingsDef>(value, name)?.into()),
+            1 => Settings::LowRank(from_py::<LowRankNutsSettingsDef>(value, name)?.into()),
+            2 => Settings::Transforming(from_py::<TransformedNutsSettingsDef>(value, name)?.into()),
+            _ => Settings::Dense(from_py::<DenseNutsSettingsDef>(value, name)?.into()),
+        })
+    }
+
+    fn set_seed(&mut self, seed: u64) {
+        match self {
+            Settings::Diag(settings) => settings.seed = seed,
+            Settings::LowRank(settings) => settings.seed = seed,
+            Settings::Transforming(settings) => settings.seed = seed,
+            Settings::Dense(settings) => settings.seed = seed,
+        }
+    }
+
+    fn run_shape(&self) -> RunShape {
+        let (variant, num_tune, num_draws, num_chains, seed) = match self {
+            Settings::Diag(settings) => (
+                "diag",
+                settings.num_tune,
+                settings.num_draws,
+                settings.num_chains,
+                settings.seed,
+            ),
+            Settings::LowRank(settings) => (
+                "low_rank",
+                settings.num_tune,
+                settings.num_draws,
+                settings.num_chains,
+                settings.seed,
+            ),
+            Settings::Transforming(settings) => (
+                "transform",
+                settings.num_tune,
+                settings.num_draws,
+                settings.num_chains,
+                settings.seed,
+            ),
+            Settings::Dense(settings) => (
+                "dense",
+                settings.num_tune,
+                settings.num_draws,
+                settings.num_chains,
+                settings.seed,
+            ),
+        };
+        RunShape {
+            variant: variant.to_string(),
+            num_tune,
+            num_draws,
+            num_chains,
+            seed,
+        }
+    }
+
+    /// Settings for a sampler on a space transformed by a `MetricModel`,
+    /// with all other options taken from `self`.
+    fn metric_settings(&self, num_tune: u64, num_draws: u64) -> TransformedNutsSettings {
+        fn convert<A: Debug + Copy + Default>(
+            settings: &NutsSettings<A>,
+            adapt_options: TransformedSettings,
+        ) -> TransformedNutsSettings {
+            TransformedNutsSettings {
+                num_tune: settings.num_tune,
+                num_draws: settings.num_draws,
+                maxdepth: settings.maxdepth,
+                store_gradient: settings.store_gradient,
+                store_unconstrained: settings.store_unconstrained,
+                max_energy_error: settings.max_energy_error,
+                store_divergences: settings.store_divergences,
+                adapt_options,
+                check_turning: settings.check_turning,
+                num_chains: settings.num_chains,
+                seed: settings.seed,
+            }
+        }
+
+        let settings = match self {
+            Settings::Diag(settings) => convert(
+                settings,
+                TransformedSettings {
+                    dual_average_options: settings.adapt_options.dual_average_options,
+                    ..Default::default()
+                },
+            ),
+            // The `MetricModel` updates the low-rank metric as often as
+            // nuts-rs would switch to a new estimate.
+            Settings::LowRank(settings) => convert(
+                settings,
+                TransformedSettings {
+                    dual_average_options: settings.adapt_options.dual_average_options,
+                    step_size_window: settings.adapt_options.step_size_window,
+                    transform_update_freq: settings.adapt_options.mass_matrix_switch_freq,
+                    ..Default::default()
+                },
+            ),
+            Settings::Transforming(settings) => convert(settings, settings.adapt_options),
+            // The metric is updated by the `MetricModel`, which decides
+            // itself when a window ends, so it needs to see every draw.
+            Settings::Dense(settings) => convert(
+                settings,
+                TransformedSettings {
+                    dual_average_options: settings.adapt_options.dual_average_options,
+                    step_size_window: settings.adapt_options.step_size_window,
+                    transform_update_freq: 1,
+                    use_orbit_for_training: false,
+                    transform_train_max_energy_error: f64::INFINITY,
+                },
+            ),
+        };
+        TransformedNutsSettings {
+            num_tune,
+            num_draws,
+            ..settings
+        }
+    }
+
+    fn set_num_draws(&mut self, num_draws: u64) {
+        match self {
+            Settings::Diag(settings) => settings.num_draws = num_draws,
+            Settings::LowRank(settings) => settings.num_draws = num_draws,
+            Settings::Transforming(settings) => settings.num_draws = num_draws,
+            Settings::Dense(settings) => settings.num_draws = num_draws,
+        }
+    }
+
+    /// The windows of the dense mass matrix adaptation for a warmup of
+    /// `num_tune` draws, if these are dense settings.
+    fn dense_adaptation(&self, num_tune: u64) -> Option<DenseAdaptation> {
+        match self {
+            Settings::Dense(settings) => {
+                Some(DenseAdaptation::new(num_tune, &settings.adapt_options))
+            }
+            _ => None,
+        }
+    }
+
+    /// The options of the low-rank mass matrix adaptation, if these are
+    /// low-rank settings.
+    fn low_rank_adaptation(&self) -> Option<LowRankAdaptation> {
+        match self {
+            Settings::LowRank(settings) => {
+                let options = settings.adapt_options.mass_matrix_options;
+                Some(LowRankAdaptation {
+                    gamma: options.gamma,
+                    eigval_cutoff: options.eigval_cutoff,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether nuts-rs stores the `mass_matrix_inv` sampler stats only
+    /// because we ask it to.
+    fn hides_inv_mass(&self) -> bool {
+        match self {
+            Settings::Diag(settings) => {
+                !settings.adapt_options.mass_matrix_options.store_mass_matrix
+            }
+            _ => false,
+        }
+    }
+
+    /// The metric that `warm_start` should take from Pathfinder.
+    fn warm_start_metric(&self) -> WarmStartMetric {
+        match self {
+            Settings::LowRank(_) => WarmStartMetric::LowRank,
+            Settings::Dense(_) => WarmStartMetric::Dense,
+            _ => WarmStartMetric::Diag,
         }
     }
 }
 
-// TODO switch to serde to expose all the options...
 #[pymethods]
 impl PyNutsSettings {
     #[staticmethod]
@@ -153,584 +480,1416 @@ impl PyNutsSettings {
         PyNutsSettings::new_tranform_adapt(seed)
     }
 
-    #[getter]
-    fn num_tune(&self) -> u64 {
-        match &self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.num_tune,
-            Settings::LowRank(nuts_settings) => nuts_settings.num_tune,
-            Settings::Transforming(nuts_settings) => nuts_settings.num_tune,
-        }
-    }
-
-    #[setter(num_tune)]
-    fn set_num_tune(&mut self, val: u64) {
-        match &mut self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.num_tune = val,
-            Settings::LowRank(nuts_settings) => nuts_settings.num_tune = val,
-            Settings::Transforming(nuts_settings) => nuts_settings.num_tune = val,
-        }
+    #[staticmethod]
+    #[allow(non_snake_case)]
+    #[pyo3(signature = (seed=None))]
+    fn Dense(seed: Option<u64>) -> Self {
+        PyNutsSettings::new_dense(seed)
     }
 
+    /// The kind of mass matrix adaptation: `diag`, `low_rank`, `transform`
+    /// or `dense`.
     #[getter]
-    fn num_chains(&self) -> usize {
-        match &self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.num_chains,
-            Settings::LowRank(nuts_settings) => nuts_settings.num_chains,
-            Settings::Transforming(nuts_settings) => nuts_settings.num_chains,
-        }
+    fn variant(&self) -> &'static str {
+        VARIANTS[self.inner.variant()]
     }
 
-    #[setter(num_chains)]
-    fn set_num_chains(&mut self, val: usize) {
-        match &mut self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.num_chains = val,
-            Settings::LowRank(nuts_settings) => nuts_settings.num_chains = val,
-            Settings::Transforming(nuts_settings) => nuts_settings.num_chains = val,
-        }
+    /// All settings as nested dicts, with the name of the variant.
+    fn to_dict<'py>(&self, py: Python<'py>) -> Result<Bound<'py, PyDict>> {
+        let dict = to_py(py, &self.inner)?;
+        dict.set_item("variant", self.variant())?;
+        Ok(dict)
     }
 
-    #[getter]
-    fn num_draws(&self) -> u64 {
-        match &self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.num_draws,
-            Settings::LowRank(nuts_settings) => nuts_settings.num_draws,
-            Settings::Transforming(nuts_settings) => nuts_settings.num_draws,
+    /// Settings from a dict as returned by `to_dict`.
+    ///
+    /// Missing options take their default values, and a missing seed is
+    /// chosen at random.
+    #[staticmethod]
+    fn from_dict(dict: &Bound<PyDict>) -> Result<Self> {
+        let dict = dict.copy()?;
+        let variant = match dict.get_item("variant")? {
+            Some(variant) => variant_index(&variant.extract::<String>()?)?,
+            None => bail!("Settings need a `variant`, one of {}", VARIANTS.join(", ")),
+        };
+        dict.del_item("variant")?;
+        let has_seed = dict.contains("seed")?;
+        let mut settings = PyNutsSettings::new(Settings::from_py(variant, &dict)?);
+        if !has_seed {
+            settings.inner.set_seed(rng().next_u64());
         }
+        Ok(settings)
     }
 
-    #[setter(num_draws)]
-    fn set_num_draws(&mut self, val: u64) {
-        match &mut self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.num_draws = val,
-            Settings::LowRank(nuts_settings) => nuts_settings.num_draws = val,
-            Settings::Transforming(nuts_settings) => nuts_settings.num_draws = val,
-        }
+    fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(&self.inner.to_json_value()?)?)
     }
 
-    #[getter]
-    fn window_switch_freq(&self) -> Result<u64> {
-        match &self.inner {
-            Settings::Diag(nuts_settings) => {
-                Ok(nuts_settings.adapt_options.mass_matrix_switch_freq)
+    #[staticmethod]
+    fn from_json(py: Python<'_>, json: &str) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+        let dict = pythonize::pythonize(py, &value)?
+            .downcast_into::<PyDict>()
+            .map_err(|_| anyhow::anyhow!("Settings JSON must be an object"))?;
+        Self::from_dict(&dict)
+    }
+
+    /// Options by name, either a top level option like `num_tune`, a
+    /// nested one like `adapt_options`, or one of the older short names
+    /// like `target_accept`.
+    fn __getattr__<'py>(&self, py: Python<'py>, name: &str) -> PyResult<Bound<'py, PyAny>> {
+        let variant = self.inner.variant();
+        let value = option_path(name, variant)
+            .and_then(|path| get_path(&to_py(py, &self.inner)?, &path, VARIANTS[variant]));
+        value.map_err(|err| PyAttributeError::new_err(err.to_string()))
+    }
+
+    /// Set an option. Dicts are merged into nested options.
+    fn __setattr__(&mut self, name: &str, value: Bound<PyAny>) -> Result<()> {
+        match name {
+            "adaptation_state" => {
+                let state: Option<PyAdaptationState> = value.extract()?;
+                self.adaptation_state = state.map(|state| state.0);
+            }
+            "trace_storage" => self.trace_storage = value.extract()?,
+            "stopping_rule" => self.stopping_rule = value.extract()?,
+            "check_gradient" => {
+                self.check_gradient = match value.downcast::<PyBool>() {
+                    Ok(check) => check.is_true().then_some(DEFAULT_TOLERANCE),
+                    Err(_) => value.extract()?,
+                }
             }
-            Settings::LowRank(nuts_settings) => {
-                Ok(nuts_settings.adapt_options.mass_matrix_switch_freq)
+            "init_strategy" => self.init.strategy = value.extract()?,
+            "init_retries" => self.init.retries = value.extract()?,
+            "pathfinder" => {
+                self.pathfinder = match value.downcast::<PyBool>() {
+                    Ok(enable) => enable.is_true().then(PathfinderSettings::default),
+                    Err(_) => value.extract()?,
+                }
+            }
+            "mass_matrix" => {
+                self.mass_matrix = if value.is_none() {
+                    None
+                } else {
+                    Some(MassMatrix::from_py(&value)?)
+                }
             }
-            Settings::Transforming(nuts_settings) => {
-                Ok(nuts_settings.adapt_options.transform_update_freq)
+            "step_size" => {
+                let step_size: Option<f64> = value.extract()?;
+                if let Some(step) = step_size {
+                    if !(step.is_finite() && step > 0.) {
+                        bail!("`step_size` must be a positive number");
+                    }
+                }
+                self.step_size = step_size;
+            }
+            "adapt" => self.adapt = value.extract()?,
+            _ => {
+                let variant = self.inner.variant();
+                let dict = to_py(value.py(), &self.inner)?;
+                set_path(
+                    &dict,
+                    &option_path(name, variant)?,
+                    &value,
+                    VARIANTS[variant],
+                )?;
+                self.inner = Settings::from_py(variant, &dict)?;
             }
         }
+        Ok(())
     }
 
-    #[setter(window_switch_freq)]
-    fn set_window_switch_freq(&mut self, val: u64) -> Result<()> {
-        match &mut self.inner {
-            Settings::Diag(nuts_settings) => {
-                nuts_settings.adapt_options.mass_matrix_switch_freq = val;
-                Ok(())
-            }
-            Settings::LowRank(nuts_settings) => {
-                nuts_settings.adapt_options.mass_matrix_switch_freq = val;
-                Ok(())
-            }
-            Settings::Transforming(nuts_settings) => {
-                nuts_settings.adapt_options.transform_update_freq = val;
-                Ok(())
-            }
-        }
+    /// Start the chains with the step size and mass matrix of a previous run.
+    ///
+    /// The chains still tune the step size and mass matrix for `num_tune`
+    /// draws, which can be much shorter than a warmup from scratch, or zero.
+    #[getter]
+    fn adaptation_state(&self) -> Option<PyAdaptationState> {
+        self.adaptation_state.clone().map(PyAdaptationState)
     }
 
+    /// Where the draws are stored while sampling, see `TraceStorage`.
     #[getter]
-    fn early_window_switch_freq(&self) -> Result<u64> {
-        match &self.inner {
-            Settings::Diag(nuts_settings) => {
-                Ok(nuts_settings.adapt_options.early_mass_matrix_switch_freq)
-            }
-            Settings::LowRank(nuts_settings) => {
-                Ok(nuts_settings.adapt_options.early_mass_matrix_switch_freq)
-            }
-            Settings::Transforming(_) => {
-                bail!("Option early_window_switch_freq not availbale for transformation adaptation")
-            }
-        }
+    fn trace_storage(&self) -> TraceStorage {
+        self.trace_storage.clone()
     }
 
-    #[setter(early_window_switch_freq)]
-    fn set_early_window_switch_freq(&mut self, val: u64) -> Result<()> {
-        match &mut self.inner {
-            Settings::Diag(nuts_settings) => {
-                nuts_settings.adapt_options.early_mass_matrix_switch_freq = val;
-                Ok(())
-            }
-            Settings::LowRank(nuts_settings) => {
-                nuts_settings.adapt_options.early_mass_matrix_switch_freq = val;
-                Ok(())
-            }
-            Settings::Transforming(_) => {
-                bail!("Option early_window_switch_freq not availbale for transformation adaptation")
-            }
-        }
+    /// Stop sampling early once the draws converged, see `StoppingRule`.
+    #[getter]
+    fn stopping_rule(&self) -> Option<StoppingRule> {
+        self.stopping_rule.clone()
     }
 
+    /// Largest relative error between the gradient and finite differences
+    /// at the initial point of each chain, or `None` to skip the check.
+    /// Setting it to `True` uses a tolerance of `1e-3`.
     #[getter]
-    fn initial_step(&self) -> f64 {
-        match &self.inner {
-            Settings::Diag(nuts_settings) => {
-                nuts_settings
-                    .adapt_options
-                    .dual_average_options
-                    .initial_step
-            }
-            Settings::LowRank(nuts_settings) => {
-                nuts_settings
-                    .adapt_options
-                    .dual_average_options
-                    .initial_step
-            }
-            Settings::Transforming(nuts_settings) => {
-                nuts_settings
-                    .adapt_options
-                    .dual_average_options
-                    .initial_step
-            }
-        }
+    fn check_gradient(&self) -> Option<f64> {
+        self.check_gradient
     }
 
-    #[setter(initial_step)]
-    fn set_initial_step(&mut self, val: f64) {
-        match &mut self.inner {
-            Settings::Diag(nuts_settings) => {
-                nuts_settings
-                    .adapt_options
-                    .dual_average_options
-                    .initial_step = val;
-            }
-            Settings::LowRank(nuts_settings) => {
-                nuts_settings
-                    .adapt_options
-                    .dual_average_options
-                    .initial_step = val;
-            }
-            Settings::Transforming(nuts_settings) => {
-                nuts_settings
-                    .adapt_options
-                    .dual_average_options
-                    .initial_step = val;
-            }
-        }
+    /// How the chains find their initial points, see `InitStrategy`.
+    #[getter]
+    fn init_strategy(&self) -> InitStrategy {
+        self.init.strategy.clone()
     }
 
+    /// How often a chain draws a new initial point if the logp or its
+    /// gradient is not finite at the previous one.
     #[getter]
-    fn maxdepth(&self) -> u64 {
-        match &self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.maxdepth,
-            Settings::LowRank(nuts_settings) => nuts_settings.maxdepth,
-            Settings::Transforming(nuts_settings) => nuts_settings.maxdepth,
-        }
+    fn init_retries(&self) -> usize {
+        self.init.retries
     }
 
-    #[setter(maxdepth)]
-    fn set_maxdepth(&mut self, val: u64) {
-        match &mut self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.maxdepth = val,
-            Settings::LowRank(nuts_settings) => nuts_settings.maxdepth = val,
-            Settings::Transforming(nuts_settings) => nuts_settings.maxdepth = val,
-        }
+    /// Run Pathfinder before warmup to choose the initial point and mass
+    /// matrix of each chain, or `None` to skip it. Setting it to `True`
+    /// uses the default `PathfinderSettings`.
+    #[getter]
+    fn pathfinder(&self) -> Option<PathfinderSettings> {
+        self.pathfinder.clone()
     }
 
+    /// The inverse mass matrix that all chains start with, or `None` to
+    /// start with an estimate from the gradient at the initial point. Can
+    /// be set to a `MassMatrix`, or to a numpy array with the diagonal or
+    /// the full inverse mass matrix.
     #[getter]
-    fn store_gradient(&self) -> bool {
-        match &self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.store_gradient,
-            Settings::LowRank(nuts_settings) => nuts_settings.store_gradient,
-            Settings::Transforming(nuts_settings) => nuts_settings.store_gradient,
-        }
+    fn mass_matrix(&self) -> Option<MassMatrix> {
+        self.mass_matrix.clone()
     }
 
-    #[setter(store_gradient)]
-    fn set_store_gradient(&mut self, val: bool) {
-        match &mut self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.store_gradient = val,
-            Settings::LowRank(nuts_settings) => nuts_settings.store_gradient = val,
-            Settings::Transforming(nuts_settings) => nuts_settings.store_gradient = val,
-        }
+    /// The step size that all chains start with, instead of searching for
+    /// one at the initial point.
+    #[getter]
+    fn step_size(&self) -> Option<f64> {
+        self.step_size
     }
 
+    /// Adapt the step size and mass matrix during warmup. Without
+    /// adaptation the chains keep their initial step size and mass matrix,
+    /// and the `num_tune` warmup draws only serve as burn-in.
     #[getter]
-    fn store_unconstrained(&self) -> bool {
-        match &self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.store_unconstrained,
-            Settings::LowRank(nuts_settings) => nuts_settings.store_unconstrained,
-            Settings::Transforming(nuts_settings) => nuts_settings.store_unconstrained,
-        }
+    fn adapt(&self) -> bool {
+        self.adapt
     }
+}
+
+/// Step size and mass matrix of each chain at the end of a run.
+///
+/// The state of a running or finished sampler comes from
+/// `PySampler.adaptation_state`, the one of a finished trace from
+/// `from_trace`. States of runs with transform adaptation cannot be used
+/// for a warm start.
+#[pyclass]
+#[derive(Clone)]
+pub struct PyAdaptationState(AdaptationState);
+
+#[pymethods]
+impl PyAdaptationState {
+    /// Read the adaptation state from a checkpoint written by
+    /// `PySampler.checkpoint`.
+    #[staticmethod]
+    fn from_checkpoint(path: PathBuf) -> Result<Self> {
+        let checkpoint = Checkpoint::read(&path)?;
+        Ok(PyAdaptationState(AdaptationState::new(&checkpoint)))
+    }
+
+    /// Read the adaptation state from the `sample_stats` of a finished
+    /// trace.
+    ///
+    /// Traces of this version of nutpie record the state in the `nutpie`
+    /// attribute. For other traces we fall back to the last step size and
+    /// `mass_matrix_inv` of each chain, which needs `store_mass_matrix` and
+    /// gives a diagonal mass matrix.
+    #[staticmethod]
+    fn from_trace(trace: &Bound<PyAny>) -> Result<Self> {
+        let py = trace.py();
+        let stats = trace
+            .getattr("sample_stats")
+            .context("The trace has no sample stats")?;
+        let provenance = stats
+            .getattr("attrs")?
+            .call_method1("get", (METADATA_KEY,))?;
+        if !provenance.is_none() {
+            let provenance: String = if provenance.is_instance_of::<PyString>() {
+                provenance.extract()?
+            } else {
+                py.import("json")?
+                    .call_method1("dumps", (provenance,))?
+                    .extract()?
+            };
+            let mut provenance: serde_json::Value =
+                serde_json::from_str(&provenance).context("Invalid nutpie attribute")?;
+            if let Some(state) = provenance.get_mut("adaptation_state") {
+                return Ok(PyAdaptationState(AdaptationState::from_value(
+                    state.take(),
+                )?));
+            }
+        }
 
-    #[setter(store_unconstrained)]
-    fn set_store_unconstrained(&mut self, val: bool) {
-        match &mut self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.store_unconstrained = val,
-            Settings::LowRank(nuts_settings) => nuts_settings.store_unconstrained = val,
-            Settings::Transforming(nuts_settings) => nuts_settings.store_unconstrained = val,
+        let contains = |name: &str| stats.contains(name).unwrap_or(false);
+        if !contains("step_size") || !contains(INV_MASS_STATS) {
+            bail!(
+                "The trace records neither its adaptation state nor the mass matrix. \
+                Sample with `store_mass_matrix=True` to keep it"
+            );
         }
+        let step_sizes: Vec<Vec<f64>> = stats
+            .get_item("step_size")?
+            .getattr("values")?
+            .call_method0("tolist")?
+            .extract()?;
+        let inv_mass = stats.get_item(INV_MASS_STATS)?;
+        let chains = step_sizes
+            .iter()
+            .enumerate()
+            .map(|(chain, steps)| {
+                // Chains that stopped early are padded with NaN.
+                let Some(last) = steps.iter().rposition(|step| step.is_finite()) else {
+                    return Ok(ChainState {
+                        chain: chain as u64,
+                        ..Default::default()
+                    });
+                };
+                let kwargs = PyDict::new(py);
+                kwargs.set_item("chain", chain)?;
+                kwargs.set_item("draw", last)?;
+                let values: Vec<f64> = inv_mass
+                    .call_method("isel", (), Some(&kwargs))?
+                    .getattr("values")?
+                    .call_method0("tolist")?
+                    .extract()?;
+                Ok(ChainState {
+                    chain: chain as u64,
+                    num_draws: last as u64 + 1,
+                    step_size: Some(steps[last]),
+                    inv_mass: Some(values),
+                    metric_source: MetricSource::Stats,
+                    ..Default::default()
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(PyAdaptationState(AdaptationState {
+            variant: "diag".to_string(),
+            chains,
+        }))
     }
 
     #[getter]
-    fn store_divergences(&self) -> bool {
-        match &self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.store_divergences,
-            Settings::LowRank(nuts_settings) => nuts_settings.store_divergences,
-            Settings::Transforming(nuts_settings) => nuts_settings.store_divergences,
-        }
+    fn num_chains(&self) -> usize {
+        self.0.chains.len()
     }
 
-    #[setter(store_divergences)]
-    fn set_store_divergences(&mut self, val: bool) {
-        match &mut self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.store_divergences = val,
-            Settings::LowRank(nuts_settings) => nuts_settings.store_divergences = val,
-            Settings::Transforming(nuts_settings) => nuts_settings.store_divergences = val,
-        }
+    #[getter]
+    fn step_size(&self) -> Vec<Option<f64>> {
+        self.0.chains.iter().map(|chain| chain.step_size).collect()
     }
 
     #[getter]
-    fn max_energy_error(&self) -> f64 {
-        match &self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.max_energy_error,
-            Settings::LowRank(nuts_settings) => nuts_settings.max_energy_error,
-            Settings::Transforming(nuts_settings) => nuts_settings.max_energy_error,
-        }
+    fn mass_matrix_inv<'py>(&self, py: Python<'py>) -> Vec<Option<Bound<'py, PyArray1<f64>>>> {
+        self.0
+            .chains
+            .iter()
+            .map(|chain| {
+                chain
+                    .inv_mass
+                    .as_ref()
+                    .map(|inv_mass| PyArray1::from_slice(py, inv_mass))
+            })
+            .collect()
     }
+}
 
-    #[setter(max_energy_error)]
-    fn set_max_energy_error(&mut self, val: f64) {
-        match &mut self.inner {
-            Settings::Diag(nuts_settings) => nuts_settings.max_energy_error = val,
-            Settings::LowRank(nuts_settings) => nuts_settings.max_energy_error = val,
-            Settings::Transforming(nuts_settings) => nuts_settings.max_energy_error = val,
-        }
+pub(crate) enum SamplerState {
+    Running(RunningSampler),
+    Finished(Option<Trace>),
+    Empty,
+}
+
+/// The sampler of a `PySampler`, as the monitor sees it.
+struct MonitoredSampler {
+    state: Weak<Mutex<SamplerState>>,
+    timings: Arc<Mutex<Timings>>,
+}
+
+impl SharedSampler for MonitoredSampler {
+    fn inspect(&mut self) -> Result<Option<Trace>> {
+        let Some(state) = self.state.upgrade() else {
+            return Ok(None);
+        };
+        let mut state = state.lock().expect("Poisoned sampler state mutex");
+        let SamplerState::Running(sampler) = state.deref_mut() else {
+            return Ok(None);
+        };
+        sampler.inspect_trace(InspectMode::StatsOnly).map(Some)
     }
 
-    #[getter]
-    fn set_target_accept(&self) -> f64 {
-        match &self.inner {
-            Settings::Diag(nuts_settings) => {
-                nuts_settings
-                    .adapt_options
-                    .dual_average_options
-                    .target_accept
-            }
-            Settings::LowRank(nuts_settings) => {
-                nuts_settings
-                    .adapt_options
-                    .dual_average_options
-                    .target_accept
+    fn abort(&mut self) -> Result<()> {
+        let Some(state) = self.state.upgrade() else {
+            return Ok(());
+        };
+        let mut state = state.lock().expect("Poisoned sampler state mutex");
+        let SamplerState::Running(sampler) = std::mem::replace(&mut *state, SamplerState::Empty)
+        else {
+            return Ok(());
+        };
+        let (result, trace) = sampler.abort();
+        *state = SamplerState::Finished(trace);
+        self.timings.lock().expect("Poisoned timings").finish();
+        result
+    }
+}
+
+/// A nuts-rs sampler, with the corrections of `reporting` applied to the
+/// traces it returns.
+pub(crate) struct RunningSampler {
+    sampler: Sampler,
+    reporting: Reporting,
+    registry: ChainRegistry,
+}
+
+enum WaitResult {
+    Trace(Trace),
+    Timeout(RunningSampler),
+    Err(anyhow::Error, Option<Trace>),
+}
+
+impl RunningSampler {
+    /// The trace so far, with the draws that `mode` asks for.
+    fn inspect_trace(&mut self, mode: InspectMode) -> Result<Trace> {
+        let sampler = &mut self.sampler;
+        let trace = self
+            .registry
+            .inspect_with(mode, || sampler.inspect_trace())?;
+        self.registry.record_stats(&trace);
+        self.reporting.check(&trace)?;
+        Ok(self.reporting.trace(trace))
+    }
+
+    /// The draws of each chain since the previous call, and the rows of
+    /// the sampler stats that belong to them, which start at
+    /// `stats_start[chain]`. Only these rows are checked and corrected by
+    /// `reporting`.
+    fn inspect_new_draws(&mut self, stats_start: &BTreeMap<u64, usize>) -> Result<Trace> {
+        let sampler = &mut self.sampler;
+        let trace = self
+            .registry
+            .inspect_with(InspectMode::Streaming, || sampler.inspect_trace())?;
+        self.registry.record_stats(&trace);
+        let chains = trace
+            .chains
+            .into_iter()
+            .map(|chain| {
+                let start = stats_start.get(&chain.chain_id).copied().unwrap_or(0);
+                if chain.stats.len() < start + chain.draws.len() {
+                    bail!("Sampler stats of chain {} are incomplete", chain.chain_id);
+                }
+                Ok(ChainOutput {
+                    stats: chain.stats.slice(start, chain.draws.len()),
+                    ..chain
+                })
+            })
+            .collect::<Result<_>>()?;
+        let trace = Trace { chains };
+        self.reporting.check(&trace)?;
+        Ok(self.reporting.trace(trace))
+    }
+
+    fn wait_timeout(self, timeout: Duration) -> WaitResult {
+        let Self {
+            sampler,
+            reporting,
+            registry,
+        } = self;
+        match sampler.wait_timeout(timeout) {
+            SamplerWaitResult::Trace(trace) => {
+                registry.record_stats(&trace);
+                match reporting.check(&trace) {
+                    Ok(()) => WaitResult::Trace(reporting.trace(trace)),
+                    Err(err) => WaitResult::Err(err, Some(reporting.trace(trace))),
+                }
             }
-            Settings::Transforming(nuts_settings) => {
-                nuts_settings
-                    .adapt_options
-                    .dual_average_options
-                    .target_accept
+            SamplerWaitResult::Timeout(sampler) => WaitResult::Timeout(RunningSampler {
+                sampler,
+                reporting,
+                registry,
+            }),
+            SamplerWaitResult::Err(err, trace) => {
+                if let Some(trace) = &trace {
+                    registry.record_stats(trace);
+                }
+                WaitResult::Err(err, trace.map(|trace| reporting.trace(trace)))
             }
         }
     }
 
-    #[setter(target_accept)]
-    fn target_accept(&mut self, val: f64) {
-        match &mut self.inner {
-            Settings::Diag(nuts_settings) => {
-                nuts_settings
-                    .adapt_options
-                    .dual_average_options
-                    .target_accept = val
-            }
-            Settings::LowRank(nuts_settings) => {
-                nuts_settings
-                    .adapt_options
-                    .dual_average_options
-                    .target_accept = val
-            }
-            Settings::Transforming(nuts_settings) => {
-                nuts_settings
-                    .adapt_options
-                    .dual_average_options
-                    .target_accept = val
-            }
+    fn abort(self) -> (Result<()>, Option<Trace>) {
+        let (result, trace) = self.sampler.abort();
+        if let Some(trace) = &trace {
+            self.registry.record_stats(trace);
         }
+        let result = result.and_then(|()| match &trace {
+            Some(trace) => self.reporting.check(trace),
+            None => Ok(()),
+        });
+        (result, trace.map(|trace| self.reporting.trace(trace)))
     }
 
-    #[getter]
-    fn store_mass_matrix(&self) -> Result<bool> {
-        match &self.inner {
-            Settings::LowRank(settings) => {
-                Ok(settings.adapt_options.mass_matrix_options.store_mass_matrix)
+    fn pause(&mut self) -> Result<()> {
+        self.sampler.pause()
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        self.sampler.resume()
+    }
+}
+
+#[derive(Clone)]
+enum InnerProgressType {
+    Callback {
+        rate: Duration,
+        n_cores: usize,
+        template: String,
+        callback: Arc<Py<PyAny>>,
+    },
+    Structured {
+        rate: Duration,
+        n_cores: usize,
+        callback: Arc<Py<PyAny>>,
+    },
+    JsonLines {
+        rate: Duration,
+        n_cores: usize,
+        path: PathBuf,
+    },
+    Indicatif {
+        rate: Duration,
+    },
+    IndicatifChains {
+        rate: Duration,
+        n_cores: usize,
+    },
+    None {},
+}
+
+#[pyclass]
+#[derive(Clone)]
+pub struct ProgressType(InnerProgressType);
+
+impl ProgressType {
+    /// Build the callback for a run with `num_tune` tuning draws per chain.
+    fn into_callback(self, num_tune: u64) -> Result<Option<ProgressCallback>> {
+        match self.0 {
+            InnerProgressType::Callback {
+                callback,
+                rate,
+                n_cores,
+                template,
+            } => {
+                let handler = ProgressHandler::new(callback, rate, template, n_cores, num_tune);
+                let callback = handler.into_callback()?;
+
+                Ok(Some(callback))
             }
-            Settings::Diag(settings) => {
-                Ok(settings.adapt_options.mass_matrix_options.store_mass_matrix)
+            InnerProgressType::Structured {
+                callback,
+                rate,
+                n_cores,
+            } => {
+                let handler = StructuredHandler::new(callback, rate, n_cores, num_tune);
+                Ok(Some(handler.into_callback()?))
+            }
+            InnerProgressType::JsonLines {
+                path,
+                rate,
+                n_cores,
+            } => {
+                let handler = JsonLinesHandler::new(path, rate, n_cores, num_tune);
+                Ok(Some(handler.into_callback()?))
             }
-            Settings::Transforming(_) => {
-                bail!("Option store_mass_matrix not availbale for transformation adaptation")
+            InnerProgressType::Indicatif { rate } => {
+                let handler = IndicatifHandler::new(rate);
+                Ok(Some(handler.into_callback()?))
             }
+            InnerProgressType::IndicatifChains { rate, n_cores } => {
+                let handler = MultiIndicatifHandler::new(rate, n_cores, num_tune);
+                Ok(Some(handler.into_callback()?))
+            }
+            InnerProgressType::None {} => Ok(None),
         }
     }
+}
 
-    #[setter(store_mass_matrix)]
-    fn set_store_mass_matrix(&mut self, val: bool) -> Result<()> {
-        match &mut self.inner {
-            Settings::LowRank(settings) => {
-                settings.adapt_options.mass_matrix_options.store_mass_matrix = val;
-                Ok(())
-            }
-            Settings::Diag(settings) => {
-                settings.adapt_options.mass_matrix_options.store_mass_matrix = val;
-                Ok(())
-            }
-            Settings::Transforming(_) => {
-                bail!("Option store_mass_matrix not availbale for transformation adaptation")
-            }
+#[pymethods]
+impl ProgressType {
+    #[staticmethod]
+    fn indicatif(rate: u64) -> Self {
+        let rate = Duration::from_millis(rate);
+        ProgressType(InnerProgressType::Indicatif { rate })
+    }
+
+    /// A terminal progress bar for each chain, and one for all chains.
+    #[staticmethod]
+    fn indicatif_chains(rate: u64, n_cores: usize) -> Self {
+        let rate = Duration::from_millis(rate);
+        ProgressType(InnerProgressType::IndicatifChains { rate, n_cores })
+    }
+
+    #[staticmethod]
+    fn none() -> Self {
+        ProgressType(InnerProgressType::None {})
+    }
+
+    #[staticmethod]
+    fn template_callback(rate: u64, template: String, n_cores: usize, callback: Py<PyAny>) -> Self {
+        let rate = Duration::from_millis(rate);
+        ProgressType(InnerProgressType::Callback {
+            callback: Arc::new(callback),
+            template,
+            n_cores,
+            rate,
+        })
+    }
+
+    /// Call `callback` with a `SamplerProgress` every `rate` milliseconds.
+    #[staticmethod]
+    fn structured_callback(rate: u64, n_cores: usize, callback: Py<PyAny>) -> Self {
+        let rate = Duration::from_millis(rate);
+        ProgressType(InnerProgressType::Structured {
+            callback: Arc::new(callback),
+            n_cores,
+            rate,
+        })
+    }
+
+    /// Append the progress as one JSON object per line to `path` every
+    /// `rate` milliseconds.
+    #[staticmethod]
+    fn json_lines(path: PathBuf, rate: u64, n_cores: usize) -> Self {
+        let rate = Duration::from_millis(rate);
+        ProgressType(InnerProgressType::JsonLines {
+            path,
+            n_cores,
+            rate,
+        })
+    }
+}
+
+/// The model of a sampler, so that we can start new samplers on it
+/// to continue a run.
+#[derive(Clone)]
+enum SamplerModel {
+    Stan(StanModel),
+    PyMc(PyMcModel),
+    PyFunc(PyModel),
+}
+
+impl SamplerModel {
+    fn backend(&self) -> Backend {
+        match self {
+            SamplerModel::Stan(model) => Backend::Stan {
+                library: model.library_path().to_path_buf(),
+                data_sha256: model.data_hash().map(String::from),
+            },
+            SamplerModel::PyMc(_) => Backend::Pymc,
+            SamplerModel::PyFunc(_) => Backend::Pyfunc,
         }
     }
 
-    #[getter]
-    fn use_grad_based_mass_matrix(&self) -> Result<bool> {
-        match &self.inner {
-            Settings::LowRank(_) => {
-                bail!("non-grad based mass matrix not available for low-rank adaptation")
-            }
-            Settings::Transforming(_) => {
-                bail!("non-grad based mass matrix not available for transforming adaptation")
-            }
-            Settings::Diag(diag) => Ok(diag
-                .adapt_options
-                .mass_matrix_options
-                .use_grad_based_estimate),
+    fn extract(model: &Bound<'_, PyAny>) -> Result<Self> {
+        if let Ok(model) = model.extract::<StanModel>() {
+            Ok(SamplerModel::Stan(model))
+        } else if let Ok(model) = model.extract::<PyMcModel>() {
+            Ok(SamplerModel::PyMc(model))
+        } else if let Ok(model) = model.extract::<PyModel>() {
+            Ok(SamplerModel::PyFunc(model))
+        } else {
+            bail!("Unsupported model type")
         }
     }
 
-    #[setter(use_grad_based_mass_matrix)]
-    fn set_use_grad_based_mass_matrix(&mut self, val: bool) -> Result<()> {
-        match &mut self.inner {
-            Settings::LowRank(_) => {
-                bail!("non-grad based mass matrix not available for low-rank adaptation");
-            }
-            Settings::Transforming(_) => {
-                bail!("non-grad based mass matrix not available for transforming adaptation");
-            }
-            Settings::Diag(diag) => {
-                diag.adapt_options
-                    .mass_matrix_options
-                    .use_grad_based_estimate = val;
-            }
+    fn sample(
+        &self,
+        settings: &Settings,
+        cores: usize,
+        callback: Option<ProgressCallback>,
+        tracking: TrackingOptions,
+    ) -> Result<RunningSampler> {
+        fn sample<M: ModelInit>(
+            model: M,
+            settings: &Settings,
+            cores: usize,
+            callback: Option<ProgressCallback>,
+            tracking: TrackingOptions,
+        ) -> Result<Sampler> {
+            let model = tracking.track(model);
+            Ok(match *settings {
+                Settings::Diag(mut settings) => {
+                    // We need the mass matrix to continue the chains later.
+                    settings.adapt_options.mass_matrix_options.store_mass_matrix = true;
+                    Sampler::new(model, settings, cores, callback)?
+                }
+                Settings::Transforming(_) | Settings::LowRank(_) | Settings::Dense(_) => {
+                    unreachable!("These settings need a transformation adapter or metric model")
+                }
+            })
         }
-        Ok(())
+
+        if let Settings::Transforming(settings) = settings {
+            return self.sample_transforming(
+                *settings,
+                Default::default(),
+                Default::default(),
+                cores,
+                callback,
+                tracking,
+            );
+        }
+
+        if let Settings::LowRank(_) | Settings::Dense(_) = settings {
+            let run = settings.run_shape();
+            let metric = MetricOptions {
+                dense: settings.dense_adaptation(run.num_tune),
+                low_rank: settings.low_rank_adaptation(),
+                ..Default::default()
+            };
+            let nuts_settings = settings.metric_settings(run.num_tune, run.num_draws);
+            return self.sample_with_metric(metric, nuts_settings, 0, cores, callback, tracking);
+        }
+
+        let reporting = Reporting {
+            hide_inv_mass: settings.hides_inv_mass(),
+            ..Default::default()
+        };
+        let registry = tracking.registry.clone();
+        let sampler = match self {
+            SamplerModel::Stan(model) => sample(model.clone(), settings, cores, callback, tracking),
+            SamplerModel::PyMc(model) => sample(model.clone(), settings, cores, callback, tracking),
+            SamplerModel::PyFunc(model) => {
+                sample(model.clone(), settings, cores, callback, tracking)
+            }
+        }?;
+        Ok(RunningSampler {
+            sampler,
+            reporting,
+            registry,
+        })
     }
 
-    #[getter]
-    fn mass_matrix_switch_freq(&self) -> Result<u64> {
-        match &self.inner {
-            Settings::Diag(settings) => Ok(settings.adapt_options.mass_matrix_switch_freq),
-            Settings::LowRank(settings) => Ok(settings.adapt_options.mass_matrix_switch_freq),
-            Settings::Transforming(_) => {
-                bail!("mass_matrix_switch_freq not available for transforming adaptation");
-            }
+    fn pathfinder(
+        &self,
+        num_paths: usize,
+        settings: &PathfinderSettings,
+        init: &InitOptions,
+        seed: u64,
+    ) -> Result<Vec<PathfinderPath>> {
+        match self {
+            SamplerModel::Stan(model) => pathfinder(model, num_paths, settings, init, seed),
+            SamplerModel::PyMc(model) => pathfinder(model, num_paths, settings, init, seed),
+            SamplerModel::PyFunc(model) => pathfinder(model, num_paths, settings, init, seed),
+        }
+    }
+
+    /// Sample with transform adaptation. The transformations of the chains
+    /// start from the adapter states in `transforms`, and the step sizes
+    /// are set up as in `MetricOptions::set_step_size`, if `reporting`
+    /// scales them.
+    fn sample_transforming(
+        &self,
+        settings: TransformedNutsSettings,
+        transforms: BTreeMap<u64, String>,
+        reporting: Reporting,
+        cores: usize,
+        callback: Option<ProgressCallback>,
+        tracking: TrackingOptions,
+    ) -> Result<RunningSampler> {
+        fn sample<M: ModelInit>(
+            model: M,
+            settings: TransformedNutsSettings,
+            cores: usize,
+            callback: Option<ProgressCallback>,
+            tracking: TrackingOptions,
+        ) -> Result<Sampler> {
+            Sampler::new(tracking.track(model), settings, cores, callback)
         }
+
+        let registry = tracking.registry.clone();
+        let step_scales = reporting.step_scales.as_ref().clone();
+        let adapter =
+            |adapter: &PyTransformAdapt| adapter.for_run(&registry, transforms, step_scales);
+        let callback = callback.map(|callback| reporting.callback(callback));
+        let sampler = match self {
+            SamplerModel::Stan(model) => sample(
+                model.map_transform_adapter(adapter),
+                settings,
+                cores,
+                callback,
+                tracking,
+            ),
+            SamplerModel::PyMc(model) => sample(model.clone(), settings, cores, callback, tracking),
+            SamplerModel::PyFunc(model) => sample(
+                model.map_transform_adapter(adapter),
+                settings,
+                cores,
+                callback,
+                tracking,
+            ),
+        }?;
+        Ok(RunningSampler {
+            sampler,
+            reporting,
+            registry,
+        })
+    }
+
+    /// Sample with an initial metric. The first `burn_in` draws of each
+    /// chain are reported as warmup, see `Reporting::burn_in`.
+    fn sample_with_metric(
+        &self,
+        metric: MetricOptions,
+        settings: TransformedNutsSettings,
+        burn_in: u64,
+        cores: usize,
+        callback: Option<ProgressCallback>,
+        tracking: TrackingOptions,
+    ) -> Result<RunningSampler> {
+        fn sample<M: ModelInit>(
+            model: M,
+            metric: MetricOptions,
+            settings: TransformedNutsSettings,
+            cores: usize,
+            callback: Option<ProgressCallback>,
+            tracking: TrackingOptions,
+        ) -> Result<Sampler> {
+            let model = MetricModel::new(model, metric, tracking.registry.clone());
+            Sampler::new(tracking.track(model), settings, cores, callback)
+        }
+
+        let reporting = Reporting {
+            step_scales: Arc::new(metric.step_scales.clone()),
+            fixed_step: metric.fixed_step,
+            burn_in,
+            hide_inv_mass: false,
+        };
+        let callback = callback.map(|callback| reporting.callback(callback));
+        let registry = tracking.registry.clone();
+        let sampler = match self {
+            SamplerModel::Stan(model) => {
+                sample(model.clone(), metric, settings, cores, callback, tracking)
+            }
+            SamplerModel::PyMc(model) => {
+                sample(model.clone(), metric, settings, cores, callback, tracking)
+            }
+            SamplerModel::PyFunc(model) => {
+                sample(model.clone(), metric, settings, cores, callback, tracking)
+            }
+        }?;
+        Ok(RunningSampler {
+            sampler,
+            reporting,
+            registry,
+        })
+    }
+}
+
+#[pyclass]
+struct PySampler {
+    state: Arc<Mutex<SamplerState>>,
+    settings: PyNutsSettings,
+    model: SamplerModel,
+    registry: ChainRegistry,
+    storage: Option<StorageOptions>,
+    resume: Option<Arc<ResumePlan>>,
+    streamed: StreamedRows,
+    cores: usize,
+    /// Shared with the monitor, which finishes them if it aborts the sampler.
+    timings: Arc<Mutex<Timings>>,
+    stopping: Option<Arc<Mutex<StopFeed>>>,
+    /// The trace that `extract_results` handed out. We keep it to extend
+    /// the run, and for checkpoints and diagnostics. Draws that are stored
+    /// on disk are not part of it.
+    extracted: Option<Trace>,
+    /// Writes the sampler stats if the trace is stored on disk, and stops
+    /// the sampler once the stopping rule is met.
+    monitor: Option<Monitor>,
+}
+
+/// How much of each chain `fetch_new_draws` returned already.
+#[derive(Default)]
+struct StreamedRows {
+    /// Rows of the trace of the nuts-rs sampler.
+    sampler: BTreeMap<u64, usize>,
+    /// Rows of the trace after stitching it onto a checkpoint.
+    output: BTreeMap<u64, usize>,
+}
+
+impl PySampler {
+    fn start(
+        options: PyNutsSettings,
+        cores: usize,
+        model: SamplerModel,
+        progress_type: ProgressType,
+    ) -> Result<PySampler> {
+        let PyNutsSettings {
+            inner: settings,
+            adaptation_state,
+            trace_storage: _,
+            stopping_rule,
+            check_gradient,
+            init,
+            pathfinder: pathfinder_settings,
+            mass_matrix,
+            step_size,
+            adapt,
+        } = options.clone();
+        let stopping = PySampler::stop_feed(stopping_rule, &settings, None);
+        let timings = Arc::new(Mutex::new(Timings::start()));
+        let storage = PySampler::run_storage(
+            &options,
+            settings.run_shape(),
+            &model,
+            cores,
+            &timings,
+            stopping.as_ref(),
+        )?;
+        let callback = progress_type.into_callback(settings.run_shape().num_tune)?;
+        let registry = ChainRegistry::default();
+        let state = Arc::new(Mutex::new(SamplerState::Empty));
+        let monitor =
+            PySampler::monitor(&state, &timings, storage.as_ref(), None, stopping.clone())?;
+        let callback = Monitor::wrap(monitor.as_ref(), callback);
+        let mut tracking = TrackingOptions {
+            registry: registry.clone(),
+            storage: storage.clone(),
+            gradient_tolerance: check_gradient,
+            init,
+            stop: stopping.clone(),
+            ..Default::default()
+        };
+        let defaults = adaptation_state.is_none()
+            && pathfinder_settings.is_none()
+            && mass_matrix.is_none()
+            && step_size.is_none()
+            && adapt;
+        let sampler = if defaults {
+            model.sample(&settings, cores, callback, tracking)?
+        } else {
+            if let Settings::Transforming(_) = settings {
+                if adaptation_state.is_some() || pathfinder_settings.is_some() {
+                    bail!(
+                        "`adaptation_state` and `pathfinder` are not supported with \
+                        transform adaptation"
+                    );
+                }
+                if mass_matrix.is_some() || step_size.is_some() || !adapt {
+                    bail!(
+                        "`mass_matrix`, `step_size` and `adapt=False` are not supported \
+                        with transform adaptation"
+                    );
+                }
+            }
+            let run = settings.run_shape();
+            let adapt_metric = adapt && run.num_tune > 0;
+            let (known_step, mut metric) =
+                match (adaptation_state, pathfinder_settings, mass_matrix) {
+                    (None, None, None) => (None, MetricOptions::default()),
+                    (Some(state), None, None) => state.warm_start(run.num_chains, adapt_metric)?,
+                    (None, Some(pathfinder_settings), None) => {
+                        // The paths should not use the same random numbers as
+                        // the chains.
+                        let seed = run.seed ^ 0x7061_7468_6669_6e64;
+                        let paths = Python::with_gil(|py| {
+                            py.allow_threads(|| {
+                                model.pathfinder(
+                                    run.num_chains,
+                                    &pathfinder_settings,
+                                    &tracking.init,
+                                    seed,
+                                )
+                            })
+                        })?;
+                        let (metric, init_points) =
+                            warm_start(&paths, settings.warm_start_metric(), adapt_metric, seed);
+                        tracking.init_points = init_points;
+                        (None, metric)
+                    }
+                    (None, None, Some(mass_matrix)) => {
+                        (None, mass_matrix.options(run.num_chains, adapt_metric))
+                    }
+                    _ => bail!(
+                        "Only one of `adaptation_state`, `pathfinder` and `mass_matrix` can be used"
+                    ),
+                };
+            // nuts-rs always tunes the step size during warmup, so without
+            // adaptation we run all draws as posterior draws. The first
+            // `num_tune` of them are still reported as warmup.
+            let mut nuts_settings = if adapt {
+                metric.dense = settings.dense_adaptation(run.num_tune);
+                metric.low_rank = settings.low_rank_adaptation();
+                settings.metric_settings(run.num_tune, run.num_draws)
+            } else {
+                settings.metric_settings(0, run.num_tune + run.num_draws)
+            };
+            if let Some(step_size) = step_size {
+                // The step size of the user replaces the ones of a warm start.
+                metric
+                    .chains
+                    .values_mut()
+                    .for_each(|chain| chain.step_size = None);
+                metric.set_step_size(&mut nuts_settings, step_size);
+            } else if let Some(step_size) = known_step {
+                metric.set_step_size(&mut nuts_settings, step_size);
+            }
+            let burn_in = if adapt { 0 } else { run.num_tune };
+            model.sample_with_metric(metric, nuts_settings, burn_in, cores, callback, tracking)?
+        };
+        *state.lock().expect("Poisoned sampler state mutex") = SamplerState::Running(sampler);
+        Ok(PySampler {
+            state,
+            settings: options,
+            model,
+            registry,
+            storage,
+            resume: None,
+            streamed: Default::default(),
+            cores,
+            timings,
+            stopping,
+            extracted: None,
+            monitor,
+        })
     }
 
-    #[setter(mass_matrix_switch_freq)]
-    fn set_mass_matrix_switch_freq(&mut self, val: u64) -> Result<()> {
-        match &mut self.inner {
-            Settings::Diag(settings) => settings.adapt_options.mass_matrix_switch_freq = val,
-            Settings::LowRank(settings) => settings.adapt_options.mass_matrix_switch_freq = val,
-            Settings::Transforming(_) => {
-                bail!("mass_matrix_switch_freq not available for transforming adaptation");
-            }
+    /// Start a sampler that continues the chains of a checkpoint.
+    ///
+    /// `settings` may ask for more posterior draws than the checkpointed
+    /// run, which extends the chains. Without `adapt`, the remaining
+    /// warmup draws only serve as burn-in.
+    fn continue_checkpoint(
+        mut checkpoint: Checkpoint,
+        options: PyNutsSettings,
+        cores: usize,
+        model: SamplerModel,
+        progress_type: ProgressType,
+    ) -> Result<PySampler> {
+        let settings = &options.inner;
+        let adapt = options.adapt;
+        let run = settings.run_shape();
+        if (
+            &checkpoint.run.variant,
+            checkpoint.run.num_tune,
+            checkpoint.run.num_chains,
+        ) != (&run.variant, run.num_tune, run.num_chains)
+            || checkpoint.run.num_draws > run.num_draws
+        {
+            bail!(
+                "The checkpoint was created with different settings: {:?}",
+                checkpoint.run
+            );
         }
-        Ok(())
-    }
-
-    #[getter]
-    fn mass_matrix_eigval_cutoff(&self) -> Result<f64> {
-        match &self.inner {
-            Settings::LowRank(inner) => Ok(inner.adapt_options.mass_matrix_options.eigval_cutoff),
-            Settings::Diag(_) => {
-                bail!("eigenvalue cutoff not available for diag mass matrix adaptation");
-            }
-            Settings::Transforming(_) => {
-                bail!("eigenvalue cutoff not available for transfor adaptation");
-            }
+        checkpoint.run.num_draws = run.num_draws;
+
+        let continuation = checkpoint.continuation()?;
+        let registry = ChainRegistry::default();
+
+        if continuation.num_tune + continuation.num_draws == 0 {
+            let mut timings = Timings::start();
+            timings.finish();
+            let timings = Arc::new(Mutex::new(timings));
+            let state = Arc::new(Mutex::new(SamplerState::Finished(Some(Trace::from(
+                std::iter::empty(),
+            )))));
+            let resume = Arc::new(continuation.plan);
+            let stopping =
+                PySampler::stop_feed(options.stopping_rule.clone(), settings, Some(&resume));
+            let storage =
+                PySampler::run_storage(&options, run, &model, cores, &timings, stopping.as_ref())?;
+            let monitor = PySampler::monitor(
+                &state,
+                &timings,
+                storage.as_ref(),
+                Some(resume.clone()),
+                None,
+            )?;
+            return Ok(PySampler {
+                state,
+                settings: options,
+                model,
+                registry,
+                storage,
+                resume: Some(resume),
+                streamed: Default::default(),
+                cores,
+                timings,
+                stopping,
+                extracted: None,
+                monitor,
+            });
         }
-    }
 
-    #[setter(mass_matrix_eigval_cutoff)]
-    fn set_mass_matrix_eigval_cutoff(&mut self, val: f64) -> Result<()> {
-        match &mut self.inner {
-            Settings::LowRank(inner) => inner.adapt_options.mass_matrix_options.eigval_cutoff = val,
-            Settings::Diag(_) => {
-                bail!("eigenvalue cutoff not available for diag mass matrix adaptation");
-            }
-            Settings::Transforming(_) => {
-                bail!("eigenvalue cutoff not available for transfor adaptation");
-            }
-        }
-        Ok(())
+        let mut nuts_settings = if adapt {
+            settings.metric_settings(continuation.num_tune, continuation.num_draws)
+        } else {
+            settings.metric_settings(0, continuation.num_tune + continuation.num_draws)
+        };
+        // The continued chains should not repeat the random numbers of
+        // the first part of the run, and continuing the same checkpoint
+        // again should give the same draws, whatever the seed of `settings`.
+        let draws_done: u64 = checkpoint
+            .chains
+            .iter()
+            .map(|chain| chain.state.num_draws)
+            .sum();
+        nuts_settings.seed = checkpoint.run.seed.wrapping_add(draws_done);
+
+        let callback = progress_type.into_callback(continuation.num_tune)?;
+        let Continuation {
+            mut metric,
+            transforms,
+            init_points,
+            plan,
+            step_size,
+            ..
+        } = continuation;
+        let resume = Arc::new(plan);
+        let stopping = PySampler::stop_feed(options.stopping_rule.clone(), settings, Some(&resume));
+        let state = Arc::new(Mutex::new(SamplerState::Empty));
+        let timings = Arc::new(Mutex::new(Timings::start()));
+        let storage = PySampler::run_storage(
+            &options,
+            run.clone(),
+            &model,
+            cores,
+            &timings,
+            stopping.as_ref(),
+        )?;
+        let monitor = PySampler::monitor(
+            &state,
+            &timings,
+            storage.as_ref(),
+            Some(resume.clone()),
+            stopping.clone(),
+        )?;
+        let callback = Monitor::wrap(monitor.as_ref(), callback);
+        let tracking = TrackingOptions {
+            registry: registry.clone(),
+            init_points,
+            storage: storage.clone(),
+            gradient_tolerance: None,
+            init: Default::default(),
+            stop: stopping.clone(),
+        };
+        let burn_in = if adapt { 0 } else { continuation.num_tune };
+        let sampler = if let Settings::Transforming(_) = settings {
+            let mut reporting = Reporting {
+                burn_in,
+                ..Default::default()
+            };
+            if let Some(step_size) = step_size {
+                let (scales, fixed_step) = step_scales(&mut nuts_settings, step_size, |chain| {
+                    transforms
+                        .get(&chain)
+                        .and_then(|transform| transform.step_size)
+                });
+                reporting.step_scales = Arc::new(scales);
+                reporting.fixed_step = fixed_step;
+            }
+            let states = transforms
+                .into_iter()
+                .map(|(chain, transform)| (chain, transform.state))
+                .collect();
+            model.sample_transforming(
+                nuts_settings,
+                states,
+                reporting,
+                cores,
+                callback,
+                tracking,
+            )?
+        } else {
+            if let Some(step_size) = step_size {
+                metric.set_step_size(&mut nuts_settings, step_size);
+            }
+            metric.dense = settings.dense_adaptation(nuts_settings.num_tune);
+            metric.low_rank = settings.low_rank_adaptation();
+            if !adapt {
+                metric
+                    .chains
+                    .values_mut()
+                    .for_each(|chain| chain.adapt = false);
+            }
+            model.sample_with_metric(metric, nuts_settings, burn_in, cores, callback, tracking)?
+        };
+        *state.lock().expect("Poisoned sampler state mutex") = SamplerState::Running(sampler);
+
+        Ok(PySampler {
+            state,
+            settings: options,
+            model,
+            registry,
+            storage,
+            resume: Some(resume),
+            streamed: Default::default(),
+            cores,
+            timings,
+            stopping,
+            extracted: None,
+            monitor,
+        })
     }
 
-    #[getter]
-    fn mass_matrix_gamma(&self) -> Result<f64> {
-        match &self.inner {
-            Settings::LowRank(inner) => Ok(inner.adapt_options.mass_matrix_options.gamma),
-            Settings::Diag(_) => {
-                bail!("gamma not available for diag mass matrix adaptation");
-            }
-            Settings::Transforming(_) => {
-                bail!("gamma not available for transform adaptation");
-            }
+    /// Create the storage of a run, and start the monitor that writes the
+    /// sampler stats of each chain to it and stops the sampler once `stop`
+    /// is met.
+    fn monitor(
+        state: &Arc<Mutex<SamplerState>>,
+        timings: &Arc<Mutex<Timings>>,
+        storage: Option<&StorageOptions>,
+        resume: Option<Arc<ResumePlan>>,
+        stop: Option<Arc<Mutex<StopFeed>>>,
+    ) -> Result<Option<Monitor>> {
+        if let Some(storage) = storage {
+            storage.create()?;
         }
-    }
-
-    #[setter(mass_matrix_gamma)]
-    fn set_mass_matrix_gamma(&mut self, val: f64) -> Result<()> {
-        match &mut self.inner {
-            Settings::LowRank(inner) => {
-                inner.adapt_options.mass_matrix_options.gamma = val;
-            }
-            Settings::Diag(_) => {
-                bail!("gamma not available for diag mass matrix adaptation");
-            }
-            Settings::Transforming(_) => {
-                bail!("gamma not available for transform adaptation");
-            }
+        let sampler = MonitoredSampler {
+            state: Arc::downgrade(state),
+            timings: timings.clone(),
+        };
+        Ok(Monitor::start(storage.cloned(), resume, stop, sampler))
+    }
+
+    /// The feed that evaluates the stopping rule of a run while it samples.
+    fn stop_feed(
+        rule: Option<StoppingRule>,
+        settings: &Settings,
+        resume: Option<&Arc<ResumePlan>>,
+    ) -> Option<Arc<Mutex<StopFeed>>> {
+        let run = settings.run_shape();
+        let feed = StopFeed::new(
+            Stopping::new(rule?),
+            run.num_chains,
+            run.num_tune,
+            resume.cloned(),
+        );
+        Some(Arc::new(Mutex::new(feed)))
+    }
+
+    fn stopping(&self) -> Option<MutexGuard<'_, StopFeed>> {
+        self.stopping
+            .as_ref()
+            .map(|feed| feed.lock().expect("Poisoned stopping rule"))
+    }
+
+    /// The trace of a finished run, also after `extract_results`.
+    fn finished_trace<'a>(&'a self, state: &'a SamplerState) -> Option<&'a Trace> {
+        match state {
+            SamplerState::Finished(trace) => trace.as_ref(),
+            SamplerState::Empty => self.extracted.as_ref(),
+            SamplerState::Running(_) => None,
         }
-        Ok(())
     }
 
-    #[getter]
-    fn train_on_orbit(&self) -> Result<bool> {
-        match &self.inner {
-            Settings::LowRank(_) => {
-                bail!("gamma not available for low rank mass matrix adaptation");
-            }
-            Settings::Diag(_) => {
-                bail!("gamma not available for diag mass matrix adaptation");
-            }
-            Settings::Transforming(inner) => Ok(inner.adapt_options.use_orbit_for_training),
+    /// The state of all chains and the trace so far.
+    fn current_checkpoint(&mut self) -> Result<Checkpoint> {
+        self.consolidate()?;
+        // Snapshot the chain state first, so that all positions belong
+        // to draws that are part of the trace we inspect afterwards.
+        let mut records = self.registry.snapshot();
+        let mut state = self.state.lock().expect("Poisoned sampler state mutex");
+        let trace = match state.deref_mut() {
+            SamplerState::Running(sampler) => sampler.inspect_trace(InspectMode::Full)?,
+            state => match self.finished_trace(state) {
+                Some(trace) => self.load_trace(trace)?,
+                None => bail!("Sampler has no trace"),
+            },
+        };
+        drop(state);
+        // The mass matrix in the sampler stats is recorded when we inspect
+        // the trace, and covers at least the draws of the snapshot.
+        for (&chain, record) in records.iter_mut() {
+            record.stats_inv_mass = self.registry.stats_inv_mass(chain);
         }
+        Checkpoint::new(
+            self.settings.inner.run_shape(),
+            trace,
+            &records,
+            self.resume.as_deref(),
+        )
     }
 
-    #[setter(train_on_orbit)]
-    fn set_train_on_orbit(&mut self, val: bool) -> Result<()> {
-        match &mut self.inner {
-            Settings::LowRank(_) => {
-                bail!("gamma not available for low rank mass matrix adaptation");
-            }
-            Settings::Diag(_) => {
-                bail!("gamma not available for diag mass matrix adaptation");
+    /// A copy of a finished trace, including the draws that are on disk.
+    fn load_trace(&self, trace: &Trace) -> Result<Trace> {
+        let mut trace = clone_trace(trace);
+        if let Some(storage) = &self.storage {
+            for chain in trace.chains.iter_mut() {
+                if let Some(draws) = storage.read_draws(chain.chain_id, &chain.draws)? {
+                    chain.draws = draws;
+                }
             }
-            Settings::Transforming(inner) => inner.adapt_options.use_orbit_for_training = val,
-        }
-        Ok(())
-    }
-
-    #[getter]
-    fn check_turning(&self) -> Result<bool> {
-        match &self.inner {
-            Settings::LowRank(inner) => Ok(inner.check_turning),
-            Settings::Diag(inner) => Ok(inner.check_turning),
-            Settings::Transforming(inner) => Ok(inner.check_turning),
         }
+        Ok(trace)
     }
 
-    #[setter(check_turning)]
-    fn set_check_turning(&mut self, val: bool) -> Result<()> {
-        match &mut self.inner {
-            Settings::LowRank(inner) => {
-                inner.check_turning = val;
-            }
-            Settings::Diag(inner) => {
-                inner.check_turning = val;
-            }
-            Settings::Transforming(inner) => {
-                inner.check_turning = val;
+    /// Replace the trace of a finished continuation by the trace of the
+    /// whole run, so that we stitch it onto the checkpoint only once.
+    fn consolidate(&mut self) -> Result<()> {
+        let Some(plan) = &self.resume else {
+            return Ok(());
+        };
+        let mut state = self.state.lock().expect("Poisoned sampler state mutex");
+        let SamplerState::Finished(Some(trace)) = state.deref_mut() else {
+            return Ok(());
+        };
+        let mut full = plan.stitch(self.load_trace(trace)?)?;
+        if let Some(storage) = &self.storage {
+            for chain in full.chains.iter_mut() {
+                storage.write_draws(chain.chain_id, &chain.draws)?;
+                chain.draws = chain.draws.slice(0, 0);
             }
         }
+        *trace = full;
+        drop(state);
+        self.resume = None;
+        self.streamed.sampler = self.streamed.output.clone();
         Ok(())
     }
-}
-
-pub(crate) enum SamplerState {
-    Running(Sampler),
-    Finished(Option<Trace>),
-    Empty,
-}
 
-#[derive(Clone)]
-enum InnerProgressType {
-    Callback {
-        rate: Duration,
-        n_cores: usize,
-        template: String,
-        callback: Arc<Py<PyAny>>,
-    },
-    Indicatif {
-        rate: Duration,
-    },
-    None {},
-}
+    /// The draws of each chain since the previous call.
+    fn new_draws(&mut self) -> Result<Vec<ChainOutput>> {
+        self.consolidate()?;
+        let mut state = self.state.lock().expect("Poisoned sampler state mutex");
+        let (chains, streaming) = match state.deref_mut() {
+            SamplerState::Running(sampler) => {
+                // In streaming mode the draw storage of each chain only
+                // returns the draws since the last inspection.
+                let trace = sampler.inspect_new_draws(&self.streamed.sampler)?;
+                (trace.chains, true)
+            }
+            state => match self.finished_trace(state) {
+                Some(trace) => (self.load_trace(trace)?.chains, false),
+                None => bail!("Sampler has no trace"),
+            },
+        };
 
-#[pyclass]
-#[derive(Clone)]
-pub struct ProgressType(InnerProgressType);
+        let mut started = Vec::with_capacity(chains.len());
+        let mut outputs = Vec::with_capacity(chains.len());
+        for chain in chains {
+            let chain_id = chain.chain_id;
+            started.push(chain_id);
+            let start = self.streamed.sampler.get(&chain_id).copied().unwrap_or(0);
+            let length = if streaming {
+                chain.draws.len()
+            } else {
+                chain.draws.len().saturating_sub(start)
+            };
+            let output = if streaming {
+                chain
+            } else {
+                if chain.stats.len() < start + length {
+                    bail!("Sampler stats of chain {} are incomplete", chain_id);
+                }
+                ChainOutput {
+                    draws: chain.draws.slice(start, length),
+                    stats: chain.stats.slice(start, length),
+                    chain_id,
+                }
+            };
+            self.streamed.sampler.insert(chain_id, start + length);
+            outputs.push(output);
+        }
 
-impl ProgressType {
-    fn into_callback(self) -> Result<Option<ProgressCallback>> {
-        match self.0 {
-            InnerProgressType::Callback {
-                callback,
-                rate,
-                n_cores,
-                template,
-            } => {
-                let handler = ProgressHandler::new(callback, rate, template, n_cores);
-                let callback = handler.into_callback()?;
+        let Some(plan) = &self.resume else {
+            return Ok(outputs);
+        };
 
-                Ok(Some(callback))
-            }
-            InnerProgressType::Indicatif { rate } => {
-                let handler = IndicatifHandler::new(rate);
-                Ok(Some(handler.into_callback()?))
-            }
-            InnerProgressType::None {} => Ok(None),
+        if !streaming {
+            outputs.extend(
+                plan.chain_ids()
+                    .filter(|chain_id| !started.contains(chain_id))
+                    .filter_map(|chain_id| plan.empty_output(chain_id)),
+            );
         }
-    }
-}
-
-#[pymethods]
-impl ProgressType {
-    #[staticmethod]
-    fn indicatif(rate: u64) -> Self {
-        let rate = Duration::from_millis(rate);
-        ProgressType(InnerProgressType::Indicatif { rate })
+        outputs
+            .into_iter()
+            .map(|output| {
+                let chain_id = output.chain_id;
+                let start = self
+                    .streamed
+                    .sampler
+                    .get(&chain_id)
+                    .map(|end| end.saturating_sub(output.draws.len()))
+                    .unwrap_or(0);
+                let skip = self.streamed.output.get(&chain_id).copied().unwrap_or(0);
+                let output = plan.stitch_rows(output, start, skip)?;
+                self.streamed
+                    .output
+                    .insert(chain_id, skip + output.draws.len());
+                Ok(output)
+            })
+            .collect()
     }
 
-    #[staticmethod]
-    fn none() -> Self {
-        ProgressType(InnerProgressType::None {})
+    /// How the trace of this sampler is produced.
+    fn provenance(&self) -> Result<Provenance> {
+        Ok(Provenance::new(
+            self.settings.to_record()?,
+            self.model.backend(),
+            self.cores,
+            self.timings.lock().expect("Poisoned timings").clone(),
+            self.stopping().map(|stopping| stopping.stopping().clone()),
+        ))
     }
 
-    #[staticmethod]
-    fn template_callback(rate: u64, template: String, n_cores: usize, callback: Py<PyAny>) -> Self {
-        let rate = Duration::from_millis(rate);
-        ProgressType(InnerProgressType::Callback {
-            callback: Arc::new(callback),
-            template,
-            n_cores,
-            rate,
-        })
+    /// The provenance of a finished run, which also records the adaptation
+    /// state each chain ended with.
+    fn finished_provenance(&self) -> Result<Provenance> {
+        let Some(trace) = &self.extracted else {
+            bail!("The results of the sampler were not extracted yet");
+        };
+        let state = AdaptationState::finished(
+            self.settings.inner.run_shape().variant,
+            trace,
+            &self.registry.snapshot(),
+        );
+        self.provenance()?.with_adaptation_state(&state)
+    }
+
+    /// The storage of a run with `settings`, which records the provenance
+    /// of the run as it starts.
+    fn run_storage(
+        settings: &PyNutsSettings,
+        run: RunShape,
+        model: &SamplerModel,
+        cores: usize,
+        timings: &Arc<Mutex<Timings>>,
+        stopping: Option<&Arc<Mutex<StopFeed>>>,
+    ) -> Result<Option<StorageOptions>> {
+        let Some(storage) = settings.trace_storage.0.clone() else {
+            return Ok(None);
+        };
+        let provenance = Provenance::new(
+            settings.to_record()?,
+            model.backend(),
+            cores,
+            timings.lock().expect("Poisoned timings").clone(),
+            stopping.map(|feed| {
+                feed.lock()
+                    .expect("Poisoned stopping rule")
+                    .stopping()
+                    .clone()
+            }),
+        );
+        Ok(Some(storage.for_run(run, provenance.to_value()?)))
+    }
+
+    fn finish_trace(&self, trace: Trace) -> Result<Trace> {
+        match &self.resume {
+            Some(plan) => plan.stitch(trace),
+            None => Ok(trace),
+        }
     }
 }
 
-#[pyclass]
-struct PySampler(Mutex<SamplerState>);
-
 #[pymethods]
 impl PySampler {
     #[staticmethod]
@@ -740,21 +1899,12 @@ impl PySampler {
         model: PyMcModel,
         progress_type: ProgressType,
     ) -> PyResult<PySampler> {
-        let callback = progress_type.into_callback()?;
-        match settings.inner {
-            Settings::LowRank(settings) => {
-                let sampler = Sampler::new(model, settings, cores, callback)?;
-                Ok(PySampler(SamplerState::Running(sampler).into()))
-            }
-            Settings::Diag(settings) => {
-                let sampler = Sampler::new(model, settings, cores, callback)?;
-                Ok(PySampler(SamplerState::Running(sampler).into()))
-            }
-            Settings::Transforming(settings) => {
-                let sampler = Sampler::new(model, settings, cores, callback)?;
-                Ok(PySampler(SamplerState::Running(sampler).into()))
-            }
-        }
+        Ok(PySampler::start(
+            settings,
+            cores,
+            SamplerModel::PyMc(model),
+            progress_type,
+        )?)
     }
 
     #[staticmethod]
@@ -764,21 +1914,12 @@ impl PySampler {
         model: StanModel,
         progress_type: ProgressType,
     ) -> PyResult<PySampler> {
-        let callback = progress_type.into_callback()?;
-        match settings.inner {
-            Settings::LowRank(settings) => {
-                let sampler = Sampler::new(model, settings, cores, callback)?;
-                Ok(PySampler(SamplerState::Running(sampler).into()))
-            }
-            Settings::Diag(settings) => {
-                let sampler = Sampler::new(model, settings, cores, callback)?;
-                Ok(PySampler(SamplerState::Running(sampler).into()))
-            }
-            Settings::Transforming(settings) => {
-                let sampler = Sampler::new(model, settings, cores, callback)?;
-                Ok(PySampler(SamplerState::Running(sampler).into()))
-            }
-        }
+        Ok(PySampler::start(
+            settings,
+            cores,
+            SamplerModel::Stan(model),
+            progress_type,
+        )?)
     }
 
     #[staticmethod]
@@ -788,26 +1929,193 @@ impl PySampler {
         model: PyModel,
         progress_type: ProgressType,
     ) -> PyResult<PySampler> {
-        let callback = progress_type.into_callback()?;
-        match settings.inner {
-            Settings::LowRank(settings) => {
-                let sampler = Sampler::new(model, settings, cores, callback)?;
-                Ok(PySampler(SamplerState::Running(sampler).into()))
-            }
-            Settings::Diag(settings) => {
-                let sampler = Sampler::new(model, settings, cores, callback)?;
-                Ok(PySampler(SamplerState::Running(sampler).into()))
-            }
-            Settings::Transforming(settings) => {
-                let sampler = Sampler::new(model, settings, cores, callback)?;
-                Ok(PySampler(SamplerState::Running(sampler).into()))
-            }
-        }
+        Ok(PySampler::start(
+            settings,
+            cores,
+            SamplerModel::PyFunc(model),
+            progress_type,
+        )?)
+    }
+
+    /// Continue sampling from a checkpoint written by `checkpoint`.
+    ///
+    /// `settings` and `model` must match the ones of the checkpointed
+    /// sampler, except that `settings.num_draws` may be increased to extend
+    /// the chains. Chains continue from their last position with the step
+    /// size and metric or normalizing flow they had reached. Their random
+    /// stream only depends on the checkpoint, not on the seed in `settings`,
+    /// and differs from the one of an uninterrupted run, so the continued
+    /// run is statistically equivalent to it, but not identical.
+    #[staticmethod]
+    fn from_checkpoint(
+        path: PathBuf,
+        settings: PyNutsSettings,
+        cores: usize,
+        model: &Bound<'_, PyAny>,
+        progress_type: ProgressType,
+    ) -> PyResult<PySampler> {
+        let checkpoint = Checkpoint::read(&path)?;
+        let model = SamplerModel::extract(model)?;
+        Ok(PySampler::continue_checkpoint(
+            checkpoint,
+            settings,
+            cores,
+            model,
+            progress_type,
+        )?)
+    }
+
+    /// Sample `draws` more posterior draws in each chain of a finished run.
+    ///
+    /// The chains continue without further tuning, with the final step size
+    /// and mass matrix of each chain. The new draws are appended to the
+    /// trace of the run.
+    fn extend(
+        &mut self,
+        py: Python<'_>,
+        draws: u64,
+        cores: usize,
+        progress_type: ProgressType,
+    ) -> PyResult<()> {
+        py.allow_threads(|| {
+            let mut state = self.state.lock().expect("Poisoned sampler state mutex");
+            if let (SamplerState::Empty, Some(trace)) = (state.deref(), self.extracted.take()) {
+                *state = SamplerState::Finished(Some(trace));
+            }
+            if !matches!(state.deref(), SamplerState::Finished(Some(_))) {
+                bail!("Only finished samplers can be extended");
+            }
+            drop(state);
+            let mut checkpoint = self.current_checkpoint()?;
+            let stopped = self
+                .stopping()
+                .is_some_and(|feed| feed.stopping().stopped().is_some());
+            if stopped {
+                // The run stopped early, so we add the draws to the
+                // ones the chains actually made.
+                let num_tune = checkpoint.run.num_tune;
+                checkpoint.run.num_draws = checkpoint
+                    .chains
+                    .iter()
+                    .map(|chain| chain.state.num_draws.saturating_sub(num_tune))
+                    .max()
+                    .unwrap_or(0);
+            }
+            let mut settings = self.settings.clone();
+            settings
+                .inner
+                .set_num_draws(checkpoint.run.num_draws + draws);
+            let sampler = PySampler::continue_checkpoint(
+                checkpoint,
+                settings,
+                cores,
+                self.model.clone(),
+                progress_type,
+            )?;
+            // The trace of the extended run starts with the trace so far,
+            // so we do not stream those draws again.
+            let streamed = std::mem::take(&mut self.streamed.output);
+            let mut timings = self.timings.lock().expect("Poisoned timings").clone();
+            timings.restart();
+            *self = sampler;
+            self.streamed.output = streamed;
+            // The monitor of the new sampler shares its timings.
+            *self.timings.lock().expect("Poisoned timings") = timings;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Write the draws so far and the state of all chains to `path`.
+    ///
+    /// Sampling continues while the checkpoint is written.
+    fn checkpoint(&mut self, py: Python<'_>, path: PathBuf) -> PyResult<()> {
+        py.allow_threads(|| self.current_checkpoint()?.write(&path))?;
+        Ok(())
+    }
+
+    /// The draws and sampler stats of each chain since the previous call.
+    ///
+    /// Returns a list of `(chain, draws, stats)` tuples. Unlike `inspect`,
+    /// this only hands out and processes the new rows. nuts-rs has no way
+    /// to return part of its sampler stats though, so it still copies the
+    /// stats of the whole chain internally.
+    fn fetch_new_draws<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        let chains = py.allow_threads(|| self.new_draws())?;
+        let list = PyList::new(
+            py,
+            chains
+                .into_iter()
+                .map(|chain| {
+                    Ok(PyTuple::new(
+                        py,
+                        [
+                            chain.chain_id.into_pyobject(py)?.into_any().unbind(),
+                            export_array(py, chain.draws)?,
+                            export_array(py, chain.stats)?,
+                        ],
+                    )?)
+                })
+                .collect::<Result<Vec<_>>>()?,
+        )?;
+        Ok(list)
+    }
+
+    /// Convergence diagnostics of the posterior draws so far.
+    ///
+    /// Returns a `pyarrow.Table` with the mean, standard deviation,
+    /// `quantiles`, Monte Carlo standard errors, bulk and tail ESS and
+    /// rank-normalized split R-hat of each scalar element of each variable.
+    #[pyo3(signature = (quantiles=vec![0.05, 0.5, 0.95]))]
+    fn diagnostics<'py>(
+        &mut self,
+        py: Python<'py>,
+        quantiles: Vec<f64>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let summary = py.allow_threads(|| {
+            self.consolidate()?;
+            let mut state = self.state.lock().expect("Poisoned sampler state mutex");
+            let trace = match state.deref_mut() {
+                SamplerState::Running(sampler) => {
+                    self.finish_trace(sampler.inspect_trace(InspectMode::Full)?)?
+                }
+                state => match self.finished_trace(state) {
+                    Some(trace) => self.load_trace(trace)?,
+                    None => bail!("Sampler has no trace"),
+                },
+            };
+            drop(state);
+            let num_tune = self.settings.inner.run_shape().num_tune as usize;
+            let draws: Vec<_> = trace
+                .chains
+                .iter()
+                .map(|chain| {
+                    let start = num_tune.min(chain.draws.len());
+                    chain.draws.slice(start, chain.draws.len() - start)
+                })
+                .collect();
+            diagnostics(&draws, &quantiles)
+        })?;
+        let batch = export_batch(py, summary, &HashMap::new())?;
+        let table = py
+            .import("pyarrow")?
+            .getattr("Table")?
+            .call_method1("from_batches", ([batch],))?;
+        Ok(table)
+    }
+
+    /// The step size and mass matrix each chain has reached so far.
+    fn adaptation_state(&mut self, py: Python<'_>) -> PyResult<PyAdaptationState> {
+        let state = py.allow_threads(|| {
+            let checkpoint = self.current_checkpoint()?;
+            Ok::<_, anyhow::Error>(AdaptationState::new(&checkpoint))
+        })?;
+        Ok(PyAdaptationState(state))
     }
 
     fn is_finished(&mut self, py: Python<'_>) -> PyResult<bool> {
         py.allow_threads(|| {
-            let guard = &mut self.0.lock().expect("Poisond sampler state mutex");
+            let guard = &mut self.state.lock().expect("Poisond sampler state mutex");
             let slot = guard.deref_mut();
 
             let state = std::mem::replace(slot, SamplerState::Empty);
@@ -818,16 +2126,18 @@ impl PySampler {
             };
 
             match sampler.wait_timeout(Duration::from_millis(1)) {
-                SamplerWaitResult::Trace(trace) => {
+                WaitResult::Trace(trace) => {
                     let _ = std::mem::replace(slot, SamplerState::Finished(Some(trace)));
+                    self.timings.lock().expect("Poisoned timings").finish();
                     Ok(true)
                 }
-                SamplerWaitResult::Timeout(sampler) => {
+                WaitResult::Timeout(sampler) => {
                     let _ = std::mem::replace(slot, SamplerState::Running(sampler));
                     Ok(false)
                 }
-                SamplerWaitResult::Err(err, trace) => {
+                WaitResult::Err(err, trace) => {
                     let _ = std::mem::replace(slot, SamplerState::Finished(trace));
+                    self.timings.lock().expect("Poisoned timings").finish();
                     Err(err.into())
                 }
             }
@@ -837,7 +2147,7 @@ impl PySampler {
     fn pause(&mut self, py: Python<'_>) -> PyResult<()> {
         py.allow_threads(|| {
             if let SamplerState::Running(ref mut control) = self
-                .0
+                .state
                 .lock()
                 .expect("Poised sampler state mutex")
                 .deref_mut()
@@ -851,7 +2161,7 @@ impl PySampler {
     fn resume(&mut self, py: Python<'_>) -> PyResult<()> {
         py.allow_threads(|| {
             if let SamplerState::Running(ref mut control) = self
-                .0
+                .state
                 .lock()
                 .expect("Poisond sampler state mutex")
                 .deref_mut()
@@ -865,66 +2175,67 @@ impl PySampler {
     #[pyo3(signature = (timeout_seconds=None))]
     fn wait(&mut self, py: Python<'_>, timeout_seconds: Option<f64>) -> PyResult<()> {
         py.allow_threads(|| {
-            let guard = &mut self.0.lock().expect("Poisond sampler state mutex");
-            let slot = guard.deref_mut();
-
             let timeout = match timeout_seconds {
                 Some(val) => Some(Duration::try_from_secs_f64(val).context("Invalid timeout")?),
                 None => None,
             };
 
-            let state = std::mem::replace(slot, SamplerState::Empty);
-
-            let SamplerState::Running(mut control) = state else {
-                let _ = std::mem::replace(slot, state);
-                return Ok(());
-            };
-
             let start_time = Instant::now();
             let step = Duration::from_millis(100);
 
-            let (final_state, retval) = loop {
+            // We only hold the lock for one step at a time, so that the
+            // monitor can get to the sampler in between.
+            loop {
+                let mut guard = self.state.lock().expect("Poisond sampler state mutex");
+                let slot = guard.deref_mut();
+
+                let state = std::mem::replace(slot, SamplerState::Empty);
+
+                let SamplerState::Running(control) = state else {
+                    let _ = std::mem::replace(slot, state);
+                    return Ok(());
+                };
+
                 let time_so_far = Instant::now().saturating_duration_since(start_time);
                 let next_timeout = match timeout {
                     Some(timeout) => {
                         let Some(remaining) = timeout.checked_sub(time_so_far) else {
-                            break (
-                                SamplerState::Running(control),
-                                Err(PyTimeoutError::new_err(
-                                    "Timeout while waiting for sampler to finish",
-                                )),
-                            );
+                            let _ = std::mem::replace(slot, SamplerState::Running(control));
+                            return Err(PyTimeoutError::new_err(
+                                "Timeout while waiting for sampler to finish",
+                            ));
                         };
                         remaining.min(step)
                     }
                     None => step,
                 };
 
-                match control.wait_timeout(next_timeout) {
-                    SamplerWaitResult::Trace(trace) => {
-                        break (SamplerState::Finished(Some(trace)), Ok(()))
-                    }
-                    SamplerWaitResult::Timeout(new_control) => {
-                        control = new_control;
+                let (next_state, retval) = match control.wait_timeout(next_timeout) {
+                    WaitResult::Trace(trace) => (SamplerState::Finished(Some(trace)), Some(Ok(()))),
+                    WaitResult::Timeout(control) => (SamplerState::Running(control), None),
+                    WaitResult::Err(err, trace) => {
+                        (SamplerState::Finished(trace), Some(Err(err.into())))
                     }
-                    SamplerWaitResult::Err(err, trace) => {
-                        break (SamplerState::Finished(trace), Err(err.into()))
-                    }
-                }
+                };
 
-                if let Err(err) = Python::with_gil(|py| py.check_signals()) {
-                    break (SamplerState::Running(control), Err(err));
+                let finished = matches!(next_state, SamplerState::Finished(_));
+                let _ = std::mem::replace(slot, next_state);
+                drop(guard);
+                if finished {
+                    self.timings.lock().expect("Poisoned timings").finish();
+                }
+                if let Some(retval) = retval {
+                    return retval;
                 }
-            };
 
-            let _ = std::mem::replace(slot, final_state);
-            retval
+                Python::with_gil(|py| py.check_signals())?;
+            }
         })
     }
 
     fn abort(&mut self, py: Python<'_>) -> PyResult<()> {
         py.allow_threads(|| {
-            let guard = &mut self.0.lock().expect("Poisond sampler state mutex");
+            let guard = &mut self.state.lock().expect("Poisond sampler state mutex");
             let slot = guard.deref_mut();
 
             let state = std::mem::replace(slot, SamplerState::Empty);
@@ -936,13 +2247,20 @@ impl PySampler {
 
             let (result, trace) = control.abort();
             let _ = std::mem::replace(slot, SamplerState::Finished(trace));
+            self.timings.lock().expect("Poisoned timings").finish();
             result?;
             Ok(())
         })
     }
 
-    fn extract_results<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
-        let guard = &mut self.0.lock().expect("Poisond sampler state mutex");
+    /// The trace of a finished sampler, as a list of `(draws, stats)` per chain.
+    ///
+    /// If the trace is stored on disk, the list contains the paths of the
+    /// files with the draws and sampler stats of each chain instead. For
+    /// a Zarr store we return the path of the store.
+    fn extract_results<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        self.consolidate()?;
+        let mut guard = self.state.lock().expect("Poisond sampler state mutex");
         let slot = guard.deref_mut();
 
         let state = std::mem::replace(slot, SamplerState::Empty);
@@ -951,6 +2269,15 @@ impl PySampler {
             let _ = std::mem::replace(slot, state);
             return Err(anyhow::anyhow!("Sampler is not finished"))?;
         };
+        drop(guard);
+        if let Some(monitor) = &mut self.monitor {
+            // Errors of aborting the sampler when it met the stopping rule.
+            if let Err(err) = monitor.stop() {
+                *self.state.lock().expect("Poisond sampler state mutex") =
+                    SamplerState::Finished(trace);
+                return Err(err)?;
+            }
+        }
 
         let Some(trace) = trace else {
             return Err(anyhow::anyhow!(
@@ -958,11 +2285,80 @@ impl PySampler {
             ))?;
         };
 
-        trace_to_list(trace, py)
+        // Keep the trace around, so that the run can still be extended.
+        // The arrays share their buffers, so this does not copy the draws.
+        let results = clone_trace(&trace);
+        self.extracted = Some(trace);
+
+        if let Some(monitor) = self.monitor.take() {
+            monitor.finish(&results)?;
+        }
+        let provenance = self.finished_provenance()?;
+        let Some(storage) = &self.storage else {
+            let metadata = provenance.metadata()?;
+            return Ok(trace_to_list(results, &metadata, py)?.into_any());
+        };
+        storage.finish_run(provenance.to_value()?)?;
+        let paths: Vec<_> = results
+            .chains
+            .iter()
+            .map(|chain| {
+                (
+                    storage.draws_path(chain.chain_id),
+                    storage.stats_path(chain.chain_id),
+                )
+            })
+            .collect();
+        if storage.format == FileFormat::Zarr {
+            return storage.dir.clone().into_bound_py_any(py);
+        }
+        Ok(PyList::new(py, paths)?.into_any())
+    }
+
+    /// The JSON record of how the finished trace was produced, or `None`
+    /// before the results were extracted. Trace files only record the
+    /// provenance of the run as it started.
+    #[getter]
+    fn provenance_json(&self) -> Result<Option<String>> {
+        if self.extracted.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::to_string(&self.finished_provenance()?)?))
+    }
+
+    /// Why sampling stopped early, or `None` if it did not.
+    #[getter]
+    fn stop_reason<'py>(&self, py: Python<'py>) -> Result<Option<Bound<'py, PyDict>>> {
+        self.stopping()
+            .and_then(|feed| feed.stopping().stopped().cloned())
+            .map(|reason| to_py(py, &reason))
+            .transpose()
+    }
+
+    /// How each chain found its initial point.
+    ///
+    /// Maps the chain index to the number of candidate points the chain
+    /// tried and the reasons why the failed ones were rejected. Chains that
+    /// were continued from a checkpoint or did not start yet are missing.
+    #[getter]
+    fn init_report(&self) -> BTreeMap<u64, (usize, String)> {
+        self.registry
+            .snapshot()
+            .into_iter()
+            .filter_map(|(chain, record)| {
+                let init = record.init?;
+                Some((chain, (init.attempts, init.summary())))
+            })
+            .collect()
     }
 
     fn is_empty(&self) -> bool {
-        match self.0.lock().expect("Poisoned sampler state lock").deref() {
+        match self
+            .state
+            .lock()
+            .expect("Poisoned sampler state lock")
+            .deref()
+        {
             SamplerState::Running(_) => false,
             SamplerState::Finished(_) => false,
             SamplerState::Empty => true,
@@ -971,18 +2367,40 @@ impl PySampler {
 
     fn inspect<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
         let trace = py.allow_threads(|| {
-            let mut guard = self.0.lock().unwrap();
+            let mut guard = self.state.lock().unwrap();
             let SamplerState::Running(ref mut sampler) = guard.deref_mut() else {
                 return Err(anyhow::anyhow!("Sampler is not running"))?;
             };
 
-            sampler.inspect_trace()
+            let trace = sampler.inspect_trace(InspectMode::Full)?;
+            self.finish_trace(trace)
         })?;
-        trace_to_list(trace, py)
+        let metadata = self.provenance()?.metadata()?;
+        trace_to_list(trace, &metadata, py)
+    }
+}
+
+fn clone_trace(trace: &Trace) -> Trace {
+    Trace {
+        chains: trace
+            .chains
+            .iter()
+            .map(|chain| ChainOutput {
+                draws: chain.draws.clone(),
+                stats: chain.stats.clone(),
+                chain_id: chain.chain_id,
+            })
+            .collect(),
     }
 }
 
-fn trace_to_list(trace: Trace, py: Python<'_>) -> PyResult<Bound<'_, PyList>> {
+/// The draws and stats of each chain as `pyarrow.RecordBatch` objects,
+/// with `metadata` in their schema.
+fn trace_to_list<'py>(
+    trace: Trace,
+    metadata: &HashMap<String, String>,
+    py: Python<'py>,
+) -> PyResult<Bound<'py, PyList>> {
     let list = PyList::new(
         py,
         trace
@@ -992,10 +2410,9 @@ fn trace_to_list(trace: Trace, py: Python<'_>) -> PyResult<Bound<'_, PyList>> {
                 Ok(PyTuple::new(
                     py,
                     [
-                        export_array(py, chain.draws)?,
-                        export_array(py, chain.stats)?,
-                    ]
-                    .into_iter(),
+                        export_batch(py, chain.draws, metadata)?,
+                        export_batch(py, chain.stats, metadata)?,
+                    ],
                 )?)
             })
             .collect::<Result<Vec<_>>>()?,
@@ -1003,7 +2420,7 @@ fn trace_to_list(trace: Trace, py: Python<'_>) -> PyResult<Bound<'_, PyList>> {
     Ok(list)
 }
 
-fn export_array(py: Python<'_>, data: Arc<dyn Array>) -> PyResult<PyObject> {
+pub(crate) fn export_array(py: Python<'_>, data: Arc<dyn Array>) -> PyResult<PyObject> {
     let pa = py.import("pyarrow")?;
     let array = pa.getattr("Array")?;
 
@@ -1023,22 +2440,125 @@ fn export_array(py: Python<'_>, data: Arc<dyn Array>) -> PyResult<PyObject> {
     Ok(data.unbind())
 }
 
+/// Export a struct array as a record batch, which unlike a pyarrow array
+/// keeps the metadata of its schema.
+fn export_batch(
+    py: Python<'_>,
+    data: Arc<dyn Array>,
+    metadata: &HashMap<String, String>,
+) -> PyResult<PyObject> {
+    let pa = py.import("pyarrow")?;
+    let batch = pa.getattr("RecordBatch")?;
+
+    let field = Field::new("", data.data_type().clone(), false).with_metadata(metadata.clone());
+    let schema = FFI_ArrowSchema::try_from(&field).context("Could not convert to arrow ffi")?;
+    let data = FFI_ArrowArray::new(&data.into_data());
+
+    let data = batch
+        .call_method1(
+            "_import_from_c",
+            (
+                (&data as *const _ as Py_uintptr_t).into_pyobject(py)?,
+                (&schema as *const _ as Py_uintptr_t).into_pyobject(py)?,
+            ),
+        )
+        .context("Could not import arrow trace in python")?;
+    Ok(data.unbind())
+}
+
 #[pyclass]
 #[derive(Debug, Clone)]
-pub struct PyTransformAdapt(Arc<Py<PyAny>>);
+pub struct PyTransformAdapt {
+    factory: Arc<Py<PyAny>>,
+    run: Option<Arc<TransformRun>>,
+}
+
+/// What the transformations of the chains of one run share.
+#[derive(Debug)]
+struct TransformRun {
+    /// Where we record the transformation of each chain, so that we can
+    /// checkpoint it.
+    registry: ChainRegistry,
+    /// Adapter states to restore in the transformations of the chains.
+    states: BTreeMap<u64, String>,
+    /// Scale of the transformation of each chain, see
+    /// `MetricOptions::set_step_size`.
+    step_scales: BTreeMap<u64, f64>,
+}
+
+/// The transformation of a chain, which the Python adapter updates in
+/// place, scaled by `scale`.
+#[derive(Debug)]
+pub struct PyTransformation {
+    adapter: Py<PyAny>,
+    scale: f64,
+}
+
+impl PyTransformation {
+    /// Map a position on the scaled space to the one of the adapter.
+    fn unscale_position(&self, py: Python<'_>, position: &[f64]) -> Py<PyArray1<f64>> {
+        PyArray1::from_iter(py, position.iter().map(|x| x * self.scale)).unbind()
+    }
+
+    fn scale_position(&self, position: &mut [f64]) {
+        position.iter_mut().for_each(|x| *x /= self.scale);
+    }
+
+    fn scale_gradient(&self, gradient: &mut [f64]) {
+        gradient.iter_mut().for_each(|x| *x *= self.scale);
+    }
+
+    fn scale_logdet(&self, logdet: f64, dim: usize) -> f64 {
+        logdet + self.scale.ln() * dim as f64
+    }
+}
 
 #[pymethods]
 impl PyTransformAdapt {
     #[new]
     pub fn new(adapter: Py<PyAny>) -> Self {
-        Self(Arc::new(adapter))
+        Self {
+            factory: Arc::new(adapter),
+            run: None,
+        }
     }
 }
 
 impl PyTransformAdapt {
+    /// An adapter for a new run, which records the transformations of its
+    /// chains in `registry`. The transformations start from the adapter
+    /// states in `states`, and are scaled by `step_scales`.
+    pub(crate) fn for_run(
+        &self,
+        registry: &ChainRegistry,
+        states: BTreeMap<u64, String>,
+        step_scales: BTreeMap<u64, f64>,
+    ) -> Self {
+        Self {
+            factory: self.factory.clone(),
+            run: Some(Arc::new(TransformRun {
+                registry: registry.clone(),
+                states,
+                step_scales,
+            })),
+        }
+    }
+
+    /// The state of the Python adapter of a transformation, which
+    /// `set_state` of a new adapter restores.
+    pub(crate) fn state(adapter: &Py<PyAny>) -> Result<String> {
+        Python::with_gil(|py| {
+            adapter
+                .call_method0(py, intern!(py, "get_state"))
+                .context("Failed to call adapter.get_state")?
+                .extract(py)
+                .context("adapter.get_state must return a string")
+        })
+    }
+
     pub fn inv_transform_normalize(
         &mut self,
-        params: &Py<PyAny>,
+        params: &PyTransformation,
         untransformed_position: &[f64],
         untransformed_gradient: &[f64],
         transformed_position: &mut [f64],
@@ -1049,6 +2569,7 @@ impl PyTransformAdapt {
             let untransformed_gradient = PyArray1::from_slice(py, untransformed_gradient);
 
             let output = params
+                .adapter
                 .getattr(py, intern!(py, "inv_transform"))
                 .context("Could not access attribute inv_transform")?
                 .call1(py, (untransformed_position, untransformed_gradient))
@@ -1087,24 +2608,27 @@ impl PyTransformAdapt {
                     .as_slice()
                     .context("Could not copy transformed_gradient")?,
             );
-            Ok(logdet)
+            params.scale_position(transformed_position);
+            params.scale_gradient(transformed_gradient);
+            Ok(params.scale_logdet(logdet, transformed_position.len()))
         })
     }
 
     pub fn init_from_transformed_position(
         &mut self,
-        params: &Py<PyAny>,
+        params: &PyTransformation,
         untransformed_position: &mut [f64],
         untransformed_gradient: &mut [f64],
         transformed_position: &[f64],
         transformed_gradient: &mut [f64],
     ) -> Result<(f64, f64)> {
         Python::with_gil(|py| {
-            let transformed_position = PyArray1::from_slice(py, transformed_position);
+            let position = params.unscale_position(py, transformed_position);
 
             let output = params
+                .adapter
                 .getattr(py, intern!(py, "init_from_transformed_position"))?
-                .call1(py, (transformed_position,))?;
+                .call1(py, (position,))?;
             let (
                 logp,
                 logdet,
@@ -1122,22 +2646,27 @@ impl PyTransformAdapt {
             untransformed_position.copy_from_slice(untransformed_position_out.as_slice()?);
             untransformed_gradient.copy_from_slice(untransformed_gradient_out.as_slice()?);
             transformed_gradient.copy_from_slice(transformed_gradient_out.as_slice()?);
-            Ok((logp, logdet))
+            params.scale_gradient(transformed_gradient);
+            Ok((
+                logp,
+                params.scale_logdet(logdet, transformed_position.len()),
+            ))
         })
     }
 
     pub fn init_from_transformed_position_part1(
         &mut self,
-        params: &Py<PyAny>,
+        params: &PyTransformation,
         untransformed_position: &mut [f64],
         transformed_position: &[f64],
     ) -> Result<Py<PyAny>> {
         Python::with_gil(|py| {
-            let transformed_position = PyArray1::from_slice(py, transformed_position);
+            let position = params.unscale_position(py, transformed_position);
 
             let output = params
+                .adapter
                 .getattr(py, intern!(py, "init_from_transformed_position_part1"))?
-                .call1(py, (transformed_position,))?;
+                .call1(py, (position,))?;
             let (untransformed_position_out, part1): (PyReadonlyArray1<f64>, Py<PyAny>) =
                 output.extract(py)?;
 
@@ -1148,7 +2677,7 @@ impl PyTransformAdapt {
 
     pub fn init_from_transformed_position_part2(
         &mut self,
-        params: &Py<PyAny>,
+        params: &PyTransformation,
         part1: Py<PyAny>,
         untransformed_gradient: &[f64],
         transformed_gradient: &mut [f64],
@@ -1157,19 +2686,21 @@ impl PyTransformAdapt {
             let untransformed_gradient = PyArray1::from_slice(py, untransformed_gradient);
 
             let output = params
+                .adapter
                 .getattr(py, intern!(py, "init_from_transformed_position_part2"))?
                 .call1(py, (part1, untransformed_gradient))?;
             let (logdet, transformed_gradient_out): (f64, PyReadonlyArray1<f64>) =
                 output.extract(py)?;
 
             transformed_gradient.copy_from_slice(transformed_gradient_out.as_slice()?);
-            Ok(logdet)
+            params.scale_gradient(transformed_gradient);
+            Ok(params.scale_logdet(logdet, transformed_gradient.len()))
         })
     }
 
     pub fn init_from_untransformed_position(
         &mut self,
-        params: &Py<PyAny>,
+        params: &PyTransformation,
         untransformed_position: &[f64],
         untransformed_gradient: &mut [f64],
         transformed_position: &mut [f64],
@@ -1179,6 +2710,7 @@ impl PyTransformAdapt {
             let untransformed_position = PyArray1::from_slice(py, untransformed_position);
 
             let output = params
+                .adapter
                 .getattr(py, intern!(py, "init_from_untransformed_position"))
                 .context("No attribute init_from_untransformed_position")?
                 .call1(py, (untransformed_position,))
@@ -1202,7 +2734,12 @@ impl PyTransformAdapt {
             untransformed_gradient.copy_from_slice(untransformed_gradient_out.as_slice()?);
             transformed_position.copy_from_slice(transformed_position_out.as_slice()?);
             transformed_gradient.copy_from_slice(transformed_gradient_out.as_slice()?);
-            Ok((logp, logdet))
+            params.scale_position(transformed_position);
+            params.scale_gradient(transformed_gradient);
+            Ok((
+                logp,
+                params.scale_logdet(logdet, transformed_position.len()),
+            ))
         })
     }
 
@@ -1212,7 +2749,7 @@ impl PyTransformAdapt {
         untransformed_positions: impl ExactSizeIterator<Item = &'a [f64]>,
         untransformed_gradients: impl ExactSizeIterator<Item = &'a [f64]>,
         untransformed_logp: impl ExactSizeIterator<Item = &'a f64>,
-        params: &'a mut Py<PyAny>,
+        params: &'a mut PyTransformation,
     ) -> Result<()> {
         Python::with_gil(|py| {
             let positions = PyList::new(
@@ -1228,6 +2765,7 @@ impl PyTransformAdapt {
             let seed = rng.next_u64();
 
             params
+                .adapter
                 .getattr(py, intern!(py, "update"))?
                 .call1(py, (seed, positions, gradients, logps))?;
             Ok(())
@@ -1240,22 +2778,38 @@ impl PyTransformAdapt {
         untransformed_position: &[f64],
         untransformed_gradient: &[f64],
         chain: u64,
-    ) -> Result<Py<PyAny>> {
+    ) -> Result<PyTransformation> {
         Python::with_gil(|py| {
             let position = PyArray1::from_slice(py, untransformed_position);
             let gradient = PyArray1::from_slice(py, untransformed_gradient);
 
             let seed = rng.next_u64();
 
-            let transformer = self.0.call1(py, (seed, position, gradient, chain))?;
+            let adapter = self.factory.call1(py, (seed, position, gradient, chain))?;
 
-            Ok(transformer)
+            let Some(run) = &self.run else {
+                return Ok(PyTransformation { adapter, scale: 1. });
+            };
+            if let Some(state) = run.states.get(&chain) {
+                adapter
+                    .call_method1(py, intern!(py, "set_state"), (state,))
+                    .with_context(|| {
+                        format!("Could not restore the transformation of chain {chain}")
+                    })?;
+            }
+            run.registry
+                .set_transform(chain, Arc::new(adapter.clone_ref(py)));
+            Ok(PyTransformation {
+                adapter,
+                scale: run.step_scales.get(&chain).copied().unwrap_or(1.),
+            })
         })
     }
 
-    pub fn transformation_id(&self, params: &Py<PyAny>) -> Result<i64> {
+    pub fn transformation_id(&self, params: &PyTransformation) -> Result<i64> {
         Python::with_gil(|py| {
             let id: i64 = params
+                .adapter
                 .getattr(py, intern!(py, "transformation_id"))?
                 .extract(py)?;
             Ok(id)
@@ -1267,13 +2821,25 @@ impl PyTransformAdapt {
 #[pymodule]
 pub fn _lib(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<PySampler>()?;
+    m.add_class::<MassMatrix>()?;
     m.add_class::<PyMcModel>()?;
     m.add_class::<LogpFunc>()?;
     m.add_class::<ExpandFunc>()?;
     m.add_class::<StanLibrary>()?;
     m.add_class::<StanModel>()?;
     m.add_class::<PyNutsSettings>()?;
+    m.add_class::<PyAdaptationState>()?;
+    m.add_class::<TraceStorage>()?;
+    m.add_class::<StoppingRule>()?;
+    m.add_class::<GradientCheck>()?;
+    m.add_class::<InitStrategy>()?;
+    m.add_class::<PathfinderSettings>()?;
+    m.add_class::<PathfinderResult>()?;
+    m.add_class::<MapResult>()?;
+    m.add_class::<LaplaceResult>()?;
     m.add_class::<PyChainProgress>()?;
+    m.add_class::<ChainProgressRecord>()?;
+    m.add_class::<SamplerProgress>()?;
     m.add_class::<ProgressType>()?;
     m.add_class::<TensorShape>()?;
     m.add_class::<PyModel>()?;
@@ -0,0 +1,947 @@
+//! Traces in Zarr v3 stores, in the layout ArviZ uses for `InferenceData`.
+//!
+//! The store has the groups `posterior` and `sample_stats`, and
+//! `warmup_posterior` and `warmup_sample_stats` for the tuning draws. Each
+//! variable is an array with dimensions `(chain, draw, *shape)`, chunked
+//! along the draws of each chain, so that chains can write their chunks
+//! independently. Coordinates are arrays named after their dimension, and
+//! the root array `chain_length` records how many draws each chain wrote,
+//! because the chunks at the end of a chain are padded with fill values.
+//! If a chain stops early, we shorten the `draw` dimension of all arrays to
+//! the draws of the shortest chain once the run is finished, so that
+//! readers do not see the padding as draws.
+//!
+//! The groups are created with the store when the run starts, and the
+//! metadata of the arrays of a group when the first chain writes to them.
+//! The root and each group carry the provenance record of the run as a JSON
+//! string in their `nutpie` attribute, like the groups of the `InferenceData`
+//! of a trace in memory. We rewrite it with the final timings at the end.
+//!
+//! We only use the `bytes` codec for numbers and `vlen-utf8` for string
+//! coordinates, so that we do not need a compression library.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{bail, Context, Result};
+use arrow::{
+    array::{
+        make_array, Array, ArrayData, ArrayRef, AsArray, BooleanArray, FixedSizeListArray,
+        LargeListArray, ListArray, StructArray,
+    },
+    buffer::{Buffer, OffsetBuffer},
+    datatypes::{
+        DataType, Fields, Float32Type, Float64Type, Int32Type, Int64Type, UInt32Type, UInt64Type,
+    },
+};
+use pyo3::prelude::*;
+use serde_json::{json, Value};
+
+use crate::{provenance::METADATA_KEY, storage::StorageOptions};
+
+/// Values of a coordinate, as given from python.
+#[derive(Clone, Debug)]
+pub(crate) enum CoordValues {
+    Int(Vec<i64>),
+    Float(Vec<f64>),
+    Str(Vec<String>),
+}
+
+impl CoordValues {
+    pub(crate) fn from_py(values: &Bound<PyAny>) -> PyResult<Self> {
+        if let Ok(values) = values.extract() {
+            return Ok(CoordValues::Int(values));
+        }
+        if let Ok(values) = values.extract() {
+            return Ok(CoordValues::Float(values));
+        }
+        let values = values
+            .try_iter()?
+            .map(|value| Ok(value?.str()?.to_string()))
+            .collect::<PyResult<_>>()?;
+        Ok(CoordValues::Str(values))
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            CoordValues::Int(values) => values.len(),
+            CoordValues::Float(values) => values.len(),
+            CoordValues::Str(values) => values.len(),
+        }
+    }
+}
+
+/// Shapes, dimension names and coordinates of the variables of a model.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TraceLayout {
+    pub(crate) shapes: HashMap<String, Vec<usize>>,
+    pub(crate) dims: HashMap<String, Vec<String>>,
+    pub(crate) coords: HashMap<String, CoordValues>,
+}
+
+impl TraceLayout {
+    pub(crate) fn from_py(
+        shapes: HashMap<String, Vec<usize>>,
+        dims: HashMap<String, Vec<String>>,
+        coords: &Bound<PyAny>,
+    ) -> PyResult<Self> {
+        let coords = coords
+            .call_method0("items")?
+            .try_iter()?
+            .map(|item| {
+                let (name, values): (String, Bound<PyAny>) = item?.extract()?;
+                Ok((name, CoordValues::from_py(&values)?))
+            })
+            .collect::<PyResult<_>>()?;
+        Ok(Self {
+            shapes,
+            dims,
+            coords,
+        })
+    }
+
+    fn dim_names(&self, name: &str, shape: &[usize]) -> Vec<String> {
+        match self.dims.get(name) {
+            Some(dims) if dims.len() == shape.len() => dims.clone(),
+            _ => (0..shape.len())
+                .map(|i| format!("{}_dim_{}", name, i))
+                .collect(),
+        }
+    }
+}
+
+/// Which part of the trace of a chain we write.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Part {
+    Draws,
+    Stats,
+}
+
+impl Part {
+    fn index(&self) -> usize {
+        match self {
+            Part::Draws => 0,
+            Part::Stats => 1,
+        }
+    }
+
+    /// The groups for the tuning draws and the posterior draws.
+    fn groups(&self) -> [&'static str; 2] {
+        match self {
+            Part::Draws => ["warmup_posterior", "posterior"],
+            Part::Stats => ["warmup_sample_stats", "sample_stats"],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ZarrType {
+    Bool,
+    Int32,
+    Int64,
+    UInt32,
+    UInt64,
+    Float32,
+    Float64,
+}
+
+impl ZarrType {
+    fn from_arrow(dtype: &DataType) -> Option<Self> {
+        Some(match dtype {
+            DataType::Boolean => ZarrType::Bool,
+            DataType::Int32 => ZarrType::Int32,
+            DataType::Int64 => ZarrType::Int64,
+            DataType::UInt32 => ZarrType::UInt32,
+            DataType::UInt64 => ZarrType::UInt64,
+            DataType::Float32 => ZarrType::Float32,
+            DataType::Float64 => ZarrType::Float64,
+            _ => return None,
+        })
+    }
+
+    fn arrow(&self) -> DataType {
+        match self {
+            ZarrType::Bool => DataType::Boolean,
+            ZarrType::Int32 => DataType::Int32,
+            ZarrType::Int64 => DataType::Int64,
+            ZarrType::UInt32 => DataType::UInt32,
+            ZarrType::UInt64 => DataType::UInt64,
+            ZarrType::Float32 => DataType::Float32,
+            ZarrType::Float64 => DataType::Float64,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ZarrType::Bool => "bool",
+            ZarrType::Int32 => "int32",
+            ZarrType::Int64 => "int64",
+            ZarrType::UInt32 => "uint32",
+            ZarrType::UInt64 => "uint64",
+            ZarrType::Float32 => "float32",
+            ZarrType::Float64 => "float64",
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            ZarrType::Bool => 1,
+            ZarrType::Int32 | ZarrType::UInt32 | ZarrType::Float32 => 4,
+            ZarrType::Int64 | ZarrType::UInt64 | ZarrType::Float64 => 8,
+        }
+    }
+
+    fn fill_value(&self) -> Value {
+        match self {
+            ZarrType::Bool => json!(false),
+            ZarrType::Float32 | ZarrType::Float64 => json!("NaN"),
+            _ => json!(0),
+        }
+    }
+
+    fn fill_bytes(&self) -> Vec<u8> {
+        match self {
+            ZarrType::Float32 => f32::NAN.to_le_bytes().to_vec(),
+            ZarrType::Float64 => f64::NAN.to_le_bytes().to_vec(),
+            _ => vec![0; self.size()],
+        }
+    }
+}
+
+/// The little endian bytes of the values of a primitive array.
+fn element_bytes(values: &dyn Array) -> Result<Vec<u8>> {
+    fn bytes<const N: usize, T: Copy>(values: &[T], f: impl Fn(T) -> [u8; N]) -> Vec<u8> {
+        values.iter().flat_map(|&value| f(value)).collect()
+    }
+    Ok(match values.data_type() {
+        DataType::Boolean => values.as_boolean().values().iter().map(u8::from).collect(),
+        DataType::Int32 => bytes(
+            values.as_primitive::<Int32Type>().values(),
+            i32::to_le_bytes,
+        ),
+        DataType::Int64 => bytes(
+            values.as_primitive::<Int64Type>().values(),
+            i64::to_le_bytes,
+        ),
+        DataType::UInt32 => bytes(
+            values.as_primitive::<UInt32Type>().values(),
+            u32::to_le_bytes,
+        ),
+        DataType::UInt64 => bytes(
+            values.as_primitive::<UInt64Type>().values(),
+            u64::to_le_bytes,
+        ),
+        DataType::Float32 => bytes(
+            values.as_primitive::<Float32Type>().values(),
+            f32::to_le_bytes,
+        ),
+        DataType::Float64 => bytes(
+            values.as_primitive::<Float64Type>().values(),
+            f64::to_le_bytes,
+        ),
+        dtype => bail!("Unsupported data type in trace: {}", dtype),
+    })
+}
+
+/// A field of the trace arrays and how we store it.
+struct Column {
+    name: String,
+    dtype: ZarrType,
+    shape: Vec<usize>,
+}
+
+impl Column {
+    /// Returns `None` for fields we do not store.
+    fn new(
+        name: &str,
+        dtype: &DataType,
+        part: Part,
+        layout: &TraceLayout,
+    ) -> Result<Option<Column>> {
+        if let Part::Stats = part {
+            // Those are coordinates or not numeric.
+            if ["chain", "draw", "divergence_message"].contains(&name) {
+                return Ok(None);
+            }
+        }
+        let (item, size) = match dtype {
+            DataType::FixedSizeList(item, size) => (item.data_type(), Some(*size as usize)),
+            DataType::List(item) | DataType::LargeList(item) => (item.data_type(), None),
+            dtype => (dtype, Some(1)),
+        };
+        let Some(zarr_type) = ZarrType::from_arrow(item) else {
+            if let Part::Stats = part {
+                return Ok(None);
+            }
+            bail!("Variable {} has unsupported type {}", name, dtype);
+        };
+        let shape = match (part, layout.shapes.get(name)) {
+            (Part::Draws, Some(shape)) => shape.clone(),
+            _ => match (dtype, size) {
+                (DataType::FixedSizeList(_, _), Some(size)) => vec![size],
+                (_, Some(_)) => vec![],
+                (_, None) if matches!(part, Part::Stats) => return Ok(None),
+                (_, None) => bail!("Unknown shape of variable {}", name),
+            },
+        };
+        Ok(Some(Column {
+            name: name.to_string(),
+            dtype: zarr_type,
+            shape,
+        }))
+    }
+
+    fn width(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    /// The flat values of an arrow column, and where each row starts, if
+    /// it has the expected number of values.
+    fn rows(&self, column: &dyn Array) -> Result<(Vec<u8>, Vec<Option<usize>>)> {
+        let width = self.width();
+        let check =
+            |start: usize, len: usize, valid: bool| (valid && len == width).then_some(start);
+        let (values, starts): (ArrayRef, Vec<_>) = match column.data_type() {
+            DataType::FixedSizeList(_, size) => {
+                let list = column.as_fixed_size_list();
+                let starts = (0..list.len())
+                    .map(|i| {
+                        check(
+                            list.value_offset(i) as usize,
+                            *size as usize,
+                            list.is_valid(i),
+                        )
+                    })
+                    .collect();
+                (list.values().clone(), starts)
+            }
+            DataType::List(_) => {
+                let list = column.as_list::<i32>();
+                let offsets = list.value_offsets();
+                let starts = (0..list.len())
+                    .map(|i| {
+                        let len = (offsets[i + 1] - offsets[i]) as usize;
+                        check(offsets[i] as usize, len, list.is_valid(i))
+                    })
+                    .collect();
+                (list.values().clone(), starts)
+            }
+            DataType::LargeList(_) => {
+                let list = column.as_list::<i64>();
+                let offsets = list.value_offsets();
+                let starts = (0..list.len())
+                    .map(|i| {
+                        let len = (offsets[i + 1] - offsets[i]) as usize;
+                        check(offsets[i] as usize, len, list.is_valid(i))
+                    })
+                    .collect();
+                (list.values().clone(), starts)
+            }
+            _ => {
+                let starts = (0..column.len())
+                    .map(|i| check(i, 1, column.is_valid(i)))
+                    .collect();
+                (column.slice(0, column.len()), starts)
+            }
+        };
+        Ok((element_bytes(values.as_ref())?, starts))
+    }
+
+    /// Build an arrow column of type `dtype` from the flat values.
+    fn to_arrow(&self, dtype: &DataType, values: Vec<u8>, rows: usize) -> Result<ArrayRef> {
+        let len = rows * self.width();
+        let values = match self.dtype {
+            ZarrType::Bool => Arc::new(
+                values
+                    .iter()
+                    .map(|&value| Some(value != 0))
+                    .collect::<BooleanArray>(),
+            ) as _,
+            zarr_type => make_array(
+                ArrayData::builder(zarr_type.arrow())
+                    .len(len)
+                    .add_buffer(Buffer::from_vec(values))
+                    .build()?,
+            ),
+        };
+        let lengths = std::iter::repeat(self.width()).take(rows);
+        Ok(match dtype {
+            DataType::FixedSizeList(item, size) => Arc::new(FixedSizeListArray::try_new(
+                item.clone(),
+                *size,
+                values,
+                None,
+            )?),
+            DataType::List(item) => Arc::new(ListArray::try_new(
+                item.clone(),
+                OffsetBuffer::from_lengths(lengths),
+                values,
+                None,
+            )?),
+            DataType::LargeList(item) => Arc::new(LargeListArray::try_new(
+                item.clone(),
+                OffsetBuffer::from_lengths(lengths),
+                values,
+                None,
+            )?),
+            _ => values,
+        })
+    }
+}
+
+/// The shape of one array of a column, in the warmup or posterior group.
+struct ArrayLayout {
+    dir: PathBuf,
+    num_draws: usize,
+    chunk_len: usize,
+}
+
+impl ArrayLayout {
+    fn new(dir: PathBuf, num_draws: usize, buffer_draws: usize) -> Self {
+        Self {
+            dir,
+            num_draws,
+            chunk_len: buffer_draws.min(num_draws).max(1),
+        }
+    }
+
+    fn chunk_path(&self, chain: u64, chunk: usize, ndim: usize) -> PathBuf {
+        let mut path = self
+            .dir
+            .join("c")
+            .join(chain.to_string())
+            .join(chunk.to_string());
+        for _ in 0..ndim {
+            path.push("0");
+        }
+        path
+    }
+}
+
+/// The warmup and posterior arrays of each stored field of a trace.
+struct StoreLayout {
+    columns: Vec<(usize, Column, [ArrayLayout; 2])>,
+    num_tune: usize,
+}
+
+impl StoreLayout {
+    fn new(storage: &StorageOptions, part: Part, fields: &Fields) -> Result<Self> {
+        let run = storage
+            .run
+            .as_ref()
+            .context("Zarr storage needs to know the number of draws")?;
+        let groups = part.groups();
+        let mut columns = Vec::new();
+        for (i, field) in fields.iter().enumerate() {
+            let Some(column) = Column::new(field.name(), field.data_type(), part, &storage.layout)?
+            else {
+                continue;
+            };
+            let arrays = [
+                ArrayLayout::new(
+                    storage.dir.join(groups[0]).join(&column.name),
+                    run.num_tune as usize,
+                    storage.buffer_draws,
+                ),
+                ArrayLayout::new(
+                    storage.dir.join(groups[1]).join(&column.name),
+                    run.num_draws as usize,
+                    storage.buffer_draws,
+                ),
+            ];
+            columns.push((i, column, arrays));
+        }
+        Ok(Self {
+            columns,
+            num_tune: run.num_tune as usize,
+        })
+    }
+
+    /// The array and the index in it of a row of the trace of a chain.
+    fn locate(&self, row: usize) -> (usize, usize) {
+        if row < self.num_tune {
+            (0, row)
+        } else {
+            (1, row - self.num_tune)
+        }
+    }
+}
+
+/// The parts of a store whose arrays exist already.
+#[derive(Debug, Default)]
+pub(crate) struct StoreArrays(Mutex<[bool; 2]>);
+
+impl StoreArrays {
+    /// Write the metadata of the arrays of `part`, unless another chain
+    /// did that already.
+    fn create(&self, part: Part, write: impl FnOnce() -> Result<()>) -> Result<()> {
+        let mut created = self.0.lock().expect("Poisoned zarr store lock");
+        if !created[part.index()] {
+            write()?;
+            created[part.index()] = true;
+        }
+        Ok(())
+    }
+}
+
+static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+/// Replace a file in one step, so that readers never see a partial file.
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = path.parent().context("Invalid path in zarr store")?;
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Could not create directory {}", dir.display()))?;
+    let tmp = dir.join(format!(
+        ".{}.{}.{}.tmp",
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("file"),
+        std::process::id(),
+        NEXT_TMP.fetch_add(1, Ordering::Relaxed),
+    ));
+    std::fs::write(&tmp, contents).with_context(|| format!("Could not write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Could not write {}", path.display()))?;
+    Ok(())
+}
+
+fn write_json(dir: &Path, value: &Value) -> Result<()> {
+    write_atomic(
+        &dir.join("zarr.json"),
+        serde_json::to_string_pretty(value)?.as_bytes(),
+    )
+}
+
+fn write_group(dir: &Path, provenance: &Value) -> Result<()> {
+    let mut attributes = json!({
+        "inference_library": "nutpie",
+        "inference_library_version": env!("CARGO_PKG_VERSION"),
+    });
+    if !provenance.is_null() {
+        attributes[METADATA_KEY] = provenance.to_string().into();
+    }
+    write_json(
+        dir,
+        &json!({"zarr_format": 3, "node_type": "group", "attributes": attributes}),
+    )
+}
+
+/// Write the root group and the groups of the trace, with the provenance
+/// record of the run in their attributes.
+pub(crate) fn write_attributes(dir: &Path, provenance: &Value) -> Result<()> {
+    let mut attributes = json!({});
+    if !provenance.is_null() {
+        attributes[METADATA_KEY] = provenance.to_string().into();
+    }
+    write_json(
+        dir,
+        &json!({"zarr_format": 3, "node_type": "group", "attributes": attributes}),
+    )?;
+    for part in [Part::Draws, Part::Stats] {
+        for group in part.groups() {
+            write_group(&dir.join(group), provenance)?;
+        }
+    }
+    Ok(())
+}
+
+fn array_metadata(
+    data_type: &str,
+    shape: &[usize],
+    chunk_shape: &[usize],
+    fill_value: Value,
+    dims: &[String],
+) -> Value {
+    let codec = if data_type == "string" {
+        json!({"name": "vlen-utf8", "configuration": {}})
+    } else {
+        json!({"name": "bytes", "configuration": {"endian": "little"}})
+    };
+    json!({
+        "zarr_format": 3,
+        "node_type": "array",
+        "shape": shape,
+        "data_type": data_type,
+        "chunk_grid": {"name": "regular", "configuration": {"chunk_shape": chunk_shape}},
+        "chunk_key_encoding": {"name": "default", "configuration": {"separator": "/"}},
+        "fill_value": fill_value,
+        "codecs": [codec],
+        "attributes": {},
+        "dimension_names": dims,
+    })
+}
+
+/// Write a one-dimensional coordinate array with a single chunk.
+fn write_coord(group: &Path, dim: &str, values: &CoordValues) -> Result<()> {
+    let dir = group.join(dim);
+    let len = values.len();
+    let (data_type, fill_value, chunk) = match values {
+        CoordValues::Int(values) => (
+            "int64",
+            json!(0),
+            values
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect(),
+        ),
+        CoordValues::Float(values) => (
+            "float64",
+            json!("NaN"),
+            values
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect(),
+        ),
+        CoordValues::Str(values) => {
+            let mut chunk = (len as u32).to_le_bytes().to_vec();
+            for value in values {
+                chunk.extend((value.len() as u32).to_le_bytes());
+                chunk.extend(value.as_bytes());
+            }
+            ("string", json!(""), chunk)
+        }
+    };
+    write_json(
+        &dir,
+        &array_metadata(
+            data_type,
+            &[len],
+            &[len.max(1)],
+            fill_value,
+            &[dim.to_string()],
+        ),
+    )?;
+    if len > 0 {
+        write_atomic(&dir.join("c").join("0"), &chunk)?;
+    }
+    Ok(())
+}
+
+fn range_coord(len: usize) -> CoordValues {
+    CoordValues::Int((0..len as i64).collect())
+}
+
+/// Create the store with its groups, but without any arrays.
+pub(crate) fn create_store(storage: &StorageOptions) -> Result<()> {
+    let num_chains = storage.run.as_ref().map_or(0, |run| run.num_chains);
+    write_attributes(&storage.dir, &storage.provenance)?;
+    write_json(
+        &storage.dir.join("chain_length"),
+        &array_metadata(
+            "int64",
+            &[num_chains],
+            &[1],
+            json!(0),
+            &["chain".to_string()],
+        ),
+    )?;
+    Ok(())
+}
+
+/// Write the metadata of the arrays and coordinates of a part of the trace.
+fn write_arrays(storage: &StorageOptions, part: Part, layout: &StoreLayout) -> Result<()> {
+    let num_chains = storage.run.as_ref().map_or(0, |run| run.num_chains);
+    for (i, group) in part.groups().into_iter().enumerate() {
+        let group = storage.dir.join(group);
+        let mut coords: HashMap<String, usize> = HashMap::new();
+        for (_, column, arrays) in layout.columns.iter() {
+            let array = &arrays[i];
+            let dims = storage.layout.dim_names(&column.name, &column.shape);
+            let mut shape = vec![num_chains, array.num_draws];
+            shape.extend(column.shape.iter());
+            let mut chunk_shape = vec![1, array.chunk_len];
+            chunk_shape.extend(column.shape.iter().map(|&len| len.max(1)));
+            let mut dim_names = vec!["chain".to_string(), "draw".to_string()];
+            dim_names.extend(dims.iter().cloned());
+            write_json(
+                &array.dir,
+                &array_metadata(
+                    column.dtype.name(),
+                    &shape,
+                    &chunk_shape,
+                    column.dtype.fill_value(),
+                    &dim_names,
+                ),
+            )?;
+            coords.extend(dims.into_iter().zip(column.shape.iter().cloned()));
+            coords.insert("draw".to_string(), array.num_draws);
+        }
+        coords.insert("chain".to_string(), num_chains);
+        for (dim, len) in coords {
+            write_coord(&group, &dim, &coord_values(storage, &dim, len))?;
+        }
+    }
+    Ok(())
+}
+
+/// The coordinate values of a dimension, or its index if the model does
+/// not give values of the right length.
+fn coord_values(storage: &StorageOptions, dim: &str, len: usize) -> CoordValues {
+    match storage.layout.coords.get(dim) {
+        Some(values) if values.len() == len => values.clone(),
+        _ => range_coord(len),
+    }
+}
+
+/// Shorten the `draw` dimension of all arrays to the draws that every
+/// chain wrote.
+pub(crate) fn trim_store(storage: &StorageOptions) -> Result<()> {
+    let run = storage
+        .run
+        .as_ref()
+        .context("Zarr storage needs to know the number of draws")?;
+    let mut num_rows = run.num_tune + run.num_draws;
+    for chain in 0..run.num_chains as u64 {
+        num_rows = num_rows.min(chain_length(storage, chain)?.unwrap_or(0) as u64);
+    }
+    let lengths = [
+        (run.num_tune, num_rows.min(run.num_tune)),
+        (run.num_draws, num_rows.saturating_sub(run.num_tune)),
+    ];
+    for part in [Part::Draws, Part::Stats] {
+        for (group, (num_draws, len)) in part.groups().into_iter().zip(lengths) {
+            if len < num_draws {
+                resize_draws(
+                    storage,
+                    &storage.dir.join(group),
+                    num_draws as usize,
+                    len as usize,
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Set the length of the `draw` dimension of the arrays in a group.
+fn resize_draws(
+    storage: &StorageOptions,
+    group: &Path,
+    num_draws: usize,
+    len: usize,
+) -> Result<()> {
+    if !group.exists() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(group)? {
+        let path = entry?.path().join("zarr.json");
+        if !path.exists() {
+            continue;
+        }
+        let mut metadata: Value = serde_json::from_slice(&std::fs::read(&path)?)
+            .with_context(|| format!("Invalid metadata in {}", path.display()))?;
+        if metadata["node_type"] != "array" {
+            continue;
+        }
+        if metadata["dimension_names"] == json!(["draw"]) {
+            let values = match coord_values(storage, "draw", num_draws) {
+                CoordValues::Int(values) => CoordValues::Int(values[..len].to_vec()),
+                CoordValues::Float(values) => CoordValues::Float(values[..len].to_vec()),
+                CoordValues::Str(values) => CoordValues::Str(values[..len].to_vec()),
+            };
+            write_coord(group, "draw", &values)?;
+        } else if metadata["dimension_names"][1] == "draw" {
+            metadata["shape"][1] = json!(len);
+            write_json(
+                path.parent().context("Invalid path in zarr store")?,
+                &metadata,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The number of draws a chain wrote to the store, if it finished.
+fn chain_length(storage: &StorageOptions, chain: u64) -> Result<Option<usize>> {
+    let path = storage
+        .dir
+        .join("chain_length")
+        .join("c")
+        .join(chain.to_string());
+    if !path.exists() {
+        return Ok(None);
+    }
+    let length = std::fs::read(&path)?;
+    Ok(Some(i64::from_le_bytes(
+        length
+            .try_into()
+            .ok()
+            .context("Invalid chain length in zarr store")?,
+    ) as usize))
+}
+
+/// Chunks of one array that are not written yet.
+struct Pending {
+    bytes: Vec<u8>,
+    rows: usize,
+    chunk: usize,
+}
+
+/// Writes the trace of one chain into a zarr store, chunk by chunk.
+pub(crate) struct ZarrChainWriter {
+    storage: StorageOptions,
+    part: Part,
+    chain: u64,
+    layout: StoreLayout,
+    pending: Vec<[Pending; 2]>,
+    rows: usize,
+}
+
+impl ZarrChainWriter {
+    pub(crate) fn create(
+        storage: &StorageOptions,
+        chain: u64,
+        part: Part,
+        fields: &Fields,
+    ) -> Result<Self> {
+        let layout = StoreLayout::new(storage, part, fields)?;
+        storage
+            .zarr_arrays
+            .create(part, || write_arrays(storage, part, &layout))?;
+        let pending = layout
+            .columns
+            .iter()
+            .map(|_| {
+                [0, 1].map(|_| Pending {
+                    bytes: Vec::new(),
+                    rows: 0,
+                    chunk: 0,
+                })
+            })
+            .collect();
+        Ok(Self {
+            storage: storage.clone(),
+            part,
+            chain,
+            layout,
+            pending,
+            rows: 0,
+        })
+    }
+
+    /// Append the rows of a struct array with the fields of the trace.
+    pub(crate) fn write(&mut self, array: &dyn Array) -> Result<()> {
+        let array = array
+            .as_struct_opt()
+            .context("Trace values must be a struct array")?;
+        for ((index, column, arrays), pending) in
+            self.layout.columns.iter().zip(self.pending.iter_mut())
+        {
+            let (values, starts) = column.rows(array.column(*index).as_ref())?;
+            let row_size = column.width() * column.dtype.size();
+            let fill = column.dtype.fill_bytes().repeat(column.width());
+            for (offset, start) in starts.into_iter().enumerate() {
+                let (group, _) = self.layout.locate(self.rows + offset);
+                let array = &arrays[group];
+                let pending = &mut pending[group];
+                if pending.chunk * array.chunk_len + pending.rows >= array.num_draws {
+                    continue;
+                }
+                match start {
+                    Some(start) => pending
+                        .bytes
+                        .extend_from_slice(&values[start * column.dtype.size()..][..row_size]),
+                    None => pending.bytes.extend_from_slice(&fill),
+                }
+                pending.rows += 1;
+                if pending.rows == array.chunk_len {
+                    flush(pending, array, column, self.chain)?;
+                }
+            }
+        }
+        self.rows += array.len();
+        Ok(())
+    }
+
+    /// Write the last, partially filled chunks.
+    pub(crate) fn finish(mut self) -> Result<()> {
+        for ((_, column, arrays), pending) in
+            self.layout.columns.iter().zip(self.pending.iter_mut())
+        {
+            for (array, pending) in arrays.iter().zip(pending.iter_mut()) {
+                if pending.rows > 0 {
+                    flush(pending, array, column, self.chain)?;
+                }
+            }
+        }
+        if let Part::Draws = self.part {
+            let path = self
+                .storage
+                .dir
+                .join("chain_length")
+                .join("c")
+                .join(self.chain.to_string());
+            write_atomic(&path, &(self.rows as i64).to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn flush(pending: &mut Pending, array: &ArrayLayout, column: &Column, chain: u64) -> Result<()> {
+    if column.width() > 0 {
+        let fill = column.dtype.fill_bytes();
+        let chunk_size = array.chunk_len * column.width() * column.dtype.size();
+        while pending.bytes.len() < chunk_size {
+            pending.bytes.extend_from_slice(&fill);
+        }
+        let path = array.chunk_path(chain, pending.chunk, column.shape.len());
+        write_atomic(&path, &pending.bytes)?;
+    }
+    pending.bytes.clear();
+    pending.rows = 0;
+    pending.chunk += 1;
+    Ok(())
+}
+
+/// Read the draws of a chain back into a struct array of the type of `like`.
+pub(crate) fn read_chain(
+    storage: &StorageOptions,
+    chain: u64,
+    like: &dyn Array,
+) -> Result<Option<ArrayRef>> {
+    let Some(rows) = chain_length(storage, chain)? else {
+        return Ok(None);
+    };
+
+    let fields = match like.data_type() {
+        DataType::Struct(fields) => fields.clone(),
+        _ => bail!("Trace values must be a struct array"),
+    };
+    let layout = StoreLayout::new(storage, Part::Draws, &fields)?;
+    if layout.columns.len() != fields.len() {
+        bail!("Not all variables of the trace are stored in the zarr store");
+    }
+    let mut columns = Vec::with_capacity(fields.len());
+    for ((_, column, arrays), field) in layout.columns.iter().zip(fields.iter()) {
+        let row_size = column.width() * column.dtype.size();
+        let mut values = Vec::with_capacity(rows * row_size);
+        let mut chunk: Option<(usize, usize, Vec<u8>)> = None;
+        for row in 0..rows {
+            let (group, index) = layout.locate(row);
+            let array = &arrays[group];
+            let key = (group, index / array.chunk_len);
+            let bytes = match &chunk {
+                Some((group, index, bytes)) if (*group, *index) == key => bytes,
+                _ => {
+                    let path = array.chunk_path(chain, key.1, column.shape.len());
+                    let bytes = if row_size == 0 {
+                        Vec::new()
+                    } else {
+                        std::fs::read(&path)
+                            .with_context(|| format!("Could not read {}", path.display()))?
+                    };
+                    &chunk.insert((key.0, key.1, bytes)).2
+                }
+            };
+            let start = (index % array.chunk_len) * row_size;
+            values.extend_from_slice(&bytes[start..start + row_size]);
+        }
+        columns.push(column.to_arrow(field.data_type(), values, rows)?);
+    }
+    Ok(Some(Arc::new(StructArray::try_new(fields, columns, None)?)))
+}
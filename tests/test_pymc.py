@@ -1,4 +1,5 @@
 from importlib.util import find_spec
+import json
 import time
 import pytest
 
@@ -7,6 +8,7 @@ if find_spec("pymc") is None:
 
 import numpy as np
 import pymc as pm
+import xarray as xr
 import pytest
 
 import nutpie
@@ -104,6 +106,100 @@ def test_low_rank_half_normal(backend, gradient_backend):
     trace.posterior.a  # noqa: B018
 
 
+@pytest.mark.pymc
+@parameterize_backends
+def test_dense(backend, gradient_backend):
+    cov = np.array([[1.0, 9.5, 0.0], [9.5, 100.0, 0.0], [0.0, 0.0, 0.01]])
+    with pm.Model() as model:
+        pm.MvNormal("a", mu=np.zeros(3), cov=cov)
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    trace = nutpie.sample(compiled, chains=2, dense_mass_matrix=True, seed=1)
+    draws = trace.posterior.a.values.reshape(-1, 3)
+    np.testing.assert_allclose(np.cov(draws.T), cov, rtol=0.2, atol=0.5)
+
+    trace = nutpie.sample(
+        compiled,
+        chains=2,
+        tune=100,
+        draws=100,
+        dense_mass_matrix=True,
+        pathfinder=True,
+    )
+    assert trace.posterior.a.shape == (2, 100, 3)
+
+    with pytest.raises(ValueError, match="Specify only one"):
+        nutpie.sample(compiled, dense_mass_matrix=True, transform_adapt=True)
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_fixed_mass_matrix(backend, gradient_backend):
+    with pm.Model() as model:
+        pm.Normal("a", sigma=np.array([1.0, 10.0]))
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    trace = nutpie.sample(
+        compiled,
+        chains=2,
+        tune=50,
+        draws=1000,
+        mass_matrix=np.array([1.0, 100.0]),
+        step_size=0.5,
+        adapt=False,
+        seed=1,
+    )
+    assert trace.warmup_posterior.a.shape == (2, 50, 2)
+    assert (trace.sample_stats.step_size == 0.5).all()
+    assert (trace.warmup_sample_stats.step_size == 0.5).all()
+    std = trace.posterior.a.std(("chain", "draw")).values
+    np.testing.assert_allclose(std, [1.0, 10.0], rtol=0.2)
+
+    trace = nutpie.sample(
+        compiled,
+        chains=1,
+        tune=100,
+        draws=100,
+        mass_matrix=np.diag([1.0, 100.0]),
+        step_size=0.5,
+    )
+    assert not (trace.sample_stats.step_size == 0.5).all()
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_no_adaptation_progress(backend, gradient_backend):
+    with pm.Model() as model:
+        pm.Normal("a", sigma=np.array([1.0, 10.0]))
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    updates = []
+    trace = nutpie.sample(
+        compiled,
+        chains=2,
+        tune=200,
+        draws=200,
+        mass_matrix=np.array([1.0, 100.0]),
+        step_size=2.5,
+        adapt=False,
+        progress_rate=1,
+        progress_callback=updates.append,
+    )
+    # The burn-in draws are reported as warmup, like in the trace.
+    chains = [chain for update in updates for chain in update.chains]
+    assert any(chain.tuning for chain in chains)
+    for chain in chains:
+        assert chain.tuning == (chain.finished_draws <= 200)
+    diverging = trace.sample_stats.diverging.sum("draw").values
+    assert [chain.divergences for chain in updates[-1].chains] == list(diverging)
+
+
 @pytest.mark.pymc
 @parameterize_backends
 def test_zero_size(backend, gradient_backend):
@@ -151,6 +247,213 @@ def test_pymc_model_no_prior(backend, gradient_backend):
     trace.posterior.a  # noqa: B018
 
 
+@pytest.mark.pymc
+@parameterize_backends
+def test_benchmark_logp(backend, gradient_backend):
+    with pm.Model() as model:
+        pm.Normal("a", shape=3)
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    times = compiled.benchmark_logp(np.zeros(3), num_evals=5, cores=[1, 2])
+    assert times.shape == (3, 5)
+    assert list(times.index.get_level_values("concurrent_cores")) == [1, 2, 2]
+    assert (times.values > 0).all()
+
+    with pytest.raises(RuntimeError):
+        compiled.benchmark_logp(np.zeros(4), num_evals=1, cores=1)
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_check_gradient(backend, gradient_backend):
+    with pm.Model() as model:
+        pm.Normal("a", shape=3)
+        pm.HalfNormal("b")
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    check = compiled.check_gradient(np.array([0.1, -1.0, 2.0, 0.5]))
+    assert check.point == [0.1, -1.0, 2.0, 0.5]
+    assert np.allclose(check.analytic, check.numeric, atol=1e-5)
+    assert check.max_relative_error < 1e-5
+    assert len(check.relative_error) == 4
+
+    trace = nutpie.sample(compiled, chains=2, tune=50, draws=50, check_gradient=True)
+    assert trace.posterior.a.shape == (2, 50, 3)
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_logp_and_grad(backend, gradient_backend):
+    with pm.Model() as model:
+        pm.Normal("a", shape=2)
+        pm.Normal("b", observed=pm.math.log(model["a"][0] + 10))
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    points = np.array([[0.0, 0.0], [1.0, -2.0], [-20.0, 0.0]])
+    logp, grad, failed = compiled.logp_and_grad(points)
+    assert logp.shape == (3,)
+    assert grad.shape == (3, 2)
+    assert list(failed) == [False, False, True]
+    assert np.isnan(logp[2])
+    assert np.isnan(grad[2]).all()
+    np.testing.assert_allclose(grad[1, 1], 2.0)
+
+    single = compiled.check_gradient(points[1])
+    np.testing.assert_allclose(logp[1], single.logp)
+    np.testing.assert_allclose(grad[1], single.analytic)
+
+    with pytest.raises(RuntimeError):
+        compiled.logp_and_grad(np.zeros((2, 3)))
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_init_strategy(backend, gradient_backend):
+    with pm.Model() as model:
+        pm.Normal("a", shape=2)
+        pm.Normal("b", observed=pm.math.log(model["a"][0] + 10))
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    trace = nutpie.sample(
+        compiled,
+        chains=4,
+        tune=50,
+        draws=50,
+        seed=1,
+        init_strategy=nutpie._lib.InitStrategy.uniform(20.0),
+    )
+    attempts = trace.sample_stats.init_attempts.values
+    failures = trace.sample_stats.init_failures.values
+    assert attempts.shape == (4,)
+    assert (attempts >= 1).all()
+    assert all((n > 1) == (reason != "") for n, reason in zip(attempts, failures))
+
+    trace = nutpie.sample(
+        compiled,
+        chains=2,
+        tune=50,
+        draws=50,
+        init_strategy=nutpie._lib.InitStrategy.callback(
+            lambda seed: np.array([1.0, -1.0])
+        ),
+    )
+    assert (trace.sample_stats.init_attempts == 1).all()
+
+    with pytest.raises(RuntimeError, match="4 attempts"):
+        nutpie.sample(
+            compiled,
+            chains=1,
+            init_strategy=nutpie._lib.InitStrategy.point(
+                np.array([-20.0, 0.0]), jitter=0.1
+            ),
+            init_retries=3,
+        )
+    with pytest.raises(RuntimeError, match="retries give the same point"):
+        nutpie.sample(
+            compiled,
+            chains=1,
+            init_strategy=nutpie._lib.InitStrategy.point(np.array([-20.0, 0.0])),
+        )
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_pathfinder(backend, gradient_backend):
+    with pm.Model(coords={"dim": ["x", "y", "z"]}) as model:
+        pm.HalfNormal("sigma")
+        pm.Normal("a", mu=3, sigma=2, dims="dim")
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    trace = compiled.pathfinder(num_paths=3, num_draws=100, seed=1)
+    assert trace.posterior.a.shape == (3, 100, 3)
+    assert trace.posterior.sigma.shape == (3, 100)
+    assert (trace.posterior.sigma > 0).all()
+    assert trace.sample_stats.log_weight.shape == (3, 100)
+    assert len(trace.sample_stats.attrs["elbo"]) == 3
+
+    result = compiled.pathfinder(num_paths=2, num_draws=50, seed=1, return_raw=True)
+    assert result.draws.shape == (2, 50, 4)
+    assert result.mean.shape == (2, 4)
+    assert (result.variance > 0).all()
+    assert all(result.converged)
+
+    trace = nutpie.sample(compiled, chains=2, tune=100, draws=100, pathfinder=True)
+    assert trace.posterior.a.shape == (2, 100, 3)
+    trace = nutpie.sample(
+        compiled,
+        chains=2,
+        tune=100,
+        draws=100,
+        low_rank_modified_mass_matrix=True,
+        pathfinder=nutpie._lib.PathfinderSettings(history=4),
+    )
+    assert trace.posterior.a.shape == (2, 100, 3)
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_find_map(backend, gradient_backend):
+    with pm.Model(coords={"dim": ["x", "y", "z"]}) as model:
+        sigma = pm.HalfNormal("sigma")
+        a = pm.Normal("a", mu=3, sigma=2, dims="dim")
+        pm.Deterministic("b", a.sum() * sigma)
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    result = compiled.find_map(seed=1)
+    assert result.converged
+    # The mode of the log transformed sigma is at sigma = 1
+    np.testing.assert_allclose(result.values["sigma"], 1.0, atol=1e-5)
+    np.testing.assert_allclose(result.values["a"], [3.0, 3.0, 3.0], atol=1e-5)
+    np.testing.assert_allclose(result.values["b"], 9.0, atol=1e-4)
+    assert result.unconstrained.shape == (4,)
+    assert len(result.logp_history) == result.num_iters + 1
+    assert result.logp == result.logp_history[-1]
+
+    result = compiled.find_map(np.zeros(4), max_iters=1)
+    assert result.num_iters == 1
+    assert result.convergence == "max_iters"
+    assert not result.converged
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_laplace(backend, gradient_backend):
+    with pm.Model(coords={"dim": ["x", "y", "z"]}) as model:
+        pm.HalfNormal("sigma")
+        pm.Normal("a", mu=3, sigma=2, dims="dim")
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    trace = compiled.laplace(num_draws=500, seed=1)
+    assert trace.posterior.a.shape == (1, 500, 3)
+    assert (trace.posterior.sigma > 0).all()
+    assert list(trace.posterior.a.coords["dim"].values) == ["x", "y", "z"]
+    assert trace.sample_stats.log_weight.shape == (1, 500)
+
+    result = compiled.laplace(num_draws=10, seed=1, return_raw=True)
+    assert result.converged
+    # The log transformed sigma has variance 1 at the mode, and a has
+    # variance 4.
+    np.testing.assert_allclose(
+        np.sort(np.diag(result.covariance)), [1.0, 4.0, 4.0, 4.0], rtol=1e-3
+    )
+    np.testing.assert_allclose(result.log_weight, result.log_weight[0], atol=1e-3)
+    assert result.draws.shape == (10, 4)
+
+
 @pytest.mark.pymc
 @parameterize_backends
 def test_blocking(backend, gradient_backend):
@@ -165,6 +468,611 @@ def test_blocking(backend, gradient_backend):
     trace.posterior.a  # noqa: B018
 
 
+@pytest.mark.pymc
+@parameterize_backends
+def test_extend(backend, gradient_backend):
+    with pm.Model() as model:
+        pm.Normal("a")
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    sampler = nutpie.sample(compiled, chains=2, tune=100, draws=50, blocking=False)
+    trace = sampler.wait()
+    assert trace.posterior.draw.shape == (50,)
+    # nuts-rs only stores the mass matrix for us.
+    assert "mass_matrix_inv" not in trace.sample_stats
+    before = sampler.adaptation_state()
+    sampler.extend(70)
+    trace = sampler.wait()
+    assert trace.posterior.draw.shape == (120,)
+    assert trace.warmup_posterior.draw.shape == (100,)
+
+    # The chains continue with the step size and mass matrix they ended with.
+    after = sampler.adaptation_state()
+    assert after.step_size == before.step_size
+    step_size = trace.sample_stats.step_size.values
+    for chain, step in enumerate(before.step_size):
+        assert (step_size[chain, 50:] == step).all()
+    for inv_mass, inv_mass_before in zip(after.mass_matrix_inv, before.mass_matrix_inv):
+        np.testing.assert_allclose(inv_mass, inv_mass_before, rtol=1e-12)
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_checkpoint(backend, gradient_backend, tmp_path):
+    import pyarrow
+
+    with pm.Model() as model:
+        pm.Normal("a", shape=3)
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    sampler = nutpie.sample(
+        compiled, chains=2, tune=100, draws=2000, seed=42, blocking=False
+    )
+    # A checkpoint only contains the chains that have draws.
+    started = set()
+    deadline = time.monotonic() + 60
+    while len(started) < 2:
+        assert time.monotonic() < deadline
+        started.update(sampler.fetch_new_draws())
+        time.sleep(0.01)
+    sampler.pause()
+    path = tmp_path / "checkpoint.arrow"
+    sampler.checkpoint(path)
+    sampler.cancel()
+
+    reader = pyarrow.ipc.open_file(path)
+    assert reader.num_record_batches == 2
+    checkpointed = [reader.get_batch(i) for i in range(2)]
+
+    # The seed of the continuation only depends on the checkpoint.
+    settings = nutpie._lib.PyNutsSettings.Diag(123)
+    settings.num_tune = 100
+    settings.num_draws = 2000
+    settings.num_chains = 2
+
+    def resume():
+        resumed = nutpie._lib.PySampler.from_checkpoint(
+            path,
+            settings,
+            2,
+            compiled._make_model(np.zeros(compiled.n_dim)),
+            nutpie._lib.ProgressType.none(),
+        )
+        resumed.wait()
+        return resumed.extract_results()
+
+    first = resume()
+    second = resume()
+    for (draws, stats), (draws_again, _), batch in zip(first, second, checkpointed):
+        assert draws.num_rows == stats.num_rows == 2100
+        assert stats.column("draw").to_pylist() == list(range(2100))
+        assert not np.isnan(stats.column("step_size").to_numpy()).any()
+
+        prefix = pyarrow.RecordBatch.from_struct_array(batch.column("draws"))
+        assert (
+            draws.column("a").slice(0, batch.num_rows).to_pylist()
+            == prefix.column("a").to_pylist()
+        )
+        assert draws.column("a").to_pylist() == draws_again.column("a").to_pylist()
+
+
+@pytest.mark.pymc
+@pytest.mark.parametrize(
+    "variant",
+    ["diag", "low_rank", "dense", pytest.param("transform", marks=pytest.mark.flow)],
+)
+def test_checkpoint_variants(variant, tmp_path):
+    with pm.Model() as model:
+        pm.MvNormal("a", mu=np.zeros(3), cov=[[1, 0.9, 0], [0.9, 1, 0], [0, 0, 4]])
+
+    if variant == "transform":
+        compiled = nutpie.compile_pymc_model(
+            model, backend="jax", gradient_backend="jax"
+        ).with_transform_adapt(num_layers=2)
+    else:
+        compiled = nutpie.compile_pymc_model(model, backend="numba")
+    kwargs = {
+        "diag": {},
+        "low_rank": {"low_rank_modified_mass_matrix": True},
+        "dense": {"dense_mass_matrix": True},
+        "transform": {"transform_adapt": True},
+    }[variant]
+    sampler = nutpie.sample(
+        compiled, chains=2, tune=300, draws=100, seed=7, blocking=False, **kwargs
+    )
+    sampler.wait()
+    before = sampler.adaptation_state()
+    path = tmp_path / "checkpoint.arrow"
+    sampler.checkpoint(path)
+
+    make_settings = {
+        "diag": nutpie._lib.PyNutsSettings.Diag,
+        "low_rank": nutpie._lib.PyNutsSettings.LowRank,
+        "dense": nutpie._lib.PyNutsSettings.Dense,
+        "transform": nutpie._lib.PyNutsSettings.Transform,
+    }[variant]
+    settings = make_settings(7)
+    settings.num_tune = 300
+    settings.num_draws = 150
+    settings.num_chains = 2
+    resumed = nutpie._lib.PySampler.from_checkpoint(
+        path,
+        settings,
+        2,
+        compiled._make_model(np.zeros(compiled.n_dim)),
+        nutpie._lib.ProgressType.none(),
+    )
+    resumed.wait()
+    after = resumed.adaptation_state()
+
+    # The chains continue with the step size and metric or flow in the
+    # checkpoint.
+    assert after.step_size == before.step_size
+    for (draws, stats), step in zip(resumed.extract_results(), before.step_size):
+        assert draws.num_rows == stats.num_rows == 450
+        assert (stats.column("step_size").to_numpy()[400:] == step).all()
+        if variant == "transform":
+            index = stats.column("transformation_index").to_numpy()
+            assert (index[400:] == index[399]).all()
+    if variant != "transform":
+        for inv_mass, inv_mass_before in zip(
+            after.mass_matrix_inv, before.mass_matrix_inv
+        ):
+            np.testing.assert_allclose(inv_mass, inv_mass_before, rtol=1e-12)
+
+
+@pytest.mark.pymc
+def test_checkpoint_equivalent(tmp_path):
+    import pyarrow
+
+    with pm.Model() as model:
+        pm.Normal("a", shape=2)
+
+    compiled = nutpie.compile_pymc_model(model, backend="numba")
+    full = nutpie.sample(compiled, chains=2, tune=200, draws=4000, seed=5)
+    full = np.concatenate(
+        [full.warmup_posterior.a.values, full.posterior.a.values], axis=1
+    )
+
+    sampler = nutpie.sample(
+        compiled, chains=2, tune=200, draws=4000, seed=5, blocking=False
+    )
+    rows = {0: 0, 1: 0}
+    deadline = time.monotonic() + 60
+    while min(rows.values()) < 300:
+        assert time.monotonic() < deadline
+        for chain, (draws, _) in sampler.fetch_new_draws().items():
+            rows[chain] += draws.num_rows
+        time.sleep(0.01)
+    sampler.pause()
+    path = tmp_path / "checkpoint.arrow"
+    sampler.checkpoint(path)
+    sampler.cancel()
+
+    settings = nutpie._lib.PyNutsSettings.Diag(5)
+    settings.num_tune = 200
+    settings.num_draws = 4000
+    settings.num_chains = 2
+    resumed = nutpie._lib.PySampler.from_checkpoint(
+        path,
+        settings,
+        2,
+        compiled._make_model(np.zeros(compiled.n_dim)),
+        nutpie._lib.ProgressType.none(),
+    )
+    resumed.wait()
+    checkpointed = pyarrow.ipc.open_file(path)
+    for chain, (draws, _) in enumerate(resumed.extract_results()):
+        stitched = np.array(draws.column("a").to_pylist())
+        assert stitched.shape == full[chain].shape
+        # The draws before the checkpoint are the ones of an uninterrupted
+        # run, but the continuation uses other random numbers.
+        num_rows = checkpointed.get_batch(chain).num_rows
+        np.testing.assert_array_equal(stitched[:num_rows], full[chain, :num_rows])
+        assert not np.array_equal(stitched[num_rows:], full[chain, num_rows:])
+
+        # Both sample the same posterior.
+        posterior = stitched[200:]
+        np.testing.assert_allclose(posterior.mean(axis=0), 0, atol=0.15)
+        np.testing.assert_allclose(posterior.std(axis=0), 1, atol=0.1)
+        np.testing.assert_allclose(
+            posterior.mean(axis=0), full[chain, 200:].mean(axis=0), atol=0.2
+        )
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_warm_start(backend, gradient_backend):
+    with pm.Model() as model:
+        pm.Normal("a", shape=3)
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    sampler = nutpie.sample(compiled, chains=2, blocking=False)
+    finished = sampler.wait()
+    state = sampler.adaptation_state()
+    assert state.num_chains == 2
+    assert all(step > 0 for step in state.step_size)
+    assert all(inv_mass.shape == (3,) for inv_mass in state.mass_matrix_inv)
+
+    from_trace = nutpie._lib.PyAdaptationState.from_trace(finished)
+    assert from_trace.step_size == state.step_size
+    for inv_mass, expected in zip(from_trace.mass_matrix_inv, state.mass_matrix_inv):
+        np.testing.assert_array_equal(inv_mass, expected)
+
+    trace = nutpie.sample(
+        compiled, chains=3, tune=0, draws=100, adaptation_state=state
+    )
+    assert trace.posterior.a.shape == (3, 100, 3)
+    assert trace.sample_stats.step_size.values[0, 0] in state.step_size
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_warm_start_low_rank(backend, gradient_backend, tmp_path):
+    with pm.Model() as model:
+        pm.MvNormal("a", mu=np.zeros(3), cov=[[1, 0.9, 0], [0.9, 1, 0], [0, 0, 2]])
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    # The trace files only record the settings of the run as it started.
+    storage = nutpie._lib.TraceStorage.arrow(tmp_path)
+    trace = nutpie.sample(
+        compiled,
+        chains=2,
+        tune=300,
+        draws=100,
+        low_rank_modified_mass_matrix=True,
+        trace_storage=storage,
+    )
+    state = nutpie._lib.PyAdaptationState.from_trace(trace)
+    assert state.num_chains == 2
+    assert list(state.step_size) == list(trace.sample_stats.step_size.values[:, -1])
+
+    trace = nutpie.sample(
+        compiled,
+        chains=2,
+        tune=0,
+        draws=100,
+        low_rank_modified_mass_matrix=True,
+        adaptation_state=state,
+    )
+    assert trace.posterior.a.shape == (2, 100, 3)
+    assert trace.sample_stats.step_size.values[0, 0] in state.step_size
+
+    # Without the record of the state we only know the diagonal mass matrix.
+    del trace.sample_stats.attrs["nutpie"]
+    with pytest.raises(RuntimeError, match="store_mass_matrix"):
+        nutpie._lib.PyAdaptationState.from_trace(trace)
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_diagnostics(backend, gradient_backend):
+    with pm.Model() as model:
+        pm.Normal("a", shape=3)
+        pm.HalfNormal("b")
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    sampler = nutpie.sample(compiled, chains=2, tune=200, draws=300, blocking=False)
+    trace = sampler.wait()
+    summary = sampler.diagnostics(quantiles=[0.1, 0.9]).to_pandas()
+
+    assert {"a", "b"} <= set(summary.variable)
+    assert {"q10", "q90", "ess_bulk", "ess_tail", "r_hat", "mcse_mean"} <= set(
+        summary.columns
+    )
+    a = summary[summary.variable == "a"]
+    assert list(a["index"]) == [0, 1, 2]
+    assert np.allclose(a["mean"], trace.posterior.a.mean(("chain", "draw")))
+    assert (summary.r_hat < 1.1).all()
+    assert (summary.ess_bulk > 50).all()
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_stopping_rule(backend, gradient_backend):
+    with pm.Model() as model:
+        pm.Normal("a", shape=2)
+        pm.Normal("b")
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    rule = nutpie._lib.StoppingRule(
+        min_ess=200, max_rhat=1.05, var_names=["a"], check_every=50
+    )
+    sampler = nutpie.sample(
+        compiled,
+        chains=2,
+        tune=100,
+        draws=100_000,
+        stopping_rule=rule,
+        blocking=False,
+    )
+    trace = sampler.wait()
+    reason = sampler.stop_reason
+    assert reason["reason"] == "converged"
+    assert reason["min_ess_bulk"] >= 200
+    assert reason["max_r_hat"] <= 1.05
+    assert trace.posterior.draw.shape[0] < 100_000
+    assert not np.isnan(trace.posterior.a.values).any()
+    provenance = json.loads(trace.posterior.attrs["nutpie"])
+    assert provenance["stopping"]["stopped"]["reason"] == "converged"
+    assert provenance["stopping"]["rule"]["min_ess"] == 200
+
+    with pytest.raises(Exception, match="Unknown variable"):
+        nutpie.sample(
+            compiled,
+            chains=1,
+            tune=10,
+            draws=1000,
+            stopping_rule=nutpie._lib.StoppingRule(min_ess=10, var_names=["c"]),
+        )
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_progress_callback(backend, gradient_backend):
+    with pm.Model() as model:
+        pm.Normal("a")
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    updates = []
+    nutpie.sample(
+        compiled,
+        chains=2,
+        tune=100,
+        draws=100,
+        progress_rate=1,
+        progress_callback=updates.append,
+    )
+    assert updates
+    final = updates[-1]
+    assert final.finished
+    assert final.num_chains == 2
+    assert final.total_finished_draws == final.total_draws == 400
+    assert final.time_sampling >= 0
+    assert [chain.chain_index for chain in final.chains] == [0, 1]
+    for chain in final.chains:
+        assert chain.finished_draws == chain.total_draws == 200
+        assert not chain.tuning
+        assert chain.step_size > 0
+        assert chain.total_num_steps >= chain.finished_draws
+        assert len(chain.divergent_draws) == chain.divergences
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_progress_time_remaining(backend, gradient_backend):
+    with pm.Model() as model:
+        pm.Normal("a", shape=5)
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    updates = []
+    nutpie.sample(
+        compiled,
+        chains=4,
+        cores=2,
+        tune=200,
+        draws=200,
+        progress_rate=1,
+        progress_callback=updates.append,
+    )
+    estimates = [
+        update for update in updates if update.time_remaining_estimate is not None
+    ]
+    assert estimates
+    for update in estimates:
+        assert (
+            0
+            <= update.time_remaining_lower
+            <= update.time_remaining_estimate
+            <= update.time_remaining_upper
+        )
+    assert updates[-1].time_remaining_estimate == 0
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_progress_file(backend, gradient_backend, tmp_path):
+    with pm.Model() as model:
+        pm.Normal("a")
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    path = tmp_path / "progress.jsonl"
+    nutpie.sample(
+        compiled,
+        chains=2,
+        tune=100,
+        draws=100,
+        progress_rate=1,
+        progress_file=path,
+    )
+    events = [json.loads(line) for line in path.read_text().splitlines()]
+    assert events[0]["event"] == "started"
+    assert events[-1]["event"] == "finished"
+    assert all(event["event"] == "progress" for event in events[1:-1])
+    timestamps = [event["timestamp"] for event in events]
+    assert timestamps == sorted(timestamps)
+    final = events[-1]
+    assert final["total_finished_draws"] == 400
+    assert [chain["finished_draws"] for chain in final["chains"]] == [200, 200]
+    assert {"divergences", "step_size", "latest_num_steps", "tuning"} <= set(
+        final["chains"][0]
+    )
+    assert "time_remaining_estimate" in final
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_progress_bar_chains(backend, gradient_backend):
+    with pm.Model() as model:
+        pm.Normal("a")
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    trace = nutpie.sample(
+        compiled,
+        chains=3,
+        tune=100,
+        draws=100,
+        progress_rate=1,
+        progress_bar="chains",
+    )
+    assert trace.posterior.a.shape == (3, 100)
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_fetch_new_draws(backend, gradient_backend):
+    with pm.Model() as model:
+        pm.Normal("a")
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    sampler = nutpie.sample(compiled, chains=2, tune=100, draws=200, blocking=False)
+    lengths = {0: 0, 1: 0}
+    while not sampler.is_finished:
+        for chain, (draws, stats) in sampler.fetch_new_draws().items():
+            assert len(draws) == len(stats)
+            assert stats.column("draw")[0].as_py() == lengths[chain]
+            lengths[chain] += len(draws)
+    for chain, (draws, _) in sampler.fetch_new_draws().items():
+        lengths[chain] += len(draws)
+    assert lengths == {0: 300, 1: 300}
+    assert sampler.fetch_new_draws() == {}
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_fetch_new_draws_concurrent(backend, gradient_backend):
+    import threading
+
+    with pm.Model() as model:
+        pm.Normal("a", shape=2)
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    # Another thread inspects the whole trace while we stream.
+    sampler = nutpie.sample(
+        compiled, chains=2, tune=100, draws=300, seed=3, blocking=False
+    )
+    done = threading.Event()
+
+    def inspect():
+        while not done.is_set() and not sampler.is_finished:
+            try:
+                sampler.inspect()
+            except RuntimeError:
+                # The sampler finished since we checked
+                break
+
+    thread = threading.Thread(target=inspect)
+    thread.start()
+    streamed = {0: [], 1: []}
+    try:
+        while not sampler.is_finished:
+            for chain, (draws, stats) in sampler.fetch_new_draws().items():
+                assert len(draws) == len(stats)
+                streamed[chain].extend(draws.column("a").to_pylist())
+        for chain, (draws, _) in sampler.fetch_new_draws().items():
+            streamed[chain].extend(draws.column("a").to_pylist())
+    finally:
+        done.set()
+        thread.join()
+
+    trace = sampler.wait()
+    for chain in range(2):
+        a = np.concatenate(
+            [
+                trace.warmup_posterior.a.sel(chain=chain).values,
+                trace.posterior.a.sel(chain=chain).values,
+            ]
+        )
+        np.testing.assert_array_equal(np.array(streamed[chain]), a)
+
+
+@pytest.mark.pymc
+@parameterize_backends
+@pytest.mark.parametrize("format", ["arrow", "parquet"])
+def test_trace_storage(backend, gradient_backend, format, tmp_path):
+    with pm.Model() as model:
+        pm.Normal("a", shape=3)
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    storage = getattr(nutpie._lib.TraceStorage, format)(tmp_path, buffer_draws=50)
+    trace = nutpie.sample(
+        compiled, chains=2, tune=100, draws=200, trace_storage=storage
+    )
+    assert trace.posterior.a.shape == (2, 200, 3)
+    assert trace.sample_stats.diverging.shape == (2, 200)
+    assert (tmp_path / f"chain-0.{format}").exists()
+    assert (tmp_path / f"chain-1-stats.{format}").exists()
+
+    provenance = json.loads(trace.posterior.attrs["nutpie"])
+    assert provenance["settings"]["trace_storage"]["kind"] == format
+    assert provenance["settings"]["trace_storage"]["buffer_draws"] == 50
+    if format == "parquet":
+        import pyarrow.parquet
+
+        schema = pyarrow.parquet.read_schema(tmp_path / "chain-1-stats.parquet")
+    else:
+        import pyarrow.ipc
+
+        schema = pyarrow.ipc.open_file(tmp_path / "chain-1-stats.arrow").schema
+    assert json.loads(schema.metadata[b"nutpie"])["settings"] == provenance["settings"]
+
+
+@pytest.mark.pymc
+@parameterize_backends
+def test_zarr_storage(backend, gradient_backend, tmp_path):
+    with pm.Model(coords={"foo": ["a", "b", "c"]}) as model:
+        pm.Normal("a", dims="foo")
+        pm.HalfNormal("b", shape=(2, 2))
+
+    compiled = nutpie.compile_pymc_model(
+        model, backend=backend, gradient_backend=gradient_backend
+    )
+    path = tmp_path / "trace.zarr"
+    storage = nutpie._lib.TraceStorage.zarr(path, buffer_draws=30)
+    trace = nutpie.sample(
+        compiled, chains=2, tune=100, draws=200, trace_storage=storage
+    )
+    assert trace.posterior.a.dims == ("chain", "draw", "foo")
+    assert list(trace.posterior.foo.values) == ["a", "b", "c"]
+    assert trace.posterior.b.shape == (2, 200, 2, 2)
+    assert trace.warmup_posterior.a.shape == (2, 100, 3)
+    assert trace.sample_stats.diverging.shape == (2, 200)
+    assert not np.isnan(trace.posterior.a.values).any()
+
+    datatree = xr.open_datatree(path, engine="zarr")
+    assert datatree["posterior"]["a"].shape == (2, 200, 3)
+    provenance = json.loads(datatree.attrs["nutpie"])
+    assert provenance["settings"]["trace_storage"]["kind"] == "zarr"
+    assert provenance["timings"]["finished_at"] is not None
+    assert json.loads(trace.posterior.attrs["nutpie"]) == provenance
+
+
 @pytest.mark.pymc
 @parameterize_backends
 @pytest.mark.timeout(20)
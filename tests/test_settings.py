@@ -0,0 +1,111 @@
+import json
+
+import numpy as np
+import pytest
+
+from nutpie import _lib
+
+
+@pytest.mark.parametrize("variant", ["Diag", "LowRank", "Transform", "Dense"])
+def test_settings_roundtrip(variant):
+    settings = getattr(_lib.PyNutsSettings, variant)(42)
+    settings.num_tune = 123
+    settings.target_accept = 0.9
+    settings.adapt_options = {"dual_average_options": {"params": {"k": 0.7}}}
+
+    data = settings.to_dict()
+    assert data["num_tune"] == 123
+    assert data["seed"] == 42
+    assert data["adapt_options"]["dual_average_options"]["target_accept"] == 0.9
+    assert data["adapt_options"]["dual_average_options"]["params"]["k"] == 0.7
+
+    restored = _lib.PyNutsSettings.from_dict(data)
+    assert restored.to_dict() == data
+    restored = _lib.PyNutsSettings.from_json(settings.to_json())
+    assert restored.to_dict() == data
+    assert json.loads(settings.to_json())["variant"] == settings.variant
+
+
+def test_settings_errors():
+    settings = _lib.PyNutsSettings.Diag(0)
+    with pytest.raises(Exception, match="adapt_options.dual_average_options.target_accept"):
+        settings.target_accept = "high"
+    with pytest.raises(Exception, match="not available for diag"):
+        settings.mass_matrix_gamma = 1e-3
+    with pytest.raises(Exception, match="low_rank.*mass_matrix_options.gama"):
+        _lib.PyNutsSettings.from_dict(
+            {"variant": "low_rank", "adapt_options": {"mass_matrix_options": {"gama": 1}}}
+        )
+    assert not hasattr(settings, "unknown_option")
+
+
+def test_settings_check_gradient():
+    settings = _lib.PyNutsSettings.Diag(0)
+    assert settings.check_gradient is None
+    settings.check_gradient = True
+    assert settings.check_gradient == 1e-3
+    settings.check_gradient = 1e-5
+    assert settings.check_gradient == 1e-5
+    settings.check_gradient = False
+    assert settings.check_gradient is None
+
+
+def test_settings_init_strategy():
+    settings = _lib.PyNutsSettings.Diag(0)
+    assert settings.init_retries == 100
+    assert repr(settings.init_strategy) == "InitStrategy.model()"
+    settings.init_strategy = _lib.InitStrategy.uniform(3.0)
+    settings.init_retries = 5
+    assert repr(settings.init_strategy) == "InitStrategy.uniform(radius=3)"
+    assert settings.init_retries == 5
+    with pytest.raises(RuntimeError, match="radius"):
+        _lib.InitStrategy.uniform(-1.0)
+
+
+def test_settings_pathfinder():
+    settings = _lib.PyNutsSettings.Diag(0)
+    assert settings.pathfinder is None
+    settings.pathfinder = True
+    assert repr(settings.pathfinder) == (
+        "PathfinderSettings(max_iters=1000, history=6, num_elbo_draws=25, num_draws=1000)"
+    )
+    settings.pathfinder = _lib.PathfinderSettings(max_iters=50, history=3)
+    assert settings.pathfinder.max_iters == 50
+    assert settings.pathfinder.history == 3
+    settings.pathfinder = False
+    assert settings.pathfinder is None
+    with pytest.raises(RuntimeError, match="history"):
+        _lib.PathfinderSettings(history=0)
+
+
+def test_settings_mass_matrix():
+    settings = _lib.PyNutsSettings.Diag(0)
+    assert settings.mass_matrix is None
+    assert settings.step_size is None
+    assert settings.adapt
+
+    settings.mass_matrix = np.array([1.0, 2.0])
+    assert repr(settings.mass_matrix) == "MassMatrix.diag(<2 parameters>)"
+    settings.mass_matrix = np.array([[1.0, 0.5], [0.5, 2.0]])
+    assert settings.mass_matrix.kind == "dense"
+    settings.mass_matrix = _lib.MassMatrix.low_rank(
+        np.ones(3), np.eye(3)[:, :1], np.array([10.0])
+    )
+    assert settings.mass_matrix.kind == "low_rank"
+    assert settings.mass_matrix.n_dim == 3
+    settings.mass_matrix = None
+    assert settings.mass_matrix is None
+
+    settings.step_size = 0.3
+    settings.adapt = False
+    assert settings.step_size == 0.3
+    assert not settings.adapt
+
+    with pytest.raises(RuntimeError, match="positive definite"):
+        _lib.MassMatrix.dense(np.array([[1.0, 2.0], [2.0, 1.0]]))
+    with pytest.raises(RuntimeError, match="symmetric"):
+        _lib.MassMatrix.dense(np.array([[1.0, 0.1], [0.2, 1.0]]))
+    with pytest.raises(RuntimeError, match="orthonormal"):
+        _lib.MassMatrix.low_rank(np.ones(2), np.ones((2, 1)), np.array([2.0]))
+    with pytest.raises(RuntimeError, match="positive"):
+        settings.step_size = -1.0
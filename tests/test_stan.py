@@ -1,3 +1,4 @@
+import json
 from importlib.util import find_spec
 import pytest
 
@@ -44,6 +45,103 @@ def test_stan_model_low_rank():
     trace.posterior.a  # noqa: B018
 
 
+@pytest.mark.stan
+def test_stan_model_dense():
+    model = """
+    data {}
+    parameters {
+        vector[2] a;
+    }
+    model {
+        a ~ multi_normal([0, 0], [[1, 0.9], [0.9, 1]]);
+    }
+    """
+
+    compiled_model = nutpie.compile_stan_model(code=model)
+    trace = nutpie.sample(compiled_model, dense_mass_matrix=True)
+    assert trace.posterior.a.shape[-1] == 2
+
+
+@pytest.mark.stan
+def test_stan_dense_adapted_covariance(tmp_path):
+    import pyarrow
+
+    model = """
+    parameters {
+        vector[2] a;
+    }
+    model {
+        a ~ multi_normal([0, 0], [[4, 0.9], [0.9, 0.25]]);
+    }
+    """
+    cov = np.array([[4.0, 0.9], [0.9, 0.25]])
+
+    compiled_model = nutpie.compile_stan_model(code=model)
+    sampler = nutpie.sample(
+        compiled_model,
+        chains=2,
+        tune=2000,
+        draws=10,
+        dense_mass_matrix=True,
+        seed=1,
+        blocking=False,
+    )
+    sampler.wait()
+    path = tmp_path / "checkpoint.arrow"
+    sampler.checkpoint(path)
+
+    # The checkpoint contains the Cholesky factor of the adapted covariance.
+    metadata = pyarrow.ipc.open_file(path).schema.metadata[b"nutpie.checkpoint"]
+    for chain in json.loads(metadata)["chains"]:
+        factor = np.array(chain["dense_factor"]).reshape(2, 2)
+        adapted = factor @ factor.T
+        np.testing.assert_allclose(np.diag(adapted), np.diag(cov), rtol=0.3)
+        corr = adapted[0, 1] / np.sqrt(adapted[0, 0] * adapted[1, 1])
+        np.testing.assert_allclose(corr, 0.9, atol=0.05)
+
+
+@pytest.mark.stan
+def test_stan_fixed_mass_matrix():
+    model = """
+    data {}
+    parameters {
+        vector[2] a;
+    }
+    model {
+        a ~ multi_normal([0, 0], [[1, 0.9], [0.9, 1]]);
+    }
+    """
+
+    compiled_model = nutpie.compile_stan_model(code=model)
+    trace = nutpie.sample(
+        compiled_model,
+        chains=2,
+        tune=20,
+        draws=200,
+        mass_matrix=np.array([[1.0, 0.9], [0.9, 1.0]]),
+        step_size=0.8,
+        adapt=False,
+    )
+    assert (trace.sample_stats.step_size == 0.8).all()
+    assert (trace.warmup_sample_stats.step_size == 0.8).all()
+    assert trace.warmup_posterior.a.shape == (2, 20, 2)
+
+    # nuts-rs searches for an initial step size, which must not change a
+    # fixed one, also for step sizes that diverge or accept everything.
+    for seed, step_size in enumerate([1e-4, 0.05, 3.0, 200.0]):
+        trace = nutpie.sample(
+            compiled_model,
+            chains=2,
+            tune=10,
+            draws=20,
+            step_size=step_size,
+            adapt=False,
+            seed=seed,
+        )
+        assert (trace.sample_stats.step_size == step_size).all()
+        assert (trace.warmup_sample_stats.step_size == step_size).all()
+
+
 @pytest.mark.stan
 def test_empty():
     model = """
@@ -205,6 +303,287 @@ def test_stan_model_data():
     trace.posterior.a  # noqa: B018
 
 
+@pytest.mark.stan
+def test_stan_trace_provenance():
+    model = """
+    data {
+        real mu;
+    }
+    parameters {
+        real a;
+    }
+    model {
+        a ~ normal(mu, 1);
+    }
+    """
+
+    compiled_model = nutpie.compile_stan_model(code=model).with_data(mu=1.0)
+    raw = nutpie.sample(
+        compiled_model,
+        chains=2,
+        tune=50,
+        draws=20,
+        cores=1,
+        seed=7,
+        return_raw_trace=True,
+    )
+    draws, stats = raw[0]
+    provenance = json.loads(draws.schema.metadata[b"nutpie"])
+    assert stats.schema.metadata == draws.schema.metadata
+    assert provenance["nutpie_version"] == nutpie.__version__
+    assert provenance["settings"]["variant"] == "diag"
+    assert provenance["settings"]["seed"] == 7
+    assert provenance["settings"]["num_tune"] == 50
+    assert provenance["settings"]["adapt"] is True
+    assert provenance["settings"]["init"]["strategy"]["type"] == "model"
+    assert provenance["settings"]["trace_storage"] == {"kind": "memory"}
+    assert provenance["backend"]["type"] == "stan"
+    assert len(provenance["backend"]["data_sha256"]) == 64
+    assert provenance["cores"] == 1
+    assert provenance["timings"]["finished_at"] >= provenance["timings"]["started_at"]
+
+    trace = nutpie.sample(compiled_model, chains=1, tune=50, draws=20)
+    assert json.loads(trace.posterior.attrs["nutpie"])["backend"]["type"] == "stan"
+
+
+@pytest.mark.stan
+def test_stan_benchmark_logp():
+    model = """
+    parameters {
+        vector[2] a;
+    }
+    model {
+        a ~ normal(0, 1);
+    }
+    """
+
+    compiled_model = nutpie.compile_stan_model(code=model)
+    times = compiled_model.benchmark_logp(np.zeros(2), num_evals=4, cores=2)
+    assert times.shape == (2, 4)
+    assert (times.values > 0).all()
+
+
+@pytest.mark.stan
+def test_stan_check_gradient():
+    model = """
+    parameters {
+        real<lower=0> sigma;
+        vector[2] a;
+    }
+    model {
+        sigma ~ normal(0, 1);
+        a ~ normal(0, sigma);
+    }
+    """
+
+    compiled_model = nutpie.compile_stan_model(code=model)
+    check = compiled_model.check_gradient(np.array([0.3, 1.0, -2.0]))
+    assert check.max_relative_error < 1e-5
+    assert np.isfinite(check.logp)
+
+    with pytest.raises(RuntimeError, match="Point has length"):
+        compiled_model.check_gradient(np.zeros(2))
+
+    trace = nutpie.sample(
+        compiled_model, chains=2, tune=50, draws=50, check_gradient=1e-4
+    )
+    assert trace.posterior.a.shape == (2, 50, 2)
+
+
+@pytest.mark.stan
+def test_stan_logp_and_grad():
+    model = """
+    parameters {
+        real a;
+    }
+    model {
+        a ~ normal(0, 1);
+    }
+    """
+
+    compiled_model = nutpie.compile_stan_model(code=model)
+    points = np.linspace(-2, 2, 17)[:, None]
+    logp, grad, failed = compiled_model.logp_and_grad(points)
+    assert not failed.any()
+    np.testing.assert_allclose(grad[:, 0], -points[:, 0])
+    np.testing.assert_allclose(logp - logp[8], -(points[:, 0] ** 2) / 2)
+
+
+@pytest.mark.stan
+def test_stan_constrain_unconstrain():
+    model = """
+    parameters {
+        real<lower=0> sigma;
+        array[2] vector<lower=-1, upper=1>[3] x;
+    }
+    transformed parameters {
+        real log_sigma = log(sigma);
+    }
+    model {
+        sigma ~ normal(0, 1);
+    }
+    generated quantities {
+        real y = normal_rng(0, 1);
+    }
+    """
+
+    compiled_model = nutpie.compile_stan_model(code=model)
+    x = np.linspace(-0.9, 0.9, 6).reshape(2, 3)
+    point = compiled_model.unconstrain({"sigma": 2.0, "x": x})
+    assert point.shape == (compiled_model.n_dim,)
+    assert np.isclose(point[0], np.log(2.0))
+
+    values = compiled_model.constrain(point)
+    assert set(values) == {"sigma", "x"}
+    assert np.isclose(values["sigma"], 2.0)
+    np.testing.assert_allclose(values["x"], x)
+
+    json_point = compiled_model.unconstrain(
+        json.dumps({"sigma": 2.0, "x": x.tolist()})
+    )
+    np.testing.assert_allclose(json_point, point)
+
+    points = np.stack([point, point])
+    values = compiled_model.constrain(points, include_tp=True, include_gq=True, seed=1)
+    assert values["x"].shape == (2, 2, 3)
+    np.testing.assert_allclose(values["log_sigma"], np.log(2.0))
+    assert values["y"].shape == (2,)
+
+    with pytest.raises(RuntimeError, match="Missing value for parameter x"):
+        compiled_model.unconstrain({"sigma": 1.0})
+    with pytest.raises(RuntimeError, match="shape"):
+        compiled_model.unconstrain({"sigma": 1.0, "x": x.T})
+
+
+@pytest.mark.stan
+def test_stan_inits():
+    model = """
+    parameters {
+        real<lower=0> sigma;
+        vector[2] a;
+    }
+    model {
+        sigma ~ lognormal(10, 0.01);
+        a ~ normal(1000, 1);
+    }
+    """
+
+    compiled_model = nutpie.compile_stan_model(code=model)
+    init = {"sigma": np.exp(10), "a": np.array([1000.0, 1000.0])}
+
+    trace = nutpie.sample(
+        compiled_model.with_inits(init, jitter=0.1), chains=2, tune=0, draws=20
+    )
+    assert (np.abs(trace.posterior.a - 1000) < 10).all()
+
+    inits = [init, json.dumps({"sigma": np.exp(10), "a": [999.0, 1001.0]})]
+    trace = nutpie.sample(compiled_model.with_inits(inits), chains=2, tune=0, draws=20)
+    assert (np.abs(trace.posterior.a - 1000) < 10).all()
+
+    with pytest.raises(RuntimeError, match="No initial values for chain 2"):
+        nutpie.sample(compiled_model.with_inits(inits), chains=3, tune=0, draws=20)
+
+    # Optimizers and Pathfinder get the chain of their initial point too
+    per_chain = compiled_model.with_inits(inits)
+    result = per_chain.find_map(seed=1)
+    np.testing.assert_allclose(result.values["a"], [1000.0, 1000.0], atol=1e-3)
+    trace = per_chain.pathfinder(num_paths=2, num_draws=20, seed=1)
+    assert (np.abs(trace.posterior.a - 1000) < 10).all()
+    trace = per_chain.laplace(num_draws=20, seed=1)
+    assert (np.abs(trace.posterior.a - 1000) < 10).all()
+    with pytest.raises(RuntimeError, match="No initial values for chain 2"):
+        per_chain.pathfinder(num_paths=3, num_draws=20, seed=1)
+    with pytest.raises(RuntimeError, match="jitter"):
+        compiled_model.with_inits(init, jitter=-1.0)
+    with pytest.raises(RuntimeError, match="chain 1"):
+        compiled_model.with_inits([init, {"sigma": 1.0}])
+    with pytest.raises(ValueError, match="init_mean"):
+        nutpie.sample(
+            compiled_model, chains=2, tune=0, draws=20, init_mean=np.ones(3)
+        )
+
+
+@pytest.mark.stan
+def test_stan_find_map():
+    model = """
+    parameters {
+        real<lower=0> sigma;
+        vector[2] a;
+    }
+    model {
+        sigma ~ lognormal(0, 1);
+        a ~ normal(5, 2);
+    }
+    generated quantities {
+        real b = a[1] + a[2];
+    }
+    """
+
+    compiled_model = nutpie.compile_stan_model(code=model)
+    result = compiled_model.find_map(seed=1)
+    assert result.converged
+    np.testing.assert_allclose(result.values["sigma"], 1.0, atol=1e-5)
+    np.testing.assert_allclose(result.values["a"], [5.0, 5.0], atol=1e-5)
+    np.testing.assert_allclose(result.values["b"], 10.0, atol=1e-4)
+
+
+@pytest.mark.stan
+def test_stan_laplace():
+    model = """
+    parameters {
+        vector[2] a;
+    }
+    model {
+        a ~ multi_normal([1, 2], [[1, 0.5], [0.5, 4]]);
+    }
+    generated quantities {
+        real b = a[1] + a[2];
+    }
+    """
+
+    compiled_model = nutpie.compile_stan_model(code=model)
+    trace = compiled_model.laplace(num_draws=100, seed=1)
+    assert trace.posterior.a.shape == (1, 100, 2)
+    np.testing.assert_allclose(
+        trace.posterior.b.values, trace.posterior.a.values.sum(-1), rtol=1e-10
+    )
+
+    result = compiled_model.laplace(num_draws=10, seed=1, return_raw=True)
+    np.testing.assert_allclose(result.mode, [1.0, 2.0], atol=1e-5)
+    np.testing.assert_allclose(
+        result.covariance, [[1.0, 0.5], [0.5, 4.0]], rtol=1e-3, atol=1e-4
+    )
+
+
+@pytest.mark.stan
+def test_stan_pathfinder():
+    model = """
+    parameters {
+        real<lower=0> sigma;
+        vector[2] a;
+    }
+    model {
+        sigma ~ lognormal(0, 1);
+        a ~ normal(5, 2);
+    }
+    generated quantities {
+        real b = a[1] + a[2];
+    }
+    """
+
+    compiled_model = nutpie.compile_stan_model(code=model)
+    trace = compiled_model.pathfinder(num_paths=2, num_draws=100, seed=1)
+    assert trace.posterior.a.shape == (2, 100, 2)
+    assert trace.posterior.b.shape == (2, 100)
+    assert (trace.posterior.sigma > 0).all()
+    assert np.isfinite(trace.sample_stats.log_weight).all()
+
+    trace = nutpie.sample(
+        compiled_model, chains=2, tune=100, draws=100, pathfinder=True
+    )
+    assert trace.posterior.a.shape == (2, 100, 2)
+
+
 @pytest.mark.stan
 def test_stan_memory_order():
     model = """